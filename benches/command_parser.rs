@@ -0,0 +1,40 @@
+//! Benchmarks parsing commands out of request URIs, without evaluating them.
+//!
+//! Run them with `cargo bench --bench command_parser`.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use ezproxy::command_parser::{CommandParse, CommandParser};
+use hyper::Uri;
+
+/// Representative request URIs, named for the benchmark report.
+fn uris() -> Vec<(&'static str, Uri)> {
+  let long_query = (1..=100)
+    .map(|i| format!("word{}", i))
+    .collect::<Vec<_>>()
+    .join("+");
+  vec![
+    ("no_args", "/?q=m".parse().unwrap()),
+    ("multi_arg", "/?q=npm+file+finder+cli".parse().unwrap()),
+    (
+      "url_encoded",
+      "/?q=g%20%22caf%C3%A9%20%26%20cr%C3%AApes%22%20%2Bparis"
+        .parse()
+        .unwrap(),
+    ),
+    ("100_args", format!("/?q=g+{}", long_query).parse().unwrap()),
+  ]
+}
+
+fn parse(c: &mut Criterion) {
+  let parser = CommandParser::default();
+  let mut group = c.benchmark_group("CommandParser::parse");
+  for (name, uri) in uris() {
+    group.bench_with_input(BenchmarkId::from_parameter(name), &uri, |b, uri| {
+      b.iter(|| parser.parse(black_box(uri)).unwrap())
+    });
+  }
+  group.finish();
+}
+
+criterion_group!(benches, parse);
+criterion_main!(benches);
@@ -0,0 +1,79 @@
+//! Benchmarks evaluating commands against registries of different sizes, for keywords that have a
+//! rule, that fall back to the default rule, and that have no rule at all, and cloning them as
+//! each connection does.
+//!
+//! Run them with `cargo bench --bench redirector`.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use ezproxy::config::ConfigRule;
+use ezproxy::redirector::Redirector;
+use ezproxy::rules::{RuleRegistry, DEFAULT_RULE_KEY};
+use ezproxy::server::AppContext;
+use hyper::Uri;
+
+/// A redirector with `size` rules, `kw0` to `kw{size - 1}`, and a default rule if `with_default`.
+fn redirector(size: usize, with_default: bool) -> Redirector {
+  let mut rules = RuleRegistry::new();
+  for i in 0..size {
+    let kw = format!("kw{}", i);
+    let template = format!("https://example.com/{}?q={{ARGS}}", i);
+    rules.insert(kw.clone(), Box::new(ConfigRule::new(kw, template)));
+  }
+  if with_default {
+    rules.insert(
+      DEFAULT_RULE_KEY,
+      Box::new(ConfigRule::new(
+        DEFAULT_RULE_KEY,
+        "https://www.google.com/search?q={ALL}",
+      )),
+    );
+  }
+  Redirector::with_rules(rules, None)
+}
+
+fn evaluate(c: &mut Criterion) {
+  let mut group = c.benchmark_group("Redirector::evaluate");
+  // Report evaluations per second.
+  group.throughput(Throughput::Elements(1));
+  for size in [10, 100, 1000] {
+    let hit: Uri = format!("/?q=kw{}+rust+async", size / 2).parse().unwrap();
+    let miss: Uri = "/?q=best+pizza+near+me".parse().unwrap();
+
+    let with_default = redirector(size, true);
+    group.bench_with_input(BenchmarkId::new("hit", size), &hit, |b, uri| {
+      b.iter(|| with_default.evaluate(black_box(uri)).unwrap())
+    });
+    group.bench_with_input(BenchmarkId::new("default", size), &miss, |b, uri| {
+      b.iter(|| with_default.evaluate(black_box(uri)).unwrap())
+    });
+
+    let without_default = redirector(size, false);
+    group.bench_with_input(BenchmarkId::new("no_rule", size), &miss, |b, uri| {
+      b.iter(|| without_default.evaluate(black_box(uri)).unwrap_err())
+    });
+  }
+  group.finish();
+}
+
+fn clone(c: &mut Criterion) {
+  // The rules are shared rather than copied, so neither should get slower as the registry grows.
+  let mut group = c.benchmark_group("clone");
+  for size in [10, 100, 1000] {
+    let redirector = redirector(size, true);
+    group.bench_with_input(
+      BenchmarkId::new("Redirector", size),
+      &redirector,
+      |b, redirector| b.iter(|| black_box(redirector).clone()),
+    );
+    let context = AppContext::new(redirector);
+    group.bench_with_input(
+      BenchmarkId::new("AppContext", size),
+      &context,
+      |b, context| b.iter(|| black_box(context).clone()),
+    );
+  }
+  group.finish();
+}
+
+criterion_group!(benches, evaluate, clone);
+criterion_main!(benches);
@@ -0,0 +1,44 @@
+//! Serves ezproxy's shortcuts alongside an application's own routes.
+//!
+//! Run it with `cargo run --example embed`, then try http://localhost:5050/?q=gh+rust-lang
+//! or http://localhost:5050/hello.
+
+use ezproxy::prelude::*;
+use ezproxy::server::{handle, AppContext};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+
+async fn route(context: AppContext, req: Request<Body>) -> http::Result<Response<Body>> {
+  match req.uri().path() {
+    "/hello" => Response::builder().body(Body::from("Hello from the host application!\n")),
+    // Everything else, including /healthz, /metrics and /stats, goes to ezproxy.
+    _ => handle(context, req).await,
+  }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), hyper::Error> {
+  let redirector = Redirector::builder()
+    .with_rule("gh", ConfigRule::new("gh", "https://github.com/{ARG1}"))
+    .with_rule(
+      "npm",
+      ConfigRule::new("npm", "https://npmjs.com/search?q={ARGS}"),
+    )
+    .with_default(ConfigRule::new(
+      "_",
+      "https://www.google.com/search?q={ALL}",
+    ))
+    .build();
+  let context = AppContext::new(redirector).with_health_path("/healthz");
+
+  let make_svc = make_service_fn(move |_conn| {
+    let context = context.clone();
+    async move { Ok::<_, Infallible>(service_fn(move |req| route(context.clone(), req))) }
+  });
+
+  let addr = SocketAddr::from(([127, 0, 0, 1], 5050));
+  println!("Listening on http://{}", addr);
+  Server::bind(&addr).serve(make_svc).await
+}
@@ -0,0 +1,45 @@
+//! Feeds arbitrary query strings to `CommandParser::parse`, which must return rather than panic.
+//!
+//! Run it with `cargo +nightly fuzz run command_parser` from the repository root.
+
+#![no_main]
+
+use ezproxy::command_parser::{CommandParse, CommandParser, CommandParserConfig, RoutingMode};
+use hyper::Uri;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+  // Percent-encode the bytes that can't appear in a URI, such as null bytes and spaces, so that
+  // every input makes it to the parser. Everything else, including `%`, `=`, `&` and `+`, is kept
+  // as is for the parser to decode.
+  let query: String = data
+    .iter()
+    .map(|&b| match b {
+      b'!' | b'$'..=b';' | b'=' | b'?'..=b'Z' | b'_' | b'a'..=b'z' | b'~' => {
+        (b as char).to_string()
+      }
+      _ => format!("%{:02X}", b),
+    })
+    .collect();
+  let uri: Uri = match format!("/?{}", query).parse() {
+    Ok(uri) => uri,
+    Err(_) => return,
+  };
+  let path: Uri = match format!("/{}", query).parse() {
+    Ok(path) => path,
+    Err(_) => return,
+  };
+
+  for routing_mode in [RoutingMode::Query, RoutingMode::Path, RoutingMode::Bang] {
+    let parser = CommandParser::from(CommandParserConfig {
+      routing_mode,
+      ..CommandParserConfig::default()
+    });
+    let _ = parser.parse(&uri);
+    let _ = parser.parse(&path);
+  }
+  // Query strings may also arrive as a posted form.
+  if let Ok(form) = std::str::from_utf8(data) {
+    let _ = CommandParser::default().parse_from_params(&querystring::querify(form));
+  }
+});
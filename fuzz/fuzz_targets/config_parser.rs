@@ -0,0 +1,30 @@
+//! Feeds arbitrary config files to `parse_rules_from_str`, which must return rather than panic.
+//!
+//! Run it with `cargo +nightly fuzz run config_parser` from the repository root.
+
+#![no_main]
+
+use ezproxy::config::{
+  parse_rules_from_json_str, parse_rules_from_str, parse_rules_from_str_with_options,
+  parse_rules_from_toml_str, rules_to_string, DuplicatePolicy, ParseOptions,
+};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+  // Config files are read as UTF-8, so invalid bytes never reach the parser. Null bytes do.
+  let config = String::from_utf8_lossy(data);
+
+  if let Ok(rules) = parse_rules_from_str(&config) {
+    // Whatever parses can be written back out.
+    let _ = rules_to_string(&rules);
+  }
+  let options = ParseOptions::default()
+    .on_duplicate(DuplicatePolicy::KeepLast)
+    .default_scheme("https")
+    .ignore_invalid_rules(true)
+    .case_insensitive(true);
+  let _ = parse_rules_from_str_with_options(&config, &options);
+  // TOML and JSON entries are turned into lines of the same format.
+  let _ = parse_rules_from_toml_str(&config);
+  let _ = parse_rules_from_json_str(&config);
+});
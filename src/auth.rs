@@ -0,0 +1,127 @@
+//! HTTP Basic authentication, for keeping ezproxy to yourself on a shared network.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use http::HeaderValue;
+use subtle::ConstantTimeEq;
+
+/// The only user name and password allowed to use ezproxy.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::auth::BasicAuth;
+/// use http::HeaderValue;
+///
+/// let auth = BasicAuth::new("travis", "hunter2");
+/// // "travis:hunter2", base64-encoded.
+/// let header = HeaderValue::from_static("Basic dHJhdmlzOmh1bnRlcjI=");
+/// assert!(auth.is_authorized(Some(&header)));
+/// assert!(!auth.is_authorized(None));
+/// ```
+#[derive(Clone)]
+pub struct BasicAuth {
+  user: String,
+  password: String,
+}
+
+impl std::fmt::Debug for BasicAuth {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("BasicAuth")
+      .field("user", &self.user)
+      .finish_non_exhaustive()
+  }
+}
+
+impl BasicAuth {
+  /// The realm sent in the `WWW-Authenticate` header of unauthorized responses.
+  pub const REALM: &'static str = "ezproxy";
+
+  /// Creates credentials for `user` with `password`.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::auth::BasicAuth;
+  ///
+  /// let auth = BasicAuth::new("travis", "hunter2");
+  /// assert_eq!(auth.user(), "travis");
+  /// ```
+  pub fn new<U: Into<String>, P: Into<String>>(user: U, password: P) -> Self {
+    BasicAuth {
+      user: user.into(),
+      password: password.into(),
+    }
+  }
+
+  /// The user name.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::auth::BasicAuth;
+  ///
+  /// assert_eq!(BasicAuth::new("travis", "hunter2").user(), "travis");
+  /// ```
+  pub fn user(&self) -> &str {
+    &self.user
+  }
+
+  /// Returns true if `authorization`, the value of a request's `Authorization` header, has this
+  /// user name and password. The comparison takes the same time however much of them matches, so
+  /// it doesn't give away how close a guess was.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::auth::BasicAuth;
+  /// use http::HeaderValue;
+  ///
+  /// let auth = BasicAuth::new("travis", "hunter2");
+  /// // "travis:hunter3", base64-encoded.
+  /// let header = HeaderValue::from_static("Basic dHJhdmlzOmh1bnRlcjM=");
+  /// assert!(!auth.is_authorized(Some(&header)));
+  /// ```
+  pub fn is_authorized(&self, authorization: Option<&HeaderValue>) -> bool {
+    let credentials = authorization
+      .and_then(|value| value.to_str().ok())
+      .and_then(|value| value.split_once(' '))
+      .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("Basic"))
+      .and_then(|(_, encoded)| STANDARD.decode(encoded.trim()).ok());
+    let (user, password) = match credentials.as_deref().and_then(|decoded| {
+      decoded
+        .iter()
+        .position(|&b| b == b':')
+        .map(|i| decoded.split_at(i))
+    }) {
+      Some((user, password)) => (user, &password[1..]),
+      None => return false,
+    };
+    let user_matches = user.ct_eq(self.user.as_bytes());
+    let password_matches = password.ct_eq(self.password.as_bytes());
+    (user_matches & password_matches).into()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn header(credentials: &str) -> HeaderValue {
+    HeaderValue::from_str(&format!("Basic {}", STANDARD.encode(credentials))).unwrap()
+  }
+
+  #[test]
+  fn checks_user_and_password() {
+    let auth = BasicAuth::new("travis", "hunter2:with:colons");
+    assert!(auth.is_authorized(Some(&header("travis:hunter2:with:colons"))));
+    assert!(!auth.is_authorized(Some(&header("travis:hunter2"))));
+    assert!(!auth.is_authorized(Some(&header("bob:hunter2:with:colons"))));
+    assert!(!auth.is_authorized(Some(&header("travis"))));
+    assert!(!auth.is_authorized(Some(&HeaderValue::from_static("Basic not-base64!"))));
+    assert!(!auth.is_authorized(Some(&HeaderValue::from_static("Bearer dHJhdmlz"))));
+    let lowercase = format!("basic {}", STANDARD.encode("travis:hunter2:with:colons"));
+    assert!(auth.is_authorized(Some(&HeaderValue::from_str(&lowercase).unwrap())));
+    assert!(!format!("{:?}", auth).contains("hunter2"));
+  }
+}
@@ -0,0 +1,535 @@
+//! Parsing of request URIs into the [`Command`] typed into the address bar.
+use hyper::Uri;
+use std::str::FromStr;
+
+/// A command typed into the address bar, e.g. `npm file finder`.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::command_parser::{CommandParse, CommandParser};
+///
+/// let cmd = CommandParser::default().parse(&"/?q=npm%20file%20finder".parse().unwrap()).unwrap();
+/// assert_eq!(cmd.name, "npm");
+/// assert_eq!(cmd.args, vec!["file", "finder"]);
+/// assert!(!cmd.is_bang);
+/// assert_eq!(cmd.raw, "npm file finder");
+/// ```
+#[derive(Debug)]
+pub struct Command {
+  /// The command, used to look up the rule to evaluate.
+  pub name: String,
+  /// Everything typed after the command.
+  pub args: Vec<String>,
+  /// Whether the command was written DuckDuckGo bang-style, e.g. `!gh`. The `!` isn't part of
+  /// `name`.
+  pub is_bang: bool,
+  /// The query as typed, decoded but not yet split into the command and its arguments.
+  pub raw: String,
+}
+
+/// Turns a request URI into a [`Command`].
+///
+/// Implement this to support query formats other than the ones [`CommandParser`] understands,
+/// then hand it to [`Redirector::with_rules`](crate::redirector::Redirector::with_rules).
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::command_parser::{Command, CommandParse};
+/// use hyper::Uri;
+///
+/// /// Reads the command from the path, e.g. `/npm`.
+/// struct PathParser;
+/// impl CommandParse for PathParser {
+///   fn parse(&self, uri: &Uri) -> Result<Command, String> {
+///     let name = uri.path().trim_start_matches('/').to_string();
+///     Ok(Command {
+///       raw: name.clone(),
+///       name,
+///       args: vec![],
+///       is_bang: false,
+///     })
+///   }
+/// }
+///
+/// assert_eq!(PathParser.parse(&"/npm".parse().unwrap()).unwrap().name, "npm");
+/// ```
+pub trait CommandParse: Send + Sync {
+  /// Parses the command out of `uri`, returning an error message if it has none.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::command_parser::{CommandParse, CommandParser};
+  ///
+  /// assert!(CommandParser::default().parse(&"/?q=m".parse().unwrap()).is_ok());
+  /// assert!(CommandParser::default().parse(&"/".parse().unwrap()).is_err());
+  /// ```
+  fn parse(&self, uri: &Uri) -> Result<Command, String>;
+
+  /// Parses the command out of `params`, e.g. a form posted as
+  /// `application/x-www-form-urlencoded`. Each parameter is a name and value as split by
+  /// [`querystring::querify`], so both are still percent-encoded. Returns an error message if
+  /// there's no command.
+  ///
+  /// By default, the parameters are put into the query of a URI for [`CommandParse::parse`].
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::command_parser::{CommandParse, CommandParser};
+  ///
+  /// let cmd = CommandParser::default().parse_from_params(&[("q", "npm+tokio")]).unwrap();
+  /// assert_eq!(cmd.name, "npm");
+  /// assert_eq!(cmd.args, vec!["tokio"]);
+  /// ```
+  fn parse_from_params(&self, params: &[(&str, &str)]) -> Result<Command, String> {
+    let query = params
+      .iter()
+      .map(|(name, value)| format!("{}={}", name, value))
+      .collect::<Vec<_>>()
+      .join("&");
+    let uri = format!("/?{}", query)
+      .parse()
+      .map_err(|e| format!("Invalid parameters {:?}: {}", query, e))?;
+    self.parse(&uri)
+  }
+}
+
+/// Where in the request URI a [`CommandParser`] finds the command.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::command_parser::RoutingMode;
+///
+/// assert_eq!("path".parse::<RoutingMode>(), Ok(RoutingMode::Path));
+/// assert_eq!(RoutingMode::default(), RoutingMode::Query);
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RoutingMode {
+  /// In a query parameter, e.g. `/?q=npm+file+finder`. The command and its arguments are split on
+  /// the separator.
+  #[default]
+  Query,
+  /// In the path, e.g. `/npm/file+finder`. The first segment is the command, and each following
+  /// segment is an argument.
+  Path,
+  /// In a query parameter like [`RoutingMode::Query`], but a bang command, e.g. `!g`, may be
+  /// anywhere in the query: `best+pizza+!g` is the command `g` with the arguments `best pizza`.
+  /// Queries without a bang are parsed like [`RoutingMode::Query`].
+  Bang,
+}
+
+impl FromStr for RoutingMode {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "query" => Ok(RoutingMode::Query),
+      "path" => Ok(RoutingMode::Path),
+      "bang" => Ok(RoutingMode::Bang),
+      _ => Err(format!(
+        "Unknown routing mode {}: expected query, path or bang",
+        s
+      )),
+    }
+  }
+}
+
+/// Options for a [`CommandParser`].
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::command_parser::{CommandParse, CommandParser, CommandParserConfig};
+///
+/// let parser = CommandParser::from(CommandParserConfig {
+///   query_param: "search".to_string(),
+///   ..CommandParserConfig::default()
+/// });
+/// let cmd = parser.parse(&"/?search=npm%20tokio".parse().unwrap()).unwrap();
+/// assert_eq!(cmd.name, "npm");
+/// ```
+#[derive(Clone, Debug)]
+pub struct CommandParserConfig {
+  /// Separates the command from its arguments, and each argument from the next. Matched
+  /// literally, so it may be several characters long, e.g. `::`. Defaults to a space.
+  pub separator: String,
+  /// The query parameter holding the command. Defaults to `q`.
+  pub query_param: String,
+  /// Where to find the command. Defaults to [`RoutingMode::Query`].
+  pub routing_mode: RoutingMode,
+  /// Whether the command's name is lowercased, so that `NPM tokio` finds the `npm` rule. Its
+  /// arguments are left as typed. Defaults to `false`.
+  pub case_insensitive: bool,
+}
+
+impl Default for CommandParserConfig {
+  fn default() -> Self {
+    Self {
+      separator: " ".to_string(),
+      query_param: "q".to_string(),
+      routing_mode: RoutingMode::default(),
+      case_insensitive: false,
+    }
+  }
+}
+
+/// Parses commands from the `q` query parameter, as sent by a browser's search engine settings.
+///
+/// The command and its arguments are separated by spaces unless another separator is given with
+/// [`CommandParser::with_separator`]. Use a [`CommandParserConfig`] to read the command from
+/// another query parameter, or from the path.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::command_parser::{CommandParse, CommandParser};
+///
+/// let cmd = CommandParser::default().parse(&"/?q=!gh+rust".parse().unwrap()).unwrap();
+/// assert_eq!(cmd.name, "gh");
+/// assert_eq!(cmd.args, vec!["rust"]);
+/// assert!(cmd.is_bang);
+/// ```
+#[derive(Debug)]
+pub struct CommandParser {
+  /// Separates the command from its arguments, and each argument from the next.
+  separator: String,
+  /// The query parameter holding the command.
+  query_param: String,
+  /// Where to find the command.
+  routing_mode: RoutingMode,
+  /// Whether the command's name is lowercased.
+  case_insensitive: bool,
+}
+
+impl Default for CommandParser {
+  fn default() -> Self {
+    CommandParserConfig::default().into()
+  }
+}
+
+impl From<CommandParserConfig> for CommandParser {
+  fn from(config: CommandParserConfig) -> Self {
+    Self {
+      separator: config.separator,
+      query_param: config.query_param,
+      routing_mode: config.routing_mode,
+      case_insensitive: config.case_insensitive,
+    }
+  }
+}
+
+impl CommandParser {
+  /// Creates a parser that splits queries on `separator` instead of spaces, e.g. `|` to parse
+  /// `yt|rust async` as the command `yt` with the single argument `rust async`. The separator is
+  /// matched literally and may be longer than one character.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::command_parser::{CommandParse, CommandParser};
+  ///
+  /// let cmd = CommandParser::with_separator('|')
+  ///   .parse(&"/?q=yt%7Crust%20async".parse().unwrap())
+  ///   .unwrap();
+  /// assert_eq!(cmd.name, "yt");
+  /// assert_eq!(cmd.args, vec!["rust async"]);
+  /// ```
+  pub fn with_separator<S: Into<String>>(separator: S) -> Self {
+    CommandParserConfig {
+      separator: separator.into(),
+      ..CommandParserConfig::default()
+    }
+    .into()
+  }
+}
+
+impl CommandParser {
+  /// Returns the decoded value of the query parameter in `params`, and the parts it splits into
+  /// on the separator.
+  fn split_params(&self, params: &[(&str, &str)]) -> Result<(String, Vec<String>), String> {
+    let query = params
+      .iter()
+      .find(|(name, _)| *name == self.query_param)
+      .map(|p| p.1.replace('+', " "))
+      .ok_or_else(|| format!("Could not find query param {}=...", self.query_param))?;
+
+    let decoded = urlencoding::decode(&query)
+      .map(|cow| cow.into_owned())
+      .map_err(|_| "Could not decode query".to_string())?;
+    // An empty separator would split between every character, so leave the query whole.
+    let parts = if self.separator.is_empty() {
+      vec![decoded.clone()]
+    } else {
+      decoded
+        .split(self.separator.as_str())
+        .map(|s| s.to_string())
+        .collect()
+    };
+    Ok((decoded, parts))
+  }
+
+  /// Returns the decoded path without its leading `/`, and its decoded, non-empty segments.
+  fn split_path(&self, uri: &Uri) -> Result<(String, Vec<String>), String> {
+    let parts = uri
+      .path()
+      .split('/')
+      .filter(|segment| !segment.is_empty())
+      .map(|segment| {
+        urlencoding::decode(&segment.replace('+', " "))
+          .map(|cow| cow.into_owned())
+          .map_err(|_| format!("Could not decode path in URI: {}", uri))
+      })
+      .collect::<Result<Vec<String>, String>>()?;
+    if parts.is_empty() {
+      return Err(format!("Could not find command in path of URI: {}", uri));
+    }
+    Ok((parts.join("/"), parts))
+  }
+}
+
+impl CommandParser {
+  /// Turns the decoded query and its parts into a command, moving a bang to the front first in
+  /// [`RoutingMode::Bang`].
+  fn command_from_parts(&self, decoded: String, mut parts: Vec<String>) -> Result<Command, String> {
+    if self.routing_mode == RoutingMode::Bang {
+      // Move the bang to the front, so it's parsed as the command.
+      if let Some(i) = parts.iter().position(|p| p.len() > 1 && p.starts_with('!')) {
+        let bang = parts.remove(i);
+        parts.insert(0, bang);
+      }
+    }
+    let (mut name, args) = match &parts[..] {
+      [] => return Err("Malformed query".to_string()),
+      [name] => (String::from(name), vec![]),
+      p => {
+        let name = p[0].to_string();
+        let args = p[1..].iter().map(|s| s.to_string()).collect();
+        (name, args)
+      }
+    };
+    if self.case_insensitive {
+      name = name.to_lowercase();
+    }
+    match name.strip_prefix('!') {
+      Some(bang) if !bang.is_empty() => Ok(Command {
+        name: bang.to_string(),
+        args,
+        is_bang: true,
+        raw: decoded,
+      }),
+      _ => Ok(Command {
+        name,
+        args,
+        is_bang: false,
+        raw: decoded,
+      }),
+    }
+  }
+}
+
+impl CommandParse for CommandParser {
+  fn parse(&self, uri: &Uri) -> Result<Command, String> {
+    log::debug!(target: "ezproxy::command_parser", "Attempt parse {}", uri);
+
+    let in_uri = |e| format!("{} in URI: {}", e, uri);
+    match self.routing_mode {
+      RoutingMode::Query | RoutingMode::Bang => {
+        let params = uri.query().map(querystring::querify).unwrap_or_default();
+        self.parse_from_params(&params).map_err(in_uri)
+      }
+      RoutingMode::Path => {
+        let (decoded, parts) = self.split_path(uri)?;
+        self.command_from_parts(decoded, parts).map_err(in_uri)
+      }
+    }
+  }
+
+  /// Parses the command out of the query parameter in `params`, whatever the routing mode.
+  fn parse_from_params(&self, params: &[(&str, &str)]) -> Result<Command, String> {
+    let (decoded, parts) = self.split_params(params)?;
+    self.command_from_parts(decoded, parts)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_bang_command() {
+    let cmd = CommandParser::default()
+      .parse(&"/?q=!gh%20rust%20async".parse().unwrap())
+      .unwrap();
+    assert_eq!(cmd.name, "gh");
+    assert_eq!(cmd.args, vec!["rust", "async"]);
+    assert!(cmd.is_bang);
+    assert_eq!(cmd.raw, "!gh rust async");
+
+    let cmd = CommandParser::default()
+      .parse(&"/?q=gh%20rust".parse().unwrap())
+      .unwrap();
+    assert_eq!(cmd.name, "gh");
+    assert!(!cmd.is_bang);
+
+    let cmd = CommandParser::default()
+      .parse(&"/?q=!".parse().unwrap())
+      .unwrap();
+    assert_eq!(cmd.name, "!");
+    assert!(!cmd.is_bang);
+  }
+
+  #[test]
+  fn parse_case_insensitive() {
+    let uri = "/?q=NPM+File+Finder".parse().unwrap();
+    let cmd = CommandParser::default().parse(&uri).unwrap();
+    assert_eq!(cmd.name, "NPM");
+    let parser = CommandParser::from(CommandParserConfig {
+      case_insensitive: true,
+      ..CommandParserConfig::default()
+    });
+    let cmd = parser.parse(&uri).unwrap();
+    assert_eq!(cmd.name, "npm");
+    assert_eq!(cmd.args, vec!["File", "Finder"]);
+    let cmd = parser.parse(&"/?q=!DDG+Rust".parse().unwrap()).unwrap();
+    assert_eq!(cmd.name, "ddg");
+    assert!(cmd.is_bang);
+  }
+
+  #[test]
+  fn parse_bang_routing_mode() {
+    let parser = CommandParser::from(CommandParserConfig {
+      routing_mode: RoutingMode::Bang,
+      ..CommandParserConfig::default()
+    });
+    for query in ["best+pizza+!g", "!g+best+pizza", "best+!g+pizza"] {
+      let cmd = parser
+        .parse(&format!("/?q={}", query).parse().unwrap())
+        .unwrap();
+      assert_eq!(cmd.name, "g", "{}", query);
+      assert_eq!(cmd.args, vec!["best", "pizza"], "{}", query);
+      assert!(cmd.is_bang);
+      assert_eq!(cmd.raw, query.replace('+', " "));
+    }
+
+    let cmd = parser.parse(&"/?q=npm+tokio+!".parse().unwrap()).unwrap();
+    assert_eq!(cmd.name, "npm");
+    assert_eq!(cmd.args, vec!["tokio", "!"]);
+    assert!(!cmd.is_bang);
+  }
+
+  #[test]
+  fn parse_errors_include_uri() {
+    let err = CommandParser::default()
+      .parse(&"/search?query=npm".parse().unwrap())
+      .unwrap_err();
+    assert_eq!(
+      err,
+      "Could not find query param q=... in URI: /search?query=npm"
+    );
+
+    let err = CommandParser::default()
+      .parse(&"/?q=%FF".parse().unwrap())
+      .unwrap_err();
+    assert_eq!(err, "Could not decode query in URI: /?q=%FF");
+  }
+
+  #[test]
+  fn parse_with_query_param() {
+    let parser = CommandParser::from(CommandParserConfig {
+      query_param: "query".to_string(),
+      ..CommandParserConfig::default()
+    });
+    let cmd = parser
+      .parse(&"/?q=m&query=npm%20tokio".parse().unwrap())
+      .unwrap();
+    assert_eq!(cmd.name, "npm");
+    assert_eq!(cmd.args, vec!["tokio"]);
+
+    let err = parser.parse(&"/?q=npm".parse().unwrap()).unwrap_err();
+    assert_eq!(err, "Could not find query param query=... in URI: /?q=npm");
+  }
+
+  #[test]
+  fn parse_from_params() {
+    let cmd = CommandParser::default()
+      .parse_from_params(&querystring::querify("lang=en&q=gh+rust%2Dlang"))
+      .unwrap();
+    assert_eq!(cmd.name, "gh");
+    assert_eq!(cmd.args, vec!["rust-lang"]);
+
+    let parser = CommandParser::from(CommandParserConfig {
+      routing_mode: RoutingMode::Path,
+      ..CommandParserConfig::default()
+    });
+    let cmd = parser.parse_from_params(&[("q", "!npm")]).unwrap();
+    assert_eq!(cmd.name, "npm");
+    assert!(cmd.is_bang);
+
+    let err = parser.parse_from_params(&[("query", "npm")]).unwrap_err();
+    assert_eq!(err, "Could not find query param q=...");
+  }
+
+  #[test]
+  fn parse_path() {
+    let parser = CommandParser::from(CommandParserConfig {
+      routing_mode: RoutingMode::Path,
+      ..CommandParserConfig::default()
+    });
+    let cmd = parser
+      .parse(&"/npm/file+finder/%E2%9C%93/".parse().unwrap())
+      .unwrap();
+    assert_eq!(cmd.name, "npm");
+    assert_eq!(cmd.args, vec!["file finder", "✓"]);
+    assert_eq!(cmd.raw, "npm/file finder/✓");
+
+    let cmd = parser.parse(&"/!gh?q=ignored".parse().unwrap()).unwrap();
+    assert_eq!(cmd.name, "gh");
+    assert!(cmd.is_bang);
+    assert!(cmd.args.is_empty());
+
+    let err = parser.parse(&"/".parse().unwrap()).unwrap_err();
+    assert_eq!(err, "Could not find command in path of URI: /");
+  }
+
+  #[test]
+  fn parse_with_separator() {
+    let cmd = CommandParser::with_separator('|')
+      .parse(&"/?q=yt%7Crust%20async".parse().unwrap())
+      .unwrap();
+    assert_eq!(cmd.name, "yt");
+    assert_eq!(cmd.args, vec!["rust async"]);
+    assert_eq!(cmd.raw, "yt|rust async");
+
+    let cmd = CommandParser::with_separator('|')
+      .parse(&"/?q=gh%7Ctokio%7Cissues".parse().unwrap())
+      .unwrap();
+    assert_eq!(cmd.name, "gh");
+    assert_eq!(cmd.args, vec!["tokio", "issues"]);
+  }
+
+  #[test]
+  fn parse_with_multi_character_separator() {
+    let cmd = CommandParser::with_separator("::")
+      .parse(&"/?q=go%20to::rust%20book::ch1".parse().unwrap())
+      .unwrap();
+    assert_eq!(cmd.name, "go to");
+    assert_eq!(cmd.args, vec!["rust book", "ch1"]);
+
+    // Separators are matched literally, not as regular expressions.
+    let cmd = CommandParser::with_separator(".*")
+      .parse(&"/?q=gh.*tokio.rs".parse().unwrap())
+      .unwrap();
+    assert_eq!(cmd.name, "gh");
+    assert_eq!(cmd.args, vec!["tokio.rs"]);
+
+    let cmd = CommandParser::with_separator("\t")
+      .parse(&"/?q=npm%09file%20finder".parse().unwrap())
+      .unwrap();
+    assert_eq!(cmd.name, "npm");
+    assert_eq!(cmd.args, vec!["file finder"]);
+  }
+}
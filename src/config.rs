@@ -1,91 +1,3229 @@
-use crate::rules::Rule;
+//! Parsing of ezproxy config files.
+//!
+//! A config file has one rule per line, written as `<keyword> = <url>`. See [`ConfigRule`] for the
+//! placeholders a URL may contain. Several keywords can share a rule by separating them with
+//! commas, e.g. `g,google = https://www.google.com/search?q={ARGS}`. Blank lines and lines starting with `#` are ignored, so they can
+//! be used for comments.
+//!
+//! A keyword starting with `re:` is a regular expression matched against commands with no exact
+//! rule; see [`RegexRule`].
+//!
+//! Several URLs separated by ` | ` are taken in turn; see [`LoadBalancerRule`].
+//!
+//! URLs separated by ` | ` may instead each be followed by a time of day range, or `[*]` for any
+//! time, to redirect to the first one whose range contains the current time; see [`TimedRule`].
+//!
+//! Related rules can be grouped under a `[name]` line, and a group's `prefix = <url>` line sets the
+//! URL its rules can refer to as `{prefix}`:
+//!
+//! ```text
+//! [github]
+//! prefix = https://github.com/
+//! issues = {prefix}search?q={ARGS}&type=issues
+//! prs = {prefix}search?q={ARGS}&type=pullrequests
+//! ```
+//!
+//! A rule can delegate to another by giving the command to run and the keyword of its rule,
+//! separated by ` -> `, e.g. `issues = gh issues {ARGS} -> gh`; see [`ChainedRule`].
+//!
+//! A `_fallback_order = <keyword>,<keyword>,...` line lists the rules to try, in order, for
+//! commands no other rule matches; see [`FallbackOrder`].
+//!
+//! A URL starting with `script:` runs a program to decide where to redirect to, optionally with a
+//! timeout in seconds, e.g. `g = script:/usr/local/bin/route.sh 2`; see [`ScriptRule`].
+//!
+//! Rules redirect with a 302 by default. To use another redirect status, put it in brackets after
+//! the keyword, e.g. `m[301] = https://gmail.com/`.
+//!
+//! A config file whose name ends in `.toml` is read as TOML instead, with each rule an entry in its
+//! `[rules]` table; see [`parse_rules_from_toml`]. One ending in `.json` is read as JSON in the same
+//! shape; see [`parse_rules_from_json`].
+use crate::rules::{
+  AsyncRule, ChainedRule, FallbackOrder, LoadBalancerRule, RegexRule, Rule, RuleDescription,
+  RuleError, RuleRegistry, ScriptRule, TimedRule, DEFAULT_STATUS_CODE, FALLBACK_ORDER_KEY,
+  REDIRECT_STATUS_CODES, REGEX_RULE_PREFIX, SCRIPT_RULE_PREFIX,
+};
+use hyper::http::uri::InvalidUri;
 use hyper::Uri;
+use indexmap::IndexMap;
 use lazy_static::lazy_static;
 use log;
 use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::fmt;
 use std::fs;
-use std::path::Path;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use toml::Spanned;
+use unicode_normalization::UnicodeNormalization;
 
-/// TODO:
-/// - Support comments
-/// - Support things like default URL vs. having ARGS (see commented-out YT)
-/// - Maybe rule needs to have produce_default() and produce_args()?
-pub fn parse_rules_from<P: AsRef<Path>>(path: P) -> HashMap<String, Box<dyn Rule>> {
+/// What to do when a config file has more than one rule for the same keyword.
+///
+/// Parses from the names used by the `--on-duplicate` flag: `error`, `warn-keep-first` and
+/// `warn-keep-last`.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::config::{DuplicatePolicy, ParseOptions};
+///
+/// let policy: DuplicatePolicy = "warn-keep-first".parse().unwrap();
+/// assert_eq!(policy, DuplicatePolicy::KeepFirst);
+///
+/// let options = ParseOptions::default().on_duplicate(policy);
+/// assert_eq!(options.duplicate_policy(), DuplicatePolicy::KeepFirst);
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+  /// Duplicates are a config error.
+  #[default]
+  Error,
+  /// The rule on the first line wins, with a warning.
+  KeepFirst,
+  /// The rule on the last line wins, with a warning.
+  KeepLast,
+}
+
+impl FromStr for DuplicatePolicy {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "error" => Ok(DuplicatePolicy::Error),
+      "warn-keep-first" => Ok(DuplicatePolicy::KeepFirst),
+      "warn-keep-last" => Ok(DuplicatePolicy::KeepLast),
+      _ => Err(format!(
+        "Unknown duplicate policy {}: expected error, warn-keep-first or warn-keep-last",
+        s
+      )),
+    }
+  }
+}
+
+/// Options controlling how [`parse_rules_from_with_options`] reads a config file.
+///
+/// `ParseOptions::default()` matches the behavior of [`parse_rules_from`].
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::config::{DuplicatePolicy, ParseOptions};
+///
+/// let options = ParseOptions::default()
+///   .on_duplicate(DuplicatePolicy::KeepLast)
+///   .default_scheme("https");
+/// assert_eq!(options.scheme(), Some("https"));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ParseOptions {
+  on_duplicate: DuplicatePolicy,
+  default_scheme: Option<String>,
+  ignore_invalid_rules: bool,
+  case_insensitive: bool,
+}
+
+impl ParseOptions {
+  /// Sets what happens when a keyword has more than one rule. Defaults to
+  /// [`DuplicatePolicy::Error`].
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::{DuplicatePolicy, ParseOptions};
+  ///
+  /// let options = ParseOptions::default().on_duplicate(DuplicatePolicy::KeepLast);
+  /// assert_eq!(options.duplicate_policy(), DuplicatePolicy::KeepLast);
+  /// ```
+  pub fn on_duplicate(mut self, policy: DuplicatePolicy) -> Self {
+    self.on_duplicate = policy;
+    self
+  }
+
+  /// Sets a scheme to prepend to URLs written without one, so that `m = gmail.com` redirects to
+  /// `https://gmail.com` with a default scheme of `https`. By default, URLs are used as written.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ParseOptions;
+  ///
+  /// assert_eq!(ParseOptions::default().default_scheme("https").scheme(), Some("https"));
+  /// ```
+  pub fn default_scheme<S: Into<String>>(mut self, scheme: S) -> Self {
+    self.default_scheme = Some(scheme.into());
+    self
+  }
+
+  /// Sets whether rules whose URL isn't valid are left out with a warning, rather than being a
+  /// [`ConfigError::InvalidUri`]. Defaults to `false`.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::{parse_rules_from_str_with_options, ParseOptions};
+  ///
+  /// let config = "m = https://gmail.com/\nc = https://calendar google.com/\n";
+  /// let options = ParseOptions::default().ignore_invalid_rules(true);
+  /// let rules = parse_rules_from_str_with_options(config, &options).unwrap();
+  /// assert!(rules.contains_key("m"));
+  /// assert!(!rules.contains_key("c"));
+  /// ```
+  pub fn ignore_invalid_rules(mut self, ignore: bool) -> Self {
+    self.ignore_invalid_rules = ignore;
+    self
+  }
+
+  /// Sets whether keywords are lowercased, so that they match commands typed in any case when
+  /// the [`CommandParser`](crate::command_parser::CommandParser) lowercases them too. Only
+  /// keywords are affected, not URLs or patterns. Defaults to `false`.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::{parse_rules_from_str_with_options, ParseOptions};
+  ///
+  /// let options = ParseOptions::default().case_insensitive(true);
+  /// let rules = parse_rules_from_str_with_options("NPM = https://npmjs.com/search?q={ARGS}\n", &options).unwrap();
+  /// assert!(rules.contains_key("npm"));
+  /// assert!(!rules.contains_key("NPM"));
+  /// ```
+  pub fn case_insensitive(mut self, case_insensitive: bool) -> Self {
+    self.case_insensitive = case_insensitive;
+    self
+  }
+
+  /// Returns what happens when a keyword has more than one rule.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::{DuplicatePolicy, ParseOptions};
+  ///
+  /// assert_eq!(ParseOptions::default().duplicate_policy(), DuplicatePolicy::Error);
+  /// ```
+  pub fn duplicate_policy(&self) -> DuplicatePolicy {
+    self.on_duplicate
+  }
+
+  /// Returns whether rules whose URL isn't valid are left out rather than being an error.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ParseOptions;
+  ///
+  /// assert!(!ParseOptions::default().ignores_invalid_rules());
+  /// ```
+  pub fn ignores_invalid_rules(&self) -> bool {
+    self.ignore_invalid_rules
+  }
+
+  /// Returns whether keywords are lowercased.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ParseOptions;
+  ///
+  /// assert!(!ParseOptions::default().is_case_insensitive());
+  /// ```
+  pub fn is_case_insensitive(&self) -> bool {
+    self.case_insensitive
+  }
+
+  /// Lowercases `keyword` if keywords are case-insensitive.
+  fn fold_case(&self, keyword: String) -> String {
+    if self.case_insensitive {
+      keyword.to_lowercase()
+    } else {
+      keyword
+    }
+  }
+
+  /// Returns the scheme prepended to URLs written without one, if any.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ParseOptions;
+  ///
+  /// assert_eq!(ParseOptions::default().scheme(), None);
+  /// ```
+  pub fn scheme(&self) -> Option<&str> {
+    self.default_scheme.as_deref()
+  }
+}
+
+/// An error reading rules from a config file.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::config::{parse_rules_from, ConfigError};
+///
+/// match parse_rules_from("/does/not/exist.txt") {
+///   Err(ConfigError::IoError(e)) => assert_eq!(e.kind(), std::io::ErrorKind::NotFound),
+///   _ => panic!("Expected an IoError"),
+/// }
+/// ```
+#[derive(Debug)]
+pub enum ConfigError {
+  /// The config file couldn't be read.
+  IoError(io::Error),
+  /// A line isn't of the form `<keyword> = <url>`.
+  MalformedLine {
+    /// The one-indexed line number.
+    line_number: usize,
+    /// The line as written.
+    content: String,
+  },
+  /// A rule's URL isn't a valid URI.
+  InvalidUri {
+    /// The one-indexed line number.
+    line_number: usize,
+    /// The rule's keyword as written, e.g. `g,google` for aliases.
+    keyword: String,
+    /// The URL as written.
+    uri: String,
+    /// Why the URL is invalid.
+    source: InvalidUri,
+  },
+  /// A comma-separated list of keywords has an empty keyword, e.g. `g,,google`.
+  EmptyAlias {
+    /// The one-indexed line number.
+    line_number: usize,
+    /// The list of keywords as written.
+    keywords: String,
+  },
+  /// A `re:` keyword isn't a valid regular expression.
+  InvalidPattern {
+    /// The one-indexed line number.
+    line_number: usize,
+    /// The pattern as written, without `re:`.
+    pattern: String,
+    /// Why the pattern is invalid.
+    source: regex::Error,
+  },
+  /// A keyword's status annotation, e.g. `[301]` in `m[301] = https://gmail.com/`, isn't a
+  /// redirect status code.
+  InvalidStatusCode {
+    /// The one-indexed line number.
+    line_number: usize,
+    /// The status as written.
+    status: String,
+  },
+  /// A keyword's argument limits, e.g. `[min=1]` in
+  /// `jira[min=1] = https://myco.atlassian.net/browse/{ARG1}`, aren't numbers, allow no argument
+  /// counts at all, or are given for a rule other than a single URL.
+  InvalidArgLimits {
+    /// The one-indexed line number.
+    line_number: usize,
+    /// The limits as written.
+    limits: String,
+  },
+  /// A `script:` rule isn't of the form `script:<path> [<timeout in seconds>]`.
+  InvalidScript {
+    /// The one-indexed line number.
+    line_number: usize,
+    /// The rule's target as written, without `script:`.
+    script: String,
+  },
+  /// A URL's time range, e.g. `[08:00-09:30]` in
+  /// `standup = https://meet.google.com/abc [08:00-09:30] | https://calendar.google.com/ [*]`,
+  /// isn't of the form `HH:MM-HH:MM` or `*`, or is missing.
+  InvalidTimeRange {
+    /// The one-indexed line number.
+    line_number: usize,
+    /// The range as written, or the URL missing one.
+    range: String,
+  },
+  /// A URL uses an environment variable, e.g. `${JIRA_HOST}`, that isn't set and has no default.
+  UndefinedEnvVar {
+    /// The one-indexed line number.
+    line_number: usize,
+    /// The name of the environment variable.
+    var: String,
+    /// The rule's keyword as written.
+    keyword: String,
+  },
+  /// A TOML config file isn't valid TOML, or its rules aren't URLs or tables with a `uri` and an
+  /// optional `status`.
+  InvalidToml(toml::de::Error),
+  /// A JSON config file isn't valid JSON, or its rules aren't URLs or objects with a `uri` and an
+  /// optional `status`.
+  InvalidJson(serde_json::Error),
+  /// A keyword has more than one rule under [`DuplicatePolicy::Error`].
+  DuplicateKeyword {
+    /// The duplicated keyword.
+    keyword: String,
+    /// The one-indexed line number of the keyword's first rule.
+    first_line: usize,
+    /// The one-indexed line number of the keyword's second rule.
+    second_line: usize,
+  },
+  /// A keyword has rules in more than one config file under [`DuplicatePolicy::Error`].
+  DuplicateKeywordInFiles {
+    /// The duplicated keyword.
+    keyword: String,
+    /// The file with the keyword's first rule.
+    first_path: PathBuf,
+    /// The file with the keyword's second rule.
+    second_path: PathBuf,
+  },
+  /// A rule given on its own rather than in a config file, e.g. with ezproxy's `--rule` flag,
+  /// couldn't be parsed.
+  InlineRule {
+    /// The one-indexed position of the rule among the inline rules.
+    position: usize,
+    /// The rule as written.
+    rule: String,
+    /// Why it couldn't be parsed.
+    source: Box<ConfigError>,
+  },
+  /// One of several config files couldn't be parsed; see [`parse_rules_from_multiple`].
+  InFile {
+    /// The file that couldn't be parsed.
+    path: PathBuf,
+    /// Why it couldn't be parsed.
+    source: Box<ConfigError>,
+  },
+}
+
+impl fmt::Display for ConfigError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ConfigError::IoError(e) => write!(f, "Could not read config: {}", e),
+      ConfigError::MalformedLine {
+        line_number,
+        content,
+      } => write!(
+        f,
+        "Malformed config line {}: expected (kw) = (url), got {}",
+        line_number, content
+      ),
+      ConfigError::InvalidUri {
+        line_number,
+        keyword,
+        uri,
+        source,
+      } => write!(
+        f,
+        "Invalid URL for {} on config line {}: {}: {}",
+        keyword, line_number, uri, source
+      ),
+      ConfigError::EmptyAlias {
+        line_number,
+        keywords,
+      } => write!(
+        f,
+        "Empty keyword on config line {}: {}",
+        line_number, keywords
+      ),
+      ConfigError::InvalidPattern {
+        line_number,
+        pattern,
+        source,
+      } => write!(
+        f,
+        "Invalid pattern on config line {}: {}: {}",
+        line_number, pattern, source
+      ),
+      ConfigError::InvalidStatusCode {
+        line_number,
+        status,
+      } => write!(
+        f,
+        "Invalid status code on config line {}: {} (expected one of 301, 302, 303, 307, 308)",
+        line_number, status
+      ),
+      ConfigError::InvalidArgLimits {
+        line_number,
+        limits,
+      } => write!(
+        f,
+        "Invalid argument limits on config line {}: {} (expected min=<n> and max=<n> with min no \
+         more than max, for a rule with a single URL)",
+        line_number, limits
+      ),
+      ConfigError::InvalidScript {
+        line_number,
+        script,
+      } => write!(
+        f,
+        "Invalid script on config line {}: expected script:(path) [(timeout in seconds)], got script:{}",
+        line_number, script
+      ),
+      ConfigError::InvalidTimeRange { line_number, range } => write!(
+        f,
+        "Invalid time range on config line {}: {} (expected [HH:MM-HH:MM] or [*] after each URL)",
+        line_number, range
+      ),
+      ConfigError::UndefinedEnvVar {
+        line_number,
+        var,
+        keyword,
+      } => write!(
+        f,
+        "Undefined environment variable {} in the URL for {} on config line {} (give it a default with ${{{}:-default}})",
+        var, keyword, line_number, var
+      ),
+      ConfigError::InvalidToml(e) => write!(f, "Invalid TOML config: {}", e),
+      ConfigError::InvalidJson(e) => write!(f, "Invalid JSON config: {}", e),
+      ConfigError::DuplicateKeyword {
+        keyword,
+        first_line,
+        second_line,
+      } => write!(
+        f,
+        "Duplicate rule for keyword {} on config lines {} and {}",
+        keyword, first_line, second_line
+      ),
+      ConfigError::DuplicateKeywordInFiles {
+        keyword,
+        first_path,
+        second_path,
+      } => write!(
+        f,
+        "Duplicate rule for keyword {} in config files {} and {}",
+        keyword,
+        first_path.display(),
+        second_path.display()
+      ),
+      ConfigError::InlineRule {
+        position,
+        rule,
+        source,
+      } => write!(
+        f,
+        "Invalid inline rule #{} ({}): {}",
+        position, rule, source
+      ),
+      ConfigError::InFile { path, source } => write!(f, "{}: {}", path.display(), source),
+    }
+  }
+}
+
+impl std::error::Error for ConfigError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      ConfigError::IoError(e) => Some(e),
+      ConfigError::InvalidUri { source, .. } => Some(source),
+      ConfigError::InvalidPattern { source, .. } => Some(source),
+      ConfigError::InvalidToml(e) => Some(e),
+      ConfigError::InvalidJson(e) => Some(e),
+      ConfigError::InlineRule { source, .. } => Some(source.as_ref()),
+      ConfigError::InFile { source, .. } => Some(source.as_ref()),
+      _ => None,
+    }
+  }
+}
+
+impl From<io::Error> for ConfigError {
+  fn from(e: io::Error) -> Self {
+    ConfigError::IoError(e)
+  }
+}
+
+/// Reads the config file at `path` and returns its rules, keyed by keyword in the order they appear
+/// in the file.
+///
+/// A file whose name ends in `.toml` is read as with [`parse_rules_from_toml`].
+///
+/// A keyword with more than one rule is an error, reported with the line numbers of both rules. Use
+/// [`parse_rules_from_with_options`] to change this and other parsing behavior.
+///
+/// # Errors
+///
+/// Returns an error if the file can't be read, if any line isn't of the form `<keyword> = <url>`,
+/// if a URL without placeholders isn't a valid URI, or if a keyword has more than one rule.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::config::parse_rules_from;
+///
+/// let path = std::env::temp_dir().join("ezproxy-doctest-parse-rules-from.txt");
+/// std::fs::write(&path, "m = https://gmail.com/\n_ = https://www.google.com/search?q={ALL}\n").unwrap();
+///
+/// let rules = parse_rules_from(&path).unwrap();
+/// assert_eq!(rules.len(), 2);
+/// assert_eq!(rules["m"].produce_uri("m", &[]).unwrap(), "https://gmail.com/");
+/// # std::fs::remove_file(&path).unwrap();
+/// ```
+pub fn parse_rules_from<P: AsRef<Path>>(path: P) -> Result<RuleRegistry, ConfigError> {
+  parse_rules_from_with_options(path, &ParseOptions::default())
+}
+
+/// Parses `content` in the config file format and returns its rules, keyed by keyword in the
+/// order they appear, as [`parse_rules_from`] does for a file.
+///
+/// # Errors
+///
+/// Returns the same errors as [`parse_rules_from`], other than [`ConfigError::IoError`].
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::config::parse_rules_from_str;
+///
+/// let rules = parse_rules_from_str("m = https://gmail.com/\n_ = https://www.google.com/search?q={ALL}\n").unwrap();
+/// assert_eq!(rules.len(), 2);
+/// assert_eq!(rules["m"].produce_uri("m", &[]).unwrap(), "https://gmail.com/");
+/// ```
+pub fn parse_rules_from_str(content: &str) -> Result<RuleRegistry, ConfigError> {
+  parse_rules_from_str_with_options(content, &ParseOptions::default())
+}
+
+/// Reads the config file at `path` as directed by `options` and returns its rules, keyed by
+/// keyword.
+///
+/// # Errors
+///
+/// Returns an error if the file can't be read, if any line isn't of the form `<keyword> = <url>`,
+/// if a URL without placeholders isn't a valid URI, or if a keyword is duplicated under
+/// [`DuplicatePolicy::Error`].
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::config::{parse_rules_from_with_options, DuplicatePolicy, ParseOptions};
+///
+/// let path = std::env::temp_dir().join("ezproxy-doctest-parse-rules-from-with-options.txt");
+/// std::fs::write(&path, "m = gmail.com\nm = mail.yahoo.com\n").unwrap();
+///
+/// let options = ParseOptions::default()
+///   .on_duplicate(DuplicatePolicy::KeepFirst)
+///   .default_scheme("https");
+/// let rules = parse_rules_from_with_options(&path, &options).unwrap();
+/// assert_eq!(rules["m"].produce_uri("m", &[]).unwrap(), "https://gmail.com/");
+/// # std::fs::remove_file(&path).unwrap();
+/// ```
+//
+// TODO:
+// - Support things like default URL vs. having ARGS (see commented-out YT)
+// - Maybe rule needs to have produce_default() and produce_args()?
+pub fn parse_rules_from_with_options<P: AsRef<Path>>(
+  path: P,
+  options: &ParseOptions,
+) -> Result<RuleRegistry, ConfigError> {
+  let (rules, errors) = check_rules_from(path, options);
+  match errors.into_iter().next() {
+    Some(e) => Err(e),
+    None => Ok(rules),
+  }
+}
+
+/// Parses `data` in the config file format as directed by `options` and returns its rules, keyed
+/// by keyword in the order they appear.
+///
+/// # Errors
+///
+/// Returns the same errors as [`parse_rules_from_with_options`], other than
+/// [`ConfigError::IoError`].
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::config::{parse_rules_from_str_with_options, ParseOptions};
+///
+/// let rules = parse_rules_from_str_with_options(
+///   "g = https://www.google.com/search?q={ARGS}",
+///   &ParseOptions::default(),
+/// )
+/// .unwrap();
+/// assert_eq!(
+///   rules["g"].produce_uri("g", &["rust".to_string()]).unwrap(),
+///   "https://www.google.com/search?q=rust"
+/// );
+/// ```
+pub fn parse_rules_from_str_with_options(
+  data: &str,
+  options: &ParseOptions,
+) -> Result<RuleRegistry, ConfigError> {
+  let (rules, errors) = check_rules_from_str(data, options);
+  match errors.into_iter().next() {
+    Some(e) => Err(e),
+    None => Ok(rules),
+  }
+}
+
+/// Reads the config file at `path` like [`parse_rules_from_with_options`], but rather than stopping
+/// at the first error, skips the lines with errors and returns every error along with the rules
+/// that could be parsed. Under [`DuplicatePolicy::Error`], the first rule for a duplicated keyword
+/// is kept.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::config::{check_rules_from, ConfigError, ParseOptions};
+///
+/// let path = std::env::temp_dir().join("ezproxy-doctest-check-rules-from.txt");
+/// std::fs::write(&path, "m = https://gmail.com/\nnot a rule\nm[200] = https://mail.yahoo.com/\n").unwrap();
+///
+/// let (rules, errors) = check_rules_from(&path, &ParseOptions::default());
+/// assert!(rules.contains_key("m"));
+/// assert!(matches!(errors[0], ConfigError::MalformedLine { line_number: 2, .. }));
+/// assert!(matches!(errors[1], ConfigError::InvalidStatusCode { line_number: 3, .. }));
+/// # std::fs::remove_file(&path).unwrap();
+/// ```
+pub fn check_rules_from<P: AsRef<Path>>(
+  path: P,
+  options: &ParseOptions,
+) -> (RuleRegistry, Vec<ConfigError>) {
+  let path = path.as_ref();
+  match fs::read_to_string(path) {
+    Ok(data) => match ConfigFormat::of(path) {
+      ConfigFormat::Lines => check_rules_from_str(&data, options),
+      ConfigFormat::Toml => check_rules_from_toml_str(&data, options),
+      ConfigFormat::Json => check_rules_from_json_str(&data, options),
+    },
+    Err(e) => (RuleRegistry::new(), vec![e.into()]),
+  }
+}
+
+/// The format of a config file, which is picked by its extension.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ConfigFormat {
+  /// One rule per line; the format of any file not ending in `.toml` or `.json`.
+  Lines,
+  Toml,
+  Json,
+}
+
+impl ConfigFormat {
+  fn of(path: &Path) -> Self {
+    match path.extension().and_then(|ext| ext.to_str()) {
+      Some(ext) if ext.eq_ignore_ascii_case("toml") => ConfigFormat::Toml,
+      Some(ext) if ext.eq_ignore_ascii_case("json") => ConfigFormat::Json,
+      _ => ConfigFormat::Lines,
+    }
+  }
+}
+
+/// A TOML config file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TomlConfig {
+  #[serde(default)]
+  rules: HashMap<String, Spanned<RuleEntry>>,
+}
+
+/// A JSON config file.
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct JsonConfig {
+  #[serde(default)]
+  rules: IndexMap<String, RuleEntry>,
+}
+
+/// An entry in a TOML or JSON config file's rules: either a rule's URL, or a table with its URL and
+/// redirect status.
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum RuleEntry {
+  Uri(String),
+  Table(RuleEntryTable),
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct RuleEntryTable {
+  uri: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  status: Option<u16>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  min_args: Option<usize>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  max_args: Option<usize>,
+}
+
+impl RuleEntry {
+  /// The entry for `rule`, or `None` if it has no template to write.
+  fn from_rule(rule: &dyn Rule) -> Option<Self> {
+    let template = rule.describe().template?;
+    let rule = match rule.downcast_ref::<EnvTemplateRule>() {
+      Some(env_rule) => &*env_rule.rule,
+      None => rule,
+    };
+    let annotation = match rule.downcast_ref::<ConfigRule>() {
+      Some(rule) => rule.annotation(),
+      None => Annotation {
+        status: Some(rule.status_code()).filter(|&status| status != DEFAULT_STATUS_CODE),
+        ..Annotation::default()
+      },
+    };
+    Some(if annotation == Annotation::default() {
+      RuleEntry::Uri(template)
+    } else {
+      RuleEntry::Table(RuleEntryTable {
+        uri: template,
+        status: annotation.status,
+        min_args: Some(annotation.min_args).filter(|&min_args| min_args > 0),
+        max_args: annotation.max_args,
+      })
+    })
+  }
+
+  /// The equivalent line of the line-based format for a rule for `kw`, so that every format
+  /// accepts the same rules.
+  fn into_line(self, kw: &str) -> String {
+    match self {
+      RuleEntry::Uri(uri) => format!("{} = {}", kw, uri),
+      RuleEntry::Table(table) => {
+        let annotation = Annotation {
+          status: table.status,
+          min_args: table.min_args.unwrap_or_default(),
+          max_args: table.max_args,
+        };
+        format!("{}{} = {}", kw, annotation, table.uri)
+      }
+    }
+  }
+}
+
+/// Reads the TOML config file at `path` and returns its rules, keyed by keyword in the order they
+/// appear in the file.
+///
+/// Each entry in the file's `[rules]` table is a rule for its key, which may list aliases or be a
+/// `re:` pattern as in the line-based format. An entry is either the rule's URL or a table with
+/// the URL as `uri` and, optionally, a redirect status as `status` and argument limits as
+/// `min_args` and `max_args`.
+///
+/// # Errors
+///
+/// Returns [`ConfigError::InvalidToml`] if the file isn't valid TOML or an entry isn't of either
+/// form, and otherwise the same errors as [`parse_rules_from`], with the line numbers of the
+/// offending entries.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::config::parse_rules_from_toml;
+///
+/// let path = std::env::temp_dir().join("ezproxy-doctest-parse-rules-from-toml.toml");
+/// std::fs::write(
+///   &path,
+///   "[rules]\ng = \"https://www.google.com/search?q={ARGS}\"\n\n[rules.gh]\nuri = \"https://github.com/{ARG1}\"\nstatus = 301\n",
+/// )
+/// .unwrap();
+///
+/// let rules = parse_rules_from_toml(&path).unwrap();
+/// assert_eq!(rules.keywords().collect::<Vec<_>>(), ["g", "gh"]);
+/// assert_eq!(rules["gh"].status_code(), 301);
+/// # std::fs::remove_file(&path).unwrap();
+/// ```
+pub fn parse_rules_from_toml<P: AsRef<Path>>(path: P) -> Result<RuleRegistry, ConfigError> {
+  parse_rules_from_toml_str(&fs::read_to_string(path)?)
+}
+
+/// Parses `content` as a TOML config file and returns its rules, as [`parse_rules_from_toml`] does
+/// for a file.
+///
+/// # Errors
+///
+/// Returns the same errors as [`parse_rules_from_toml`], other than [`ConfigError::IoError`].
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::config::parse_rules_from_toml_str;
+///
+/// let rules = parse_rules_from_toml_str("[rules]\nm = \"https://gmail.com/\"\n").unwrap();
+/// assert_eq!(rules["m"].produce_uri("m", &[]).unwrap(), "https://gmail.com/");
+/// ```
+pub fn parse_rules_from_toml_str(content: &str) -> Result<RuleRegistry, ConfigError> {
+  let (rules, errors) = check_rules_from_toml_str(content, &ParseOptions::default());
+  match errors.into_iter().next() {
+    Some(e) => Err(e),
+    None => Ok(rules),
+  }
+}
+
+/// Parses `data` as a TOML config file, collecting the rules of the entries that parse and the
+/// errors of those that don't.
+fn check_rules_from_toml_str(
+  data: &str,
+  options: &ParseOptions,
+) -> (RuleRegistry, Vec<ConfigError>) {
+  let config: TomlConfig = match toml::from_str(data) {
+    Ok(config) => config,
+    Err(e) => return (RuleRegistry::new(), vec![ConfigError::InvalidToml(e)]),
+  };
+  let mut entries: Vec<_> = config.rules.into_iter().collect();
+  entries.sort_by_key(|(_, rule)| rule.span().start);
+  let lines = entries
+    .into_iter()
+    .map(|(kw, rule)| {
+      let line_number = data[..rule.span().start].matches('\n').count() + 1;
+      (line_number, rule.into_inner().into_line(&kw))
+    })
+    .collect();
+  check_rule_lines(lines, options)
+}
+
+/// Reads the JSON config file at `path` and returns its rules, keyed by keyword in the order they
+/// appear in the file.
+///
+/// The file is an object whose `rules` object has an entry for each rule, keyed by its keyword(s)
+/// as in the line-based format. An entry is either the rule's URL or an object with the URL as
+/// `uri` and, optionally, a redirect status as `status` and argument limits as `min_args` and
+/// `max_args`:
+///
+/// ```json
+/// {"rules": {"g": "https://google.com/search?q={ARGS}", "m": {"uri": "https://gmail.com/", "status": 301}}}
+/// ```
+///
+/// # Errors
+///
+/// Returns [`ConfigError::InvalidJson`] if the file isn't valid JSON or an entry isn't of either
+/// form, and otherwise the same errors as [`parse_rules_from`]. As JSON is rarely written a rule
+/// per line, the "line number" of an error is the one-indexed position of its entry among the
+/// rules.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::config::parse_rules_from_json;
+///
+/// let path = std::env::temp_dir().join("ezproxy-doctest-parse-rules-from-json.json");
+/// std::fs::write(
+///   &path,
+///   r#"{"rules": {"g": "https://google.com/search?q={ARGS}", "m": {"uri": "https://gmail.com/", "status": 301}}}"#,
+/// )
+/// .unwrap();
+///
+/// let rules = parse_rules_from_json(&path).unwrap();
+/// assert_eq!(rules.keywords().collect::<Vec<_>>(), ["g", "m"]);
+/// assert_eq!(rules["m"].status_code(), 301);
+/// # std::fs::remove_file(&path).unwrap();
+/// ```
+pub fn parse_rules_from_json<P: AsRef<Path>>(path: P) -> Result<RuleRegistry, ConfigError> {
+  parse_rules_from_json_str(&fs::read_to_string(path)?)
+}
+
+/// Parses `content` as a JSON config file and returns its rules, as [`parse_rules_from_json`] does
+/// for a file.
+///
+/// # Errors
+///
+/// Returns the same errors as [`parse_rules_from_json`], other than [`ConfigError::IoError`].
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::config::parse_rules_from_json_str;
+///
+/// let rules = parse_rules_from_json_str(r#"{"rules": {"m": "https://gmail.com/"}}"#).unwrap();
+/// assert_eq!(rules["m"].produce_uri("m", &[]).unwrap(), "https://gmail.com/");
+/// ```
+pub fn parse_rules_from_json_str(content: &str) -> Result<RuleRegistry, ConfigError> {
+  let (rules, errors) = check_rules_from_json_str(content, &ParseOptions::default());
+  match errors.into_iter().next() {
+    Some(e) => Err(e),
+    None => Ok(rules),
+  }
+}
+
+/// Parses `data` as a JSON config file, collecting the rules of the entries that parse and the
+/// errors of those that don't.
+fn check_rules_from_json_str(
+  data: &str,
+  options: &ParseOptions,
+) -> (RuleRegistry, Vec<ConfigError>) {
+  let config: JsonConfig = match serde_json::from_str(data) {
+    Ok(config) => config,
+    Err(e) => return (RuleRegistry::new(), vec![ConfigError::InvalidJson(e)]),
+  };
+  let lines = config
+    .rules
+    .into_iter()
+    .enumerate()
+    .map(|(i, (kw, rule))| (i + 1, rule.into_line(&kw)))
+    .collect();
+  check_rule_lines(lines, options)
+}
+
+/// Parses `data`, collecting the rules of the lines that parse and the errors of those that don't.
+fn check_rules_from_str(data: &str, options: &ParseOptions) -> (RuleRegistry, Vec<ConfigError>) {
+  check_rule_lines(expand_groups(data), options)
+}
+
+/// Parses each of `lines`, given with its one-indexed line number, collecting the rules of the
+/// lines that parse and the errors of those that don't. Problems that don't stop a rule from
+/// loading, like duplicate keywords, are logged as warnings.
+fn check_rule_lines<S: AsRef<str>>(
+  lines: Vec<(usize, S)>,
+  options: &ParseOptions,
+) -> (RuleRegistry, Vec<ConfigError>) {
+  let (rules, errors, warnings) = read_rule_lines(lines, options);
+  for warning in warnings {
+    log::warn!("{}", warning);
+  }
+  (rules, errors)
+}
+
+/// Does the work of [`check_rule_lines`], returning the warnings instead of logging them.
+fn read_rule_lines<S: AsRef<str>>(
+  lines: Vec<(usize, S)>,
+  options: &ParseOptions,
+) -> (RuleRegistry, Vec<ConfigError>, Vec<String>) {
+  let mut rules = RuleRegistry::new();
+  let mut errors = Vec::new();
+  let mut warnings = Vec::new();
+  let mut rule_lines: HashMap<String, usize> = HashMap::new();
+  for (line_number, line) in lines {
+    let line = line.as_ref();
+    let (keywords, rule) = match parse_line(line, line_number, options) {
+      Ok(parsed) => parsed,
+      Err(e @ ConfigError::InvalidUri { .. }) if options.ignores_invalid_rules() => {
+        warnings.push(format!("Ignoring invalid rule: {}", e));
+        continue;
+      }
+      Err(e) => {
+        errors.push(e);
+        continue;
+      }
+    };
+
+    for kw in keywords {
+      if let Some(&first_line) = rule_lines.get(&kw) {
+        match options.duplicate_policy() {
+          DuplicatePolicy::KeepLast => {
+            warnings.push(format!("Replacing duplicate rule for {}", kw))
+          }
+          DuplicatePolicy::KeepFirst => {
+            warnings.push(format!("Ignoring duplicate rule for {}", kw));
+            continue;
+          }
+          DuplicatePolicy::Error => {
+            errors.push(ConfigError::DuplicateKeyword {
+              keyword: kw,
+              first_line,
+              second_line: line_number,
+            });
+            continue;
+          }
+        }
+      }
+      log::info!("Insert {}", kw);
+      rule_lines.insert(kw.clone(), line_number);
+      rules.insert_shared(kw, rule.clone());
+    }
+  }
+  for [(first, first_line), (second, second_line)] in near_duplicates(&rule_lines) {
+    warnings.push(format!(
+      "Keywords {:?} on line {} and {:?} on line {} differ only in case or whitespace",
+      first, first_line, second, second_line
+    ));
+  }
+  (rules, errors, warnings)
+}
+
+/// Returns the pairs of keywords in `rule_lines` that differ only in case or surrounding
+/// whitespace, which is usually a mistake, along with their line numbers, ordered by line.
+fn near_duplicates(rule_lines: &HashMap<String, usize>) -> Vec<[(&str, usize); 2]> {
+  let mut by_folded: HashMap<String, Vec<(&str, usize)>> = HashMap::new();
+  for (kw, &line_number) in rule_lines {
+    by_folded
+      .entry(kw.trim().to_lowercase())
+      .or_default()
+      .push((kw.as_str(), line_number));
+  }
+  let mut pairs = Vec::new();
+  for mut similar in by_folded.into_values() {
+    similar.sort_by_key(|&(kw, line_number)| (line_number, kw));
+    pairs.extend(similar.windows(2).map(|pair| [pair[0], pair[1]]));
+  }
+  pairs.sort_by_key(|[first, second]| (first.1, second.1));
+  pairs
+}
+
+/// Expands rule groups in `data`, returning each line holding a rule along with its one-indexed
+/// line number.
+///
+/// A `[name]` line starts a group, which lasts until the next one. Within a group,
+/// `prefix = <url>` sets the URL that `{prefix}` is replaced with in the group's rules.
+fn expand_groups(data: &str) -> Vec<(usize, Cow<'_, str>)> {
+  lazy_static! {
+    static ref GROUP_RE: Regex = Regex::new(r"^\[[^\]]+\]$").unwrap();
+    static ref PREFIX_RE: Regex = Regex::new(r"^prefix\s*=\s*(\S.*)$").unwrap();
+  }
+  const PREFIX_STR: &str = "{prefix}";
+
+  let mut lines = Vec::new();
+  let mut in_group = false;
+  let mut prefix: Option<&str> = None;
+  for (i, line) in data.lines().enumerate() {
+    if is_blank_or_comment(line) {
+      continue;
+    }
+    let trimmed = line.trim();
+    if GROUP_RE.is_match(trimmed) {
+      in_group = true;
+      prefix = None;
+      continue;
+    }
+    if in_group {
+      if let Some(captures) = PREFIX_RE.captures(trimmed) {
+        prefix = Some(captures.get(1).unwrap().as_str().trim());
+        continue;
+      }
+    }
+    let line = match prefix {
+      Some(prefix) if line.contains(PREFIX_STR) => Cow::Owned(line.replace(PREFIX_STR, prefix)),
+      _ => Cow::Borrowed(line),
+    };
+    lines.push((i + 1, line));
+  }
+  lines
+}
+
+/// The options in brackets after a keyword, e.g. `[301]` in `m[301] = https://gmail.com/` or
+/// `[min=1,max=2]` in `jira[min=1,max=2] = https://myco.atlassian.net/browse/{ARG1}`.
+#[derive(Debug, Default, PartialEq)]
+struct Annotation {
+  status: Option<u16>,
+  min_args: usize,
+  max_args: Option<usize>,
+}
+
+impl Annotation {
+  /// Parses the comma-separated options between the brackets, given in any order.
+  fn parse(options: &str, line_number: usize) -> Result<Self, ConfigError> {
+    let invalid_limits = || ConfigError::InvalidArgLimits {
+      line_number,
+      limits: options.to_string(),
+    };
+    let mut annotation = Annotation::default();
+    for option in options.split(',') {
+      match option.split_once('=') {
+        Some((name, count)) => {
+          let count = count.parse().map_err(|_| invalid_limits())?;
+          match name {
+            "min" => annotation.min_args = count,
+            "max" => annotation.max_args = Some(count),
+            _ => return Err(invalid_limits()),
+          }
+        }
+        None => match option.parse::<u16>() {
+          Ok(code) if REDIRECT_STATUS_CODES.contains(&code) => annotation.status = Some(code),
+          _ => {
+            return Err(ConfigError::InvalidStatusCode {
+              line_number,
+              status: option.to_string(),
+            })
+          }
+        },
+      }
+    }
+    if annotation.min_args > annotation.max_args.unwrap_or(usize::MAX) {
+      return Err(invalid_limits());
+    }
+    Ok(annotation)
+  }
+
+  /// Whether the annotation limits the number of arguments.
+  fn has_limits(&self) -> bool {
+    self.min_args > 0 || self.max_args.is_some()
+  }
+}
+
+/// Formats the annotation as it's written in a config file, brackets included, or as nothing if
+/// it has no options.
+impl fmt::Display for Annotation {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let mut options = Vec::new();
+    if let Some(status) = self.status {
+      options.push(status.to_string());
+    }
+    if self.min_args > 0 {
+      options.push(format!("min={}", self.min_args));
+    }
+    if let Some(max_args) = self.max_args {
+      options.push(format!("max={}", max_args));
+    }
+    if options.is_empty() {
+      Ok(())
+    } else {
+      write!(f, "[{}]", options.join(","))
+    }
+  }
+}
+
+/// Parses a config line that isn't blank or a comment into its keywords and their rule.
+fn parse_line(
+  line: &str,
+  line_number: usize,
+  options: &ParseOptions,
+) -> Result<(Vec<String>, Arc<dyn Rule>), ConfigError> {
   lazy_static! {
     static ref RULE_RE: Regex = Regex::new(r#"^(.+)\s=\s(.+)"#).unwrap();
   }
-  let data = fs::read_to_string(path).unwrap();
-  let config_rules = data.trim().split("\n").map(|line| {
-    let ex = format!("Malformed config URL {}: expected (kw) = (url)", line);
-    let captures = RULE_RE.captures(line).expect(&ex);
-    ConfigRule::new(&captures[1], &captures[2])
-  });
-  let mut rules: HashMap<String, Box<dyn Rule>> = HashMap::new();
-  for cfg_rule in config_rules {
-    log::info!("Insert {}", cfg_rule.kw());
-    rules.insert(cfg_rule.kw().to_string(), Box::new(cfg_rule));
+  let captures = RULE_RE
+    .captures(line)
+    .ok_or_else(|| ConfigError::MalformedLine {
+      line_number,
+      content: line.to_string(),
+    })?;
+  // The captures keep any extra whitespace around `=`, which would otherwise end up in the
+  // keyword and URL.
+  let kw = captures[1].trim();
+  let written = captures[2].trim();
+  let uri = expand_env_vars(written, kw, line_number)?;
+  let (keywords, rule) = parse_rule(kw, &uri, line_number, options)?;
+  // Keep the variables, rather than their values, in the template the rule is listed and saved
+  // with, so that secrets don't leak through the admin API or a rewritten config.
+  if matches!(uri, Cow::Owned(_)) && rule.template().is_some() {
+    let rule = EnvTemplateRule {
+      written: written.to_string(),
+      rule,
+    };
+    return Ok((keywords, Arc::new(rule)));
   }
-  rules
+  Ok((keywords, rule))
 }
 
-#[derive(Debug)]
+/// Parses the rule for the keyword(s) `kw` redirecting to `uri`, whose environment variables
+/// have already been expanded.
+fn parse_rule(
+  kw: &str,
+  uri: &str,
+  line_number: usize,
+  options: &ParseOptions,
+) -> Result<(Vec<String>, Arc<dyn Rule>), ConfigError> {
+  lazy_static! {
+    static ref SCHEME_RE: Regex = Regex::new(r"^[A-Za-z][A-Za-z0-9+.-]*://").unwrap();
+    static ref ANNOTATION_RE: Regex = Regex::new(r"^(.+)\[([^\]]*)\]$").unwrap();
+    static ref TARGETS_RE: Regex = Regex::new(r"\s\|\s").unwrap();
+    static ref CHAIN_RE: Regex = Regex::new(r"^(\S.*?)\s+->\s+(\S+)$").unwrap();
+    static ref TIMED_RE: Regex = Regex::new(r"^(.+)\s\[([^\]]*)\]$").unwrap();
+  }
+  let with_scheme = |uri: &str| match options.scheme() {
+    Some(scheme) if !SCHEME_RE.is_match(uri) => format!("{}://{}", scheme, uri),
+    _ => uri.to_string(),
+  };
+  match kw.strip_prefix(REGEX_RULE_PREFIX) {
+    Some(pattern) => {
+      let rule = RegexRule::new(pattern, with_scheme(uri)).map_err(|source| {
+        ConfigError::InvalidPattern {
+          line_number,
+          pattern: pattern.to_string(),
+          source,
+        }
+      })?;
+      check_template(rule.template().unwrap_or_default(), kw, line_number)?;
+      Ok((vec![kw.to_string()], Arc::new(rule)))
+    }
+    None => {
+      let (kw, written_annotation) = match ANNOTATION_RE.captures(kw) {
+        Some(captures) => (
+          captures.get(1).unwrap().as_str(),
+          captures.get(2).map(|options| options.as_str()),
+        ),
+        None => (kw, None),
+      };
+      let annotation = match written_annotation {
+        Some(written) => Annotation::parse(written, line_number)?,
+        None => Annotation::default(),
+      };
+      let explicit_status = annotation.status;
+      let status_code = explicit_status.unwrap_or(DEFAULT_STATUS_CODE);
+
+      let keywords = kw
+        .split(',')
+        .map(|alias| options.fold_case(alias.trim().nfc().collect()))
+        .collect::<Vec<_>>();
+      if keywords.iter().any(String::is_empty) {
+        return Err(ConfigError::EmptyAlias {
+          line_number,
+          keywords: kw.to_string(),
+        });
+      }
+      // Only rules with a single URL check their argument count.
+      let single_url = keywords != [FALLBACK_ORDER_KEY]
+        && !uri.starts_with(SCRIPT_RULE_PREFIX)
+        && !CHAIN_RE.is_match(uri)
+        && !TARGETS_RE.is_match(uri)
+        && !TIMED_RE.is_match(uri);
+      if annotation.has_limits() && !single_url {
+        return Err(ConfigError::InvalidArgLimits {
+          line_number,
+          limits: written_annotation.unwrap_or_default().to_string(),
+        });
+      }
+      if keywords == [FALLBACK_ORDER_KEY] {
+        let order = uri
+          .split(',')
+          .map(|alias| options.fold_case(alias.trim().nfc().collect()))
+          .collect::<Vec<_>>();
+        if order.iter().any(String::is_empty) {
+          return Err(ConfigError::EmptyAlias {
+            line_number,
+            keywords: uri.to_string(),
+          });
+        }
+        return Ok((keywords, Arc::new(FallbackOrder::new(order))));
+      }
+      if let Some(script) = uri.strip_prefix(SCRIPT_RULE_PREFIX) {
+        let rule = parse_script(script, line_number)?.with_status_code(status_code);
+        return Ok((keywords, Arc::new(rule)));
+      }
+      if let Some(captures) = CHAIN_RE.captures(uri) {
+        let mut words = captures[1].split_whitespace();
+        let command = words.next().unwrap_or_default();
+        let target = options.fold_case(captures[2].to_string());
+        let mut rule = ChainedRule::new(&target, command, words);
+        if let Some(status_code) = explicit_status {
+          rule = rule.with_status_code(status_code);
+        }
+        return Ok((keywords, Arc::new(rule)));
+      }
+      let targets = TARGETS_RE.split(uri).collect::<Vec<_>>();
+      if targets.iter().any(|target| TIMED_RE.is_match(target)) {
+        let invalid_range = |range: &str| ConfigError::InvalidTimeRange {
+          line_number,
+          range: range.to_string(),
+        };
+        let entries = targets
+          .iter()
+          .map(|target| {
+            let captures = TIMED_RE
+              .captures(target)
+              .ok_or_else(|| invalid_range(target))?;
+            let range = match &captures[2] {
+              "*" => None,
+              range => Some(range.parse().map_err(|_| invalid_range(range))?),
+            };
+            let template = with_scheme(captures[1].trim());
+            check_template(&template, kw, line_number)?;
+            Ok((range, template))
+          })
+          .collect::<Result<Vec<_>, ConfigError>>()?;
+        let rule = TimedRule::new(entries).with_status_code(status_code);
+        return Ok((keywords, Arc::new(rule)));
+      }
+      let targets = targets.into_iter().map(with_scheme).collect::<Vec<_>>();
+      for target in &targets {
+        check_template(target, kw, line_number)?;
+      }
+      if targets.len() > 1 {
+        let rule = LoadBalancerRule::new(targets).with_status_code(status_code);
+        return Ok((keywords, Arc::new(rule)));
+      }
+      let mut cfg_rule = ConfigRule::new(&keywords[0], targets.into_iter().next().unwrap())
+        .with_status_code(status_code)
+        .with_min_args(annotation.min_args);
+      if let Some(max_args) = annotation.max_args {
+        cfg_rule = cfg_rule.with_max_args(max_args);
+      }
+      Ok((keywords, Arc::new(cfg_rule)))
+    }
+  }
+}
+
+/// A rule whose URL used environment variables, which reports the URL as written, with the
+/// variables unexpanded, as its template.
+struct EnvTemplateRule {
+  written: String,
+  rule: Arc<dyn Rule>,
+}
+
+impl Rule for EnvTemplateRule {
+  fn produce_uri(&self, cmd: &str, args: &[String]) -> Result<Uri, RuleError> {
+    Rule::produce_uri(&*self.rule, cmd, args)
+  }
+
+  fn produce_location(&self, cmd: &str, args: &[String]) -> Result<String, RuleError> {
+    Rule::produce_location(&*self.rule, cmd, args)
+  }
+
+  fn template(&self) -> Option<&str> {
+    Some(&self.written)
+  }
+
+  fn status_code(&self) -> u16 {
+    self.rule.status_code()
+  }
+
+  fn pattern(&self) -> Option<&Regex> {
+    self.rule.pattern()
+  }
+
+  fn describe(&self) -> RuleDescription {
+    RuleDescription {
+      template: Some(self.written.clone()),
+      ..self.rule.describe()
+    }
+  }
+
+  fn as_async(&self) -> Option<&dyn AsyncRule> {
+    self.rule.as_async()
+  }
+
+  fn as_chained(&self) -> Option<&ChainedRule> {
+    self.rule.as_chained()
+  }
+}
+
+/// Replaces each `${NAME}` in `uri` with the value of the environment variable `NAME`, and each
+/// `${NAME:-default}` with its value or, if it's unset or empty, `default`.
+fn expand_env_vars<'a>(
+  uri: &'a str,
+  keyword: &str,
+  line_number: usize,
+) -> Result<Cow<'a, str>, ConfigError> {
+  lazy_static! {
+    static ref ENV_VAR_RE: Regex =
+      Regex::new(r"\$\{([A-Za-z_][A-Za-z0-9_]*)(?::-([^}]*))?\}").unwrap();
+  }
+  if !uri.contains("${") {
+    return Ok(Cow::Borrowed(uri));
+  }
+  let mut expanded = String::with_capacity(uri.len());
+  let mut last = 0;
+  for captures in ENV_VAR_RE.captures_iter(uri) {
+    let whole = captures.get(0).unwrap();
+    let var = &captures[1];
+    let value = match (std::env::var(var), captures.get(2)) {
+      (Ok(value), Some(default)) if value.is_empty() => default.as_str().to_string(),
+      (Ok(value), _) => value,
+      (Err(_), Some(default)) => default.as_str().to_string(),
+      (Err(_), None) => {
+        return Err(ConfigError::UndefinedEnvVar {
+          line_number,
+          var: var.to_string(),
+          keyword: keyword.to_string(),
+        })
+      }
+    };
+    expanded.push_str(&uri[last..whole.start()]);
+    expanded.push_str(&value);
+    last = whole.end();
+  }
+  expanded.push_str(&uri[last..]);
+  Ok(Cow::Owned(expanded))
+}
+
+/// Checks that `template` is a valid URI once its placeholders are filled in, by filling them in
+/// with a dummy value.
+fn check_template(template: &str, keyword: &str, line_number: usize) -> Result<(), ConfigError> {
+  lazy_static! {
+    static ref PLACEHOLDER_RE: Regex = Regex::new(r"\{[^{}]*\}").unwrap();
+  }
+  PLACEHOLDER_RE
+    .replace_all(template, "x")
+    .parse::<Uri>()
+    .map(|_| ())
+    .map_err(|source| ConfigError::InvalidUri {
+      line_number,
+      keyword: keyword.to_string(),
+      uri: template.to_string(),
+      source,
+    })
+}
+
+/// Parses the target of a `script:` rule: the path of the program to run, optionally followed
+/// by its timeout in seconds.
+fn parse_script(script: &str, line_number: usize) -> Result<ScriptRule, ConfigError> {
+  let invalid = || ConfigError::InvalidScript {
+    line_number,
+    script: script.to_string(),
+  };
+  let mut fields = script.split_whitespace();
+  let rule = ScriptRule::new(fields.next().ok_or_else(invalid)?);
+  let rule = match fields.next() {
+    Some(timeout) => match timeout.parse::<f64>() {
+      Ok(secs) if secs.is_finite() && secs > 0.0 => {
+        rule.with_timeout(Duration::from_secs_f64(secs))
+      }
+      _ => return Err(invalid()),
+    },
+    None => rule,
+  };
+  match fields.next() {
+    Some(_) => Err(invalid()),
+    None => Ok(rule),
+  }
+}
+
+/// Parses `rules`, each written like a line of a config file, as directed by `options`. When two
+/// rules have the same keyword, the later one wins.
+///
+/// # Errors
+///
+/// Returns [`ConfigError::InlineRule`] with the position of the first rule that can't be parsed.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::config::{parse_inline_rules, ConfigError, ParseOptions};
+///
+/// let rules = parse_inline_rules(&["m = https://gmail.com/"], &ParseOptions::default()).unwrap();
+/// assert_eq!(rules["m"].produce_uri("m", &[]).unwrap(), "https://gmail.com/");
+///
+/// let rules = ["m = https://gmail.com/", "m=https://gmail.com/"];
+/// match parse_inline_rules(&rules, &ParseOptions::default()) {
+///   Err(ConfigError::InlineRule { position, .. }) => assert_eq!(position, 2),
+///   _ => panic!("Expected InlineRule"),
+/// }
+/// ```
+pub fn parse_inline_rules<S: AsRef<str>>(
+  rules: &[S],
+  options: &ParseOptions,
+) -> Result<RuleRegistry, ConfigError> {
+  let mut parsed = RuleRegistry::new();
+  for (i, rule) in rules.iter().enumerate() {
+    let rule = rule.as_ref();
+    let inline_rules =
+      parse_rules_from_str_with_options(rule, options).map_err(|e| ConfigError::InlineRule {
+        position: i + 1,
+        rule: rule.to_string(),
+        source: Box::new(e),
+      })?;
+    parsed.extend(inline_rules);
+  }
+  Ok(parsed)
+}
+
+/// Reads each of the config files at `paths`, in order, and merges their rules into one map, keyed
+/// by keyword in the order they appear. A keyword with rules in more than one file is an error,
+/// just like a keyword with more than one rule in the same file.
+///
+/// # Errors
+///
+/// Returns [`ConfigError::InFile`] if any file can't be parsed by [`parse_rules_from`], or
+/// [`ConfigError::DuplicateKeywordInFiles`] if two files have a rule for the same keyword.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::config::parse_rules_from_multiple;
+///
+/// let work = std::env::temp_dir().join("ezproxy-doctest-parse-rules-from-multiple-work.txt");
+/// let personal = std::env::temp_dir().join("ezproxy-doctest-parse-rules-from-multiple-personal.txt");
+/// std::fs::write(&work, "jira = https://myco.atlassian.net/\n").unwrap();
+/// std::fs::write(&personal, "m = https://gmail.com/\n").unwrap();
+///
+/// let rules = parse_rules_from_multiple(&[&work, &personal]).unwrap();
+/// assert_eq!(rules.keywords().collect::<Vec<_>>(), vec!["jira", "m"]);
+/// # std::fs::remove_file(&work).unwrap();
+/// # std::fs::remove_file(&personal).unwrap();
+/// ```
+pub fn parse_rules_from_multiple<P: AsRef<Path>>(paths: &[P]) -> Result<RuleRegistry, ConfigError> {
+  parse_rules_from_multiple_with_options(paths, &ParseOptions::default())
+}
+
+/// Reads each of the config files at `paths`, in order, as directed by `options`, and merges their
+/// rules into one map. The [`DuplicatePolicy`] applies across files as well as within them.
+///
+/// # Errors
+///
+/// Returns [`ConfigError::InFile`] if any file can't be parsed by
+/// [`parse_rules_from_with_options`], or [`ConfigError::DuplicateKeywordInFiles`] if two files have
+/// a rule for the same keyword under [`DuplicatePolicy::Error`].
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::config::{parse_rules_from_multiple_with_options, DuplicatePolicy, ParseOptions};
+///
+/// let base = std::env::temp_dir().join("ezproxy-doctest-parse-rules-from-multiple-base.txt");
+/// let local = std::env::temp_dir().join("ezproxy-doctest-parse-rules-from-multiple-local.txt");
+/// std::fs::write(&base, "m = https://gmail.com/\n").unwrap();
+/// std::fs::write(&local, "m = https://mail.yahoo.com/\n").unwrap();
+///
+/// let options = ParseOptions::default().on_duplicate(DuplicatePolicy::KeepLast);
+/// let rules = parse_rules_from_multiple_with_options(&[&base, &local], &options).unwrap();
+/// assert_eq!(rules["m"].produce_uri("m", &[]).unwrap(), "https://mail.yahoo.com/");
+/// # std::fs::remove_file(&base).unwrap();
+/// # std::fs::remove_file(&local).unwrap();
+/// ```
+pub fn parse_rules_from_multiple_with_options<P: AsRef<Path>>(
+  paths: &[P],
+  options: &ParseOptions,
+) -> Result<RuleRegistry, ConfigError> {
+  let mut rules = RuleRegistry::new();
+  let mut rule_paths: HashMap<String, &Path> = HashMap::new();
+  for path in paths {
+    let path = path.as_ref();
+    let file_rules =
+      parse_rules_from_with_options(path, options).map_err(|e| ConfigError::InFile {
+        path: path.to_path_buf(),
+        source: Box::new(e),
+      })?;
+    for (kw, rule) in file_rules.into_shared() {
+      if let Some(first_path) = rule_paths.get(&kw) {
+        match options.duplicate_policy() {
+          DuplicatePolicy::KeepLast => log::warn!(
+            "Replacing duplicate rule for {} from {} with the one in {}",
+            kw,
+            first_path.display(),
+            path.display()
+          ),
+          DuplicatePolicy::KeepFirst => {
+            log::warn!("Ignoring duplicate rule for {} in {}", kw, path.display());
+            continue;
+          }
+          DuplicatePolicy::Error => {
+            return Err(ConfigError::DuplicateKeywordInFiles {
+              keyword: kw,
+              first_path: first_path.to_path_buf(),
+              second_path: path.to_path_buf(),
+            })
+          }
+        }
+      }
+      rule_paths.insert(kw.clone(), path);
+      rules.insert_shared(kw, rule);
+    }
+  }
+  Ok(rules)
+}
+
+/// Whether a config line should be skipped: it's empty, all whitespace, or a `#` comment.
+fn is_blank_or_comment(line: &str) -> bool {
+  let line = line.trim();
+  line.is_empty() || line.starts_with('#')
+}
+
+/// Returns the rules of `base` overlaid with those of `overlay`: a keyword in both gets
+/// `overlay`'s rule, and every other keyword keeps the rule it has. See [`RuleRegistry::extend`]
+/// to merge in place.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::config::{merge, parse_rules_from_str};
+///
+/// let base = parse_rules_from_str("m = https://gmail.com/\nc = https://calendar.google.com/\n").unwrap();
+/// let overlay = parse_rules_from_str("m = https://mail.yahoo.com/\n").unwrap();
+///
+/// let merged = merge(base, overlay);
+/// assert_eq!(merged.len(), 2);
+/// assert_eq!(merged["m"].produce_uri("m", &[]).unwrap(), "https://mail.yahoo.com/");
+/// ```
+pub fn merge(base: RuleRegistry, overlay: RuleRegistry) -> RuleRegistry {
+  let mut merged = base;
+  merged.extend(overlay);
+  merged
+}
+
+/// Serializes `rules` in the config file format, one `<keyword> = <url>` line per rule, in the order
+/// they're iterated. Rules that don't redirect with [`DEFAULT_STATUS_CODE`] have their status annotated.
+///
+/// Rules without a [`Rule::template`] can't be written as a config line and are left out.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::config::{rules_to_string, ConfigRule};
+/// use ezproxy::rules::RuleRegistry;
+///
+/// let mut rules = RuleRegistry::new();
+/// rules.insert("npm", Box::new(ConfigRule::new("npm", "https://npmjs.com/search?q={ARGS}")));
+/// rules.insert("m", Box::new(ConfigRule::new("m", "https://gmail.com/")));
+///
+/// assert_eq!(
+///   rules_to_string(&rules),
+///   "npm = https://npmjs.com/search?q={ARGS}\nm = https://gmail.com/\n"
+/// );
+/// ```
+pub fn rules_to_string(rules: &RuleRegistry) -> String {
+  let mut config = String::new();
+  for (kw, rule) in rules.iter() {
+    match RuleEntry::from_rule(rule) {
+      Some(entry) => {
+        config.push_str(&entry.into_line(kw));
+        config.push('\n');
+      }
+      None => log::warn!(
+        "Rule for {} has no template; leaving it out of the config",
+        kw
+      ),
+    }
+  }
+  config
+}
+
+/// Writes `rules` as a TOML config file, in the form read by [`parse_rules_from_toml`]. Like
+/// [`rules_to_string`], it leaves out rules without a template.
+///
+/// Entries are written inline rather than as `[rules.<keyword>]` tables so that they stay in order.
+fn rules_to_toml_string(rules: &RuleRegistry) -> String {
+  let mut config = String::from("[rules]\n");
+  for (kw, rule) in rules.iter() {
+    let entry = match RuleEntry::from_rule(rule) {
+      Some(entry) => entry,
+      None => {
+        log::warn!(
+          "Rule for {} has no template; leaving it out of the config",
+          kw
+        );
+        continue;
+      }
+    };
+    let key = if !kw.is_empty()
+      && kw
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+      kw.to_string()
+    } else {
+      toml::Value::String(kw.to_string()).to_string()
+    };
+    let entry = toml::Value::try_from(entry).expect("a rule entry is valid TOML");
+    config.push_str(&format!("{} = {}\n", key, entry));
+  }
+  config
+}
+
+/// Writes `rules` as a JSON config file, in the form read by [`parse_rules_from_json`]. Like
+/// [`rules_to_string`], it leaves out rules without a template.
+fn rules_to_json_string(rules: &RuleRegistry) -> String {
+  let mut config = JsonConfig {
+    rules: IndexMap::new(),
+  };
+  for (kw, rule) in rules.iter() {
+    match RuleEntry::from_rule(rule) {
+      Some(entry) => {
+        config.rules.insert(kw.to_string(), entry);
+      }
+      None => log::warn!(
+        "Rule for {} has no template; leaving it out of the config",
+        kw
+      ),
+    }
+  }
+  let mut json = serde_json::to_string_pretty(&config).expect("a rule entry is valid JSON");
+  json.push('\n');
+  json
+}
+
+/// Replaces the config file at `path` with `rules`, written as by [`rules_to_string`] under a
+/// comment saying when they were written. A file whose name ends in `.toml` or `.json` is instead
+/// written in that format, as read by [`parse_rules_from_toml`] or [`parse_rules_from_json`]; JSON
+/// has no comments, so it's written without one.
+///
+/// The file is written next to `path` first and then renamed over it, so a crash never leaves it
+/// half written.
+///
+/// # Errors
+///
+/// Returns an error if the file can't be written.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::config::{parse_rules_from, write_rules_to, ConfigRule};
+/// use ezproxy::rules::RuleRegistry;
+///
+/// let mut rules = RuleRegistry::new();
+/// rules.insert("m", Box::new(ConfigRule::new("m", "https://gmail.com/")));
+///
+/// let path = std::env::temp_dir().join("ezproxy-doctest-write-rules-to.txt");
+/// write_rules_to(&rules, &path).unwrap();
+/// assert!(std::fs::read_to_string(&path).unwrap().starts_with("# Written by ezproxy at "));
+/// assert!(parse_rules_from(&path).unwrap().contains_key("m"));
+/// # std::fs::remove_file(&path).unwrap();
+/// ```
+pub fn write_rules_to<P: AsRef<Path>>(rules: &RuleRegistry, path: P) -> Result<(), ConfigError> {
+  let path = path.as_ref();
+  let written_at = OffsetDateTime::now_utc()
+    .format(&Rfc3339)
+    .unwrap_or_else(|_| "an unknown time".to_string());
+  let config = match ConfigFormat::of(path) {
+    ConfigFormat::Lines => format!(
+      "# Written by ezproxy at {}\n{}",
+      written_at,
+      rules_to_string(rules)
+    ),
+    ConfigFormat::Toml => format!(
+      "# Written by ezproxy at {}\n{}",
+      written_at,
+      rules_to_toml_string(rules)
+    ),
+    ConfigFormat::Json => rules_to_json_string(rules),
+  };
+
+  let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+  tmp_name.push(".tmp");
+  let tmp_path = path.with_file_name(tmp_name);
+  {
+    let mut file = fs::File::create(&tmp_path)?;
+    io::Write::write_all(&mut file, config.as_bytes())?;
+    file.sync_all()?;
+  }
+  fs::rename(&tmp_path, path)?;
+  Ok(())
+}
+
+/// A rule read from a config file: a keyword and the URL template it redirects to.
+///
+/// The template may contain the following placeholders, which are URL-encoded when
+/// substituted:
+///
+/// - `{ARGS}`: every argument after the command, separated by spaces.
+/// - `{ALL}`: the command followed by its arguments, separated by spaces.
+/// - `{ARGS_FROM:2}`, `{ARGS_FROM:3}`, ...: the arguments from the given one onward, separated by
+///   spaces. Like `{ARG1}`, these are one-indexed, and producing a URI fails if there are fewer
+///   arguments.
+/// - `{ARGS_CSV}`: every argument after the command, separated by commas.
+/// - `{ARGS_JSON}`: every argument after the command, as a JSON array of strings.
+/// - `{ARG1}`, `{ARG2}`, ...: a single argument. These are one-indexed, so `{ARG1}` is the first
+///   argument after the command. Producing a URI fails if the argument wasn't given, unless the
+///   placeholder has a fallback after a colon, e.g. `{ARG2:main}`, which is used instead.
+///
+/// A template without placeholders always redirects to the same URL.
+///
+/// With the `serde` feature, which is on by default, a rule serializes as
+/// `{"keyword": ..., "uri": ..., "status": ...}`. `status` may be left out when deserializing,
+/// and the keyword and template are cleaned up as by [`ConfigRule::new`]. Deserializing fails if
+/// `min_args` is more than `max_args`.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::config::ConfigRule;
+/// use ezproxy::rules::Rule;
+///
+/// let rule = ConfigRule::new("_", "https://www.google.com/search?q={ALL}");
+/// let uri = rule.produce_uri("best", &["pizza".to_string()]).unwrap();
+/// assert_eq!(uri, "https://www.google.com/search?q=best%20pizza");
+/// ```
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "ConfigRuleFields"))]
 pub struct ConfigRule {
+  #[cfg_attr(feature = "serde", serde(rename = "keyword"))]
   kw: String,
   uri: String,
+  #[cfg_attr(feature = "serde", serde(rename = "status"))]
+  status_code: u16,
+  #[cfg_attr(feature = "serde", serde(skip_serializing_if = "is_zero"))]
+  min_args: usize,
+  #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+  max_args: Option<usize>,
+}
+
+/// The serialized form of a [`ConfigRule`], before it's cleaned up by [`ConfigRule::new`].
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct ConfigRuleFields {
+  keyword: String,
+  uri: String,
+  #[serde(default = "default_status_code")]
+  status: u16,
+  #[serde(default)]
+  min_args: usize,
+  #[serde(default)]
+  max_args: Option<usize>,
+}
+
+#[cfg(feature = "serde")]
+fn default_status_code() -> u16 {
+  DEFAULT_STATUS_CODE
+}
+
+#[cfg(feature = "serde")]
+fn is_zero(n: &usize) -> bool {
+  *n == 0
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<ConfigRuleFields> for ConfigRule {
+  type Error = String;
+
+  fn try_from(fields: ConfigRuleFields) -> Result<Self, Self::Error> {
+    let rule = ConfigRule::new(fields.keyword, fields.uri)
+      .with_status_code(fields.status)
+      .with_min_args(fields.min_args);
+    match fields.max_args {
+      Some(max_args) if max_args < fields.min_args => Err(format!(
+        "min_args ({}) is more than max_args ({})",
+        fields.min_args, max_args
+      )),
+      Some(max_args) => Ok(rule.with_max_args(max_args)),
+      None => Ok(rule),
+    }
+  }
 }
 
 impl ConfigRule {
+  /// Creates a rule redirecting `kw` to the URL template `uri`.
+  ///
+  /// Leading and trailing whitespace is trimmed from both `kw` and `uri`, and `kw` is
+  /// NFC-normalized so that it matches however its Unicode characters were typed. The template
+  /// isn't validated until [`Rule::produce_uri`] is called.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ConfigRule;
+  ///
+  /// let rule = ConfigRule::new("npm", "https://npmjs.com/search?q={ARGS}");
+  /// assert_eq!(rule.kw(), "npm");
+  /// ```
   pub fn new<K: Into<String>, U: Into<String>>(kw: K, uri: U) -> Self {
     Self {
-      kw: kw.into(),
-      uri: uri.into(),
+      kw: kw.into().trim().nfc().collect(),
+      uri: uri.into().trim().to_string(),
+      status_code: DEFAULT_STATUS_CODE,
+      min_args: 0,
+      max_args: None,
     }
   }
 
+  /// Redirects with `status_code` instead of [`DEFAULT_STATUS_CODE`], e.g. 301 for a permanent
+  /// redirect that browsers may cache.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ConfigRule;
+  /// use ezproxy::rules::Rule;
+  ///
+  /// let rule = ConfigRule::new("m", "https://gmail.com/").with_status_code(301);
+  /// assert_eq!(rule.status_code(), 301);
+  /// assert_eq!(rule.to_string(), "m[301] = https://gmail.com/");
+  /// ```
+  pub fn with_status_code(mut self, status_code: u16) -> Self {
+    self.status_code = status_code;
+    self
+  }
+
+  /// Makes producing a URI fail with [`RuleError::MissingArgument`] when fewer than `min_args`
+  /// arguments are given, rather than producing a URL missing them. Defaults to 0.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ConfigRule;
+  /// use ezproxy::rules::{Rule, RuleError};
+  ///
+  /// let rule = ConfigRule::new("jira", "https://myco.atlassian.net/issues/?jql={ARGS}").with_min_args(1);
+  /// assert!(matches!(rule.produce_uri("jira", &[]), Err(RuleError::MissingArgument { index: 1 })));
+  /// assert_eq!(rule.to_string(), "jira[min=1] = https://myco.atlassian.net/issues/?jql={ARGS}");
+  /// ```
+  pub fn with_min_args(mut self, min_args: usize) -> Self {
+    self.min_args = min_args;
+    self
+  }
+
+  /// Makes producing a URI fail with [`RuleError::TooManyArguments`] when more than `max_args`
+  /// arguments are given. By default, there's no limit.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ConfigRule;
+  /// use ezproxy::rules::{Rule, RuleError};
+  ///
+  /// let rule = ConfigRule::new("gh", "https://github.com/{ARG1}").with_max_args(1);
+  /// let args = ["rust-lang".to_string(), "rust".to_string()];
+  /// assert!(matches!(
+  ///   rule.produce_uri("gh", &args),
+  ///   Err(RuleError::TooManyArguments { max: 1, given: 2 })
+  /// ));
+  /// ```
+  pub fn with_max_args(mut self, max_args: usize) -> Self {
+    self.max_args = Some(max_args);
+    self
+  }
+
+  /// Returns the keyword that triggers this rule.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ConfigRule;
+  ///
+  /// assert_eq!(ConfigRule::new("m", "https://gmail.com/").kw(), "m");
+  /// ```
   pub fn kw(&self) -> &str {
     &self.kw
   }
+
+  /// Returns the URL template this rule redirects to, placeholders included.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ConfigRule;
+  ///
+  /// let rule = ConfigRule::new("npm", "https://npmjs.com/search?q={ARGS}");
+  /// assert_eq!(rule.uri(), "https://npmjs.com/search?q={ARGS}");
+  /// ```
+  pub fn uri(&self) -> &str {
+    &self.uri
+  }
+
+  /// Returns the fewest arguments this rule accepts.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ConfigRule;
+  ///
+  /// assert_eq!(ConfigRule::new("m", "https://gmail.com/").min_args(), 0);
+  /// ```
+  pub fn min_args(&self) -> usize {
+    self.min_args
+  }
+
+  /// Returns the most arguments this rule accepts, if there's a limit.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ConfigRule;
+  ///
+  /// assert_eq!(ConfigRule::new("m", "https://gmail.com/").max_args(), None);
+  /// ```
+  pub fn max_args(&self) -> Option<usize> {
+    self.max_args
+  }
+
+  /// The options written in brackets after the keyword in a config file.
+  fn annotation(&self) -> Annotation {
+    Annotation {
+      status: Some(self.status_code).filter(|&status| status != DEFAULT_STATUS_CODE),
+      min_args: self.min_args,
+      max_args: self.max_args,
+    }
+  }
 }
 
+/// Formats the rule as a config file line, `<keyword> = <url>`, or `<keyword>[<options>] = <url>`
+/// if it doesn't redirect with [`DEFAULT_STATUS_CODE`] or limits its number of arguments.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::config::ConfigRule;
+///
+/// let rule = ConfigRule::new("npm", "https://npmjs.com/search?q={ARGS}");
+/// assert_eq!(rule.to_string(), "npm = https://npmjs.com/search?q={ARGS}");
+/// ```
 impl fmt::Display for ConfigRule {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-    write!(f, "ConfigRule[kw={}, uri={}]", self.kw, self.uri)
+    write!(f, "{}{} = {}", self.kw, self.annotation(), self.uri)
   }
 }
 
-impl Rule for ConfigRule {
-  fn produce_uri(&self, cmd: &str, args: &[String]) -> Result<Uri, String> {
+impl fmt::Debug for ConfigRule {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "ConfigRule[kw={}, uri={}, status_code={}]",
+      self.kw, self.uri, self.status_code
+    )
+  }
+}
+
+impl ConfigRule {
+  /// Substitutes `cmd` and `args` into the template, checking that the result is a valid URI.
+  /// Unlike the parsed [`Uri`], the returned string keeps any fragment.
+  fn expand(&self, cmd: &str, args: &[String]) -> Result<(String, Uri), RuleError> {
     const ARGS_STR: &str = "{ARGS}";
     const ALL_STR: &str = "{ALL}";
+    const ARGS_CSV_STR: &str = "{ARGS_CSV}";
+    const ARGS_JSON_STR: &str = "{ARGS_JSON}";
 
-    let uri_str = if self.uri.contains(ALL_STR) {
+    if args.len() < self.min_args {
+      return Err(RuleError::MissingArgument {
+        index: args.len() + 1,
+      });
+    }
+    if let Some(max) = self.max_args.filter(|&max| args.len() > max) {
+      return Err(RuleError::TooManyArguments {
+        max,
+        given: args.len(),
+      });
+    }
+    let uri = replace_positional_args(&self.uri, args)?;
+    let uri = replace_args_from(&uri, args)?;
+    let mut uri_str = if uri.contains(ALL_STR) {
       let all_str = format!("{} {}", cmd, args.join(" "));
-      self.uri.replace(ALL_STR, &urlencoding::encode(&all_str))
-    } else if self.uri.contains(ARGS_STR) {
-      self
-        .uri
-        .replace(ARGS_STR, &urlencoding::encode(&args.join(" ")))
+      uri.replace(ALL_STR, &urlencoding::encode(&all_str))
+    } else if uri.contains(ARGS_STR) {
+      uri.replace(ARGS_STR, &urlencoding::encode(&args.join(" ")))
     } else {
-      self.uri.clone()
+      uri
     };
+    if uri_str.contains(ARGS_CSV_STR) {
+      uri_str = uri_str.replace(ARGS_CSV_STR, &urlencoding::encode(&args.join(",")));
+    }
+    if uri_str.contains(ARGS_JSON_STR) {
+      let json = serde_json::to_string(args).expect("a list of strings is valid JSON");
+      uri_str = uri_str.replace(ARGS_JSON_STR, &urlencoding::encode(&json));
+    }
 
     log::debug!("Produce URI {}", uri_str);
-    uri_str
-      .parse::<Uri>()
-      .map_err(|e| format!("URI Parse error for {}: {}", uri_str, e).into())
+    let parsed = uri_str.parse::<Uri>()?;
+    Ok((uri_str, parsed))
   }
 }
 
+impl Rule for ConfigRule {
+  /// Produces the URI for this rule's template. Any fragment in the template is dropped, since
+  /// [`Uri`] can't represent one; use [`Rule::produce_location`] to keep it.
+  fn produce_uri(&self, cmd: &str, args: &[String]) -> Result<Uri, RuleError> {
+    self.expand(cmd, args).map(|(_, uri)| uri)
+  }
+
+  fn produce_location(&self, cmd: &str, args: &[String]) -> Result<String, RuleError> {
+    self.expand(cmd, args).map(|(location, _)| location)
+  }
+
+  fn template(&self) -> Option<&str> {
+    Some(&self.uri)
+  }
+
+  fn status_code(&self) -> u16 {
+    self.status_code
+  }
+
+  fn describe(&self) -> RuleDescription {
+    RuleDescription::from_template(self.kw.clone(), self.uri.clone())
+  }
+}
+
+/// Replaces each `{ARGn}` placeholder in `template` with the URL-encoded `n`th argument. `n` is
+/// one-indexed, so `{ARG1}` is the first argument. A missing argument is replaced with the
+/// placeholder's fallback, e.g. `main` in `{ARG2:main}`, if it has one.
+fn replace_positional_args(template: &str, args: &[String]) -> Result<String, RuleError> {
+  lazy_static! {
+    static ref ARG_RE: Regex = Regex::new(r"\{ARG(\d+)(?::([^}]*))?\}").unwrap();
+  }
+  let mut replaced = String::with_capacity(template.len());
+  let mut last = 0;
+  for captures in ARG_RE.captures_iter(template) {
+    let placeholder = captures.get(0).unwrap();
+    let n: usize = captures[1].parse().map_err(|_| {
+      RuleError::TemplateError(format!("Invalid placeholder {}", placeholder.as_str()))
+    })?;
+    let arg = match n.checked_sub(1).and_then(|i| args.get(i)) {
+      _ if n == 0 => {
+        return Err(RuleError::TemplateError(
+          "Invalid placeholder {ARG0}: argument placeholders are one-indexed, so use {ARG1} for the first argument"
+            .to_string(),
+        ))
+      }
+      Some(arg) => arg.as_str(),
+      None => match captures.get(2) {
+        Some(fallback) => fallback.as_str(),
+        None => {
+          return Err(RuleError::MissingArgument { index: n })
+        }
+      },
+    };
+    replaced.push_str(&template[last..placeholder.start()]);
+    replaced.push_str(&urlencoding::encode(arg));
+    last = placeholder.end();
+  }
+  replaced.push_str(&template[last..]);
+  Ok(replaced)
+}
+
+/// Replaces each `{ARGS_FROM:n}` placeholder in `template` with the URL-encoded arguments from the
+/// `n`th onward, separated by spaces. `n` is one-indexed, so `{ARGS_FROM:1}` is every argument.
+fn replace_args_from(template: &str, args: &[String]) -> Result<String, RuleError> {
+  lazy_static! {
+    static ref ARGS_FROM_RE: Regex = Regex::new(r"\{ARGS_FROM:(\d+)\}").unwrap();
+  }
+  let mut replaced = String::with_capacity(template.len());
+  let mut last = 0;
+  for captures in ARGS_FROM_RE.captures_iter(template) {
+    let placeholder = captures.get(0).unwrap();
+    let n: usize = captures[1].parse().map_err(|_| {
+      RuleError::TemplateError(format!("Invalid placeholder {}", placeholder.as_str()))
+    })?;
+    let from = match n.checked_sub(1) {
+      None => {
+        return Err(RuleError::TemplateError(
+          "Invalid placeholder {ARGS_FROM:0}: argument placeholders are one-indexed, so use {ARGS_FROM:1} for every argument"
+            .to_string(),
+        ))
+      }
+      Some(from) if from >= args.len() => return Err(RuleError::MissingArgument { index: n }),
+      Some(from) => from,
+    };
+    replaced.push_str(&template[last..placeholder.start()]);
+    replaced.push_str(&urlencoding::encode(&args[from..].join(" ")));
+    last = placeholder.end();
+  }
+  replaced.push_str(&template[last..]);
+  Ok(replaced)
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
+  use crate::rules::test_utils::MockRule;
+  use assert_fs::prelude::*;
+
+  #[test]
+  fn keywords_are_trimmed_and_near_duplicates_found() {
+    let config = "g  = https://www.google.com/search?q={ARGS}\ng = https://duckduckgo.com/?q={ARGS}\nG = https://bing.com/search?q={ARGS}\n";
+    let options = ParseOptions::default().on_duplicate(DuplicatePolicy::KeepLast);
+    let rules = parse_rules_from_str_with_options(config, &options).unwrap();
+    assert_eq!(rules.len(), 2);
+    assert_eq!(
+      rules.get("g").unwrap().template(),
+      Some("https://duckduckgo.com/?q={ARGS}")
+    );
+    assert!(rules.contains_key("G"));
+    // Both spellings of "g" map to the same slot, so they're warned about as duplicates, and only
+    // "G" as a near-duplicate.
+    let (_, errors, warnings) = read_rule_lines(expand_groups(config), &options);
+    assert!(errors.is_empty());
+    assert_eq!(
+      warnings,
+      vec![
+        "Replacing duplicate rule for g",
+        r#"Keywords "g" on line 2 and "G" on line 3 differ only in case or whitespace"#,
+      ]
+    );
+    let rule_lines = HashMap::from([("g".to_string(), 1), ("npm".to_string(), 2)]);
+    assert!(near_duplicates(&rule_lines).is_empty());
+  }
 
   #[test]
   fn new_config_rule() {
     let config_rule = ConfigRule::new("test_kw", "test_uri");
     assert_eq!(config_rule.kw(), "test_kw");
+    assert_eq!(config_rule.uri(), "test_uri");
+  }
+
+  #[test]
+  fn display_config_rule() {
+    let config_rule = ConfigRule::new("m", "https://gmail.com/");
+    assert_eq!(format!("{}", config_rule), "m = https://gmail.com/");
+    assert_eq!(
+      format!("{:?}", config_rule),
+      "ConfigRule[kw=m, uri=https://gmail.com/, status_code=302]"
+    );
+  }
+
+  #[test]
+  fn written_rules_parse_to_the_same_rules() {
+    let rules = parse_rules_from_str(
+      "m[301] = https://gmail.com/\n\
+       jira[min=1,max=1] = https://myco.atlassian.net/browse/{ARG1}\n\
+       g,google = https://www.google.com/search?q={ARGS}\n\
+       re:^jira-(\\d+)$ = https://myco.atlassian.net/browse/JIRA-{MATCH_1}\n\
+       gh = https://github.com/{ARG1}/tree/{ARG2:main}\n\
+       _ = https://duckduckgo.com/?q={ALL}\n",
+    )
+    .unwrap();
+    let dir = assert_fs::TempDir::new().unwrap();
+    let path = dir.path().join("ezproxy.txt");
+    write_rules_to(&rules, &path).unwrap();
+
+    let written = fs::read_to_string(&path).unwrap();
+    let (header, body) = written.split_once('\n').unwrap();
+    assert!(header.starts_with("# Written by ezproxy at "), "{}", header);
+    assert_eq!(body, rules_to_string(&rules));
+    assert!(!dir.path().join("ezproxy.txt.tmp").exists());
+
+    let reparsed = parse_rules_from(&path).unwrap();
+    assert_eq!(
+      reparsed.keywords().collect::<Vec<_>>(),
+      rules.keywords().collect::<Vec<_>>()
+    );
+    for (keyword, rule) in rules.iter() {
+      assert_eq!(
+        reparsed[keyword].describe().template,
+        rule.describe().template,
+        "{}",
+        keyword
+      );
+      assert_eq!(reparsed[keyword].status_code(), rule.status_code());
+    }
+    assert_eq!(rules_to_string(&reparsed), rules_to_string(&rules));
+  }
+
+  #[test]
+  fn written_toml_rules_parse_to_the_same_rules() {
+    let rules = parse_rules_from_str(
+      "m[301] = https://gmail.com/\n\
+       jira[min=1,max=1] = https://myco.atlassian.net/browse/{ARG1}\n\
+       g,google = https://www.google.com/search?q={ARGS}\n\
+       re:^jira-(\\d+)$ = https://myco.atlassian.net/browse/JIRA-{MATCH_1}\n",
+    )
+    .unwrap();
+    let dir = assert_fs::TempDir::new().unwrap();
+    let path = dir.path().join("ezproxy.toml");
+    write_rules_to(&rules, &path).unwrap();
+
+    let written = fs::read_to_string(&path).unwrap();
+    assert!(
+      written.starts_with("# Written by ezproxy at "),
+      "{}",
+      written
+    );
+    let reparsed = parse_rules_from(&path).unwrap();
+    assert_eq!(rules_to_string(&reparsed), rules_to_string(&rules));
+  }
+
+  #[test]
+  fn written_json_rules_parse_to_the_same_rules() {
+    let rules = parse_rules_from_str(
+      "m[301] = https://gmail.com/\n\
+       jira[min=1,max=1] = https://myco.atlassian.net/browse/{ARG1}\n\
+       g,google = https://www.google.com/search?q={ARGS}\n\
+       re:^jira-(\\d+)$ = https://myco.atlassian.net/browse/JIRA-{MATCH_1}\n",
+    )
+    .unwrap();
+    let dir = assert_fs::TempDir::new().unwrap();
+    let path = dir.path().join("ezproxy.json");
+    write_rules_to(&rules, &path).unwrap();
+
+    let written: serde_json::Value =
+      serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+    assert_eq!(
+      written["rules"]["m"],
+      serde_json::json!({"uri": "https://gmail.com/", "status": 301})
+    );
+    assert_eq!(
+      written["rules"]["g"],
+      "https://www.google.com/search?q={ARGS}"
+    );
+    let reparsed = parse_rules_from(&path).unwrap();
+    assert_eq!(rules_to_string(&reparsed), rules_to_string(&rules));
+  }
+
+  #[test]
+  fn parse_rules_from_json_errors() {
+    assert!(matches!(
+      parse_rules_from_json_str(r#"{"rules": {"g": "https://www.google.com/",}}"#),
+      Err(ConfigError::InvalidJson(_))
+    ));
+    assert!(matches!(
+      parse_rules_from_json_str(r#"{"rules": {"g": {"url": "https://www.google.com/"}}}"#),
+      Err(ConfigError::InvalidJson(_))
+    ));
+    assert!(matches!(
+      parse_rules_from_json_str(
+        r#"{"rules": {"m": "https://gmail.com/", "g": {"uri": "https://www.google.com/", "status": 200}}}"#
+      ),
+      Err(ConfigError::InvalidStatusCode { line_number: 2, .. })
+    ));
+  }
+
+  #[test]
+  fn env_vars_are_written_unexpanded() {
+    std::env::set_var("EZPROXY_TEST_JIRA_TOKEN", "s3cr3t");
+    let config =
+      "jira[301] = https://myco.atlassian.net/browse/{ARG1}?token=${EZPROXY_TEST_JIRA_TOKEN}\n\
+                  m = https://gmail.com/\n";
+    let rules = parse_rules_from_str(config).unwrap();
+    assert_eq!(
+      rules["jira"]
+        .produce_uri("jira", &["ABC-1".to_string()])
+        .unwrap(),
+      "https://myco.atlassian.net/browse/ABC-1?token=s3cr3t"
+    );
+    assert_eq!(rules["jira"].status_code(), 301);
+
+    let written = rules_to_string(&rules);
+    assert_eq!(written, config);
+    assert!(!written.contains("s3cr3t"));
+    assert!(!rules_to_toml_string(&rules).contains("s3cr3t"));
+    assert!(!rules_to_json_string(&rules).contains("s3cr3t"));
+    let reparsed = parse_rules_from_str(&written).unwrap();
+    assert_eq!(
+      reparsed["jira"]
+        .produce_uri("jira", &["ABC-1".to_string()])
+        .unwrap(),
+      "https://myco.atlassian.net/browse/ABC-1?token=s3cr3t"
+    );
+  }
+
+  #[test]
+  fn parse_rules_expand_env_vars() {
+    std::env::set_var("EZPROXY_TEST_JIRA_HOST", "myco.atlassian.net");
+    std::env::set_var("EZPROXY_TEST_EMPTY", "");
+    std::env::remove_var("EZPROXY_TEST_UNSET");
+    let rules = parse_rules_from_str(
+      "jira = https://${EZPROXY_TEST_JIRA_HOST}/browse/{ARG1}\n\
+       wiki = https://${EZPROXY_TEST_UNSET:-wiki.example.com}/${EZPROXY_TEST_EMPTY:-home}\n\
+       blank = https://example.com/${EZPROXY_TEST_EMPTY}\n",
+    )
+    .unwrap();
+    assert_eq!(
+      rules["jira"]
+        .produce_uri("jira", &["ABC-1".to_string()])
+        .unwrap(),
+      "https://myco.atlassian.net/browse/ABC-1"
+    );
+    assert_eq!(
+      rules["wiki"].produce_uri("wiki", &[]).unwrap(),
+      "https://wiki.example.com/home"
+    );
+    assert_eq!(
+      rules["blank"].produce_uri("blank", &[]).unwrap(),
+      "https://example.com/"
+    );
+    // The template keeps the variables, not their values.
+    assert_eq!(
+      rules["jira"].template(),
+      Some("https://${EZPROXY_TEST_JIRA_HOST}/browse/{ARG1}")
+    );
+
+    match parse_rules_from_str("m = https://gmail.com/\nx,y = https://${EZPROXY_TEST_UNSET}/\n") {
+      Err(e @ ConfigError::UndefinedEnvVar { line_number: 2, .. }) => {
+        assert_eq!(
+          e.to_string(),
+          "Undefined environment variable EZPROXY_TEST_UNSET in the URL for x,y on config line 2 \
+           (give it a default with ${EZPROXY_TEST_UNSET:-default})"
+        );
+      }
+      other => panic!("Expected UndefinedEnvVar, got {:?}", other.map(|_| ())),
+    }
+  }
+
+  #[test]
+  fn parse_rules_from_toml_tables_and_strings() {
+    let rules = parse_rules_from_toml_str(
+      "[rules]\n\
+       g = \"https://www.google.com/search?q={ARGS}\"\n\
+       \"m,mail\" = { uri = \"https://gmail.com/\", status = 308 }\n\
+       \n\
+       [rules.gh]\n\
+       uri = \"https://github.com/{ARG1}\"\n\
+       status = 301\n\
+       \n\
+       [rules.c]\n\
+       uri = \"https://calendar.google.com/\"\n",
+    )
+    .unwrap();
+    assert_eq!(
+      rules.keywords().collect::<Vec<_>>(),
+      ["g", "m", "mail", "gh", "c"]
+    );
+    assert_eq!(rules["g"].status_code(), DEFAULT_STATUS_CODE);
+    assert_eq!(rules["mail"].status_code(), 308);
+    assert_eq!(rules["gh"].status_code(), 301);
+    assert_eq!(
+      rules["gh"]
+        .produce_uri("gh", &["rust-lang".to_string()])
+        .unwrap(),
+      "https://github.com/rust-lang"
+    );
+    assert_eq!(rules["c"].status_code(), DEFAULT_STATUS_CODE);
+  }
+
+  #[test]
+  fn parse_rules_from_toml_errors() {
+    assert!(matches!(
+      parse_rules_from_toml_str("[rules]\ng = https://www.google.com/\n"),
+      Err(ConfigError::InvalidToml(_))
+    ));
+    assert!(matches!(
+      parse_rules_from_toml_str("[rules.g]\nurl = \"https://www.google.com/\"\n"),
+      Err(ConfigError::InvalidToml(_))
+    ));
+    assert!(matches!(
+      parse_rules_from_toml_str(
+        "[rules]\nm = \"https://gmail.com/\"\n\n[rules.g]\nuri = \"https://www.google.com/\"\nstatus = 200\n"
+      ),
+      Err(ConfigError::InvalidStatusCode { line_number: 4, .. })
+    ));
+    assert!(matches!(
+      parse_rules_from_toml_str(
+        "[rules]\nm = \"https://gmail.com/\"\n\"m,mail\" = \"https://mail.yahoo.com/\"\n"
+      ),
+      Err(ConfigError::DuplicateKeyword {
+        first_line: 2,
+        second_line: 3,
+        ..
+      })
+    ));
+  }
+
+  #[test]
+  fn parse_rules_from_detects_toml() {
+    let config_file = assert_fs::NamedTempFile::new("config.toml").unwrap();
+    config_file
+      .write_str("[rules]\nm = \"https://gmail.com/\"\n")
+      .unwrap();
+    let rules = parse_rules_from(config_file.path()).unwrap();
+    assert_eq!(rules["m"].template(), Some("https://gmail.com/"));
+  }
+
+  #[test]
+  fn merge_disjoint_registries() {
+    let base =
+      parse_rules_from_str("m = https://gmail.com/\nc = https://calendar.google.com/\n").unwrap();
+    let overlay = parse_rules_from_str("npm = https://npmjs.com/\n").unwrap();
+    let merged = merge(base, overlay);
+    assert_eq!(
+      rules_to_string(&merged),
+      "m = https://gmail.com/\nc = https://calendar.google.com/\nnpm = https://npmjs.com/\n"
+    );
+  }
+
+  #[test]
+  fn merge_fully_overlapping_registries() {
+    let base =
+      parse_rules_from_str("m = https://gmail.com/\nc = https://calendar.google.com/\n").unwrap();
+    let overlay =
+      parse_rules_from_str("c = https://outlook.com/calendar\nm[301] = https://mail.yahoo.com/\n")
+        .unwrap();
+    let merged = merge(base, overlay);
+    assert_eq!(
+      rules_to_string(&merged),
+      "m[301] = https://mail.yahoo.com/\nc = https://outlook.com/calendar\n"
+    );
+  }
+
+  #[test]
+  fn merge_partially_overlapping_registries() {
+    let base = parse_rules_from_str(
+      "m = https://gmail.com/\nre:^jira-(\\d+)$ = https://old/{MATCH_1}\ng = https://google.com/\n",
+    )
+    .unwrap();
+    let overlay = parse_rules_from_str(
+      "re:^jira-(\\d+)$ = https://new/{MATCH_1}\nnpm,n = https://npmjs.com/search?q={ARGS}\n",
+    )
+    .unwrap();
+    let mut merged = merge(base, overlay);
+    assert_eq!(
+      merged.keywords().collect::<Vec<_>>(),
+      vec!["m", r"re:^jira-(\d+)$", "g", "npm", "n"]
+    );
+    assert_eq!(
+      merged
+        .pattern_rule("jira-7")
+        .map(|(_, rule)| rule.template()),
+      Some(Some("https://new/{MATCH_1}"))
+    );
+    assert_eq!(merged["n"].template(), merged["npm"].template());
+
+    // Merging nothing in changes nothing.
+    merged.extend(RuleRegistry::new());
+    assert_eq!(merged.len(), 5);
+  }
+
+  #[test]
+  #[cfg(feature = "serde")]
+  fn config_rule_serde_round_trip() {
+    let rule = ConfigRule::new("m", "https://gmail.com/").with_status_code(301);
+    let json = serde_json::to_string(&rule).unwrap();
+    assert_eq!(
+      json,
+      r#"{"keyword":"m","uri":"https://gmail.com/","status":301}"#
+    );
+    let parsed: ConfigRule = serde_json::from_str(&json).unwrap();
+    assert_eq!(parsed.to_string(), rule.to_string());
+
+    let parsed: ConfigRule =
+      serde_json::from_str(r#"{"keyword": " cafe\u0301 ", "uri": "https://example.com/{ARGS}"}"#)
+        .unwrap();
+    assert_eq!(parsed.kw(), "caf\u{e9}");
+    assert_eq!(parsed.status_code(), DEFAULT_STATUS_CODE);
+    assert!(serde_json::from_str::<ConfigRule>(r#"{"keyword": "m"}"#).is_err());
+
+    let limited = ConfigRule::new("gh", "https://github.com/{ARG1}")
+      .with_min_args(1)
+      .with_max_args(2);
+    let parsed: ConfigRule =
+      serde_json::from_str(&serde_json::to_string(&limited).unwrap()).unwrap();
+    assert_eq!(parsed.to_string(), limited.to_string());
+    let err = serde_json::from_str::<ConfigRule>(
+      r#"{"keyword": "gh", "uri": "https://github.com/{ARG1}", "min_args": 2, "max_args": 1}"#,
+    )
+    .unwrap_err();
+    assert!(
+      err
+        .to_string()
+        .contains("min_args (2) is more than max_args (1)"),
+      "{}",
+      err
+    );
+  }
+
+  #[test]
+  fn config_rule_downcasts_from_dyn_rule() {
+    let rules: RuleRegistry = [(
+      "m",
+      Box::new(ConfigRule::new("m", "https://gmail.com/")) as Box<dyn Rule>,
+    )]
+    .into_iter()
+    .collect();
+    let rule = rules["m"].downcast_ref::<ConfigRule>().unwrap().clone();
+    assert_eq!(rule.uri(), "https://gmail.com/");
+    assert!(rules["m"].downcast_ref::<RegexRule>().is_none());
+    assert!(rules["m"].as_any().is::<ConfigRule>());
+  }
+
+  #[test]
+  fn new_config_rule_trims_whitespace() {
+    let config_rule = ConfigRule::new("  m  ", "  https://gmail.com/  ");
+    assert_eq!(config_rule.kw(), "m");
+    assert_eq!(
+      config_rule.produce_uri("m", &[]).unwrap(),
+      "https://gmail.com/"
+    );
+  }
+
+  #[test]
+  fn new_config_rule_normalizes_keyword() {
+    // "café" written with a combining acute accent.
+    let config_rule = ConfigRule::new("cafe\u{301}", "https://example.com/");
+    assert_eq!(config_rule.kw(), "caf\u{e9}");
+  }
+
+  #[test]
+  fn parse_rules_from_padded_lines() {
+    let rules = parse_rules_from_str(
+      "  m  =  https://gmail.com/  \nnpm   =   https://npmjs.com/search?q={ARGS}\n",
+    )
+    .unwrap();
+    assert_eq!(
+      rules["m"].produce_uri("m", &[]).unwrap(),
+      "https://gmail.com/"
+    );
+    assert_eq!(
+      rules["npm"]
+        .produce_uri("npm", &["file".to_string()])
+        .unwrap(),
+      "https://npmjs.com/search?q=file"
+    );
+  }
+
+  fn config_file(content: &str) -> assert_fs::NamedTempFile {
+    let config_file = assert_fs::NamedTempFile::new("config.txt").unwrap();
+    config_file.write_str(content).unwrap();
+    config_file
+  }
+
+  #[test]
+  fn parse_rules_from_skips_comments_and_blank_lines() {
+    let rules = parse_rules_from_str(
+      "# Mail\n\
+       m = https://gmail.com/\n\
+       \n   \n\
+       \t# Docs, indented\n\
+       rt = https://docs.rs/tokio/latest/tokio/runtime/index.html#struct.Runtime\n",
+    )
+    .unwrap();
+    assert_eq!(rules.len(), 2);
+    assert_eq!(
+      rules["rt"].produce_location("rt", &[]).unwrap(),
+      "https://docs.rs/tokio/latest/tokio/runtime/index.html#struct.Runtime"
+    );
+  }
+
+  #[test]
+  fn parse_options_keep_last_duplicate() {
+    let options = ParseOptions::default().on_duplicate(DuplicatePolicy::KeepLast);
+    let rules = parse_rules_from_str_with_options(
+      "m = https://gmail.com/\nm = https://mail.yahoo.com/\n",
+      &options,
+    )
+    .unwrap();
+    assert_eq!(
+      rules["m"].produce_uri("m", &[]).unwrap(),
+      "https://mail.yahoo.com/"
+    );
+  }
+
+  #[test]
+  fn duplicate_policy_from_str() {
+    assert_eq!("error".parse(), Ok(DuplicatePolicy::Error));
+    assert_eq!("warn-keep-first".parse(), Ok(DuplicatePolicy::KeepFirst));
+    assert_eq!("warn-keep-last".parse(), Ok(DuplicatePolicy::KeepLast));
+    assert!("keep-last".parse::<DuplicatePolicy>().is_err());
+  }
+
+  #[test]
+  fn parse_options_keep_first_duplicate() {
+    let options = ParseOptions::default().on_duplicate(DuplicatePolicy::KeepFirst);
+    let rules = parse_rules_from_str_with_options(
+      "m = https://gmail.com/\nm = https://mail.yahoo.com/\n",
+      &options,
+    )
+    .unwrap();
+    assert_eq!(
+      rules["m"].produce_uri("m", &[]).unwrap(),
+      "https://gmail.com/"
+    );
+  }
+
+  #[test]
+  fn parse_rules_from_errors_on_duplicate() {
+    match parse_rules_from_str("m = https://gmail.com/\n# Yahoo\nm = https://mail.yahoo.com/\n") {
+      Err(ConfigError::DuplicateKeyword {
+        keyword,
+        first_line,
+        second_line,
+      }) => {
+        assert_eq!(keyword, "m");
+        assert_eq!(first_line, 1);
+        assert_eq!(second_line, 3);
+      }
+      _ => panic!("Expected DuplicateKeyword"),
+    }
+  }
+
+  #[test]
+  fn parse_rules_from_missing_file() {
+    let dir = assert_fs::TempDir::new().unwrap();
+    match parse_rules_from(dir.path().join("missing.txt")) {
+      Err(ConfigError::IoError(e)) => assert_eq!(e.kind(), io::ErrorKind::NotFound),
+      _ => panic!("Expected IoError"),
+    }
+  }
+
+  #[test]
+  fn parse_rules_from_malformed_line() {
+    match parse_rules_from_str("m = https://gmail.com/\n\nnot a rule\n") {
+      Err(ConfigError::MalformedLine {
+        line_number,
+        content,
+      }) => {
+        assert_eq!(line_number, 3);
+        assert_eq!(content, "not a rule");
+      }
+      _ => panic!("Expected MalformedLine"),
+    }
+  }
+
+  #[test]
+  fn parse_rules_from_invalid_uri() {
+    let err = parse_rules_from_str("m = https://gmail.com/\nbad = https://exa mple.com/\n")
+      .err()
+      .unwrap();
+    assert_eq!(
+      err.to_string(),
+      "Invalid URL for bad on config line 2: https://exa mple.com/: invalid uri character"
+    );
+    assert!(std::error::Error::source(&err).is_some());
+  }
+
+  #[test]
+  fn parse_options_default_scheme() {
+    let options = ParseOptions::default().default_scheme("https");
+    let rules = parse_rules_from_str_with_options(
+      "m = gmail.com\nc = http://calendar.google.com/\n",
+      &options,
+    )
+    .unwrap();
+    assert_eq!(
+      rules["m"].produce_uri("m", &[]).unwrap(),
+      "https://gmail.com/"
+    );
+    assert_eq!(
+      rules["c"].produce_uri("c", &[]).unwrap(),
+      "http://calendar.google.com/"
+    );
+  }
+
+  #[test]
+  fn rules_to_string_round_trips() {
+    let config = "_ = https://www.google.com/search?q={ALL}\n\
+                  gh = https://github.com/{ARG1}/{ARG2}\n\
+                  m = https://gmail.com/\n";
+    let rules = parse_rules_from_str(config).unwrap();
+    assert_eq!(rules_to_string(&rules), config);
+  }
+
+  #[test]
+  fn parse_rules_from_status_codes() {
+    let err = parse_rules_from_str("m[301] = https://gmail.com/\nform[ 307 ] = https://example.com/submit\nnpm = https://npmjs.com/\n").err().unwrap();
+    assert_eq!(
+      err.to_string(),
+      "Invalid status code on config line 2:  307  (expected one of 301, 302, 303, 307, 308)"
+    );
+
+    let config = "form[307] = https://example.com/submit\n\
+                  m[301] = https://gmail.com/\n\
+                  npm = https://npmjs.com/\n";
+    let rules = parse_rules_from_str(config).unwrap();
+    assert_eq!(rules["m"].status_code(), 301);
+    assert_eq!(rules["form"].status_code(), 307);
+    assert_eq!(rules["npm"].status_code(), 302);
+    assert_eq!(rules_to_string(&rules), config);
+  }
+
+  #[test]
+  fn parse_rules_from_arg_limits() {
+    let config = "jira[min=1] = https://myco.atlassian.net/issues/?jql={ARGS}\n\
+                  gh[301,min=1,max=2] = https://github.com/{ARG1}/{ARG2:}\n";
+    let rules = parse_rules_from_str(config).unwrap();
+    assert!(matches!(
+      rules["jira"].produce_uri("jira", &[]),
+      Err(RuleError::MissingArgument { index: 1 })
+    ));
+    let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+    assert_eq!(
+      rules["gh"]
+        .produce_uri("gh", &args(&["rust-lang", "rust"]))
+        .unwrap(),
+      "https://github.com/rust-lang/rust"
+    );
+    assert!(matches!(
+      rules["gh"].produce_uri("gh", &args(&["rust-lang", "rust", "issues"])),
+      Err(RuleError::TooManyArguments { max: 2, given: 3 })
+    ));
+    assert_eq!(rules["gh"].status_code(), 301);
+    assert_eq!(rules_to_string(&rules), config);
+
+    for (line, limits) in [
+      (
+        "jira[min=2,max=1] = https://myco.atlassian.net/browse/{ARG1}",
+        "min=2,max=1",
+      ),
+      (
+        "jira[min=one] = https://myco.atlassian.net/browse/{ARG1}",
+        "min=one",
+      ),
+      (
+        "jira[least=1] = https://myco.atlassian.net/browse/{ARG1}",
+        "least=1",
+      ),
+      (
+        "g[min=1] = https://google.com/?q={ARGS} | https://bing.com/?q={ARGS}",
+        "min=1",
+      ),
+      ("g[max=1] = script:/usr/local/bin/g", "max=1"),
+    ] {
+      match parse_rules_from_str(line) {
+        Err(ConfigError::InvalidArgLimits {
+          line_number: 1,
+          limits: written,
+        }) => assert_eq!(written, limits, "{}", line),
+        other => panic!("Expected InvalidArgLimits for {}, got {:?}", line, other),
+      }
+    }
+  }
+
+  #[test]
+  fn parse_inline_rules_override_earlier_rules() {
+    let rules = parse_inline_rules(
+      &[
+        "m = https://gmail.com/",
+        "g,google = https://www.google.com/",
+        "m = https://mail.yahoo.com/",
+      ],
+      &ParseOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(
+      rules.keywords().collect::<Vec<_>>(),
+      vec!["m", "g", "google"]
+    );
+    assert_eq!(
+      rules["m"].produce_uri("m", &[]).unwrap(),
+      "https://mail.yahoo.com/"
+    );
+
+    let rules = ["m = https://gmail.com/", "g[200] = https://www.google.com/"];
+    let err = match parse_inline_rules(&rules, &ParseOptions::default()) {
+      Err(e) => e,
+      Ok(_) => panic!("Expected InlineRule"),
+    };
+    assert_eq!(
+      err.to_string(),
+      "Invalid inline rule #2 (g[200] = https://www.google.com/): Invalid status code on config line 1: 200 (expected one of 301, 302, 303, 307, 308)"
+    );
+  }
+
+  #[test]
+  fn parse_rules_from_keeps_config_order() {
+    let rules = parse_rules_from_str("npm = https://npmjs.com/search?q={ARGS}\nm = https://gmail.com/\n# Fallback\n_ = https://www.google.com/search?q={ALL}\nc = https://calendar.google.com/\n").unwrap();
+    assert_eq!(
+      rules.keywords().collect::<Vec<_>>(),
+      vec!["npm", "m", "_", "c"]
+    );
+  }
+
+  #[test]
+  fn parse_rules_from_multiple_files() {
+    let work = config_file("jira = https://myco.atlassian.net/\ng = https://www.google.com/\n");
+    let personal = config_file("m = https://gmail.com/\ng = https://duckduckgo.com/\n");
+    let paths = [work.path(), personal.path()];
+
+    match parse_rules_from_multiple(&paths) {
+      Err(ConfigError::DuplicateKeywordInFiles {
+        keyword,
+        first_path,
+        second_path,
+      }) => {
+        assert_eq!(keyword, "g");
+        assert_eq!(first_path, work.path());
+        assert_eq!(second_path, personal.path());
+      }
+      _ => panic!("Expected DuplicateKeywordInFiles"),
+    }
+
+    let options = ParseOptions::default().on_duplicate(DuplicatePolicy::KeepFirst);
+    let rules = parse_rules_from_multiple_with_options(&paths, &options).unwrap();
+    assert_eq!(rules.keywords().collect::<Vec<_>>(), vec!["jira", "g", "m"]);
+    assert_eq!(
+      rules["g"].produce_uri("g", &[]).unwrap(),
+      "https://www.google.com/"
+    );
+
+    let options = ParseOptions::default().on_duplicate(DuplicatePolicy::KeepLast);
+    let rules = parse_rules_from_multiple_with_options(&paths, &options).unwrap();
+    assert_eq!(
+      rules["g"].produce_uri("g", &[]).unwrap(),
+      "https://duckduckgo.com/"
+    );
+
+    let broken = config_file("m = https://gmail.com/\nnot a rule\n");
+    match parse_rules_from_multiple(&[work.path(), broken.path()]) {
+      Err(ConfigError::InFile { path, source }) => {
+        assert_eq!(path, broken.path());
+        assert!(matches!(
+          *source,
+          ConfigError::MalformedLine { line_number: 2, .. }
+        ));
+      }
+      _ => panic!("Expected InFile"),
+    }
+  }
+
+  #[test]
+  fn parse_rules_from_aliases() {
+    let rules = parse_rules_from_str(
+      "g, google ,search[301] = https://www.google.com/search?q={ARGS}\nm = https://gmail.com/\n",
+    )
+    .unwrap();
+    assert_eq!(rules.len(), 4);
+    for kw in ["g", "google", "search"] {
+      assert_eq!(
+        rules[kw].produce_uri(kw, &["rust".to_string()]).unwrap(),
+        "https://www.google.com/search?q=rust"
+      );
+      assert_eq!(rules[kw].status_code(), 301);
+    }
+
+    match parse_rules_from_str("m = https://gmail.com/\ng,,google = https://www.google.com/\n") {
+      Err(ConfigError::EmptyAlias {
+        line_number,
+        keywords,
+      }) => {
+        assert_eq!(line_number, 2);
+        assert_eq!(keywords, "g,,google");
+      }
+      _ => panic!("Expected EmptyAlias"),
+    }
+
+    match parse_rules_from_str("g,google = https://www.google.com/\ngoogle = https://google.com/\n")
+    {
+      Err(ConfigError::DuplicateKeyword { keyword, .. }) => assert_eq!(keyword, "google"),
+      _ => panic!("Expected DuplicateKeyword"),
+    }
+  }
+
+  #[test]
+  fn parse_rules_from_regex_rules() {
+    let config = "m = https://gmail.com/\n\
+                  re:^jira-(\\d+)$ = https://myco.atlassian.net/browse/JIRA-{MATCH_1}\n";
+    let rules = parse_rules_from_str(config).unwrap();
+    let rule = &rules[r"re:^jira-(\d+)$"];
+    assert!(rule.pattern().unwrap().is_match("jira-42"));
+    assert_eq!(
+      rule.produce_uri("jira-42", &[]).unwrap(),
+      "https://myco.atlassian.net/browse/JIRA-42"
+    );
+    assert_eq!(rules_to_string(&rules), config);
+
+    match parse_rules_from_str("re:^jira-(\\d+$ = https://myco.atlassian.net/\n") {
+      Err(ConfigError::InvalidPattern {
+        line_number,
+        pattern,
+        ..
+      }) => {
+        assert_eq!(line_number, 1);
+        assert_eq!(pattern, r"^jira-(\d+$");
+      }
+      _ => panic!("Expected InvalidPattern"),
+    }
+  }
+
+  #[test]
+  fn parse_rules_from_rejects_non_redirect_status() {
+    match parse_rules_from_str("m[200] = https://gmail.com/\n") {
+      Err(ConfigError::InvalidStatusCode {
+        line_number,
+        status,
+      }) => {
+        assert_eq!(line_number, 1);
+        assert_eq!(status, "200");
+      }
+      _ => panic!("Expected InvalidStatusCode"),
+    }
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn script_rules() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = assert_fs::TempDir::new().unwrap();
+    let script = dir.child("route.sh");
+    script
+      .write_str(
+        "#!/bin/sh\n\
+         [ \"$2\" = fail ] && exit 3\n\
+         [ \"$2\" = slow ] && sleep 5\n\
+         [ \"$2\" = quiet ] && exit 0\n\
+         [ \"$2\" = docs ] && echo https://example.com/docs#install && exit 0\n\
+         echo \"https://example.com/$1/$2\"\n",
+      )
+      .unwrap();
+    fs::set_permissions(script.path(), fs::Permissions::from_mode(0o755)).unwrap();
+    let rules = parse_rules_from_str(&format!(
+      "g,google[301] = script:{} 0.2\n",
+      script.path().display()
+    ))
+    .unwrap();
+    let rule = &rules["google"];
+    assert_eq!(rule.status_code(), 301);
+    assert_eq!(
+      rule.describe().template,
+      Some(format!("script:{} 0.2", script.path().display()))
+    );
+
+    let args = |arg: &str| vec![arg.to_string()];
+    assert_eq!(
+      rule.produce_uri("g", &args("rust")).unwrap(),
+      "https://example.com/g/rust"
+    );
+    let async_rule = rule.as_async().unwrap();
+    assert_eq!(
+      async_rule.produce_uri("g", &args("rust")).await.unwrap(),
+      "https://example.com/g/rust"
+    );
+    // Locations are passed on as printed, so they keep their fragment.
+    assert_eq!(
+      rule.produce_location("g", &args("docs")).unwrap(),
+      "https://example.com/docs#install"
+    );
+    assert_eq!(
+      async_rule
+        .produce_location("g", &args("docs"))
+        .await
+        .unwrap(),
+      "https://example.com/docs#install"
+    );
+    for arg in ["fail", "slow", "quiet"] {
+      assert!(matches!(
+        rule.produce_uri("g", &args(arg)),
+        Err(RuleError::ExternalError(_))
+      ));
+      assert!(matches!(
+        async_rule.produce_uri("g", &args(arg)).await,
+        Err(RuleError::ExternalError(_))
+      ));
+    }
+  }
+
+  #[test]
+  fn script_rules_round_trip() {
+    let config = "g = script:/bin/route.sh 2\n\
+                  h[301] = script:/bin/route.sh 0.5\n\
+                  s = script:/bin/route.sh\n";
+    let rules = parse_rules_from_str(config).unwrap();
+    let written = rules_to_string(&rules);
+    assert_eq!(written, config);
+    let reparsed = parse_rules_from_str(&written).unwrap();
+    let timeout = |kw: &str| reparsed[kw].downcast_ref::<ScriptRule>().unwrap().timeout();
+    assert_eq!(timeout("g"), Duration::from_secs(2));
+    assert_eq!(timeout("h"), Duration::from_millis(500));
+    assert_eq!(timeout("s"), crate::rules::DEFAULT_SCRIPT_TIMEOUT);
+  }
+
+  #[test]
+  fn parse_rules_from_rejects_invalid_scripts() {
+    for script in ["/bin/route.sh soon", "/bin/route.sh 0", "/bin/route.sh 1 2"] {
+      match parse_rules_from_str(&format!("g = script:{}\n", script)) {
+        Err(ConfigError::InvalidScript {
+          line_number,
+          script: written,
+        }) => {
+          assert_eq!(line_number, 1);
+          assert_eq!(written, script);
+        }
+        _ => panic!("Expected InvalidScript for {}", script),
+      }
+    }
+  }
+
+  #[test]
+  fn load_balanced_rules() {
+    let rules = parse_rules_from_str_with_options(
+      "cdn[307] = cdn1.example.com/{ARGS} | https://cdn2.example.com/{ARGS}\n\
+       m = https://gmail.com/\n",
+      &ParseOptions::default().default_scheme("https"),
+    )
+    .unwrap();
+    let args = ["logo.png".to_string()];
+    let locations = (0..3)
+      .map(|_| rules["cdn"].produce_location("cdn", &args).unwrap())
+      .collect::<Vec<_>>();
+    assert_eq!(
+      locations,
+      [
+        "https://cdn1.example.com/logo.png",
+        "https://cdn2.example.com/logo.png",
+        "https://cdn1.example.com/logo.png",
+      ]
+    );
+    assert_eq!(rules["cdn"].status_code(), 307);
+    assert_eq!(
+      rules_to_string(&rules),
+      "cdn[307] = https://cdn1.example.com/{ARGS} | https://cdn2.example.com/{ARGS}\n\
+       m = https://gmail.com/\n"
+    );
+
+    match parse_rules_from_str_with_options(
+      "cdn = https://cdn1.example.com/ | https://cdn 2.example.com/\n",
+      &ParseOptions::default(),
+    ) {
+      Err(ConfigError::InvalidUri { uri, .. }) => assert_eq!(uri, "https://cdn 2.example.com/"),
+      _ => panic!("Expected InvalidUri"),
+    }
+  }
+
+  #[test]
+  fn timed_rules() {
+    let rules = parse_rules_from_str_with_options(
+      "standup = meet.google.com/abc [08:00-09:30] | calendar.google.com/ [*]\n",
+      &ParseOptions::default().default_scheme("https"),
+    )
+    .unwrap();
+    assert_eq!(
+      rules_to_string(&rules),
+      "standup = https://meet.google.com/abc [08:00-09:30] | https://calendar.google.com/ [*]\n"
+    );
+    assert!(rules["standup"].produce_uri("standup", &[]).is_ok());
+
+    for (rule, range) in [
+      ("a = https://a.example.com/ [8:00-09:30]", "8:00-09:30"),
+      ("a = https://a.example.com/ [08:00-24:00]", "08:00-24:00"),
+      (
+        "a = https://a.example.com/ [*] | https://b.example.com/",
+        "https://b.example.com/",
+      ),
+    ] {
+      match parse_rules_from_str_with_options(rule, &ParseOptions::default()) {
+        Err(ConfigError::InvalidTimeRange {
+          line_number,
+          range: written,
+        }) => {
+          assert_eq!(line_number, 1);
+          assert_eq!(written, range);
+        }
+        _ => panic!("Expected InvalidTimeRange for {}", rule),
+      }
+    }
+  }
+
+  #[test]
+  fn parse_rules_from_validates_templates() {
+    for (config, keyword) in [
+      ("gh,github = https://github .com/{ARG1}", "gh,github"),
+      ("s[301] = https://example.com/search?q={ARGS} now", "s"),
+      (
+        r"re:^jira-(\d+)$ = https://myco.atlassian .net/{MATCH_1}",
+        r"re:^jira-(\d+)$",
+      ),
+    ] {
+      match parse_rules_from_str_with_options(config, &ParseOptions::default()) {
+        Err(ConfigError::InvalidUri {
+          line_number,
+          keyword: written,
+          ..
+        }) => {
+          assert_eq!(line_number, 1);
+          assert_eq!(written, keyword);
+        }
+        _ => panic!("Expected InvalidUri for {}", config),
+      }
+    }
+    assert!(parse_rules_from_str_with_options(
+      "gh = https://{ARG1:www}.github.com/{ARGS_FROM:2}#{ARG2}",
+      &ParseOptions::default()
+    )
+    .is_ok());
+  }
+
+  #[test]
+  fn ignore_invalid_rules() {
+    let config =
+      "m = https://gmail.com/\nc = https://calendar google.com/\nm = https://mail.yahoo.com/\n";
+    let options = ParseOptions::default().ignore_invalid_rules(true);
+    // Only invalid URLs are ignored; other errors still are errors.
+    assert!(matches!(
+      parse_rules_from_str_with_options(config, &options),
+      Err(ConfigError::DuplicateKeyword { .. })
+    ));
+  }
+
+  #[test]
+  fn rule_groups() {
+    let rules = parse_rules_from_str_with_options(
+      "prefix = https://example.com/{prefix}\n\
+       [github]\n\
+       prefix = https://github.com/\n\
+       issues = {prefix}search?q={ARGS}&type=issues\n\
+       \n\
+       prs    = {prefix}search?q={ARGS}&type=pullrequests\n\
+       [gitlab]\n\
+       prefix = gitlab.com/\n\
+       gl = {prefix}explore?name={ARGS}\n\
+       [empty]\n\
+       m = https://gmail.com/\n",
+      &ParseOptions::default().default_scheme("https"),
+    )
+    .unwrap();
+    assert_eq!(
+      rules_to_string(&rules),
+      "prefix = https://example.com/{prefix}\n\
+       issues = https://github.com/search?q={ARGS}&type=issues\n\
+       prs = https://github.com/search?q={ARGS}&type=pullrequests\n\
+       gl = https://gitlab.com/explore?name={ARGS}\n\
+       m = https://gmail.com/\n"
+    );
+
+    // Line numbers still refer to the file as written.
+    match parse_rules_from_str_with_options(
+      "[github]\nprefix = https://github.com/\nnot a rule\n",
+      &ParseOptions::default(),
+    ) {
+      Err(ConfigError::MalformedLine { line_number, .. }) => assert_eq!(line_number, 3),
+      _ => panic!("Expected MalformedLine"),
+    }
+  }
+
+  #[test]
+  fn rules_to_string_skips_rules_without_template() {
+    let mut rules = RuleRegistry::new();
+    rules.insert(
+      "m".to_string(),
+      Box::new(ConfigRule::new("m", "https://gmail.com/")),
+    );
+    rules.insert(
+      "x".to_string(),
+      Box::new(MockRule::always_redirect(Uri::from_static(
+        "https://example.com/",
+      ))),
+    );
+    rules.insert("!ddg".to_string(), Box::new(crate::rules::BangRule));
+    assert_eq!(rules_to_string(&rules), "m = https://gmail.com/\n");
   }
 
   #[test]
@@ -110,6 +3248,171 @@ mod tests {
     assert_eq!(uri.to_string(), "http://example.com/arg1%20arg2");
   }
 
+  #[test]
+  fn produce_uri_positional_args() {
+    let config_rule = ConfigRule::new("gh", "https://github.com/{ARG1}/{ARG2}/issues?q={ARG3}");
+    let args = vec![
+      "rust-lang".to_string(),
+      "rust".to_string(),
+      "async fn".to_string(),
+    ];
+    let uri = config_rule.produce_uri("gh", &args).unwrap();
+    assert_eq!(
+      uri.to_string(),
+      "https://github.com/rust-lang/rust/issues?q=async%20fn"
+    );
+  }
+
+  #[test]
+  fn produce_uri_positional_args_with_args() {
+    let config_rule = ConfigRule::new("gh", "https://github.com/{ARG1}/search?q={ARGS}");
+    let args = vec!["rust-lang".to_string(), "async".to_string()];
+    let uri = config_rule.produce_uri("gh", &args).unwrap();
+    assert_eq!(
+      uri.to_string(),
+      "https://github.com/rust-lang/search?q=rust-lang%20async"
+    );
+  }
+
+  #[test]
+  fn produce_uri_positional_arg_missing() {
+    let config_rule = ConfigRule::new("jira", "https://myco.atlassian.net/browse/{ARG2}");
+    let err = config_rule
+      .produce_uri("jira", &["PROJ-123".to_string()])
+      .unwrap_err();
+    assert!(
+      matches!(err, RuleError::MissingArgument { index: 2 }),
+      "{}",
+      err
+    );
+  }
+
+  #[test]
+  fn produce_uri_positional_arg_zero() {
+    let config_rule = ConfigRule::new("jira", "https://myco.atlassian.net/browse/{ARG0}");
+    let err = config_rule
+      .produce_uri("jira", &["PROJ-123".to_string()])
+      .unwrap_err();
+    assert!(err.to_string().contains("one-indexed"), "{}", err);
+  }
+
+  #[test]
+  fn produce_uri_positional_arg_fallback() {
+    let config_rule = ConfigRule::new("gh", "https://github.com/{ARG1}/tree/{ARG2:main}");
+    let uri = config_rule
+      .produce_uri("gh", &["tokio".to_string()])
+      .unwrap();
+    assert_eq!(uri.to_string(), "https://github.com/tokio/tree/main");
+
+    let args = vec!["tokio".to_string(), "v1.x".to_string()];
+    let uri = config_rule.produce_uri("gh", &args).unwrap();
+    assert_eq!(uri.to_string(), "https://github.com/tokio/tree/v1.x");
+
+    let config_rule = ConfigRule::new("s", "https://example.com/?q={ARG1:}");
+    let uri = config_rule.produce_uri("s", &[]).unwrap();
+    assert_eq!(uri.to_string(), "https://example.com/?q=");
+  }
+
+  #[test]
+  fn produce_location_keeps_fragment() {
+    let config_rule = ConfigRule::new(
+      "rt",
+      "https://docs.rs/tokio/latest/tokio/runtime/index.html#struct.Runtime",
+    );
+    assert_eq!(
+      config_rule.produce_location("rt", &[]).unwrap(),
+      "https://docs.rs/tokio/latest/tokio/runtime/index.html#struct.Runtime"
+    );
+    assert_eq!(
+      config_rule.produce_uri("rt", &[]).unwrap(),
+      "https://docs.rs/tokio/latest/tokio/runtime/index.html"
+    );
+  }
+
+  #[test]
+  fn produce_location_fragment_with_query() {
+    let config_rule = ConfigRule::new("s", "https://example.com/search?q={ARGS}#results");
+    let args = vec!["rust".to_string()];
+    assert_eq!(
+      config_rule.produce_location("s", &args).unwrap(),
+      "https://example.com/search?q=rust#results"
+    );
+  }
+
+  #[test]
+  fn produce_location_args_in_fragment() {
+    let config_rule = ConfigRule::new("docs", "https://example.com/docs#{ARGS}");
+    let args = vec!["installation".to_string()];
+    assert_eq!(
+      config_rule.produce_location("docs", &args).unwrap(),
+      "https://example.com/docs#installation"
+    );
+    assert_eq!(
+      config_rule.produce_uri("docs", &args).unwrap(),
+      "https://example.com/docs"
+    );
+  }
+
+  #[test]
+  fn produce_uri_args_from() {
+    let config_rule = ConfigRule::new("ghpr", "https://github.com/{ARG1}/pulls?q={ARGS_FROM:2}");
+    let args = vec![
+      "tokio-rs/tokio".to_string(),
+      "is:open".to_string(),
+      "runtime".to_string(),
+    ];
+    assert_eq!(
+      config_rule.produce_uri("ghpr", &args).unwrap(),
+      "https://github.com/tokio-rs%2Ftokio/pulls?q=is%3Aopen%20runtime"
+    );
+    assert!(matches!(
+      config_rule.produce_uri("ghpr", &args[..1]),
+      Err(RuleError::MissingArgument { index: 2 })
+    ));
+
+    let config_rule = ConfigRule::new("s", "https://example.com/?q={ARGS_FROM:0}");
+    let err = config_rule.produce_uri("s", &args).unwrap_err();
+    assert!(err.to_string().contains("one-indexed"), "{}", err);
+  }
+
+  #[test]
+  fn produce_uri_args_csv() {
+    let config_rule = ConfigRule::new("tags", "https://example.com/?tags={ARGS_CSV}");
+    let args = vec!["rust".to_string(), "async io".to_string()];
+    assert_eq!(
+      config_rule.produce_uri("tags", &args).unwrap(),
+      "https://example.com/?tags=rust%2Casync%20io"
+    );
+    assert_eq!(
+      config_rule.produce_uri("tags", &[]).unwrap(),
+      "https://example.com/?tags="
+    );
+  }
+
+  #[test]
+  fn produce_uri_args_json() {
+    let config_rule = ConfigRule::new("labels", "https://example.com/?labels={ARGS_JSON}");
+    let args = vec!["rust".to_string(), "say \"hi\"".to_string()];
+    assert_eq!(
+      config_rule.produce_uri("labels", &args).unwrap(),
+      "https://example.com/?labels=%5B%22rust%22%2C%22say%20%5C%22hi%5C%22%22%5D"
+    );
+    assert_eq!(
+      config_rule.produce_uri("labels", &[]).unwrap(),
+      "https://example.com/?labels=%5B%5D"
+    );
+  }
+
+  #[test]
+  fn produce_uri_args_with_csv() {
+    let config_rule = ConfigRule::new("s", "https://example.com/?q={ARGS}&tags={ARGS_CSV}");
+    let args = vec!["rust".to_string(), "hyper".to_string()];
+    assert_eq!(
+      config_rule.produce_uri("s", &args).unwrap(),
+      "https://example.com/?q=rust%20hyper&tags=rust%2Chyper"
+    );
+  }
+
   #[test]
   fn produce_uri_no_replace() {
     let config_rule = ConfigRule::new("test_kw", "http://example.com/");
@@ -120,4 +3423,72 @@ mod tests {
     let uri = result.unwrap();
     assert_eq!(uri.to_string(), "http://example.com/");
   }
+
+  mod properties {
+    use super::*;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+    use proptest::string::string_regex;
+
+    /// URLs without placeholders, e.g. `https://abc.com/x-1/y?k=v`.
+    fn plain_url() -> impl Strategy<Value = String> {
+      string_regex(concat!(
+        r"https?://[a-z]{1,10}\.(com|org|dev)",
+        r"(/[a-zA-Z0-9_.-]{1,8}){0,3}(\?[a-z]{1,5}=[a-z0-9]{0,5})?",
+      ))
+      .unwrap()
+    }
+
+    /// Templates mixing URL characters with placeholders, well-formed or not.
+    fn template() -> impl Strategy<Value = String> {
+      string_regex(concat!(
+        r"(https?://[a-z]{1,5}\.com/)?",
+        r"([a-z0-9{}:/?=&%# ]|\{ARGS\}|\{ALL\}|\{ARG[0-9]{1,2}(:[a-z]{0,3})?\}",
+        r"|\{ARGS_FROM:[0-9]{1,2}\}|\{ARGS_CSV\}|\{ARGS_JSON\}){0,8}",
+      ))
+      .unwrap()
+    }
+
+    fn args() -> impl Strategy<Value = Vec<String>> {
+      vec(any::<String>(), 0..5)
+    }
+
+    proptest! {
+      #[test]
+      fn templates_without_placeholders_are_used_as_is(
+        url in plain_url(),
+        cmd in any::<String>(),
+        args in args(),
+      ) {
+        let rule = ConfigRule::new("kw", url.as_str());
+        prop_assert_eq!(rule.produce_uri(&cmd, &args).unwrap(), url.parse::<Uri>().unwrap());
+      }
+
+      #[test]
+      fn args_are_url_encoded(url in plain_url(), args in args()) {
+        let separator = if url.contains('?') { "&q=" } else { "?q=" };
+        let template = format!("{}{}{{ARGS}}", url, separator);
+        let rule = ConfigRule::new("kw", template);
+        let uri = rule.produce_uri("kw", &args).unwrap();
+        let encoded = urlencoding::encode(&args.join(" ")).into_owned();
+        prop_assert!(
+          uri.to_string().contains(&encoded),
+          "{} doesn't contain {}",
+          uri,
+          encoded
+        );
+      }
+
+      #[test]
+      fn producing_uris_never_panics(
+        template in template(),
+        cmd in any::<String>(),
+        args in args(),
+      ) {
+        let rule = ConfigRule::new("kw", template);
+        let _ = rule.produce_uri(&cmd, &args);
+        let _ = rule.produce_location(&cmd, &args);
+      }
+    }
+  }
 }
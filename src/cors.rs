@@ -0,0 +1,94 @@
+//! Cross-origin resource sharing (CORS), for calling ezproxy from JavaScript on other sites, e.g.
+//! a browser extension or single-page app.
+
+use http::HeaderValue;
+
+/// The origins allowed to call ezproxy from JavaScript.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::cors::Cors;
+/// use http::HeaderValue;
+///
+/// let cors = Cors::new(["https://example.com"]);
+/// let origin = HeaderValue::from_static("https://example.com");
+/// assert_eq!(cors.allow_origin(Some(&origin)), Some(origin.clone()));
+/// assert_eq!(cors.allow_origin(Some(&HeaderValue::from_static("https://evil.com"))), None);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Cors {
+  origins: Vec<String>,
+}
+
+impl Cors {
+  /// The `Access-Control-Allow-Methods` header: redirects need `GET`, or `POST` for forms.
+  pub const ALLOW_METHODS: &'static str = "GET, POST";
+  /// The `Access-Control-Max-Age` header, in seconds: browsers can cache preflights for a day.
+  pub const MAX_AGE: u32 = 86400;
+
+  /// Allows calls from each of `origins`, e.g. `https://example.com`. The origin `*` allows
+  /// calls from anywhere.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::cors::Cors;
+  ///
+  /// assert!(Cors::new(["*"]).allows_any());
+  /// assert!(!Cors::new(Vec::<String>::new()).allows_any());
+  /// ```
+  pub fn new<I, S>(origins: I) -> Self
+  where
+    I: IntoIterator<Item = S>,
+    S: Into<String>,
+  {
+    Cors {
+      origins: origins.into_iter().map(Into::into).collect(),
+    }
+  }
+
+  /// Returns true if some origin is allowed, i.e. CORS headers will be added to some responses.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::cors::Cors;
+  ///
+  /// assert!(Cors::new(["https://example.com"]).allows_any());
+  /// assert!(!Cors::default().allows_any());
+  /// ```
+  pub fn allows_any(&self) -> bool {
+    !self.origins.is_empty()
+  }
+
+  /// Returns the `Access-Control-Allow-Origin` header for a request from `origin`, its `Origin`
+  /// header, or `None` if it isn't allowed. A request without an `Origin` isn't a cross-origin
+  /// request, so it gets no header.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::cors::Cors;
+  /// use http::HeaderValue;
+  ///
+  /// let cors = Cors::new(["*"]);
+  /// let origin = HeaderValue::from_static("https://example.com");
+  /// assert_eq!(cors.allow_origin(Some(&origin)).unwrap(), "*");
+  /// assert_eq!(cors.allow_origin(None), None);
+  /// ```
+  pub fn allow_origin(&self, origin: Option<&HeaderValue>) -> Option<HeaderValue> {
+    let origin = origin?;
+    if self.origins.iter().any(|allowed| allowed == "*") {
+      Some(HeaderValue::from_static("*"))
+    } else if self
+      .origins
+      .iter()
+      .any(|allowed| allowed.as_bytes() == origin.as_bytes())
+    {
+      Some(origin.clone())
+    } else {
+      None
+    }
+  }
+}
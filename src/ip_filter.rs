@@ -0,0 +1,110 @@
+//! Decides which clients may use ezproxy by their IP address.
+
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+/// Allows or denies clients by the networks their addresses are in.
+///
+/// A client in any denied network is refused. Otherwise, if there are allowed networks, only
+/// clients in one of them are let in; with only allowed networks, ezproxy is private to them.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::ip_filter::IpFilter;
+/// use std::net::IpAddr;
+///
+/// let filter = IpFilter::new()
+///   .with_allowed("192.168.1.0/24".parse().unwrap())
+///   .with_denied("192.168.1.13/32".parse().unwrap());
+/// assert!(filter.is_allowed(Some(IpAddr::from([192, 168, 1, 12]))));
+/// assert!(!filter.is_allowed(Some(IpAddr::from([192, 168, 1, 13]))));
+/// assert!(!filter.is_allowed(Some(IpAddr::from([10, 0, 0, 1]))));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct IpFilter {
+  allowed: Vec<IpNet>,
+  denied: Vec<IpNet>,
+}
+
+impl IpFilter {
+  /// Creates a filter that allows every client.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::ip_filter::IpFilter;
+  /// use std::net::IpAddr;
+  ///
+  /// assert!(IpFilter::new().is_allowed(Some(IpAddr::from([10, 0, 0, 1]))));
+  /// assert!(IpFilter::new().is_empty());
+  /// ```
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Lets in clients in `net`, and keeps out any client not in an allowed network.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::ip_filter::IpFilter;
+  /// use std::net::IpAddr;
+  ///
+  /// let filter = IpFilter::new().with_allowed("10.0.0.0/8".parse().unwrap());
+  /// assert!(filter.is_allowed(Some(IpAddr::from([10, 1, 2, 3]))));
+  /// assert!(!filter.is_allowed(Some(IpAddr::from([127, 0, 0, 1]))));
+  /// ```
+  pub fn with_allowed(mut self, net: IpNet) -> Self {
+    self.allowed.push(net);
+    self
+  }
+
+  /// Keeps out clients in `net`, even if they're in an allowed network too.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::ip_filter::IpFilter;
+  /// use std::net::IpAddr;
+  ///
+  /// let filter = IpFilter::new().with_denied("203.0.113.0/24".parse().unwrap());
+  /// assert!(!filter.is_allowed(Some(IpAddr::from([203, 0, 113, 7]))));
+  /// assert!(filter.is_allowed(Some(IpAddr::from([127, 0, 0, 1]))));
+  /// ```
+  pub fn with_denied(mut self, net: IpNet) -> Self {
+    self.denied.push(net);
+    self
+  }
+
+  /// Returns true if the filter neither allows nor denies any networks, so it lets everyone in.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::ip_filter::IpFilter;
+  ///
+  /// assert!(IpFilter::new().is_empty());
+  /// assert!(!IpFilter::new().with_denied("10.0.0.0/8".parse().unwrap()).is_empty());
+  /// ```
+  pub fn is_empty(&self) -> bool {
+    self.allowed.is_empty() && self.denied.is_empty()
+  }
+
+  /// Returns true if the client at `ip` may use ezproxy. A client whose address isn't known,
+  /// e.g. one connected over a Unix domain socket, can't be in any network, so it's only let in
+  /// if there are no allowed networks.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::ip_filter::IpFilter;
+  ///
+  /// assert!(IpFilter::new().with_denied("10.0.0.0/8".parse().unwrap()).is_allowed(None));
+  /// assert!(!IpFilter::new().with_allowed("10.0.0.0/8".parse().unwrap()).is_allowed(None));
+  /// ```
+  pub fn is_allowed(&self, ip: Option<IpAddr>) -> bool {
+    let in_any = |nets: &[IpNet]| ip.is_some_and(|ip| nets.iter().any(|net| net.contains(&ip)));
+    !in_any(&self.denied) && (self.allowed.is_empty() || in_any(&self.allowed))
+  }
+}
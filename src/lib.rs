@@ -1,2 +1,53 @@
+//! ezproxy: keyboard shortcuts for your address bar.
+//!
+//! This crate exposes the building blocks used by the `ezproxy` binary: the [`rules::Rule`] trait
+//! that turns a command into a redirect target, the [`config`] module which reads rules from a
+//! config file, and the [`redirector::Redirector`] which parses requests and evaluates them
+//! against those rules. The [`server`] module answers HTTP requests with a redirector, for
+//! embedding ezproxy in another hyper application.
+//!
+//! # Examples
+//!
+//! ```
+//! use ezproxy::config::ConfigRule;
+//! use ezproxy::rules::Rule;
+//!
+//! let rule = ConfigRule::new("npm", "https://npmjs.com/search?q={ARGS}");
+//! let uri = rule.produce_uri("npm", &["file".to_string(), "finder".to_string()]).unwrap();
+//! assert_eq!(uri.to_string(), "https://npmjs.com/search?q=file%20finder");
+//! ```
+#![deny(missing_docs)]
+
+pub mod auth;
+pub mod command_parser;
 pub mod config;
+pub mod cors;
+pub mod ip_filter;
+pub mod rate_limit;
+pub mod redirector;
+pub mod req_timer;
 pub mod rules;
+pub mod server;
+
+/// Re-exports of the types most library users need.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::prelude::*;
+///
+/// let mut rules = RuleRegistry::new();
+/// rules.insert(DEFAULT_RULE_KEY, Box::new(ConfigRule::new(DEFAULT_RULE_KEY, "https://www.google.com/search?q={ALL}")));
+///
+/// let redirector = Redirector::with_rules(rules, None);
+/// let resolution = redirector.evaluate(&"/?q=best%20pizza".parse().unwrap()).unwrap();
+/// assert_eq!(resolution.location, "https://www.google.com/search?q=best%20pizza");
+/// ```
+pub mod prelude {
+  pub use crate::command_parser::{
+    Command, CommandParse, CommandParser, CommandParserConfig, RoutingMode,
+  };
+  pub use crate::config::{ConfigRule, DuplicatePolicy, ParseOptions};
+  pub use crate::redirector::{EvaluateError, Redirector, RedirectorBuilder, Resolution, RuleStat};
+  pub use crate::rules::{RegexRule, Rule, RuleError, RuleRegistry, DEFAULT_RULE_KEY};
+}
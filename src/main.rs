@@ -1,193 +1,2420 @@
-use clap::Parser;
-use ezproxy::config;
-use ezproxy::rules::*;
-use http::Uri;
+use arc_swap::ArcSwap;
+use clap::{Parser, Subcommand};
+use ezproxy::auth::BasicAuth;
+use ezproxy::command_parser::{CommandParser, CommandParserConfig, RoutingMode};
+use ezproxy::config::{
+    check_rules_from, parse_inline_rules, parse_rules_from_multiple_with_options, write_rules_to,
+    ConfigError, DuplicatePolicy, ParseOptions,
+};
+use ezproxy::cors::Cors;
+use ezproxy::ip_filter::IpFilter;
+use ezproxy::rate_limit::RateLimiter;
+use ezproxy::redirector::Redirector;
+use ezproxy::rules::{PlaceholderMode, Rule, RuleRegistry, DEFAULT_RULE_KEY, FALLBACK_ORDER_KEY};
+use ezproxy::server::{handle, AccessLog, AppContext, Metrics, RemoteAddr, DEFAULT_MAX_BODY_SIZE};
+use futures_util::stream::{self, StreamExt};
+use http::header::CONTENT_TYPE;
+use http::{Method, Uri};
+use hyper::body::HttpBody;
+use hyper::server::accept::{self, Accept};
+use hyper::server::conn::AddrIncoming;
+use hyper::server::Builder;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response, Server};
-use log;
-use pretty_env_logger;
-use querystring;
-use std::collections::HashMap;
+use lazy_static::lazy_static;
+use regex::Regex;
+use rustls_pemfile::Item;
+use serde::{Deserialize, Serialize};
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+use socket2::{Domain, Protocol, Socket, Type};
 use std::convert::Infallible;
-use std::fmt::Debug;
-use std::net::SocketAddr;
-use std::path::Path;
-use std::path::PathBuf;
-use std::sync::Arc;
-use std::time::SystemTime;
-use urlencoding;
-
-pub fn get_request_uid() -> String {
-    format!(
-        "request-{}",
-        SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
-    )
+use std::fmt::{Debug, Display};
+use std::fs::File;
+use std::future::{self, Future};
+use std::io::{self, BufReader};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::oneshot;
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+
+/// Reopens the access log whenever the process receives SIGUSR1, so that it can be rotated.
+#[cfg(unix)]
+fn spawn_reopen_on_user1(access_log: AccessLog) -> io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut user1s = signal(SignalKind::user_defined1())?;
+    tokio::spawn(async move {
+        while user1s.recv().await.is_some() {
+            access_log.reopen_file();
+        }
+    });
+    Ok(())
 }
 
-macro_rules! time_request {
-    ($req_blk:block) => {{
-        let rid = get_request_uid();
-        let start = SystemTime::now();
-        let res = $req_blk;
-        let duration_ms = start.elapsed().unwrap();
-        log::trace!("[{}] Completed in {}micros", rid, duration_ms.as_micros());
-        res
-    }};
-}
-
-#[derive(Debug)]
-struct Command {
-    name: String,
-    args: Vec<String>,
-}
-
-#[derive(Default, Debug)]
-struct CommandParser {}
-impl CommandParser {
-    pub fn parse(&self, uri: &Uri) -> Result<Command, String> {
-        log::debug!(target: "ezproxy::command_parser", "Attempt parse {}", uri);
-
-        let query = uri
-            .query()
-            .map(|qs| querystring::querify(qs))
-            .and_then(|params| {
-                params.into_iter().find(|param| match param {
-                    ("q", _) => true,
-                    _ => false,
-                })
-            })
-            .map_or(Err("Could not find query param q=...".to_string()), |p| {
-                Ok(p.1.into())
-            })
-            .map(|q: String| q.replace("+", " "))?;
-
-        let decoded = urlencoding::decode(&query)
-            .map(|cow| cow.into_owned())
-            .map_err(|_| "Could not decode query".to_owned())?;
-        let parts: Vec<String> = decoded.split(" ").map(|s| s.to_string()).collect();
-        match &parts[..] {
-            [] => Err("Malformed query".to_string()),
-            [name] => Ok(Command {
-                name: String::from(name),
-                args: vec![],
-            }),
-            p => {
-                let name = p[0].to_string();
-                let args = p[1..].iter().map(|s| s.to_string()).collect();
-                Ok(Command { name, args })
-            }
+#[cfg(not(unix))]
+fn spawn_reopen_on_user1(_access_log: AccessLog) -> io::Result<()> {
+    log::warn!(target: "ezproxy::access_log", "Reopening the access log on SIGUSR1 is only supported on Unix");
+    Ok(())
+}
+
+/// Binds a listener to `addr` with `SO_REUSEPORT` set, so that a new ezproxy process can start
+/// listening on the same port before the old one exits.
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+fn reuse_port_listener(addr: &SocketAddr) -> io::Result<std::net::TcpListener> {
+    let socket = Socket::new(
+        Domain::for_address(*addr),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&(*addr).into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+#[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+fn reuse_port_listener(_addr: &SocketAddr) -> io::Result<std::net::TcpListener> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "--reuse-port is only supported on Linux and macOS",
+    ))
+}
+
+/// Number of TLS handshakes that may be in progress at once, so that one slow client can't hold up
+/// the rest.
+const MAX_CONCURRENT_HANDSHAKES: usize = 64;
+
+/// Builds a TLS acceptor from the PEM certificate chain at `cert_path` and the PEM private key
+/// (PKCS#8, PKCS#1 or SEC1) at `key_path`.
+fn load_tls_acceptor(cert_path: &Path, key_path: &Path) -> io::Result<TlsAcceptor> {
+    let invalid_data = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+    let certs: Vec<Certificate> =
+        rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))?
+            .into_iter()
+            .map(Certificate)
+            .collect();
+    if certs.is_empty() {
+        return Err(invalid_data(format!(
+            "No certificates found in {}",
+            cert_path.display()
+        )));
+    }
+    let key = rustls_pemfile::read_all(&mut BufReader::new(File::open(key_path)?))?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| invalid_data(format!("No private key found in {}", key_path.display())))?;
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| invalid_data(e.to_string()))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Accepts connections on `listener` and completes their TLS handshakes. Connections that fail
+/// to be accepted or to complete the handshake are logged and dropped, rather than stopping the
+/// server.
+fn tls_incoming(
+    listener: tokio::net::TcpListener,
+    acceptor: TlsAcceptor,
+) -> impl Accept<Conn = tokio_rustls::server::TlsStream<tokio::net::TcpStream>, Error = io::Error> {
+    let connections = stream::unfold(listener, |listener| async move {
+        let conn = listener.accept().await;
+        Some((conn, listener))
+    })
+    .filter_map(|conn| {
+        future::ready(
+            conn.map_err(
+                |e| log::warn!(target: "ezproxy::tls", "Could not accept connection: {}", e),
+            )
+            .ok(),
+        )
+    })
+    .map(move |(stream, _)| acceptor.accept(stream))
+    .buffer_unordered(MAX_CONCURRENT_HANDSHAKES)
+    .filter_map(|tls_stream| {
+        future::ready(
+            tls_stream
+                .map_err(|e| log::warn!(target: "ezproxy::tls", "TLS handshake failed: {}", e))
+                .ok()
+                .map(Ok),
+        )
+    });
+    accept::from_stream(connections)
+}
+
+/// Binds a listener to the Unix domain socket at `path`. A socket file left behind by a server that
+/// was killed is replaced, but one that's still accepting connections is left alone.
+#[cfg(unix)]
+fn bind_unix_socket(path: &Path) -> io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let is_socket = std::fs::symlink_metadata(path)
+        .map(|metadata| metadata.file_type().is_socket())
+        .unwrap_or(false);
+    if is_socket {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "another server is listening on this socket",
+            ));
         }
+        std::fs::remove_file(path)?;
     }
+    tokio::net::UnixListener::bind(path)
 }
 
-struct Redirector {
-    cmd_parser: CommandParser,
-    rules: HashMap<String, Box<dyn Rule>>,
+/// Accepts connections on `listener`. Connections that fail to be accepted are logged and dropped,
+/// rather than stopping the server.
+#[cfg(unix)]
+fn unix_incoming(
+    listener: tokio::net::UnixListener,
+) -> impl Accept<Conn = tokio::net::UnixStream, Error = io::Error> {
+    let connections = stream::unfold(listener, |listener| async move {
+        let conn = listener.accept().await;
+        Some((conn, listener))
+    })
+    .filter_map(|conn| {
+        future::ready(
+            conn.map_err(
+                |e| log::warn!(target: "ezproxy::boot", "Could not accept connection: {}", e),
+            )
+            .ok()
+            .map(|(stream, _)| Ok(stream)),
+        )
+    });
+    accept::from_stream(connections)
 }
-impl Redirector {
-    pub fn with_rules(rules: HashMap<String, Box<dyn Rule>>) -> Self {
-        Self {
-            rules,
-            cmd_parser: CommandParser::default(),
+
+/// Removes the file at its path when dropped, so that a server that stops doesn't leave its
+/// socket or PID file behind.
+struct RemoveOnDrop(PathBuf);
+
+impl Drop for RemoveOnDrop {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            log::warn!(target: "ezproxy::boot", "Could not remove {}: {}", self.0.display(), e);
         }
     }
+}
+
+/// Detaches the process from its terminal by forking twice, so that it runs in the background
+/// outside of the terminal's session. stdin is redirected to /dev/null, and stdout and stderr to
+/// `log_file`, or /dev/null if it's `None`.
+///
+/// The working directory isn't changed, so relative paths keep working on reload. This must be
+/// called before any threads are started, including the tokio runtime's.
+#[cfg(unix)]
+fn daemonize(log_file: Option<&Path>) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // Open these first, so that errors are reported on the terminal.
+    let null = File::options().read(true).write(true).open("/dev/null")?;
+    let log = match log_file {
+        Some(path) => File::options().create(true).append(true).open(path)?,
+        None => null.try_clone()?,
+    };
 
-    pub fn with_config<P: AsRef<Path>>(config_path: P) -> Self {
-        let rules = config::parse_rules_from(config_path);
-        Redirector::with_rules(rules)
+    // SAFETY: the process is single-threaded, so the child can safely carry on after fork.
+    unsafe {
+        // The first child isn't a process group leader, so it can start a new session without a
+        // controlling terminal. The second child isn't a session leader, so it can never acquire
+        // one again.
+        fork_and_exit_parent()?;
+        if libc::setsid() == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        fork_and_exit_parent()?;
+        for (file, fd) in [(&null, 0), (&log, 1), (&log, 2)] {
+            if libc::dup2(file.as_raw_fd(), fd) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Forks, exiting in the parent and returning in the child.
+///
+/// # Safety
+///
+/// The process must be single-threaded.
+#[cfg(unix)]
+unsafe fn fork_and_exit_parent() -> io::Result<()> {
+    match libc::fork() {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(()),
+        _ => libc::_exit(0),
     }
+}
+
+#[cfg(not(unix))]
+fn daemonize(_log_file: Option<&Path>) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "--daemon is only supported on Unix",
+    ))
+}
+
+/// Writes this process's PID to `path`, returning a guard that removes the file when dropped.
+fn write_pid_file(path: &Path) -> io::Result<RemoveOnDrop> {
+    std::fs::write(path, format!("{}\n", std::process::id()))?;
+    Ok(RemoveOnDrop(path.to_path_buf()))
+}
 
-    pub fn evaluate(&self, uri: &Uri) -> Result<Uri, String> {
-        let cmd = self.cmd_parser.parse(&uri)?;
-        log::debug!(target: "ezproxy::redirector", "Attempting redirector for {:?}", cmd);
-        if let Some(rule) = self.rules.get(&cmd.name) {
-            rule.produce_uri(&cmd.name, &cmd.args)
-        } else if let Some(default_rule) = self.rules.get(DEFAULT_RULE_KEY) {
-            log::debug!(target: "ezproxy::redirector", "No rule found for {}. Using default", cmd.name);
-            default_rule.produce_uri(&cmd.name, &cmd.args)
-        } else {
-            Err(format!(
-                "Could not find rule for cmd {}, and no default given",
-                cmd.name
-            ))
+/// Prefixes the message of `e` with `what` failed, keeping its kind.
+fn failed(what: impl Display, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {}", what, e))
+}
+
+/// Serves requests on the Unix domain socket at `path` until `shutdown` completes, or the server
+/// fails.
+#[cfg(unix)]
+async fn serve_unix_socket<S: Future<Output = ()>>(
+    path: &Path,
+    context: AppContext,
+    shutdown: S,
+) -> io::Result<hyper::Result<()>> {
+    let listener = bind_unix_socket(path)
+        .map_err(|e| failed(format!("Could not bind to {}", path.display()), e))?;
+    let _socket_file = RemoveOnDrop(path.to_path_buf());
+    Ok(serve(Server::builder(unix_incoming(listener)), context, shutdown).await)
+}
+
+#[cfg(not(unix))]
+async fn serve_unix_socket<S: Future<Output = ()>>(
+    _path: &Path,
+    _context: AppContext,
+    _shutdown: S,
+) -> io::Result<hyper::Result<()>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "--socket is only supported on Unix",
+    ))
+}
+
+/// Completes when the process is asked to stop, by SIGINT (Ctrl-C) or, on Unix, SIGTERM.
+async fn stop_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                return;
+            }
+            Err(e) => {
+                log::warn!(target: "ezproxy::shutdown", "Could not listen for SIGTERM: {}", e)
+            }
         }
     }
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        log::warn!(target: "ezproxy::shutdown", "Could not listen for Ctrl-C: {}", e);
+        future::pending::<()>().await;
+    }
+}
+
+/// Completes when `signal` does, telling the server to stop accepting connections and finish the
+/// requests in flight. If they aren't finished within `timeout`, `deadline` is sent to so the
+/// caller can stop waiting for them.
+async fn shutdown_after<S: Future<Output = ()>>(
+    signal: S,
+    timeout: Duration,
+    deadline: oneshot::Sender<()>,
+) {
+    signal.await;
+    log::info!(
+        target: "ezproxy::shutdown",
+        "Shutting down. Waiting up to {}s for requests in flight",
+        timeout.as_secs()
+    );
+    tokio::spawn(async move {
+        tokio::time::sleep(timeout).await;
+        // The server may already have stopped, dropping the receiver.
+        let _ = deadline.send(());
+    });
 }
 
-fn uri_from_conn<T>(req: &mut Request<T>) -> Uri {
-    req.uri().to_owned()
+/// A connection that may know the address of the client on the other end.
+trait PeerAddr {
+    fn peer_addr(&self) -> Option<SocketAddr>;
 }
 
-fn somehow_make_response(uri_result: Result<Uri, String>) -> http::Result<Response<Body>> {
-    let builder = Response::builder().header("X-EZ-Made-This", "true");
+impl PeerAddr for hyper::server::conn::AddrStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        Some(self.remote_addr())
+    }
+}
 
-    match uri_result {
-        Ok(uri) => builder
-            .status(302)
-            .header("Location", format!("{}", uri))
-            .body(Body::from("")),
-        Err(msg) => builder.status(500).body(Body::from(msg)),
+impl PeerAddr for tokio_rustls::server::TlsStream<tokio::net::TcpStream> {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.get_ref().0.peer_addr().ok()
     }
 }
 
+#[cfg(unix)]
+impl PeerAddr for tokio::net::UnixStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+/// Serves requests from `builder`'s connections until `shutdown` completes and the requests in
+/// flight are finished, or the server fails.
+async fn serve<I, S>(builder: Builder<I>, context: AppContext, shutdown: S) -> hyper::Result<()>
+where
+    I: Accept,
+    I::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    I::Conn: AsyncRead + AsyncWrite + PeerAddr + Unpin + Send + 'static,
+    S: Future<Output = ()>,
+{
+    let make_service = make_service_fn(move |conn: &I::Conn| {
+        let context = context.clone();
+        let peer_addr = conn.peer_addr();
+        let service = service_fn(move |mut req: Request<Body>| {
+            if let Some(addr) = peer_addr {
+                req.extensions_mut().insert(RemoteAddr(addr));
+            }
+            handle(context.clone(), req)
+        });
+        async move { Ok::<_, Infallible>(service) }
+    });
+    builder
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown)
+        .await
+}
+
+/// State shared by the requests to the admin API.
 #[derive(Clone)]
-struct AppContext {
-    redirector: Arc<Redirector>,
+struct AdminContext {
+    redirector: Arc<ArcSwap<Redirector>>,
+    /// Config file rewritten with every rule after each change, if any.
+    persist_path: Option<Arc<Path>>,
+    /// Held while a rule is changed and the config file rewritten, so that changes are written in
+    /// the order they're made.
+    changes: Arc<Mutex<()>>,
+    /// The largest request body accepted, in bytes.
+    max_body_size: usize,
+}
+
+/// The body of a `POST /rules` or `PUT /rules/{keyword}` request to the admin API. `keyword` is
+/// only required by `POST`.
+#[derive(Deserialize, Debug)]
+struct AdminRule {
+    keyword: Option<String>,
+    uri: String,
+}
+
+/// Returns a JSON response to an admin API request.
+fn admin_response(status: u16, body: serde_json::Value) -> http::Result<Response<Body>> {
+    Response::builder()
+        .header("X-EZ-Made-This", "true")
+        .header(CONTENT_TYPE, "application/json")
+        .status(status)
+        .body(Body::from(body.to_string()))
+}
+
+/// Returns an admin API error response with `message`.
+fn admin_error(status: u16, message: impl std::fmt::Display) -> http::Result<Response<Body>> {
+    admin_response(status, serde_json::json!({ "error": message.to_string() }))
+}
+
+/// Parses the rule for `keyword` the way a config line `<keyword> = <uri>` would be parsed.
+fn parse_admin_rule(keyword: &str, uri: &str) -> Result<Box<dyn Rule>, String> {
+    if keyword.is_empty()
+        || keyword
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, ',' | '=' | '[' | ']'))
+    {
+        return Err(format!("Invalid keyword {:?}", keyword));
+    }
+    if uri.contains(['\n', '\r']) {
+        return Err("The URL must be a single line".to_string());
+    }
+    let line = format!("{} = {}", keyword, uri.trim());
+    parse_inline_rules(&[line], &ParseOptions::default())
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .next()
+        .map(|(_, rule)| rule)
+        .ok_or_else(|| format!("No rule in {:?}", uri))
 }
 
-async fn handle(context: AppContext, mut req: Request<Body>) -> http::Result<Response<Body>> {
-    time_request!({
-        let eval_result = match context.redirector.evaluate(&uri_from_conn(&mut req)) {
-            Ok(uri) => {
-                log::info!(target: "ezproxy::handle", "Returning uri {}", uri);
-                Ok(uri)
+/// Applies a change to the rules, then rewrites the persisted config if there is one. Returns the
+/// response to send, or an error response if the change was rejected.
+fn change_rules(
+    context: &AdminContext,
+    change: impl FnOnce(&Redirector) -> Result<(u16, serde_json::Value), (u16, String)>,
+) -> http::Result<Response<Body>> {
+    let _changes = context
+        .changes
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let redirector = context.redirector.load_full();
+    let (status, body) = match change(&redirector) {
+        Ok(response) => response,
+        Err((status, message)) => return admin_error(status, message),
+    };
+    if let Some(path) = &context.persist_path {
+        if let Err(e) = write_rules_to(&redirector.rules(), path) {
+            log::error!(target: "ezproxy::admin", "Could not write {}: {}", path.display(), e);
+            return admin_error(
+                500,
+                format!(
+                    "The change was made, but {} could not be written: {}",
+                    path.display(),
+                    e
+                ),
+            );
+        }
+    }
+    admin_response(status, body)
+}
+
+/// Reads the body of an admin API request, or returns an error response if it's larger than
+/// the context allows or can't be read.
+async fn read_admin_body(
+    context: &AdminContext,
+    mut body: Body,
+) -> Result<Vec<u8>, http::Result<Response<Body>>> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| admin_error(400, e))?;
+        if bytes.len() + chunk.len() > context.max_body_size {
+            return Err(admin_error(413, "Request body too large"));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
+/// Returns the address the admin API listens on: `port` on the loopback address of the same
+/// family as `host`, since the API has no authentication of its own.
+fn admin_addr(host: IpAddr, port: u16) -> SocketAddr {
+    let loopback = match host {
+        IpAddr::V4(_) => IpAddr::from(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(_) => IpAddr::from(Ipv6Addr::LOCALHOST),
+    };
+    SocketAddr::new(loopback, port)
+}
+
+/// Returns the JSON listing of the rule for `keyword`, as in `GET /rules`.
+fn rule_json(keyword: &str, rule: &dyn Rule) -> serde_json::Value {
+    serde_json::to_value(RuleListing::new(keyword, rule)).unwrap()
+}
+
+/// Serves the admin API, which lists and changes the rules of the running server:
+///
+/// - `GET /rules` lists every rule, like `ezproxy list --json`.
+/// - `POST /rules` with `{"keyword": ..., "uri": ...}` adds a rule.
+/// - `PUT /rules/{keyword}` with `{"uri": ...}` replaces a rule.
+/// - `DELETE /rules/{keyword}` removes a rule.
+async fn handle_admin(context: AdminContext, req: Request<Body>) -> http::Result<Response<Body>> {
+    let (parts, body) = req.into_parts();
+    let path = parts.uri.path();
+    let keyword = match path.strip_prefix("/rules/") {
+        Some(keyword) => match urlencoding::decode(keyword) {
+            Ok(keyword) => Some(keyword.into_owned()),
+            Err(e) => return admin_error(400, e),
+        },
+        None if path == "/rules" => None,
+        None => return admin_error(404, format!("No such endpoint {}", path)),
+    };
+    let body: Option<AdminRule> = match parts.method {
+        Method::POST | Method::PUT => {
+            let bytes = match read_admin_body(&context, body).await {
+                Ok(bytes) => bytes,
+                Err(resp) => return resp,
+            };
+            match serde_json::from_slice(&bytes) {
+                Ok(rule) => Some(rule),
+                Err(e) => return admin_error(400, format!("Invalid rule: {}", e)),
             }
-            Err(e) => {
-                log::error!(target: "ezproxy::handle", "Error evaluating request: {}", e);
-                Err(e)
+        }
+        _ => None,
+    };
+    match (parts.method, keyword, body) {
+        (Method::GET, None, _) => {
+            let redirector = context.redirector.load();
+            let rules = redirector.rules();
+            let mut listings: Vec<RuleListing> = rules
+                .iter()
+                .map(|(kw, rule)| RuleListing::new(kw, rule))
+                .collect();
+            listings.sort_unstable_by_key(|listing| listing.keyword);
+            admin_response(200, serde_json::to_value(listings).unwrap())
+        }
+        (Method::POST, None, Some(AdminRule { keyword, uri })) => {
+            let keyword = match keyword {
+                Some(keyword) => keyword,
+                None => return admin_error(400, "Missing keyword"),
+            };
+            change_rules(&context, |redirector| {
+                if redirector.rules().contains_key(&keyword) {
+                    return Err((409, format!("There's already a rule for {}", keyword)));
+                }
+                let rule = parse_admin_rule(&keyword, &uri).map_err(|e| (400, e))?;
+                let json = rule_json(&keyword, rule.as_ref());
+                redirector.insert_rule(keyword, rule);
+                Ok((201, json))
+            })
+        }
+        (
+            Method::PUT,
+            Some(keyword),
+            Some(AdminRule {
+                keyword: body_keyword,
+                uri,
+            }),
+        ) => {
+            if body_keyword.is_some_and(|kw| kw != keyword) {
+                return admin_error(400, "The keyword in the body doesn't match the URL");
             }
-        };
-        somehow_make_response(eval_result)
-    })
+            change_rules(&context, |redirector| {
+                if !redirector.rules().contains_key(&keyword) {
+                    return Err((404, format!("No rule for {}", keyword)));
+                }
+                let rule = parse_admin_rule(&keyword, &uri).map_err(|e| (400, e))?;
+                let json = rule_json(&keyword, rule.as_ref());
+                redirector.insert_rule(keyword, rule);
+                Ok((200, json))
+            })
+        }
+        (Method::DELETE, Some(keyword), _) => change_rules(&context, |redirector| {
+            if redirector.remove_rule(&keyword) {
+                Ok((200, serde_json::json!({ "removed": keyword })))
+            } else {
+                Err((404, format!("No rule for {}", keyword)))
+            }
+        }),
+        (method, _, _) => admin_error(405, format!("{} is not allowed on {}", method, path)),
+    }
+}
+
+/// Serves the admin API on the listener of `builder` until the process exits.
+async fn serve_admin(builder: Builder<AddrIncoming>, context: AdminContext) -> hyper::Result<()> {
+    let make_service = make_service_fn(move |_conn| {
+        let context = context.clone();
+        let service = service_fn(move |req| handle_admin(context.clone(), req));
+        async move { Ok::<_, Infallible>(service) }
+    });
+    builder.serve(make_service).await
+}
+
+/// Parses an IPv4 or IPv6 address, accepting IPv6 addresses in brackets as they're written in URLs.
+fn parse_host(host: &str) -> Result<IpAddr, String> {
+    let unbracketed = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+    match unbracketed.parse::<IpAddr>() {
+        Ok(IpAddr::V4(_)) if unbracketed != host => {
+            Err("only IPv6 addresses may be written in brackets".to_string())
+        }
+        Ok(addr) => Ok(addr),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Parses a network in CIDR notation, e.g. 192.168.1.0/24, or a single address, which is
+/// treated as a network of just that address.
+fn parse_ip_net(net: &str) -> Result<ipnet::IpNet, String> {
+    net.parse::<ipnet::IpNet>()
+        .or_else(|e| net.parse::<IpAddr>().map(ipnet::IpNet::from).map_err(|_| e))
+        .map_err(|e| e.to_string())
+}
+
+/// Accepts any non-empty separator, since an empty one can't split anything.
+fn parse_separator(separator: &str) -> Result<String, String> {
+    if separator.is_empty() {
+        Err("the separator can't be empty".to_string())
+    } else {
+        Ok(separator.to_string())
+    }
+}
+
+/// How log lines are written to stderr.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum LogFormat {
+    /// Human-readable text.
+    Text,
+    /// One JSON object per line, for log aggregators.
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format {}: expected text or json", s)),
+        }
+    }
+}
+
+/// Builds the subscriber that writes logs allowed by `filter` to `writer` in `format`. In JSON,
+/// an event's fields, e.g. `request_id`, sit alongside its `timestamp`, `level`, `target` and
+/// `message`.
+fn log_subscriber<W>(
+    format: LogFormat,
+    filter: EnvFilter,
+    writer: W,
+) -> Box<dyn tracing::Subscriber + Send + Sync>
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer);
+    match format {
+        LogFormat::Text => Box::new(builder.finish()),
+        LogFormat::Json => Box::new(
+            builder
+                .json()
+                .flatten_event(true)
+                .with_current_span(false)
+                .with_span_list(false)
+                .finish(),
+        ),
+    }
 }
 
 /// Keyboard shortcuts for your address bar
-#[derive(Parser, Debug)]
-#[clap(author, version, about, long_about = None)]
+#[derive(Parser, Clone, Debug)]
+#[clap(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     /// Path to the config file used to specify shortcuts. See example-configs/simple.txt for a starter config.
-    #[clap(value_parser, value_name = "FILE")]
-    config: PathBuf,
+    /// Several files may be given, e.g. work.txt personal.txt, and their shortcuts are merged in order.
+    #[clap(
+        value_parser,
+        value_name = "FILE",
+        required_unless_present_any = &["extra-config", "rule"]
+    )]
+    config: Vec<PathBuf>,
+
+    /// Another config file to merge in after the ones given as arguments. May be repeated.
+    #[clap(long = "config", short = 'c', value_parser, value_name = "FILE")]
+    extra_config: Vec<PathBuf>,
+
+    /// A shortcut written like a config file line, e.g. "g = https://www.google.com/search?q={ARGS}".
+    /// May be repeated. Overrides any shortcut for the same keyword in the config files.
+    #[clap(long, short, value_parser, value_name = "RULE")]
+    rule: Vec<String>,
+
+    /// Address which ezproxy will listen on, e.g. 0.0.0.0 to accept connections from other machines.
+    /// IPv6 addresses may be written with or without brackets, e.g. [::1] or ::1
+    #[clap(long, value_parser = parse_host, value_name = "ADDRESS", default_value_t = IpAddr::from([127, 0, 0, 1]))]
+    host: IpAddr,
 
     /// Port which ezproxy will run on
     #[clap(short, long, value_parser, default_value_t = 5050)]
     port: u16,
+
+    /// Listen on a Unix domain socket at this path instead of a TCP port, e.g. to sit behind a
+    /// reverse proxy on the same machine. Unix only.
+    #[clap(
+        long,
+        value_parser,
+        value_name = "PATH",
+        conflicts_with_all = &["host", "port", "reuse-port", "tls-cert"]
+    )]
+    socket: Option<PathBuf>,
+
+    /// Respond to failed requests with a generic error instead of the full error message, which
+    /// can reveal details of your config. The full message is still logged.
+    #[clap(long, visible_alias = "production", action)]
+    hide_errors: bool,
+
+    /// Require every request to log in as this user with HTTP Basic authentication. Requires
+    /// --auth-password
+    #[clap(long, value_parser, value_name = "USER", requires = "auth-password")]
+    auth_user: Option<String>,
+
+    /// The password for --auth-user. Requires --auth-user
+    #[clap(long, value_parser, value_name = "PASSWORD", requires = "auth-user")]
+    auth_password: Option<String>,
+
+    /// Let health checks and metrics through without logging in. Requires --auth-user
+    #[clap(long, action, requires = "auth-user")]
+    public_health: bool,
+
+    /// Let JavaScript on this origin, e.g. https://example.com, call ezproxy. May be given more
+    /// than once; * allows every origin
+    #[clap(long, value_parser, value_name = "ORIGIN")]
+    cors_origin: Vec<String>,
+
+    /// Allow each client at most this many requests per second, answering the rest with 429 Too
+    /// Many Requests
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..), value_name = "N")]
+    rate_limit: Option<u32>,
+
+    /// Forget the rate limit of clients that haven't made a request in this long
+    #[clap(
+        long,
+        value_parser,
+        value_name = "SECS",
+        default_value_t = 60,
+        requires = "rate-limit"
+    )]
+    rate_limit_idle_secs: u64,
+
+    /// Only answer clients in this network, e.g. 192.168.1.0/24 or 127.0.0.1. May be given more
+    /// than once. Clients on a Unix domain socket have no address, so they're refused too
+    #[clap(long, value_parser = parse_ip_net, value_name = "CIDR")]
+    allow_ip: Vec<ipnet::IpNet>,
+
+    /// Refuse clients in this network, even if --allow-ip lets them in. May be given more than once
+    #[clap(long, value_parser = parse_ip_net, value_name = "CIDR")]
+    deny_ip: Vec<ipnet::IpNet>,
+
+    /// Take each client's address from the last X-Forwarded-For entry, for running behind a proxy.
+    /// Affects --rate-limit, --allow-ip and --deny-ip
+    #[clap(long, action)]
+    trust_proxy: bool,
+
+    /// Largest body, in bytes, of a form posted with the command instead of a query string.
+    /// Larger ones are refused with 413 Payload Too Large
+    #[clap(long, value_parser, value_name = "BYTES", default_value_t = DEFAULT_MAX_BODY_SIZE)]
+    max_body_size: usize,
+
+    /// URL to redirect to when no rule matches a command and the config has no `_` rule, e.g.
+    /// `https://www.google.com/search?q={ALL}`
+    #[clap(long, value_parser, value_name = "URL")]
+    not_found_redirect: Option<String>,
+
+    /// Set SO_REUSEPORT on the listening socket, so that a new ezproxy can start on the same port
+    /// before the old one shuts down. Linux and macOS only.
+    #[clap(long, action)]
+    reuse_port: bool,
+
+    /// Where to find the command in request URLs: `query`, e.g. /?q=npm+file+finder, `path`, e.g.
+    /// /npm/file+finder, or `bang`, which is like `query` but finds a bang command like `!g`
+    /// anywhere in the query
+    #[clap(long, value_parser, value_name = "MODE", default_value = "query")]
+    routing_mode: RoutingMode,
+
+    /// Query parameter holding the command, for browsers or search engine settings that don't use
+    /// `q`
+    #[clap(long, value_parser, value_name = "NAME", default_value = "q")]
+    query_param: String,
+
+    /// Text separating the command from its arguments, and each argument from the next, e.g. `|`
+    /// or `::`. It's matched literally rather than as a pattern. When it isn't a space, spaces are
+    /// kept as part of the arguments.
+    #[clap(
+        long,
+        alias = "delimiter",
+        value_parser = parse_separator,
+        value_name = "TEXT",
+        default_value = " "
+    )]
+    separator: String,
+
+    /// How many leading words of a command may form its keyword, joined by
+    /// `--subcommand-separator`. With 2, `gh issues list` runs the `gh.issues` rule with the
+    /// argument `list`, or the `gh` rule if there's no `gh.issues` rule.
+    #[clap(long, value_parser = clap::value_parser!(u8).range(1..), value_name = "N", default_value_t = 1)]
+    max_command_depth: u8,
+
+    /// Text joining the words of a nested keyword; see `--max-command-depth`
+    #[clap(long, value_parser, value_name = "TEXT", default_value = ".")]
+    subcommand_separator: String,
+
+    /// Serve an admin API on this port for listing, adding, changing and removing rules without
+    /// a restart: `GET /rules`, `POST /rules`, `PUT /rules/{keyword}` and `DELETE /rules/{keyword}`.
+    /// It has no authentication, so it only listens on localhost. Changes are lost on restart or reload unless
+    /// `--admin-persist-config` is set.
+    #[clap(long, value_parser, value_name = "PORT")]
+    admin_port: Option<u16>,
+
+    /// Rewrite this config file with every rule each time the admin API changes one
+    #[clap(long, value_parser, value_name = "PATH", requires = "admin-port")]
+    admin_persist_config: Option<PathBuf>,
+
+    /// Path that answers health checks with `200 OK` instead of redirecting, for load balancers and
+    /// Kubernetes probes
+    #[clap(long, value_parser, value_name = "PATH", default_value = "/health")]
+    health_path: String,
+
+    /// Path that serves Prometheus metrics instead of redirecting
+    #[clap(long, value_parser, value_name = "PATH", default_value = "/metrics")]
+    metrics_path: String,
+
+    /// Path that serves per-rule hit counts and latencies as JSON instead of redirecting
+    #[clap(long, value_parser, value_name = "PATH", default_value = "/stats")]
+    stats_path: String,
+
+    /// What to do when the config has more than one rule for a keyword: `error`, `warn-keep-first`
+    /// or `warn-keep-last`
+    #[clap(long, value_parser, value_name = "POLICY", default_value = "error")]
+    on_duplicate: DuplicatePolicy,
+
+    /// Start even if some rules have an invalid URL, leaving them out with a warning
+    #[clap(long, action)]
+    ignore_invalid_rules: bool,
+
+    /// Match keywords no matter their case, so that `NPM tokio` finds the `npm` shortcut. Only
+    /// keywords are affected; arguments are passed on as typed
+    #[clap(long, action)]
+    case_insensitive: bool,
+
+    /// Append a line for each request to this file: `<timestamp> <method> <uri> <status> <location>
+    /// <duration_ms>`. On SIGUSR1, the file is reopened, so that it can be rotated.
+    #[clap(long, value_parser, value_name = "FILE")]
+    access_log: Option<PathBuf>,
+
+    /// How to write logs: `text`, or `json` for one JSON object per line. Which logs are written is
+    /// set by the RUST_LOG environment variable.
+    #[clap(long, value_parser, value_name = "FORMAT", default_value = "text")]
+    log_format: LogFormat,
+
+    /// Run in the background, detached from the terminal. Unix only.
+    #[clap(long, action)]
+    daemon: bool,
+
+    /// Write the process ID to this file, and remove it on shutdown, e.g. for start-stop-daemon
+    #[clap(long, value_parser, value_name = "FILE")]
+    pid_file: Option<PathBuf>,
+
+    /// With --daemon, append output to this file instead of discarding it
+    #[clap(long, value_parser, value_name = "FILE", requires = "daemon")]
+    log_file: Option<PathBuf>,
+
+    /// Don't reload the config file on SIGHUP, for environments where SIGHUP means something else
+    #[clap(long, action)]
+    no_reload: bool,
+
+    /// On SIGTERM or Ctrl-C, how long to wait for requests in flight to finish before exiting anyway
+    #[clap(long, value_parser, value_name = "SECS", default_value_t = 10)]
+    shutdown_timeout_secs: u64,
+
+    /// Serve HTTPS using the PEM certificate chain in this file. Requires --tls-key.
+    #[clap(long, value_parser, value_name = "FILE", requires = "tls-key")]
+    tls_cert: Option<PathBuf>,
+
+    /// Serve HTTPS using the PEM private key in this file. Requires --tls-cert.
+    #[clap(long, value_parser, value_name = "FILE", requires = "tls-cert")]
+    tls_key: Option<PathBuf>,
 }
 
-#[tokio::main]
-async fn main() {
-    pretty_env_logger::init();
+#[derive(Subcommand, Clone, Debug)]
+enum Command {
+    /// Check config files for errors without starting the server. Exits with status 1 if any are
+    /// found.
+    Validate {
+        /// Config files to check
+        #[clap(value_parser, value_name = "FILE", required = true)]
+        config: Vec<PathBuf>,
+    },
+    /// Print where a query would redirect to without starting the server, e.g.
+    /// `ezproxy test "npm file finder" config.txt`. Exits with status 1 if no rule applies, or 2 if
+    /// the rule fails.
+    Test {
+        /// The query, as typed into the address bar
+        query: String,
+        /// Config files to load, in addition to any given with --config or --rule
+        #[clap(value_parser, value_name = "FILE")]
+        config: Vec<PathBuf>,
+    },
+    /// Print every shortcut in the config files, sorted by keyword
+    List {
+        /// Config files to list
+        #[clap(value_parser, value_name = "FILE", required = true)]
+        config: Vec<PathBuf>,
+        /// Print a JSON array instead of plain text
+        #[clap(long, action)]
+        json: bool,
+    },
+}
+
+impl Args {
+    /// How to parse the config files and inline rules.
+    fn parse_options(&self) -> ParseOptions {
+        ParseOptions::default()
+            .on_duplicate(self.on_duplicate)
+            .ignore_invalid_rules(self.ignore_invalid_rules)
+            .case_insensitive(self.case_insensitive)
+    }
 
-    let args = Args::parse();
+    /// The config files to load, in the order their rules are merged.
+    fn config_paths(&self) -> Vec<&Path> {
+        self.config
+            .iter()
+            .chain(&self.extra_config)
+            .map(PathBuf::as_path)
+            .collect()
+    }
 
-    let addr = SocketAddr::from(([127, 0, 0, 1], args.port));
-    log::info!(target: "ezproxy::boot", "Starting on {}", addr);
+    /// The config files to load, for log messages.
+    fn config_display(&self) -> String {
+        if self.config_paths().is_empty() {
+            return "--rule shortcuts".to_string();
+        }
+        self.config_paths()
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
 
-    let context = AppContext {
-        redirector: Arc::new(Redirector::with_config(&args.config)),
+/// Builds the redirector for the config and options in `args`.
+fn load_redirector(args: &Args) -> Result<Redirector, ConfigError> {
+    let parser_config = CommandParserConfig {
+        separator: args.separator.clone(),
+        query_param: args.query_param.clone(),
+        routing_mode: args.routing_mode,
+        case_insensitive: args.case_insensitive,
     };
-    let make_service = make_service_fn(move |_conn| {
-        let context = context.clone();
-        let service = service_fn(move |req| handle(context.clone(), req));
-        async move { Ok::<_, Infallible>(service) }
+    let options = args.parse_options();
+    let mut rules = parse_rules_from_multiple_with_options(&args.config_paths(), &options)?;
+    rules.extend(parse_inline_rules(&args.rule, &options)?);
+    let mut redirector =
+        Redirector::with_rules(rules, Some(Arc::new(CommandParser::from(parser_config))))
+            .with_max_command_depth(usize::from(args.max_command_depth))
+            .with_subcommand_separator(args.subcommand_separator.clone());
+    if let Some(url) = &args.not_found_redirect {
+        redirector = redirector.with_not_found_redirect(url);
+    }
+    Ok(redirector)
+}
+
+/// Returns the number of arguments a rule needs: the highest `n` of its `{ARGn}` placeholders
+/// without a fallback.
+fn required_args(rule: &dyn Rule) -> usize {
+    lazy_static! {
+        static ref REQUIRED_ARG_RE: Regex = Regex::new(r"\{ARG(\d+)\}").unwrap();
+    }
+    rule.template()
+        .map(|template| {
+            REQUIRED_ARG_RE
+                .captures_iter(template)
+                .filter_map(|captures| captures[1].parse().ok())
+                .max()
+                .unwrap_or(0)
+        })
+        .unwrap_or(0)
+}
+
+/// Checks the config files at `paths`, writing each problem to `out`. Returns whether there were
+/// no errors; warnings don't count.
+fn validate<P: AsRef<Path>>(
+    paths: &[P],
+    options: &ParseOptions,
+    out: &mut impl io::Write,
+) -> io::Result<bool> {
+    let mut ok = true;
+    for path in paths {
+        let path = path.as_ref();
+        let (rules, errors) = check_rules_from(path, options);
+        for e in &errors {
+            writeln!(out, "error: {}: {}", path.display(), e)?;
+        }
+        ok &= errors.is_empty();
+        for (kw, rule) in &rules {
+            // Patterns only produce URIs for commands they match, rules without a template,
+            // like scripts, aren't worth running just to check them, and chained rules may
+            // delegate to rules in other files, as may the fallback order.
+            if rule.pattern().is_some()
+                || rule.template().is_none()
+                || rule.as_chained().is_some()
+                || kw == FALLBACK_ORDER_KEY
+            {
+                continue;
+            }
+            let required = required_args(rule);
+            if required > 0 {
+                writeln!(
+                    out,
+                    "warning: {}: {} uses {{ARG{}}}, so it fails unless it's given at least {} argument(s)",
+                    path.display(),
+                    kw,
+                    required,
+                    required
+                )?;
+            }
+            let args = vec!["arg".to_string(); required];
+            if let Err(e) = rule.produce_location(kw, &args) {
+                writeln!(out, "error: {}: {}: {}", path.display(), kw, e)?;
+                ok = false;
+            }
+        }
+    }
+    if ok && paths.len() > 1 {
+        if let Err(e) = parse_rules_from_multiple_with_options(paths, options) {
+            writeln!(out, "error: {}", e)?;
+            ok = false;
+        }
+    }
+    Ok(ok)
+}
+
+/// A shortcut as printed by `ezproxy list`.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+struct RuleListing<'a> {
+    keyword: &'a str,
+    template: Option<String>,
+    status: u16,
+    /// Which placeholders the template fills in with the command: `ALL`, `ARGS`, `NAMED` or
+    /// `none`.
+    placeholders: &'static str,
+    default: bool,
+}
+
+impl<'a> RuleListing<'a> {
+    fn new(keyword: &'a str, rule: &dyn Rule) -> Self {
+        let description = rule.describe();
+        let placeholders = match description.placeholder_mode {
+            PlaceholderMode::All => "ALL",
+            PlaceholderMode::Args => "ARGS",
+            PlaceholderMode::Named => "NAMED",
+            PlaceholderMode::None => "none",
+        };
+        RuleListing {
+            keyword,
+            template: description.template,
+            status: rule.status_code(),
+            placeholders,
+            default: keyword == DEFAULT_RULE_KEY,
+        }
+    }
+}
+
+/// Writes `rules` to `out` sorted by keyword, one per line, or as a JSON array if `json` is set.
+fn list_rules(rules: &RuleRegistry, json: bool, out: &mut impl io::Write) -> io::Result<()> {
+    let mut listings: Vec<RuleListing> = rules
+        .iter()
+        .map(|(kw, rule)| RuleListing::new(kw, rule))
+        .collect();
+    listings.sort_unstable_by_key(|listing| listing.keyword);
+    if json {
+        return writeln!(out, "{}", serde_json::to_string(&listings)?);
+    }
+    let width = listings.iter().map(|l| l.keyword.len()).max().unwrap_or(0);
+    for listing in listings {
+        write!(
+            out,
+            "{:width$}  {:5}  {}  {}",
+            listing.keyword,
+            listing.placeholders,
+            listing.status,
+            listing.template.as_deref().unwrap_or("-"),
+            width = width
+        )?;
+        if listing.default {
+            write!(out, "  (default)")?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// Evaluates `query` against the config and options in `args`, writing the result to `out`.
+/// Returns the exit status for `ezproxy test`: 0 on a redirect, 1 if no rule applies, or 2 if the
+/// rule fails.
+fn test_query(args: &Args, query: &str, out: &mut impl io::Write) -> Result<i32, ConfigError> {
+    // The query is passed as a query parameter no matter how the server routes requests.
+    let routing_mode = match args.routing_mode {
+        RoutingMode::Path => RoutingMode::Query,
+        mode => mode,
+    };
+    let args = Args {
+        routing_mode,
+        ..args.clone()
+    };
+    let redirector = load_redirector(&args)?;
+    let uri: Uri = format!(
+        "/?{}={}",
+        urlencoding::encode(&args.query_param),
+        urlencoding::encode(query)
+    )
+    .parse()
+    .expect("an encoded query is a valid URI");
+    let keyword = match redirector.keyword_for(&uri) {
+        Ok(Some(keyword)) => keyword,
+        Ok(None) => {
+            writeln!(out, "No rule for {}", query)?;
+            return Ok(1);
+        }
+        Err(e) => {
+            writeln!(out, "{}", e)?;
+            return Ok(1);
+        }
+    };
+    match redirector.evaluate(&uri) {
+        Ok(resolution) => {
+            writeln!(
+                out,
+                "Rule: {}\nTarget: {}\nStatus: {}",
+                keyword, resolution.location, resolution.status
+            )?;
+            Ok(0)
+        }
+        Err(e) => {
+            writeln!(out, "Rule: {}\nError: {}", keyword, e)?;
+            Ok(2)
+        }
+    }
+}
+
+/// Reloads the config whenever the process receives SIGHUP. If the new config can't be loaded,
+/// the old rules are kept.
+#[cfg(unix)]
+fn spawn_reload_on_hangup(
+    args: Arc<Args>,
+    redirector: Arc<ArcSwap<Redirector>>,
+    metrics: Arc<Metrics>,
+) -> io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            log::info!(target: "ezproxy::reload", "Received SIGHUP. Reloading {}", args.config_display());
+            match load_redirector(&args) {
+                Ok(new_redirector) => {
+                    log::info!(target: "ezproxy::reload", "Loaded {} rules", new_redirector.rules().len());
+                    redirector.store(Arc::new(new_redirector));
+                    metrics.observe_reload(true);
+                }
+                Err(e) => {
+                    metrics.observe_reload(false);
+                    log::error!(target: "ezproxy::reload", "Could not reload config, keeping the old rules: {}", e)
+                }
+            }
+        }
     });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn spawn_reload_on_hangup(
+    _args: Arc<Args>,
+    _redirector: Arc<ArcSwap<Redirector>>,
+    _metrics: Arc<Metrics>,
+) -> io::Result<()> {
+    log::warn!(target: "ezproxy::reload", "Reloading on SIGHUP is only supported on Unix");
+    Ok(())
+}
 
-    let server = Server::bind(&addr).serve(make_service);
+fn main() {
+    let args = Arc::new(Args::parse());
+    log_subscriber(args.log_format, EnvFilter::from_default_env(), io::stderr).init();
+    match &args.command {
+        Some(Command::Validate { config }) => {
+            let options = ParseOptions::default().on_duplicate(args.on_duplicate);
+            match validate(config, &options, &mut io::stderr()) {
+                Ok(true) => {
+                    println!("Config OK");
+                    std::process::exit(0)
+                }
+                _ => std::process::exit(1),
+            }
+        }
+        Some(Command::Test { query, config }) => {
+            let args = Args {
+                config: config.clone(),
+                ..(*args).clone()
+            };
+            match test_query(&args, query, &mut io::stdout()) {
+                Ok(status) => std::process::exit(status),
+                Err(e) => {
+                    eprintln!("Could not load config: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(Command::List { config, json }) => {
+            let options = args.parse_options();
+            let rules = match parse_rules_from_multiple_with_options(config, &options) {
+                Ok(rules) => rules,
+                Err(e) => {
+                    eprintln!("Could not load config: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            if let Err(e) = list_rules(&rules, *json, &mut io::stdout()) {
+                eprintln!("Could not list rules: {}", e);
+                std::process::exit(1);
+            }
+            std::process::exit(0);
+        }
+        None => {}
+    }
+
+    let addr = SocketAddr::new(args.host, args.port);
+    match &args.socket {
+        Some(path) => log::info!(target: "ezproxy::boot", "Starting on {}", path.display()),
+        None => log::info!(target: "ezproxy::boot", "Starting on {}", addr),
+    }
 
-    if let Err(e) = server.await {
+    let redirector = match load_redirector(&args) {
+        Ok(redirector) => redirector,
+        Err(e) => {
+            eprintln!("Could not load config: {}", e);
+            std::process::exit(1);
+        }
+    };
+    log::info!(target: "ezproxy::boot", "Loaded {} rules", redirector.rules().len());
+
+    // Daemonize only once the config has loaded, so that errors in it are reported on the terminal.
+    if args.daemon {
+        if let Err(e) = daemonize(args.log_file.as_deref()) {
+            eprintln!("Could not start as a daemon: {}", e);
+            std::process::exit(1);
+        }
+    }
+    let pid_file = match &args.pid_file {
+        Some(path) => match write_pid_file(path) {
+            Ok(pid_file) => Some(pid_file),
+            Err(e) => {
+                eprintln!("Could not write PID file {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let result = match tokio::runtime::Runtime::new() {
+        Ok(runtime) => {
+            let result = runtime.block_on(run(args, redirector));
+            // Don't wait for rules still running on blocking threads.
+            runtime.shutdown_background();
+            result
+        }
+        Err(e) => Err(failed("Could not start the runtime", e)),
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        // Exiting skips destructors, so remove the PID file first.
+        drop(pid_file);
+        std::process::exit(1);
+    }
+}
+
+/// Returns the filter for the client addresses allowed and denied by `args`.
+fn ip_filter(args: &Args) -> IpFilter {
+    let filter = args
+        .allow_ip
+        .iter()
+        .fold(IpFilter::new(), |filter, net| filter.with_allowed(*net));
+    args.deny_ip
+        .iter()
+        .fold(filter, |filter, net| filter.with_denied(*net))
+}
+
+/// Serves requests as directed by `args` until the server is stopped. Fails if the server can't
+/// start, or if requests were still in flight when the shutdown timeout ran out.
+async fn run(args: Arc<Args>, redirector: Redirector) -> io::Result<()> {
+    let addr = SocketAddr::new(args.host, args.port);
+    let mut context = AppContext::new(redirector)
+        .with_hide_errors(args.hide_errors)
+        .with_health_path(&args.health_path)
+        .with_metrics_path(&args.metrics_path)
+        .with_stats_path(&args.stats_path)
+        .with_trust_proxy(args.trust_proxy)
+        .with_max_body_size(args.max_body_size)
+        .with_ip_filter(ip_filter(&args))
+        .with_public_health(args.public_health)
+        .with_cors(Cors::new(&args.cors_origin));
+    if let (Some(user), Some(password)) = (&args.auth_user, &args.auth_password) {
+        context = context.with_basic_auth(BasicAuth::new(user, password));
+    }
+    if let Some(rate) = args.rate_limit {
+        context = context.with_rate_limiter(RateLimiter::new(
+            rate,
+            Duration::from_secs(args.rate_limit_idle_secs),
+        ));
+    }
+    if !args.no_reload {
+        spawn_reload_on_hangup(
+            args.clone(),
+            context.redirector().clone(),
+            context.metrics().clone(),
+        )
+        .map_err(|e| failed("Could not listen for SIGHUP", e))?;
+    }
+    if let Some(path) = &args.access_log {
+        let access_log = AccessLog::open(path.clone())
+            .await
+            .map_err(|e| failed(format!("Could not open access log {}", path.display()), e))?;
+        spawn_reopen_on_user1(access_log.clone())
+            .map_err(|e| failed("Could not listen for SIGUSR1", e))?;
+        context = context.with_access_log(access_log);
+    }
+    if let Some(port) = args.admin_port {
+        let admin_addr = admin_addr(args.host, port);
+        let admin_context = AdminContext {
+            redirector: context.redirector().clone(),
+            persist_path: args.admin_persist_config.as_deref().map(Arc::from),
+            changes: Arc::default(),
+            max_body_size: args.max_body_size,
+        };
+        // Bind before serving anything, so that a port in use stops ezproxy from starting.
+        let builder = Server::try_bind(&admin_addr).map_err(|e| {
+            failed(
+                format!("Could not bind the admin API to {}", admin_addr),
+                io::Error::other(e),
+            )
+        })?;
+        log::info!(target: "ezproxy::boot", "Serving the admin API on {}", admin_addr);
+        tokio::spawn(async move {
+            if let Err(e) = serve_admin(builder, admin_context).await {
+                // Redirects don't depend on the admin API, so keep serving them.
+                log::error!(target: "ezproxy::admin", "Admin API stopped: {}", e);
+            }
+        });
+    }
+    let tls_acceptor = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => Some(
+            load_tls_acceptor(cert, key)
+                .map_err(|e| failed("Could not load TLS certificate and key", e))?,
+        ),
+        _ => None,
+    };
+
+    let (deadline, deadline_passed) = oneshot::channel();
+    let shutdown = shutdown_after(
+        stop_signal(),
+        Duration::from_secs(args.shutdown_timeout_secs),
+        deadline,
+    );
+    let serving = async {
+        match (&args.socket, tls_acceptor) {
+            (Some(path), _) => serve_unix_socket(path, context, shutdown).await,
+            (None, Some(acceptor)) => {
+                let listener = if args.reuse_port {
+                    reuse_port_listener(&addr)
+                } else {
+                    std::net::TcpListener::bind(addr)
+                        .and_then(|l| l.set_nonblocking(true).map(|_| l))
+                }
+                .and_then(tokio::net::TcpListener::from_std)
+                .map_err(|e| failed(format!("Could not bind to {}", addr), e))?;
+                Ok(serve(
+                    Server::builder(tls_incoming(listener, acceptor)),
+                    context,
+                    shutdown,
+                )
+                .await)
+            }
+            (None, None) => {
+                let builder = if args.reuse_port {
+                    reuse_port_listener(&addr)
+                        .and_then(|l| Server::from_tcp(l).map_err(io::Error::other))
+                        .map_err(|e| {
+                            failed(format!("Could not bind to {} with SO_REUSEPORT", addr), e)
+                        })?
+                } else {
+                    Server::try_bind(&addr).map_err(|e| {
+                        failed(format!("Could not bind to {}", addr), io::Error::other(e))
+                    })?
+                };
+                Ok(serve(builder, context, shutdown).await)
+            }
+        }
+    };
+    // Giving up on the server drops it, socket file guard and all, so the socket is still removed.
+    let result = tokio::select! {
+        result = serving => result?,
+        Ok(()) = deadline_passed => {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("Requests still in flight after {}s; exiting", args.shutdown_timeout_secs),
+            ));
+        }
+    };
+    if let Err(e) = result {
         eprintln!("Server error: {}", e);
     }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ezproxy::config::ConfigRule;
+    use ezproxy::server::test_utils::{body_string, context, rules};
+
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    #[test]
+    fn reuse_port_listeners_share_a_port() {
+        let first = reuse_port_listener(&SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        let addr = first.local_addr().unwrap();
+        let second = reuse_port_listener(&addr).unwrap();
+        assert_eq!(second.local_addr().unwrap(), addr);
+    }
+
+    #[test]
+    fn tls_flags_require_each_other() {
+        let err =
+            Args::try_parse_from(["ezproxy", "--tls-cert", "cert.pem", "config.txt"]).unwrap_err();
+        assert_eq!(err.kind(), clap::ErrorKind::MissingRequiredArgument);
+        let err =
+            Args::try_parse_from(["ezproxy", "--tls-key", "key.pem", "config.txt"]).unwrap_err();
+        assert_eq!(err.kind(), clap::ErrorKind::MissingRequiredArgument);
+
+        let args = Args::try_parse_from([
+            "ezproxy",
+            "--tls-cert",
+            "cert.pem",
+            "--tls-key",
+            "key.pem",
+            "config.txt",
+        ])
+        .unwrap();
+        assert_eq!(args.tls_cert, Some(PathBuf::from("cert.pem")));
+        assert_eq!(args.tls_key, Some(PathBuf::from("key.pem")));
+    }
+
+    #[test]
+    fn host_defaults_to_loopback() {
+        let args = Args::try_parse_from(["ezproxy", "config.txt"]).unwrap();
+        assert_eq!(args.host, IpAddr::from([127, 0, 0, 1]));
+
+        let args = Args::try_parse_from(["ezproxy", "--host", "0.0.0.0", "config.txt"]).unwrap();
+        assert_eq!(args.host, IpAddr::from([0, 0, 0, 0]));
+
+        let err =
+            Args::try_parse_from(["ezproxy", "--host", "localhost", "config.txt"]).unwrap_err();
+        assert_eq!(err.kind(), clap::ErrorKind::ValueValidation);
+    }
+
+    #[test]
+    fn multiple_configs() {
+        let args = Args::try_parse_from(["ezproxy", "work.txt"]).unwrap();
+        assert_eq!(args.config_paths(), vec![Path::new("work.txt")]);
+        let args = Args::try_parse_from([
+            "ezproxy",
+            "work.txt",
+            "personal.txt",
+            "--config",
+            "dev.txt",
+            "-c",
+            "local.txt",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.config_paths(),
+            vec![
+                Path::new("work.txt"),
+                Path::new("personal.txt"),
+                Path::new("dev.txt"),
+                Path::new("local.txt")
+            ]
+        );
+        assert_eq!(
+            args.config_display(),
+            "work.txt, personal.txt, dev.txt, local.txt"
+        );
+        let args = Args::try_parse_from(["ezproxy", "--config", "dev.txt"]).unwrap();
+        assert_eq!(args.config_paths(), vec![Path::new("dev.txt")]);
+        let err = Args::try_parse_from(["ezproxy"]).unwrap_err();
+        assert_eq!(err.kind(), clap::ErrorKind::MissingRequiredArgument);
+    }
+
+    #[test]
+    fn inline_rules_override_config() {
+        let config = std::env::temp_dir().join("ezproxy-test-inline-rules-override-config.txt");
+        std::fs::write(
+            &config,
+            "m = https://gmail.com/\nc = https://calendar.google.com/\n",
+        )
+        .unwrap();
+        let args = Args::try_parse_from([
+            "ezproxy".as_ref(),
+            config.as_os_str(),
+            "-r".as_ref(),
+            "m = https://mail.yahoo.com/".as_ref(),
+        ])
+        .unwrap();
+        let redirector = load_redirector(&args).unwrap();
+        std::fs::remove_file(&config).unwrap();
+        assert_eq!(
+            redirector
+                .evaluate(&"/?q=m".parse().unwrap())
+                .unwrap()
+                .location,
+            "https://mail.yahoo.com/"
+        );
+        assert!(redirector.rules().contains_key("c"));
+
+        let args =
+            Args::try_parse_from(["ezproxy", "--rule", "g = https://www.google.com/"]).unwrap();
+        assert!(args.config_paths().is_empty());
+        assert_eq!(load_redirector(&args).unwrap().rules().len(), 1);
+
+        let args =
+            Args::try_parse_from(["ezproxy", "--rule", "g=https://www.google.com/"]).unwrap();
+        match load_redirector(&args) {
+            Err(ConfigError::InlineRule { position, .. }) => assert_eq!(position, 1),
+            _ => panic!("Expected InlineRule"),
+        }
+    }
+
+    #[test]
+    fn validate_subcommand() {
+        let args = Args::try_parse_from(["ezproxy", "validate", "a.txt", "b.txt"]).unwrap();
+        match args.command {
+            Some(Command::Validate { config }) => {
+                assert_eq!(config, vec![PathBuf::from("a.txt"), PathBuf::from("b.txt")])
+            }
+            _ => panic!("Expected validate"),
+        }
+        let err = Args::try_parse_from(["ezproxy", "validate"]).unwrap_err();
+        assert_eq!(err.kind(), clap::ErrorKind::MissingRequiredArgument);
+    }
+
+    #[test]
+    fn validate_reports_every_problem() {
+        let dir = std::env::temp_dir();
+        let good = dir.join("ezproxy-test-validate-good.txt");
+        let bad = dir.join("ezproxy-test-validate-bad.txt");
+        std::fs::write(
+            &good,
+            "m = https://gmail.com/\ngh = https://github.com/{ARG1}/{ARG2:}\nre:^jira-(\\d+)$ = https://jira/{MATCH_1}\n",
+        )
+        .unwrap();
+        std::fs::write(
+            &bad,
+            "m = https://gmail.com/\nnot a rule\nz = https://example.com/{ARG0}\n",
+        )
+        .unwrap();
+
+        let mut out = Vec::new();
+        assert!(validate(&[&good], &ParseOptions::default(), &mut out).unwrap());
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!(
+                "warning: {}: gh uses {{ARG1}}, so it fails unless it's given at least 1 argument(s)\n",
+                good.display()
+            )
+        );
+
+        let mut out = Vec::new();
+        assert!(!validate(&[&good, &bad], &ParseOptions::default(), &mut out).unwrap());
+        let out = String::from_utf8(out).unwrap();
+        std::fs::remove_file(&good).unwrap();
+        std::fs::remove_file(&bad).unwrap();
+        let errors: Vec<&str> = out.lines().filter(|l| l.starts_with("error")).collect();
+        assert_eq!(errors.len(), 2, "{}", out);
+        assert!(errors[0].contains("Malformed config line 2"));
+        assert!(errors[1].contains("z: Invalid placeholder {ARG0}"));
+    }
+
+    #[test]
+    fn test_subcommand() {
+        let config = std::env::temp_dir().join("ezproxy-test-test-subcommand.txt");
+        std::fs::write(
+            &config,
+            "npm = https://npmjs.com/search?q={ARGS}\ngh = https://github.com/{ARG1}\n",
+        )
+        .unwrap();
+        let args = Args::try_parse_from([
+            "ezproxy".as_ref(),
+            "--routing-mode".as_ref(),
+            "path".as_ref(),
+            config.as_os_str(),
+        ])
+        .unwrap();
+        let run = |query: &str| {
+            let mut out = Vec::new();
+            let status = test_query(&args, query, &mut out).unwrap();
+            (status, String::from_utf8(out).unwrap())
+        };
+
+        assert_eq!(
+            run("npm file finder"),
+            (
+                0,
+                "Rule: npm\nTarget: https://npmjs.com/search?q=file%20finder\nStatus: 302\n"
+                    .to_string()
+            )
+        );
+        assert_eq!(run("m"), (1, "No rule for m\n".to_string()));
+        let (status, out) = run("gh");
+        assert_eq!(status, 2);
+        assert!(out.starts_with("Rule: gh\nError: "), "{}", out);
+        std::fs::remove_file(&config).unwrap();
+
+        let args =
+            Args::try_parse_from(["ezproxy", "test", "npm file finder", "config.txt"]).unwrap();
+        match args.command {
+            Some(Command::Test { query, config }) => {
+                assert_eq!(query, "npm file finder");
+                assert_eq!(config, vec![PathBuf::from("config.txt")]);
+            }
+            _ => panic!("Expected test"),
+        }
+    }
+
+    #[test]
+    fn list_sorts_rules_and_notes_placeholders() {
+        let mut rules = rules(&[
+            ("npm", "https://npmjs.com/search?q={ARGS}"),
+            ("_", "https://www.google.com/search?q={ALL}"),
+            ("gh", "https://github.com/{ARG1}"),
+        ]);
+        rules.insert(
+            "m".to_string(),
+            Box::new(ConfigRule::new("m", "https://gmail.com/").with_status_code(301)),
+        );
+
+        let mut out = Vec::new();
+        list_rules(&rules, false, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "_    ALL    302  https://www.google.com/search?q={ALL}  (default)\n\
+             gh   NAMED  302  https://github.com/{ARG1}\n\
+             m    none   301  https://gmail.com/\n\
+             npm  ARGS   302  https://npmjs.com/search?q={ARGS}\n"
+        );
+
+        let mut out = Vec::new();
+        list_rules(&rules, true, &mut out).unwrap();
+        let listings: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(listings.as_array().unwrap().len(), 4);
+        assert_eq!(
+            listings[0],
+            serde_json::json!({
+                "keyword": "_",
+                "template": "https://www.google.com/search?q={ALL}",
+                "status": 302,
+                "placeholders": "ALL",
+                "default": true,
+            })
+        );
+        assert_eq!(listings[3]["keyword"], "npm");
+    }
+
+    fn admin_context(config: &[(&str, &str)], persist_path: Option<&Path>) -> AdminContext {
+        AdminContext {
+            redirector: context(config).redirector().clone(),
+            persist_path: persist_path.map(Arc::from),
+            changes: Arc::default(),
+            max_body_size: 1024,
+        }
+    }
+
+    async fn admin_request(
+        context: &AdminContext,
+        method: Method,
+        path: &str,
+        body: &str,
+    ) -> (u16, serde_json::Value) {
+        let req = Request::builder()
+            .method(method)
+            .uri(path)
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let resp = handle_admin(context.clone(), req).await.unwrap();
+        let status = resp.status().as_u16();
+        (
+            status,
+            serde_json::from_str(&body_string(resp).await).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn admin_api_manages_rules() {
+        let admin = admin_context(&[("m", "https://gmail.com/")], None);
+        let location = |q: &str| {
+            admin
+                .redirector
+                .load()
+                .evaluate(&format!("/?q={}", q).parse().unwrap())
+                .map(|resolution| resolution.location)
+        };
+
+        let (status, rules) = admin_request(&admin, Method::GET, "/rules", "").await;
+        assert_eq!(status, 200);
+        assert_eq!(rules[0]["keyword"], "m");
+        assert_eq!(rules[0]["template"], "https://gmail.com/");
+
+        let (status, rule) = admin_request(
+            &admin,
+            Method::POST,
+            "/rules",
+            r#"{"keyword": "c", "uri": "https://calendar.google.com/"}"#,
+        )
+        .await;
+        assert_eq!(status, 201);
+        assert_eq!(rule["keyword"], "c");
+        assert_eq!(location("c").unwrap(), "https://calendar.google.com/");
+
+        let (status, _) = admin_request(
+            &admin,
+            Method::POST,
+            "/rules",
+            r#"{"keyword": "c", "uri": "https://outlook.com/"}"#,
+        )
+        .await;
+        assert_eq!(status, 409);
+
+        let (status, _) = admin_request(
+            &admin,
+            Method::PUT,
+            "/rules/c",
+            r#"{"uri": "https://outlook.com/calendar"}"#,
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(location("c").unwrap(), "https://outlook.com/calendar");
+
+        let (status, _) = admin_request(
+            &admin,
+            Method::PUT,
+            "/rules/x",
+            r#"{"uri": "https://example.com/"}"#,
+        )
+        .await;
+        assert_eq!(status, 404);
+
+        let (status, _) = admin_request(&admin, Method::DELETE, "/rules/c", "").await;
+        assert_eq!(status, 200);
+        assert!(location("c").is_err());
+        let (status, _) = admin_request(&admin, Method::DELETE, "/rules/c", "").await;
+        assert_eq!(status, 404);
+
+        for body in [
+            r#"{"keyword": "bad", "uri": "https://exa mple.com/{"}"#,
+            r#"{"keyword": "two words", "uri": "https://example.com/"}"#,
+            r#"{"uri": "https://example.com/"}"#,
+            "not json",
+        ] {
+            let (status, error) = admin_request(&admin, Method::POST, "/rules", body).await;
+            assert_eq!(status, 400, "{}", body);
+            assert!(error["error"].is_string());
+        }
+        assert_eq!(
+            admin_request(&admin, Method::GET, "/other", "").await.0,
+            404
+        );
+        assert_eq!(
+            admin_request(&admin, Method::PATCH, "/rules", "").await.0,
+            405
+        );
+        let huge = format!(
+            r#"{{"keyword": "big", "uri": "https://example.com/{}"}}"#,
+            "a".repeat(1024)
+        );
+        assert_eq!(
+            admin_request(&admin, Method::POST, "/rules", &huge).await.0,
+            413
+        );
+        assert!(!admin.redirector.load().rules().contains_key("big"));
+    }
+
+    #[test]
+    fn admin_api_listens_on_loopback() {
+        let any_v4 = IpAddr::from([0, 0, 0, 0]);
+        assert_eq!(
+            admin_addr(any_v4, 5051),
+            SocketAddr::from(([127, 0, 0, 1], 5051))
+        );
+        let any_v6 = IpAddr::from(Ipv6Addr::UNSPECIFIED);
+        assert_eq!(
+            admin_addr(any_v6, 5051),
+            SocketAddr::new(IpAddr::from(Ipv6Addr::LOCALHOST), 5051)
+        );
+    }
+
+    #[tokio::test]
+    async fn admin_api_persists_config() {
+        let dir = assert_fs::TempDir::new().unwrap();
+        let path = dir.path().join("ezproxy.txt");
+        let admin = admin_context(&[("m", "https://gmail.com/")], Some(&path));
+
+        let (status, _) = admin_request(
+            &admin,
+            Method::POST,
+            "/rules",
+            r#"{"keyword": "npm", "uri": "https://npmjs.com/search?q={ARGS}"}"#,
+        )
+        .await;
+        assert_eq!(status, 201);
+        let written = std::fs::read_to_string(&path).unwrap();
+        let (header, rules) = written.split_once('\n').unwrap();
+        assert!(header.starts_with("# Written by ezproxy at "), "{}", header);
+        assert_eq!(
+            rules,
+            "m = https://gmail.com/\n\
+             npm = https://npmjs.com/search?q={ARGS}\n"
+        );
+
+        admin_request(&admin, Method::DELETE, "/rules/m", "").await;
+        let rules = ezproxy::config::parse_rules_from(&path).unwrap();
+        assert_eq!(rules.keywords().collect::<Vec<_>>(), vec!["npm"]);
+        assert!(!dir.path().join("ezproxy.txt.tmp").exists());
+    }
+
+    #[test]
+    fn admin_persist_config_requires_admin_port() {
+        let err = Args::try_parse_from([
+            "ezproxy",
+            "--admin-persist-config",
+            "rules.txt",
+            "config.txt",
+        ])
+        .unwrap_err();
+        assert_eq!(err.kind(), clap::ErrorKind::MissingRequiredArgument);
+        let args = Args::try_parse_from([
+            "ezproxy",
+            "--admin-port",
+            "5051",
+            "--admin-persist-config",
+            "rules.txt",
+            "config.txt",
+        ])
+        .unwrap();
+        assert_eq!(args.admin_port, Some(5051));
+    }
+
+    #[test]
+    fn pid_file_removed_on_drop() {
+        let dir = assert_fs::TempDir::new().unwrap();
+        let path = dir.path().join("ezproxy.pid");
+        let pid_file = write_pid_file(&path).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+        drop(pid_file);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn daemon_flags() {
+        let args = Args::try_parse_from([
+            "ezproxy",
+            "--daemon",
+            "--pid-file",
+            "ezproxy.pid",
+            "--log-file",
+            "ezproxy.log",
+            "config.txt",
+        ])
+        .unwrap();
+        assert!(args.daemon);
+        assert_eq!(args.pid_file, Some(PathBuf::from("ezproxy.pid")));
+        assert_eq!(args.log_file, Some(PathBuf::from("ezproxy.log")));
+        let err = Args::try_parse_from(["ezproxy", "--log-file", "ezproxy.log", "config.txt"])
+            .unwrap_err();
+        assert_eq!(err.kind(), clap::ErrorKind::MissingRequiredArgument);
+    }
+
+    /// Collects everything written to it, for checking log output.
+    #[derive(Clone, Default)]
+    struct LogBuffer(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl io::Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            io::Write::write(&mut *self.0.lock().unwrap(), buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn json_logs_have_request_fields() {
+        let logs = LogBuffer::default();
+        let writer = logs.clone();
+        let subscriber =
+            log_subscriber(LogFormat::Json, EnvFilter::new("ezproxy=info"), move || {
+                writer.clone()
+            });
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let req = Request::get("/?q=m").body(Body::empty()).unwrap();
+        handle(context(&[("m", "https://gmail.com/")]), req)
+            .await
+            .unwrap();
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let line: serde_json::Value = serde_json::from_str(logs.lines().next().unwrap()).unwrap();
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["target"], "ezproxy::handle");
+        assert_eq!(line["uri"], "/?q=m");
+        assert_eq!(line["rule"], "m");
+        assert_eq!(line["location"], "https://gmail.com/");
+        assert!(line["timestamp"].is_string());
+        assert!(line["request_id"].as_str().unwrap().starts_with("request-"));
+        assert!(line["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("Returning uri https://gmail.com/"));
+    }
+
+    #[test]
+    fn log_format_flag() {
+        let args = Args::try_parse_from(["ezproxy", "config.txt"]).unwrap();
+        assert_eq!(args.log_format, LogFormat::Text);
+        let args = Args::try_parse_from(["ezproxy", "--log-format", "json", "config.txt"]).unwrap();
+        assert_eq!(args.log_format, LogFormat::Json);
+        let err =
+            Args::try_parse_from(["ezproxy", "--log-format", "xml", "config.txt"]).unwrap_err();
+        assert_eq!(err.kind(), clap::ErrorKind::ValueValidation);
+    }
+
+    #[test]
+    fn ignore_invalid_rules_flag() {
+        let config = std::env::temp_dir().join("ezproxy-test-ignore-invalid-rules.txt");
+        std::fs::write(
+            &config,
+            "m = https://gmail.com/\nc = https://calendar google.com/\n",
+        )
+        .unwrap();
+        let args = Args::parse_from(["ezproxy", config.to_str().unwrap()]);
+        assert!(matches!(
+            load_redirector(&args),
+            Err(ConfigError::InFile { .. })
+        ));
+        let args = Args::parse_from([
+            "ezproxy",
+            "--ignore-invalid-rules",
+            config.to_str().unwrap(),
+        ]);
+        let redirector = load_redirector(&args);
+        std::fs::remove_file(&config).unwrap();
+        let redirector = redirector.unwrap();
+        assert!(redirector.rules().contains_key("m"));
+        assert!(!redirector.rules().contains_key("c"));
+    }
+
+    #[test]
+    fn on_duplicate_flag() {
+        let args = Args::try_parse_from(["ezproxy", "config.txt"]).unwrap();
+        assert_eq!(args.on_duplicate, DuplicatePolicy::Error);
+        let args =
+            Args::try_parse_from(["ezproxy", "--on-duplicate", "warn-keep-last", "config.txt"])
+                .unwrap();
+        assert_eq!(args.on_duplicate, DuplicatePolicy::KeepLast);
+        let err = Args::try_parse_from(["ezproxy", "--on-duplicate", "ignore", "config.txt"])
+            .unwrap_err();
+        assert_eq!(err.kind(), clap::ErrorKind::ValueValidation);
+    }
+
+    #[test]
+    fn parse_ipv6_hosts() {
+        assert_eq!(
+            parse_host("::1"),
+            Ok(IpAddr::from([0, 0, 0, 0, 0, 0, 0, 1]))
+        );
+        assert_eq!(
+            parse_host("[::1]"),
+            Ok(IpAddr::from([0, 0, 0, 0, 0, 0, 0, 1]))
+        );
+        assert_eq!(
+            parse_host("[fe80::1]"),
+            Ok("fe80::1".parse::<IpAddr>().unwrap())
+        );
+        assert!(parse_host("[127.0.0.1]").is_err());
+        assert!(parse_host("[::1").is_err());
+        assert_eq!(
+            SocketAddr::new(parse_host("[::1]").unwrap(), 5050).to_string(),
+            "[::1]:5050"
+        );
+    }
+
+    #[test]
+    fn routing_mode_flag() {
+        let args = Args::try_parse_from(["ezproxy", "config.txt"]).unwrap();
+        assert_eq!(args.routing_mode, RoutingMode::Query);
+        let args =
+            Args::try_parse_from(["ezproxy", "--routing-mode", "path", "config.txt"]).unwrap();
+        assert_eq!(args.routing_mode, RoutingMode::Path);
+        let args =
+            Args::try_parse_from(["ezproxy", "--routing-mode", "bang", "config.txt"]).unwrap();
+        assert_eq!(args.routing_mode, RoutingMode::Bang);
+        let err = Args::try_parse_from(["ezproxy", "--routing-mode", "fragment", "config.txt"])
+            .unwrap_err();
+        assert_eq!(err.kind(), clap::ErrorKind::ValueValidation);
+    }
+
+    #[test]
+    fn separator_flag() {
+        let args = Args::try_parse_from(["ezproxy", "config.txt"]).unwrap();
+        assert_eq!(args.separator, " ");
+        let args = Args::try_parse_from(["ezproxy", "--separator", "|", "config.txt"]).unwrap();
+        assert_eq!(args.separator, "|");
+        let args = Args::try_parse_from(["ezproxy", "--delimiter", "::", "config.txt"]).unwrap();
+        assert_eq!(args.separator, "::");
+        let err = Args::try_parse_from(["ezproxy", "--separator", "", "config.txt"]).unwrap_err();
+        assert_eq!(err.kind(), clap::ErrorKind::ValueValidation);
+    }
+
+    #[test]
+    fn max_command_depth_flag() {
+        let args = Args::try_parse_from(["ezproxy", "config.txt"]).unwrap();
+        assert_eq!(args.max_command_depth, 1);
+        assert_eq!(args.subcommand_separator, ".");
+        let args = Args::try_parse_from([
+            "ezproxy",
+            "--max-command-depth",
+            "2",
+            "--subcommand-separator",
+            "/",
+            "config.txt",
+        ])
+        .unwrap();
+        assert_eq!(args.max_command_depth, 2);
+        assert_eq!(args.subcommand_separator, "/");
+        let err = Args::try_parse_from(["ezproxy", "--max-command-depth", "0", "config.txt"])
+            .unwrap_err();
+        assert_eq!(err.kind(), clap::ErrorKind::ValueValidation);
+    }
+
+    #[test]
+    fn ip_filter_flags() {
+        let args = Args::try_parse_from([
+            "ezproxy",
+            "--allow-ip",
+            "192.168.1.0/24",
+            "--allow-ip",
+            "::1",
+            "--deny-ip",
+            "192.168.1.13",
+            "config.txt",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.allow_ip,
+            vec![
+                "192.168.1.0/24".parse::<ipnet::IpNet>().unwrap(),
+                "::1/128".parse().unwrap()
+            ]
+        );
+        let filter = ip_filter(&args);
+        assert!(filter.is_allowed(Some(IpAddr::from([192, 168, 1, 12]))));
+        assert!(!filter.is_allowed(Some(IpAddr::from([192, 168, 1, 13]))));
+        assert!(!filter.is_allowed(Some(IpAddr::from([10, 0, 0, 1]))));
+
+        let args = Args::try_parse_from(["ezproxy", "config.txt"]).unwrap();
+        assert!(ip_filter(&args).is_empty());
+        let err = Args::try_parse_from(["ezproxy", "--deny-ip", "192.168.1.0/33", "config.txt"])
+            .unwrap_err();
+        assert_eq!(err.kind(), clap::ErrorKind::ValueValidation);
+    }
+
+    #[test]
+    fn auth_flags_require_each_other() {
+        for flags in [
+            &["--auth-user", "travis"][..],
+            &["--auth-password", "hunter2"],
+            &["--public-health"],
+        ] {
+            let err = Args::try_parse_from(["ezproxy"].iter().chain(flags).chain(&["config.txt"]))
+                .unwrap_err();
+            assert_eq!(err.kind(), clap::ErrorKind::MissingRequiredArgument);
+        }
+        let args = Args::try_parse_from([
+            "ezproxy",
+            "--auth-user",
+            "travis",
+            "--auth-password",
+            "hunter2",
+            "--public-health",
+            "config.txt",
+        ])
+        .unwrap();
+        assert_eq!(args.auth_user.as_deref(), Some("travis"));
+        assert_eq!(args.auth_password.as_deref(), Some("hunter2"));
+        assert!(args.public_health);
+    }
+
+    #[test]
+    fn cors_origin_flag() {
+        let args = Args::try_parse_from(["ezproxy", "config.txt"]).unwrap();
+        assert!(args.cors_origin.is_empty());
+        let args = Args::try_parse_from([
+            "ezproxy",
+            "--cors-origin",
+            "https://example.com",
+            "--cors-origin",
+            "chrome-extension://abcdef",
+            "config.txt",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.cors_origin,
+            vec!["https://example.com", "chrome-extension://abcdef"]
+        );
+    }
+
+    #[test]
+    fn case_insensitive_flag() {
+        let config = std::env::temp_dir().join("ezproxy-test-case-insensitive.txt");
+        std::fs::write(&config, "NPM = https://npmjs.com/search?q={ARGS}\n").unwrap();
+        let run = |flags: &[&str], query: &str| {
+            let mut argv = vec!["ezproxy"];
+            argv.extend(flags);
+            argv.push(config.to_str().unwrap());
+            let args = Args::try_parse_from(argv).unwrap();
+            let mut out = Vec::new();
+            let status = test_query(&args, query, &mut out).unwrap();
+            (status, String::from_utf8(out).unwrap())
+        };
+
+        assert_eq!(run(&[], "npm Tokio").0, 1);
+        assert_eq!(
+            run(&["--case-insensitive"], "nPm Tokio"),
+            (
+                0,
+                "Rule: npm\nTarget: https://npmjs.com/search?q=Tokio\nStatus: 302\n".to_string()
+            )
+        );
+        std::fs::remove_file(&config).unwrap();
+    }
+
+    #[test]
+    fn max_body_size_flag() {
+        let args = Args::try_parse_from(["ezproxy", "config.txt"]).unwrap();
+        assert_eq!(args.max_body_size, DEFAULT_MAX_BODY_SIZE);
+        let args =
+            Args::try_parse_from(["ezproxy", "--max-body-size", "1024", "config.txt"]).unwrap();
+        assert_eq!(args.max_body_size, 1024);
+    }
+
+    #[test]
+    fn rate_limit_flags() {
+        let args = Args::try_parse_from(["ezproxy", "config.txt"]).unwrap();
+        assert_eq!(args.rate_limit, None);
+        assert!(!args.trust_proxy);
+        let args = Args::try_parse_from([
+            "ezproxy",
+            "--rate-limit",
+            "5",
+            "--rate-limit-idle-secs",
+            "30",
+            "--trust-proxy",
+            "config.txt",
+        ])
+        .unwrap();
+        assert_eq!(args.rate_limit, Some(5));
+        assert_eq!(args.rate_limit_idle_secs, 30);
+        assert!(args.trust_proxy);
+        let err = Args::try_parse_from(["ezproxy", "--rate-limit", "0", "config.txt"]).unwrap_err();
+        assert_eq!(err.kind(), clap::ErrorKind::ValueValidation);
+        let err = Args::try_parse_from(["ezproxy", "--rate-limit-idle-secs", "30", "config.txt"])
+            .unwrap_err();
+        assert_eq!(err.kind(), clap::ErrorKind::MissingRequiredArgument);
+    }
+
+    #[test]
+    fn socket_conflicts_with_port() {
+        let err = Args::try_parse_from([
+            "ezproxy",
+            "--socket",
+            "/tmp/ezproxy.sock",
+            "--port",
+            "8080",
+            "config.txt",
+        ])
+        .unwrap_err();
+        assert_eq!(err.kind(), clap::ErrorKind::ArgumentConflict);
+
+        let args = Args::try_parse_from(["ezproxy", "--socket", "/tmp/ezproxy.sock", "config.txt"])
+            .unwrap();
+        assert_eq!(args.socket, Some(PathBuf::from("/tmp/ezproxy.sock")));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn serves_over_unix_socket() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let dir = assert_fs::TempDir::new().unwrap();
+        let path = dir.path().join("ezproxy.sock");
+        // A socket left behind by a killed server is replaced.
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let listener = bind_unix_socket(&path).unwrap();
+        assert_eq!(
+            bind_unix_socket(&path).unwrap_err().kind(),
+            io::ErrorKind::AddrInUse
+        );
+
+        let server = tokio::spawn(serve(
+            Server::builder(unix_incoming(listener)),
+            context(&[("m", "https://gmail.com/")]),
+            future::pending(),
+        ));
+        let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET /?q=m HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 302"), "{}", response);
+        assert!(
+            response.contains("location: https://gmail.com/"),
+            "{}",
+            response
+        );
+        server.abort();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn remove_on_drop_removes_socket_file() {
+        let dir = assert_fs::TempDir::new().unwrap();
+        let path = dir.path().join("ezproxy.sock");
+        std::fs::write(&path, "").unwrap();
+        drop(RemoveOnDrop(path.clone()));
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn shutdown_waits_for_requests_in_flight() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let builder = Server::from_tcp(listener.into_std().unwrap()).unwrap();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let (deadline, _) = oneshot::channel();
+        let mut server = tokio::spawn(serve(
+            builder,
+            context(&[("m", "https://gmail.com/")]),
+            shutdown_after(
+                async {
+                    stopped.await.ok();
+                },
+                Duration::from_secs(10),
+                deadline,
+            ),
+        ));
+
+        // Start a request, then ask the server to stop before finishing it.
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /?q=m HTTP/1.1\r\nHost: localhost\r\n")
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        stop.send(()).unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(50), &mut server)
+            .await
+            .is_err());
+
+        stream
+            .write_all(b"Connection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 302"), "{}", response);
+        server.await.unwrap().unwrap();
+        assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn run_returns_startup_errors() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        let args = Args::try_parse_from(["ezproxy", "--no-reload", "--port", &port, "config.txt"])
+            .unwrap();
+        let err = run(Arc::new(args), Redirector::builder().build())
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with(&format!("Could not bind to 127.0.0.1:{}", port)),
+            "{}",
+            err
+        );
+
+        let args = Args::try_parse_from([
+            "ezproxy",
+            "--no-reload",
+            "--port",
+            "0",
+            "--admin-port",
+            &port,
+            "config.txt",
+        ])
+        .unwrap();
+        let err = run(Arc::new(args), Redirector::builder().build())
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().starts_with(&format!(
+                "Could not bind the admin API to 127.0.0.1:{}",
+                port
+            )),
+            "{}",
+            err
+        );
+
+        let args = Args::try_parse_from([
+            "ezproxy",
+            "--no-reload",
+            "--tls-cert",
+            "missing-cert.pem",
+            "--tls-key",
+            "missing-key.pem",
+            "config.txt",
+        ])
+        .unwrap();
+        let err = run(Arc::new(args), Redirector::builder().build())
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("Could not load TLS certificate and key"),
+            "{}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn shutdown_gives_up_after_timeout() {
+        let (deadline, deadline_passed) = oneshot::channel();
+        shutdown_after(async {}, Duration::from_millis(10), deadline).await;
+        tokio::time::timeout(Duration::from_secs(5), deadline_passed)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[test]
+    fn load_tls_acceptor_reads_pem_files() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = assert_fs::TempDir::new().unwrap();
+        let cert_path = dir.path().join("cert.pem");
+        let key_path = dir.path().join("key.pem");
+        std::fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+        assert!(load_tls_acceptor(&cert_path, &key_path).is_ok());
+
+        // A certificate is not a key.
+        let err = load_tls_acceptor(&cert_path, &cert_path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().starts_with("No private key found in"));
+    }
 }
@@ -0,0 +1,196 @@
+//! Limits how many requests each client can make per second. Every client gets a token bucket
+//! holding up to a second's worth of requests, refilled continuously, so short bursts are allowed
+//! but a sustained flood isn't.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A client's remaining allowance.
+#[derive(Debug)]
+struct TokenBucket {
+  tokens: f64,
+  /// When `tokens` was last refilled.
+  updated: Instant,
+}
+
+#[derive(Debug)]
+struct Buckets {
+  by_ip: HashMap<IpAddr, TokenBucket>,
+  /// When idle buckets were last dropped.
+  swept: Instant,
+}
+
+/// Allows each IP address `rate` requests per second.
+///
+/// Clients that haven't made a request for the idle window are forgotten, so the limiter doesn't
+/// grow without bound. A forgotten client starts over with a full bucket, which it would have had
+/// anyway as long as the window is at least a second.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::rate_limit::RateLimiter;
+/// use std::net::IpAddr;
+/// use std::time::Duration;
+///
+/// let limiter = RateLimiter::new(2, Duration::from_secs(60));
+/// let ip = IpAddr::from([127, 0, 0, 1]);
+/// assert!(limiter.check(ip).is_ok());
+/// assert!(limiter.check(ip).is_ok());
+/// assert!(limiter.check(ip).is_err());
+/// assert!(limiter.check(IpAddr::from([10, 0, 0, 1])).is_ok());
+/// ```
+#[derive(Debug)]
+pub struct RateLimiter {
+  rate: f64,
+  idle_window: Duration,
+  buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+  /// Creates a limiter allowing `rate` requests per second per IP address, forgetting clients
+  /// after `idle_window` without a request.
+  ///
+  /// # Panics
+  ///
+  /// Panics if `rate` is 0.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::rate_limit::RateLimiter;
+  /// use std::time::Duration;
+  ///
+  /// let limiter = RateLimiter::new(10, Duration::from_secs(60));
+  /// assert_eq!(limiter.rate(), 10);
+  /// ```
+  pub fn new(rate: u32, idle_window: Duration) -> Self {
+    assert!(
+      rate > 0,
+      "The rate limit must allow at least 1 request per second"
+    );
+    RateLimiter {
+      rate: f64::from(rate),
+      idle_window,
+      buckets: Mutex::new(Buckets {
+        by_ip: HashMap::new(),
+        swept: Instant::now(),
+      }),
+    }
+  }
+
+  /// The number of requests per second allowed per IP address.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::rate_limit::RateLimiter;
+  /// use std::time::Duration;
+  ///
+  /// assert_eq!(RateLimiter::new(5, Duration::from_secs(60)).rate(), 5);
+  /// ```
+  pub fn rate(&self) -> u32 {
+    self.rate as u32
+  }
+
+  /// Takes one request from `ip`'s allowance. If it has none left, returns how long until it
+  /// has.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::rate_limit::RateLimiter;
+  /// use std::net::IpAddr;
+  /// use std::time::Duration;
+  ///
+  /// let limiter = RateLimiter::new(1, Duration::from_secs(60));
+  /// let ip = IpAddr::from([127, 0, 0, 1]);
+  /// assert!(limiter.check(ip).is_ok());
+  /// let retry_after = limiter.check(ip).unwrap_err();
+  /// assert!(retry_after <= Duration::from_secs(1));
+  /// ```
+  pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+    self.check_at(ip, Instant::now())
+  }
+
+  fn check_at(&self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+    let mut buckets = self
+      .buckets
+      .lock()
+      .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if now.saturating_duration_since(buckets.swept) >= self.idle_window {
+      let idle_window = self.idle_window;
+      buckets
+        .by_ip
+        .retain(|_, bucket| now.saturating_duration_since(bucket.updated) < idle_window);
+      buckets.swept = now;
+    }
+
+    let rate = self.rate;
+    let bucket = buckets.by_ip.entry(ip).or_insert(TokenBucket {
+      tokens: rate,
+      updated: now,
+    });
+    let refill = now.saturating_duration_since(bucket.updated).as_secs_f64() * rate;
+    bucket.tokens = (bucket.tokens + refill).min(rate);
+    bucket.updated = now;
+    if bucket.tokens >= 1.0 {
+      bucket.tokens -= 1.0;
+      Ok(())
+    } else {
+      Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+    }
+  }
+
+  /// The number of clients currently remembered.
+  #[cfg(test)]
+  fn len(&self) -> usize {
+    self
+      .buckets
+      .lock()
+      .unwrap_or_else(|poisoned| poisoned.into_inner())
+      .by_ip
+      .len()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn allowance_refills_over_time() {
+    let limiter = RateLimiter::new(2, Duration::from_secs(60));
+    let ip = IpAddr::from([127, 0, 0, 1]);
+    let start = Instant::now();
+    assert!(limiter.check_at(ip, start).is_ok());
+    assert!(limiter.check_at(ip, start).is_ok());
+    assert_eq!(limiter.check_at(ip, start), Err(Duration::from_millis(500)));
+    assert!(limiter
+      .check_at(ip, start + Duration::from_millis(500))
+      .is_ok());
+    assert!(limiter
+      .check_at(ip, start + Duration::from_millis(500))
+      .is_err());
+    // The allowance never exceeds a second's worth.
+    let later = start + Duration::from_secs(10);
+    assert!(limiter.check_at(ip, later).is_ok());
+    assert!(limiter.check_at(ip, later).is_ok());
+    assert!(limiter.check_at(ip, later).is_err());
+  }
+
+  #[test]
+  fn idle_clients_are_forgotten() {
+    let limiter = RateLimiter::new(1, Duration::from_secs(60));
+    let start = Instant::now();
+    for i in 0..10 {
+      assert!(limiter.check_at(IpAddr::from([10, 0, 0, i]), start).is_ok());
+    }
+    assert_eq!(limiter.len(), 10);
+    let later = start + Duration::from_secs(61);
+    assert!(limiter.check_at(IpAddr::from([10, 0, 0, 0]), later).is_ok());
+    assert_eq!(limiter.len(), 1);
+  }
+}
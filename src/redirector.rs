@@ -0,0 +1,1626 @@
+//! The [`Redirector`], which evaluates requests against a set of rules.
+use crate::command_parser::{Command, CommandParse, CommandParser, CommandParserConfig};
+use crate::config::{self, ConfigError, ConfigRule};
+use crate::rules::{
+  BangRule, FallbackOrder, Rule, RuleError, RuleRegistry, BANG_DEFAULT_RULE_KEY, DEFAULT_RULE_KEY,
+  FALLBACK_ORDER_KEY, MAX_CHAIN_DEPTH,
+};
+use hyper::Uri;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant};
+use unicode_normalization::UnicodeNormalization;
+
+/// Why [`Redirector::evaluate`] couldn't resolve a request.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::redirector::{EvaluateError, Redirector};
+/// use ezproxy::rules::RuleRegistry;
+///
+/// let redirector = Redirector::with_rules(RuleRegistry::new(), None);
+/// match redirector.evaluate(&"/?q=m".parse().unwrap()) {
+///   Err(EvaluateError::NoRule { command }) => assert_eq!(command, "m"),
+///   _ => panic!("Expected a NoRule error"),
+/// }
+/// ```
+#[derive(Debug)]
+pub enum EvaluateError {
+  /// The command couldn't be parsed out of the request.
+  InvalidCommand(String),
+  /// No rule applies to the command, and there's no fallback.
+  NoRule {
+    /// The command's name.
+    command: String,
+  },
+  /// The rule for the command failed.
+  Rule {
+    /// The keyword the rule is reported under; see [`Resolution::keyword`].
+    keyword: String,
+    /// Why the rule failed.
+    source: RuleError,
+  },
+}
+
+impl EvaluateError {
+  /// The HTTP status code to answer with. A request that ezproxy can't make sense of, e.g. one
+  /// without the query parameter, is the client's fault (400); anything else is ezproxy's (500).
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::redirector::{EvaluateError, Redirector};
+  ///
+  /// let redirector = Redirector::builder().build();
+  /// let err = redirector.evaluate(&"/".parse().unwrap()).unwrap_err();
+  /// assert_eq!(err.status_code(), 400);
+  /// let err = redirector.evaluate(&"/?q=m".parse().unwrap()).unwrap_err();
+  /// assert_eq!(err.status_code(), 500);
+  /// ```
+  pub fn status_code(&self) -> u16 {
+    match self {
+      EvaluateError::InvalidCommand(_) => 400,
+      EvaluateError::NoRule { .. } | EvaluateError::Rule { .. } => 500,
+    }
+  }
+}
+
+impl fmt::Display for EvaluateError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      EvaluateError::InvalidCommand(msg) => write!(f, "{}", msg),
+      EvaluateError::NoRule { command } => write!(
+        f,
+        "Could not find rule for cmd {}, and no default given",
+        command
+      ),
+      EvaluateError::Rule { source, .. } => write!(f, "{}", source),
+    }
+  }
+}
+
+impl Error for EvaluateError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      EvaluateError::Rule { source, .. } => Some(source),
+      _ => None,
+    }
+  }
+}
+
+/// The outcome of successfully evaluating a request.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::config::ConfigRule;
+/// use ezproxy::redirector::Redirector;
+/// use ezproxy::rules::RuleRegistry;
+///
+/// let mut rules = RuleRegistry::new();
+/// rules.insert("m".to_string(), Box::new(ConfigRule::new("m", "https://gmail.com/")));
+///
+/// let resolution = Redirector::with_rules(rules, None)
+///   .evaluate(&"/?q=m".parse().unwrap())
+///   .unwrap();
+/// assert_eq!(resolution.location, "https://gmail.com/");
+/// assert_eq!(resolution.status, 302);
+/// assert_eq!(resolution.keyword, "m");
+/// ```
+#[derive(Debug)]
+pub struct Resolution {
+  /// Where to redirect to. This is the exact `Location` header, so unlike a [`Uri`] it keeps any
+  /// fragment.
+  pub location: String,
+  /// The HTTP status code to redirect with, from [`Rule::status_code`].
+  pub status: u16,
+  /// The keyword of the rule that produced `location`. This is the command itself, unless it fell
+  /// back to a pattern rule, [`BANG_DEFAULT_RULE_KEY`] (including DuckDuckGo), or
+  /// [`DEFAULT_RULE_KEY`] (including the not-found redirect).
+  pub keyword: String,
+  /// How long the matching rule took to produce `location`, excluding query parsing and rule
+  /// lookup.
+  pub elapsed: Duration,
+}
+
+/// How often a rule has been used, and how long it took.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::config::ConfigRule;
+/// use ezproxy::redirector::Redirector;
+/// use ezproxy::rules::RuleRegistry;
+///
+/// let mut rules = RuleRegistry::new();
+/// rules.insert("m".to_string(), Box::new(ConfigRule::new("m", "https://gmail.com/")));
+///
+/// let redirector = Redirector::with_rules(rules, None);
+/// redirector.evaluate(&"/?q=m".parse().unwrap()).unwrap();
+/// let stat = &redirector.stats()["m"];
+/// assert_eq!(stat.hits, 1);
+/// assert_eq!(stat.errors, 0);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct RuleStat {
+  /// How many times the rule produced a redirect.
+  pub hits: u64,
+  /// How many times the rule failed to produce a redirect.
+  pub errors: u64,
+  /// The total time the rule took, in microseconds, whether it succeeded or failed.
+  pub total_micros: u64,
+}
+
+impl RuleStat {
+  /// Returns the average time the rule took, in microseconds, or 0 if it was never used.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::redirector::RuleStat;
+  ///
+  /// let stat = RuleStat { hits: 3, errors: 1, total_micros: 100 };
+  /// assert_eq!(stat.avg_micros(), 25);
+  /// assert_eq!(RuleStat::default().avg_micros(), 0);
+  /// ```
+  pub fn avg_micros(&self) -> u64 {
+    self
+      .total_micros
+      .checked_div(self.hits + self.errors)
+      .unwrap_or(0)
+  }
+}
+
+/// Parses commands out of request URIs and evaluates them against a set of rules.
+///
+/// Cloning a redirector is cheap: clones share their rules and [`Redirector::stats`] rather than
+/// copying them, so a rule added with [`Redirector::insert_rule`] is seen by every clone.
+///
+/// A command is evaluated by the rule for its keyword. Commands without one fall back to the
+/// following, in order:
+///
+/// 1. The first rule with a [`Rule::pattern`] matching the command. Patterns are tried in the order
+///    they appear in the rules, i.e. config file order for [`Redirector::with_config`].
+/// 2. For bang commands like `!gh`, the [`BANG_DEFAULT_RULE_KEY`] rule, or else DuckDuckGo.
+/// 3. The [`DEFAULT_RULE_KEY`] rule.
+/// 4. The redirect set with [`Redirector::with_not_found_redirect`].
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::config::ConfigRule;
+/// use ezproxy::redirector::Redirector;
+/// use ezproxy::rules::RuleRegistry;
+///
+/// let mut rules = RuleRegistry::new();
+/// rules.insert("npm".to_string(), Box::new(ConfigRule::new("npm", "https://npmjs.com/search?q={ARGS}")));
+///
+/// let redirector = Redirector::with_rules(rules, None);
+/// let resolution = redirector.evaluate(&"/?q=npm%20file%20finder".parse().unwrap()).unwrap();
+/// assert_eq!(resolution.location, "https://npmjs.com/search?q=file%20finder");
+/// ```
+#[derive(Clone)]
+pub struct Redirector {
+  cmd_parser: Arc<dyn CommandParse>,
+  rules: Arc<RwLock<RuleRegistry>>,
+  not_found_rule: Option<Arc<dyn Rule>>,
+  /// Keyed by the keyword of the rule the stat is for; see [`Resolution::keyword`].
+  stats: Arc<RwLock<HashMap<String, RuleStat>>>,
+  /// How many leading words of a command may form its keyword; see
+  /// [`Redirector::with_max_command_depth`].
+  max_command_depth: usize,
+  /// Joins the words of a nested keyword, e.g. `.` in `gh.issues`.
+  subcommand_separator: String,
+  /// The keywords of the rules to try, in order, for commands no rule matches; see
+  /// [`Redirector::with_fallback_chain`].
+  fallback_chain: Vec<String>,
+}
+
+/// Builds a [`Redirector`] from rules created in code, without a config file.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::config::ConfigRule;
+/// use ezproxy::redirector::RedirectorBuilder;
+///
+/// let redirector = RedirectorBuilder::default()
+///   .with_rule("m", ConfigRule::new("m", "https://gmail.com/"))
+///   .with_default(ConfigRule::new("_", "https://www.google.com/search?q={ALL}"))
+///   .with_query_param("search")
+///   .build();
+/// let resolution = redirector.evaluate(&"/?search=m".parse().unwrap()).unwrap();
+/// assert_eq!(resolution.location, "https://gmail.com/");
+/// let resolution = redirector.evaluate(&"/?search=best%20pizza".parse().unwrap()).unwrap();
+/// assert_eq!(resolution.location, "https://www.google.com/search?q=best%20pizza");
+/// ```
+#[derive(Clone, Default)]
+pub struct RedirectorBuilder {
+  rules: RuleRegistry,
+  parser_config: CommandParserConfig,
+}
+
+impl RedirectorBuilder {
+  /// Adds `rule` for `keyword`, replacing any rule already added for it.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ConfigRule;
+  /// use ezproxy::redirector::RedirectorBuilder;
+  ///
+  /// let redirector = RedirectorBuilder::default()
+  ///   .with_rule("m", ConfigRule::new("m", "https://gmail.com/"))
+  ///   .build();
+  /// assert!(redirector.rules().contains_key("m"));
+  /// ```
+  pub fn with_rule<K: Into<String>, R: Rule + 'static>(
+    &mut self,
+    keyword: K,
+    rule: R,
+  ) -> &mut Self {
+    self.rules.insert(keyword, Box::new(rule));
+    self
+  }
+
+  /// Adds `rule` as the fallback for commands without a rule of their own, i.e. the
+  /// [`DEFAULT_RULE_KEY`] rule.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ConfigRule;
+  /// use ezproxy::redirector::RedirectorBuilder;
+  /// use ezproxy::rules::DEFAULT_RULE_KEY;
+  ///
+  /// let redirector = RedirectorBuilder::default()
+  ///   .with_default(ConfigRule::new(DEFAULT_RULE_KEY, "https://duckduckgo.com/?q={ALL}"))
+  ///   .build();
+  /// assert!(redirector.rules().contains_key(DEFAULT_RULE_KEY));
+  /// ```
+  pub fn with_default<R: Rule + 'static>(&mut self, rule: R) -> &mut Self {
+    self.with_rule(DEFAULT_RULE_KEY, rule)
+  }
+
+  /// Reads commands from the query parameter `param` instead of `q`.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ConfigRule;
+  /// use ezproxy::redirector::RedirectorBuilder;
+  ///
+  /// let redirector = RedirectorBuilder::default()
+  ///   .with_rule("m", ConfigRule::new("m", "https://gmail.com/"))
+  ///   .with_query_param("search")
+  ///   .build();
+  /// assert!(redirector.evaluate(&"/?search=m".parse().unwrap()).is_ok());
+  /// assert!(redirector.evaluate(&"/?q=m".parse().unwrap()).is_err());
+  /// ```
+  pub fn with_query_param<P: Into<String>>(&mut self, param: P) -> &mut Self {
+    self.parser_config.query_param = param.into();
+    self
+  }
+
+  /// Creates a redirector evaluating the rules added so far. The builder can keep being used
+  /// afterwards; rules are shared with the redirectors it built, not copied.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::redirector::RedirectorBuilder;
+  ///
+  /// assert!(RedirectorBuilder::default().build().rules().is_empty());
+  /// ```
+  pub fn build(&self) -> Redirector {
+    let parser = CommandParser::from(self.parser_config.clone());
+    Redirector::with_rules(self.rules.clone(), Some(Arc::new(parser)))
+  }
+}
+
+impl Redirector {
+  /// Returns a [`RedirectorBuilder`] for creating a redirector from rules written in code.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ConfigRule;
+  /// use ezproxy::redirector::Redirector;
+  ///
+  /// let redirector = Redirector::builder()
+  ///   .with_rule("m", ConfigRule::new("m", "https://gmail.com/"))
+  ///   .build();
+  /// assert!(redirector.rules().contains_key("m"));
+  /// ```
+  pub fn builder() -> RedirectorBuilder {
+    RedirectorBuilder::default()
+  }
+
+  /// Creates a redirector evaluating `rules`, keyed by keyword. Rules with a [`Rule::pattern`] are
+  /// tried in the order of `rules`. Commands are parsed by
+  /// `parser`, or by a default [`CommandParser`] if it's `None`.
+  ///
+  /// Keywords and commands are NFC-normalized before they're compared, so they match no matter
+  /// how their Unicode characters were composed.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::command_parser::CommandParser;
+  /// use ezproxy::redirector::Redirector;
+  /// use ezproxy::rules::RuleRegistry;
+  /// use std::sync::Arc;
+  ///
+  /// let redirector = Redirector::with_rules(RuleRegistry::new(), Some(Arc::new(CommandParser::with_separator('|'))));
+  /// assert!(redirector.rules().is_empty());
+  /// ```
+  pub fn with_rules(rules: RuleRegistry, parser: Option<Arc<dyn CommandParse>>) -> Self {
+    let mut normalized = RuleRegistry::new();
+    for (kw, rule) in rules.into_shared() {
+      normalized.insert_shared(kw.nfc().collect(), rule);
+    }
+    let fallback_chain = normalized
+      .get(FALLBACK_ORDER_KEY)
+      .and_then(|rule| rule.downcast_ref::<FallbackOrder>())
+      .map(|order| order.keywords().to_vec())
+      .unwrap_or_default();
+    Self {
+      rules: Arc::new(RwLock::new(normalized)),
+      cmd_parser: parser.unwrap_or_else(|| Arc::new(CommandParser::default())),
+      not_found_rule: None,
+      stats: Arc::default(),
+      max_command_depth: 1,
+      subcommand_separator: ".".to_string(),
+      fallback_chain,
+    }
+  }
+
+  /// Creates a redirector evaluating the rules in the config file at `config_path`. Commands are
+  /// parsed by a [`CommandParser`] configured by `parser_config`, or a default one if it's `None`.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the config can't be read or parsed; see [`config::parse_rules_from`].
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::redirector::Redirector;
+  ///
+  /// let path = std::env::temp_dir().join("ezproxy-doctest-with-config.txt");
+  /// std::fs::write(&path, "m = https://gmail.com/\n").unwrap();
+  ///
+  /// let redirector = Redirector::with_config(&path, None).unwrap();
+  /// assert!(redirector.rules().contains_key("m"));
+  /// # std::fs::remove_file(&path).unwrap();
+  /// ```
+  pub fn with_config<P: AsRef<Path>>(
+    config_path: P,
+    parser_config: Option<CommandParserConfig>,
+  ) -> Result<Self, ConfigError> {
+    let rules = config::parse_rules_from(config_path)?;
+    let parser = parser_config.map(|c| Arc::new(CommandParser::from(c)) as Arc<dyn CommandParse>);
+    Ok(Redirector::with_rules(rules, parser))
+  }
+
+  /// Redirects commands with no matching rule (and no default rule) to `url`, which may use the
+  /// same placeholders as a config file URL.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::redirector::Redirector;
+  /// use ezproxy::rules::RuleRegistry;
+  ///
+  /// let redirector = Redirector::with_rules(RuleRegistry::new(), None)
+  ///   .with_not_found_redirect("https://www.google.com/search?q={ALL}");
+  /// let resolution = redirector.evaluate(&"/?q=best%20pizza".parse().unwrap()).unwrap();
+  /// assert_eq!(resolution.location, "https://www.google.com/search?q=best%20pizza");
+  /// ```
+  pub fn with_not_found_redirect<U: Into<String>>(mut self, url: U) -> Self {
+    self.not_found_rule = Some(Arc::new(ConfigRule::new(DEFAULT_RULE_KEY, url)));
+    self
+  }
+
+  /// Tries the rules for each of `keywords`, in order, for commands no rule or pattern matches,
+  /// until one succeeds, instead of the default rule. The command is passed to each as typed. A
+  /// keyword without a rule is skipped, and `_` may be listed to try the default rule. If none
+  /// succeed, the last one's error is returned. Bang commands still go to the bang default.
+  ///
+  /// Defaults to the keywords of the rules' [`FallbackOrder`], if they have one, or else none.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ConfigRule;
+  /// use ezproxy::redirector::Redirector;
+  /// use ezproxy::rules::RuleRegistry;
+  ///
+  /// let mut rules = RuleRegistry::new();
+  /// rules.insert("g", Box::new(ConfigRule::new("g", "https://www.google.com/search?q={ALL}")));
+  /// rules.insert("wiki", Box::new(ConfigRule::new("wiki", "https://en.wikipedia.org/wiki/{ARG1}")));
+  ///
+  /// let redirector = Redirector::with_rules(rules, None).with_fallback_chain(["wiki", "g"]);
+  /// // wiki needs an argument, so the command falls through to g.
+  /// let resolution = redirector.evaluate_query("rust", &[]).unwrap();
+  /// assert_eq!(resolution.keyword, "g");
+  /// ```
+  pub fn with_fallback_chain<I, S>(mut self, keywords: I) -> Self
+  where
+    I: IntoIterator<Item = S>,
+    S: Into<String>,
+  {
+    self.fallback_chain = keywords
+      .into_iter()
+      .map(|kw| kw.into().nfc().collect())
+      .collect();
+    self
+  }
+
+  /// Lets the first `depth` words of a command form its keyword, joined by the subcommand
+  /// separator (`.` unless set with [`Redirector::with_subcommand_separator`]). With a depth of 2,
+  /// `gh issues list` is evaluated by the `gh.issues` rule with the argument `list`. If there's no
+  /// rule for the deeper keyword, shallower ones are tried, down to the command alone. Defaults to
+  /// 1, which only ever looks up the command.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ConfigRule;
+  /// use ezproxy::redirector::Redirector;
+  /// use ezproxy::rules::RuleRegistry;
+  ///
+  /// let mut rules = RuleRegistry::new();
+  /// rules.insert("gh".to_string(), Box::new(ConfigRule::new("gh", "https://github.com/{ARG1}")));
+  /// rules.insert("gh.issues".to_string(), Box::new(ConfigRule::new("gh.issues", "https://github.com/issues?q={ARGS}")));
+  ///
+  /// let redirector = Redirector::with_rules(rules, None).with_max_command_depth(2);
+  /// let resolution = redirector.evaluate(&"/?q=gh%20issues%20open".parse().unwrap()).unwrap();
+  /// assert_eq!(resolution.location, "https://github.com/issues?q=open");
+  /// assert_eq!(resolution.keyword, "gh.issues");
+  /// let resolution = redirector.evaluate(&"/?q=gh%20tokio-rs".parse().unwrap()).unwrap();
+  /// assert_eq!(resolution.location, "https://github.com/tokio-rs");
+  /// ```
+  pub fn with_max_command_depth(mut self, depth: usize) -> Self {
+    self.max_command_depth = depth.max(1);
+    self
+  }
+
+  /// Replaces the `.` joining the words of a nested keyword; see
+  /// [`Redirector::with_max_command_depth`].
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ConfigRule;
+  /// use ezproxy::redirector::Redirector;
+  /// use ezproxy::rules::RuleRegistry;
+  ///
+  /// let mut rules = RuleRegistry::new();
+  /// rules.insert("gh/pr".to_string(), Box::new(ConfigRule::new("gh/pr", "https://github.com/pulls")));
+  ///
+  /// let redirector = Redirector::with_rules(rules, None)
+  ///   .with_max_command_depth(2)
+  ///   .with_subcommand_separator("/");
+  /// let resolution = redirector.evaluate(&"/?q=gh%20pr".parse().unwrap()).unwrap();
+  /// assert_eq!(resolution.location, "https://github.com/pulls");
+  /// ```
+  pub fn with_subcommand_separator<S: Into<String>>(mut self, separator: S) -> Self {
+    self.subcommand_separator = separator.into();
+    self
+  }
+
+  /// Replaces the parser used to parse commands out of request URIs.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::command_parser::CommandParser;
+  /// use ezproxy::config::ConfigRule;
+  /// use ezproxy::redirector::Redirector;
+  /// use ezproxy::rules::RuleRegistry;
+  /// use std::sync::Arc;
+  ///
+  /// let mut rules = RuleRegistry::new();
+  /// rules.insert("yt".to_string(), Box::new(ConfigRule::new("yt", "https://youtube.com/results?search_query={ARGS}")));
+  ///
+  /// let redirector = Redirector::with_rules(rules, None)
+  ///   .with_command_parser(Arc::new(CommandParser::with_separator('|')));
+  /// let resolution = redirector.evaluate(&"/?q=yt%7Crust%20async".parse().unwrap()).unwrap();
+  /// assert_eq!(resolution.location, "https://youtube.com/results?search_query=rust%20async");
+  /// ```
+  pub fn with_command_parser(mut self, cmd_parser: Arc<dyn CommandParse>) -> Self {
+    self.cmd_parser = cmd_parser;
+    self
+  }
+
+  /// Returns the rules this redirector evaluates, keyed by keyword. Rules can't be inserted or
+  /// removed while the returned guard is held, so drop it before calling
+  /// [`Redirector::insert_rule`] or [`Redirector::remove_rule`].
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::redirector::Redirector;
+  /// use ezproxy::rules::RuleRegistry;
+  ///
+  /// assert!(Redirector::with_rules(RuleRegistry::new(), None).rules().is_empty());
+  /// ```
+  pub fn rules(&self) -> RwLockReadGuard<'_, RuleRegistry> {
+    self
+      .rules
+      .read()
+      .unwrap_or_else(|poisoned| poisoned.into_inner())
+  }
+
+  /// Adds `rule` for `keyword`, replacing the rule already there, if any. The change is seen by
+  /// every clone of this redirector. A replaced rule keeps its place among the pattern rules; a
+  /// new one is tried after the rest.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ConfigRule;
+  /// use ezproxy::redirector::Redirector;
+  /// use ezproxy::rules::RuleRegistry;
+  ///
+  /// let redirector = Redirector::with_rules(RuleRegistry::new(), None);
+  /// redirector.insert_rule("m".to_string(), Box::new(ConfigRule::new("m", "https://gmail.com/")));
+  /// let resolution = redirector.evaluate(&"/?q=m".parse().unwrap()).unwrap();
+  /// assert_eq!(resolution.location, "https://gmail.com/");
+  /// ```
+  pub fn insert_rule(&self, keyword: String, rule: Box<dyn Rule>) {
+    self
+      .write_rules()
+      .insert(keyword.nfc().collect::<String>(), rule);
+  }
+
+  /// Removes the rule for `keyword`, returning whether there was one. The change is seen by every
+  /// clone of this redirector.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ConfigRule;
+  /// use ezproxy::redirector::Redirector;
+  /// use ezproxy::rules::RuleRegistry;
+  ///
+  /// let mut rules = RuleRegistry::new();
+  /// rules.insert("m".to_string(), Box::new(ConfigRule::new("m", "https://gmail.com/")));
+  ///
+  /// let redirector = Redirector::with_rules(rules, None);
+  /// assert!(redirector.remove_rule("m"));
+  /// assert!(!redirector.remove_rule("m"));
+  /// assert!(redirector.evaluate(&"/?q=m".parse().unwrap()).is_err());
+  /// ```
+  pub fn remove_rule(&self, keyword: &str) -> bool {
+    let keyword: String = keyword.nfc().collect();
+    self.write_rules().remove(&keyword)
+  }
+
+  fn write_rules(&self) -> RwLockWriteGuard<'_, RuleRegistry> {
+    self
+      .rules
+      .write()
+      .unwrap_or_else(|poisoned| poisoned.into_inner())
+  }
+
+  /// Parses the command out of `uri` and evaluates it, returning where to redirect to.
+  ///
+  /// Returns an error if the command can't be parsed, no rule applies, or the rule fails.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::redirector::Redirector;
+  /// use ezproxy::rules::RuleRegistry;
+  ///
+  /// let redirector = Redirector::with_rules(RuleRegistry::new(), None);
+  /// assert!(redirector.evaluate(&"/?q=m".parse().unwrap()).is_err());
+  /// ```
+  pub fn evaluate(&self, uri: &Uri) -> Result<Resolution, EvaluateError> {
+    let cmd = self
+      .parse_command(uri)
+      .map_err(EvaluateError::InvalidCommand)?;
+    self.evaluate_command(cmd)
+  }
+
+  /// Like [`Redirector::evaluate`], for the query string of a request URI, e.g.
+  /// `q=npm+file+finder`, as handed over by HTTP frameworks other than hyper. A leading `?` is
+  /// ignored. Since there's no path, this can't evaluate commands routed by
+  /// [`RoutingMode::Path`](crate::command_parser::RoutingMode::Path).
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ConfigRule;
+  /// use ezproxy::redirector::Redirector;
+  /// use ezproxy::rules::RuleRegistry;
+  ///
+  /// let mut rules = RuleRegistry::new();
+  /// rules.insert("npm", Box::new(ConfigRule::new("npm", "https://npmjs.com/search?q={ARGS}")));
+  ///
+  /// let redirector = Redirector::with_rules(rules, None);
+  /// let resolution = redirector.evaluate_str("q=npm+file+finder").unwrap();
+  /// assert_eq!(resolution.location, "https://npmjs.com/search?q=file%20finder");
+  /// ```
+  pub fn evaluate_str(&self, query: &str) -> Result<Resolution, EvaluateError> {
+    let query = query.strip_prefix('?').unwrap_or(query);
+    let uri: Uri = format!("/?{}", query)
+      .parse()
+      .map_err(|e| EvaluateError::InvalidCommand(format!("Invalid query {:?}: {}", query, e)))?;
+    self.evaluate(&uri)
+  }
+
+  /// Evaluates the command `cmd` with `args` without parsing a request, as if they'd been typed
+  /// into the address bar. Commands starting with `!` are bang commands, as usual.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ConfigRule;
+  /// use ezproxy::redirector::Redirector;
+  /// use ezproxy::rules::RuleRegistry;
+  ///
+  /// let mut rules = RuleRegistry::new();
+  /// rules.insert("npm", Box::new(ConfigRule::new("npm", "https://npmjs.com/search?q={ARGS}")));
+  ///
+  /// let redirector = Redirector::with_rules(rules, None);
+  /// let resolution = redirector.evaluate_query("npm", &["file", "finder"]).unwrap();
+  /// assert_eq!(resolution.location, "https://npmjs.com/search?q=file%20finder");
+  /// assert_eq!(redirector.evaluate_query("!gh", &[]).unwrap().keyword, "!default");
+  /// ```
+  pub fn evaluate_query(&self, cmd: &str, args: &[&str]) -> Result<Resolution, EvaluateError> {
+    let raw = std::iter::once(cmd)
+      .chain(args.iter().copied())
+      .collect::<Vec<_>>()
+      .join(" ");
+    let (name, is_bang) = match cmd.strip_prefix('!') {
+      Some(name) if !name.is_empty() => (name, true),
+      _ => (cmd, false),
+    };
+    let mut cmd = Command {
+      name: name.to_string(),
+      args: args.iter().map(|arg| arg.to_string()).collect(),
+      is_bang,
+      raw,
+    };
+    self.normalize_command(&mut cmd);
+    self.evaluate_command(cmd)
+  }
+
+  /// Evaluates an already parsed command; see [`Redirector::evaluate`].
+  fn evaluate_command(&self, cmd: Command) -> Result<Resolution, EvaluateError> {
+    log::debug!(target: "ezproxy::redirector", "Attempting redirector for {:?}", cmd);
+    let mut result = Err(EvaluateError::NoRule {
+      command: cmd.name.clone(),
+    });
+    for (rule, keyword) in self.select_rules(&cmd) {
+      result = self.prepare(&cmd, rule, keyword).and_then(|prepared| {
+        let start = Instant::now();
+        let location = prepared
+          .rule
+          .produce_location(&prepared.name, &prepared.args);
+        self.finish(
+          prepared.status,
+          &prepared.keyword,
+          location,
+          start.elapsed(),
+        )
+      });
+      if result.is_ok() {
+        break;
+      }
+    }
+    result
+  }
+
+  /// Like [`Redirector::evaluate`], but without blocking the async runtime: rules with an
+  /// [`AsyncRule`](crate::rules::AsyncRule) implementation (see [`Rule::as_async`]) are awaited, and other rules run on
+  /// tokio's blocking thread pool. Must be called from within a tokio runtime.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ConfigRule;
+  /// use ezproxy::redirector::Redirector;
+  /// use ezproxy::rules::RuleRegistry;
+  ///
+  /// let mut rules = RuleRegistry::new();
+  /// rules.insert("m".to_string(), Box::new(ConfigRule::new("m", "https://gmail.com/")));
+  ///
+  /// let redirector = Redirector::with_rules(rules, None);
+  /// # tokio::runtime::Runtime::new().unwrap().block_on(async {
+  /// let resolution = redirector.evaluate_async(&"/?q=m".parse().unwrap()).await.unwrap();
+  /// assert_eq!(resolution.location, "https://gmail.com/");
+  /// # });
+  /// ```
+  pub async fn evaluate_async(&self, uri: &Uri) -> Result<Resolution, EvaluateError> {
+    let cmd = self
+      .parse_command(uri)
+      .map_err(EvaluateError::InvalidCommand)?;
+    self.evaluate_command_async(cmd).await
+  }
+
+  /// Like [`Redirector::evaluate_async`], for a command in `params` rather than a request URI,
+  /// e.g. the fields of a form posted to ezproxy; see [`CommandParse::parse_from_params`].
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ConfigRule;
+  /// use ezproxy::redirector::Redirector;
+  /// use ezproxy::rules::RuleRegistry;
+  ///
+  /// let mut rules = RuleRegistry::new();
+  /// rules.insert("npm", Box::new(ConfigRule::new("npm", "https://npmjs.com/search?q={ARGS}")));
+  ///
+  /// let redirector = Redirector::with_rules(rules, None);
+  /// # tokio::runtime::Runtime::new().unwrap().block_on(async {
+  /// let params = querystring::querify("q=npm+file+finder");
+  /// let resolution = redirector.evaluate_params_async(&params).await.unwrap();
+  /// assert_eq!(resolution.location, "https://npmjs.com/search?q=file%20finder");
+  /// # });
+  /// ```
+  pub async fn evaluate_params_async(
+    &self,
+    params: &[(&str, &str)],
+  ) -> Result<Resolution, EvaluateError> {
+    let mut cmd = self
+      .cmd_parser
+      .parse_from_params(params)
+      .map_err(EvaluateError::InvalidCommand)?;
+    self.normalize_command(&mut cmd);
+    self.evaluate_command_async(cmd).await
+  }
+
+  /// Evaluates an already parsed command; see [`Redirector::evaluate_async`].
+  async fn evaluate_command_async(&self, cmd: Command) -> Result<Resolution, EvaluateError> {
+    log::debug!(target: "ezproxy::redirector", "Attempting redirector for {:?}", cmd);
+    let mut result = Err(EvaluateError::NoRule {
+      command: cmd.name.clone(),
+    });
+    for (rule, keyword) in self.select_rules(&cmd) {
+      let Prepared {
+        name,
+        args,
+        rule,
+        keyword,
+        status,
+      } = match self.prepare(&cmd, rule, keyword) {
+        Ok(prepared) => prepared,
+        Err(e) => {
+          result = Err(e);
+          continue;
+        }
+      };
+      let start = Instant::now();
+      let location = match rule.as_async() {
+        Some(rule) => rule.produce_location(&name, &args).await,
+        None => {
+          let rule = Arc::clone(&rule);
+          tokio::task::spawn_blocking(move || rule.produce_location(&name, &args))
+            .await
+            .unwrap_or_else(|e| Err(RuleError::ExternalError(Box::new(e))))
+        }
+      };
+      result = self.finish(status, &keyword, location, start.elapsed());
+      if result.is_ok() {
+        break;
+      }
+    }
+    result
+  }
+
+  /// Parses the command out of `uri` and returns the keyword of the rule that would evaluate it,
+  /// as reported in [`Resolution::keyword`], or `None` if no rule applies. Unlike
+  /// [`Redirector::evaluate`], the rule isn't run and no stats are recorded.
+  ///
+  /// Returns an error message if the command can't be parsed.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ConfigRule;
+  /// use ezproxy::redirector::Redirector;
+  /// use ezproxy::rules::RuleRegistry;
+  ///
+  /// let mut rules = RuleRegistry::new();
+  /// rules.insert("m".to_string(), Box::new(ConfigRule::new("m", "https://gmail.com/")));
+  ///
+  /// let redirector = Redirector::with_rules(rules, None);
+  /// assert_eq!(redirector.keyword_for(&"/?q=m".parse().unwrap()), Ok(Some("m".to_string())));
+  /// assert_eq!(redirector.keyword_for(&"/?q=c".parse().unwrap()), Ok(None));
+  /// ```
+  pub fn keyword_for(&self, uri: &Uri) -> Result<Option<String>, String> {
+    let cmd = self.parse_command(uri)?;
+    Ok(
+      self
+        .select_rules(&cmd)
+        .into_iter()
+        .next()
+        .map(|(_, keyword)| keyword),
+    )
+  }
+
+  /// Returns how often each rule has been used since this redirector was created, keyed by the
+  /// keyword reported in [`Resolution::keyword`]. Rules that have never been used are left out.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::redirector::Redirector;
+  /// use ezproxy::rules::RuleRegistry;
+  ///
+  /// let redirector = Redirector::with_rules(RuleRegistry::new(), None);
+  /// redirector.evaluate(&"/?q=!gh".parse().unwrap()).unwrap();
+  /// assert_eq!(redirector.stats()["!default"].hits, 1);
+  /// ```
+  pub fn stats(&self) -> HashMap<String, RuleStat> {
+    self
+      .stats
+      .read()
+      .unwrap_or_else(|poisoned| poisoned.into_inner())
+      .clone()
+  }
+
+  fn record(&self, keyword: &str, succeeded: bool, elapsed: Duration) {
+    let mut stats = self
+      .stats
+      .write()
+      .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let stat = stats.entry(keyword.to_string()).or_default();
+    if succeeded {
+      stat.hits += 1;
+    } else {
+      stat.errors += 1;
+    }
+    stat.total_micros += elapsed.as_micros() as u64;
+  }
+
+  /// Prepares to evaluate `cmd` with `rule`, reported under `keyword`, following
+  /// [`ChainedRule`](crate::rules::ChainedRule)s to the rule they delegate to.
+  fn prepare(
+    &self,
+    cmd: &Command,
+    mut rule: Arc<dyn Rule>,
+    keyword: String,
+  ) -> Result<Prepared, EvaluateError> {
+    // Bang rules see the command with its `!` so that `{ALL}` reproduces the query.
+    let mut name = if cmd.is_bang && keyword == BANG_DEFAULT_RULE_KEY {
+      format!("!{}", cmd.name)
+    } else {
+      cmd.name.clone()
+    };
+    let mut args = cmd.args.clone();
+    let mut status = None;
+    let mut chain = vec![keyword.clone()];
+    while let Some(chained) = rule.as_chained() {
+      let target = chained.target().to_string();
+      let looped = chain.contains(&target);
+      chain.push(target.clone());
+      if looped || chain.len() > MAX_CHAIN_DEPTH + 1 {
+        return Err(EvaluateError::Rule {
+          keyword,
+          source: RuleError::CycleDetected { chain },
+        });
+      }
+      log::debug!(target: "ezproxy::redirector", "Rule for {} delegates to {}", name, target);
+      status = status.or(chained.status_override());
+      name = chained.command().to_string();
+      args = chained.args_for(&args);
+      rule = self
+        .rules()
+        .get_shared(&target)
+        .map(|(_, rule)| Arc::clone(rule))
+        .ok_or(EvaluateError::NoRule { command: target })?;
+    }
+    let status = status.unwrap_or_else(|| rule.status_code());
+    Ok(Prepared {
+      name,
+      args,
+      rule,
+      keyword,
+      status,
+    })
+  }
+
+  /// Records the outcome of running the rule for `keyword` and turns it into a [`Resolution`]
+  /// redirecting with `status`.
+  fn finish(
+    &self,
+    status: u16,
+    keyword: &str,
+    location: Result<String, RuleError>,
+    elapsed: Duration,
+  ) -> Result<Resolution, EvaluateError> {
+    self.record(keyword, location.is_ok(), elapsed);
+    Ok(Resolution {
+      location: location.map_err(|source| EvaluateError::Rule {
+        keyword: keyword.to_string(),
+        source,
+      })?,
+      status,
+      keyword: keyword.to_string(),
+      elapsed,
+    })
+  }
+
+  fn parse_command(&self, uri: &Uri) -> Result<Command, String> {
+    let mut cmd = self.cmd_parser.parse(uri)?;
+    self.normalize_command(&mut cmd);
+    Ok(cmd)
+  }
+
+  /// Prepares a parsed command for rule lookup: NFC-normalizes its name and nests it; see
+  /// [`Redirector::with_max_command_depth`].
+  fn normalize_command(&self, cmd: &mut Command) {
+    cmd.name = cmd.name.nfc().collect();
+    self.nest_command(cmd);
+  }
+
+  /// Moves leading arguments of `cmd` into its name when there's a rule for the nested keyword
+  /// they form, trying the deepest keyword first; see [`Redirector::with_max_command_depth`].
+  fn nest_command(&self, cmd: &mut Command) {
+    let max_depth = self.max_command_depth.min(cmd.args.len() + 1);
+    for depth in (2..=max_depth).rev() {
+      let keyword: String = std::iter::once(cmd.name.as_str())
+        .chain(cmd.args[..depth - 1].iter().map(String::as_str))
+        .collect::<Vec<_>>()
+        .join(&self.subcommand_separator)
+        .nfc()
+        .collect();
+      if self.rules().contains_key(&keyword) {
+        cmd.name = keyword;
+        cmd.args.drain(..depth - 1);
+        return;
+      }
+    }
+  }
+
+  /// Selects the rules to try evaluating `cmd` with, in order, until one succeeds: the fallback
+  /// chain's for a command no rule or pattern matches, if there is a chain, or else the one
+  /// [`Redirector::select_rule`] picks.
+  fn select_rules(&self, cmd: &Command) -> Vec<(Arc<dyn Rule>, String)> {
+    if !self.fallback_chain.is_empty() && !cmd.is_bang {
+      let rules = self.rules();
+      if rules.get_shared(&cmd.name).is_none() && rules.pattern_rule(&cmd.name).is_none() {
+        log::debug!(target: "ezproxy::redirector", "No rule found for {}. Using fallback chain", cmd.name);
+        return self
+          .fallback_chain
+          .iter()
+          .filter_map(|kw| rules.get_shared(kw))
+          .map(|(kw, rule)| (Arc::clone(rule), kw.to_string()))
+          .collect();
+      }
+    }
+    self.select_rule(cmd).into_iter().collect()
+  }
+
+  /// Returns the rule that evaluates `cmd`, following the fallbacks described on [`Redirector`],
+  /// along with the keyword it's reported under.
+  fn select_rule(&self, cmd: &Command) -> Option<(Arc<dyn Rule>, String)> {
+    let rules = self.rules();
+    if let Some((kw, rule)) = rules.get_shared(&cmd.name) {
+      Some((Arc::clone(rule), kw.to_string()))
+    } else if let Some((kw, rule)) = rules.pattern_rule(&cmd.name) {
+      log::debug!(target: "ezproxy::redirector", "No rule found for {}. Using pattern", cmd.name);
+      Some((Arc::clone(rule), kw.to_string()))
+    } else if cmd.is_bang {
+      if let Some((_, bang_rule)) = rules.get_shared(BANG_DEFAULT_RULE_KEY) {
+        log::debug!(target: "ezproxy::redirector", "No rule found for !{}. Using bang default", cmd.name);
+        Some((Arc::clone(bang_rule), BANG_DEFAULT_RULE_KEY.to_string()))
+      } else {
+        log::debug!(target: "ezproxy::redirector", "No rule found for !{}. Forwarding to DuckDuckGo", cmd.name);
+        Some((Arc::new(BangRule), BANG_DEFAULT_RULE_KEY.to_string()))
+      }
+    } else if let Some((_, default_rule)) = rules.get_shared(DEFAULT_RULE_KEY) {
+      log::debug!(target: "ezproxy::redirector", "No rule found for {}. Using default", cmd.name);
+      Some((Arc::clone(default_rule), DEFAULT_RULE_KEY.to_string()))
+    } else if let Some(not_found_rule) = &self.not_found_rule {
+      log::debug!(target: "ezproxy::redirector", "No rule found for {}. Using not-found redirect", cmd.name);
+      Some((Arc::clone(not_found_rule), DEFAULT_RULE_KEY.to_string()))
+    } else {
+      None
+    }
+  }
+}
+
+/// A rule selected to evaluate a command, along with what to evaluate it with.
+struct Prepared {
+  /// The name of the command to pass to the rule.
+  name: String,
+  /// The arguments to pass to the rule.
+  args: Vec<String>,
+  rule: Arc<dyn Rule>,
+  /// The keyword the evaluation is reported under; see [`Resolution::keyword`].
+  keyword: String,
+  /// The status to redirect with.
+  status: u16,
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::rules::RegexRule;
+  use crate::server::test_utils::rules;
+
+  #[test]
+  fn pattern_rules_apply_after_keywords() {
+    let mut rules = rules(&[
+      ("jira-1", "https://example.com/first-ticket"),
+      (DEFAULT_RULE_KEY, "https://www.google.com/search?q={ALL}"),
+    ]);
+    rules.insert(
+      r"re:^jira-(\d+)$".to_string(),
+      Box::new(
+        RegexRule::new(
+          r"^jira-(\d+)$",
+          "https://myco.atlassian.net/browse/JIRA-{MATCH_1}?q={ARGS}",
+        )
+        .unwrap(),
+      ),
+    );
+    let redirector = Redirector::with_rules(rules, None);
+    let evaluate = |q: &str| {
+      redirector
+        .evaluate(&format!("/?q={}", q).parse().unwrap())
+        .unwrap()
+        .location
+    };
+    assert_eq!(
+      evaluate("jira-123%20comments"),
+      "https://myco.atlassian.net/browse/JIRA-123?q=comments"
+    );
+    assert_eq!(evaluate("jira-1"), "https://example.com/first-ticket");
+    assert_eq!(
+      redirector
+        .evaluate(&"/?q=jira-42".parse().unwrap())
+        .unwrap()
+        .keyword,
+      r"re:^jira-(\d+)$"
+    );
+    assert_eq!(
+      evaluate("jira-abc"),
+      "https://www.google.com/search?q=jira-abc%20"
+    );
+  }
+
+  #[test]
+  fn bang_command_uses_rule_without_bang() {
+    let redirector =
+      Redirector::with_rules(rules(&[("gh", "https://github.com/search?q={ARGS}")]), None);
+    let uri = "/?q=!gh%20rust%20async".parse().unwrap();
+    assert_eq!(
+      redirector.evaluate(&uri).unwrap().location,
+      "https://github.com/search?q=rust%20async"
+    );
+  }
+
+  #[test]
+  fn unknown_bang_command_uses_bang_default_rule() {
+    let redirector = Redirector::with_rules(
+      rules(&[
+        (
+          BANG_DEFAULT_RULE_KEY,
+          "https://search.brave.com/search?q={ALL}",
+        ),
+        (DEFAULT_RULE_KEY, "https://www.google.com/search?q={ALL}"),
+      ]),
+      None,
+    );
+    let uri = "/?q=!w%20rust".parse().unwrap();
+    assert_eq!(
+      redirector.evaluate(&uri).unwrap().location,
+      "https://search.brave.com/search?q=%21w%20rust"
+    );
+  }
+
+  #[test]
+  fn unknown_bang_command_forwards_to_duckduckgo() {
+    let redirector = Redirector::with_rules(
+      rules(&[(DEFAULT_RULE_KEY, "https://www.google.com/search?q={ALL}")]),
+      None,
+    );
+    let uri = "/?q=!w%20rust".parse().unwrap();
+    let resolution = redirector.evaluate(&uri).unwrap();
+    assert_eq!(resolution.location, "https://duckduckgo.com/?q=%21w%20rust");
+    assert_eq!(resolution.keyword, BANG_DEFAULT_RULE_KEY);
+  }
+
+  #[test]
+  fn not_found_redirect_used_for_unknown_commands() {
+    let redirector = Redirector::with_rules(rules(&[("m", "https://gmail.com/")]), None)
+      .with_not_found_redirect("https://www.google.com/search?q={ALL}");
+
+    let uri = "/?q=m".parse().unwrap();
+    assert_eq!(
+      redirector.evaluate(&uri).unwrap().location,
+      "https://gmail.com/"
+    );
+    let uri = "/?q=best%20pizza".parse().unwrap();
+    let resolution = redirector.evaluate(&uri).unwrap();
+    assert_eq!(
+      resolution.location,
+      "https://www.google.com/search?q=best%20pizza"
+    );
+    assert_eq!(resolution.keyword, DEFAULT_RULE_KEY);
+  }
+
+  #[test]
+  fn default_rule_takes_precedence_over_not_found_redirect() {
+    let redirector = Redirector::with_rules(
+      rules(&[(DEFAULT_RULE_KEY, "https://duckduckgo.com/?q={ALL}")]),
+      None,
+    )
+    .with_not_found_redirect("https://www.google.com/search?q={ALL}");
+
+    let uri = "/?q=best%20pizza".parse().unwrap();
+    assert_eq!(
+      redirector.evaluate(&uri).unwrap().location,
+      "https://duckduckgo.com/?q=best%20pizza"
+    );
+  }
+
+  #[test]
+  fn stats_count_hits_and_errors() {
+    let redirector = Redirector::with_rules(
+      rules(&[
+        ("m", "https://gmail.com/"),
+        ("gh", "https://github.com/{ARG1}"),
+      ]),
+      None,
+    );
+    let clone = redirector.clone();
+    for q in ["m", "m", "gh%20tokio", "gh", "nope"] {
+      let _ = clone.evaluate(&format!("/?q={}", q).parse().unwrap());
+    }
+
+    let stats = redirector.stats();
+    assert_eq!(stats.len(), 2);
+    assert_eq!((stats["m"].hits, stats["m"].errors), (2, 0));
+    assert_eq!((stats["gh"].hits, stats["gh"].errors), (1, 1));
+  }
+
+  #[test]
+  fn unicode_keywords() {
+    let redirector = Redirector::with_rules(
+      rules(&[
+        ("中文", "https://baidu.com/"),
+        // "café" written with a precomposed é.
+        ("caf\u{e9}", "https://example.com/cafe"),
+        // "señor" written with a combining tilde.
+        ("sen\u{303}or", "https://example.com/senor"),
+      ]),
+      None,
+    );
+
+    let uri = "/?q=%E4%B8%AD%E6%96%87".parse().unwrap();
+    assert_eq!(
+      redirector.evaluate(&uri).unwrap().location,
+      "https://baidu.com/"
+    );
+    // "café" typed with a combining acute accent.
+    let uri = "/?q=cafe%CC%81".parse().unwrap();
+    assert_eq!(
+      redirector.evaluate(&uri).unwrap().location,
+      "https://example.com/cafe"
+    );
+    // "señor" typed with a precomposed ñ.
+    let uri = "/?q=se%C3%B1or".parse().unwrap();
+    assert_eq!(
+      redirector.evaluate(&uri).unwrap().location,
+      "https://example.com/senor"
+    );
+  }
+
+  #[test]
+  fn with_config_exposes_loaded_rules() {
+    let path = std::env::temp_dir().join("ezproxy-test-with-config-exposes-loaded-rules.txt");
+    std::fs::write(
+      &path,
+      "m = https://gmail.com/\n_ = https://www.google.com/search?q={ALL}\n",
+    )
+    .unwrap();
+    let redirector = Redirector::with_config(&path, None).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let rules = redirector.rules();
+    let mut keywords: Vec<&str> = rules.keywords().collect();
+    keywords.sort_unstable();
+    assert_eq!(keywords, vec!["_", "m"]);
+  }
+
+  #[test]
+  fn clones_share_rules() {
+    let redirector = Redirector::with_rules(rules(&[("m", "https://gmail.com/")]), None);
+    let clone = redirector.clone();
+    assert!(Arc::ptr_eq(&redirector.rules, &clone.rules));
+    assert_eq!(
+      clone.evaluate(&"/?q=m".parse().unwrap()).unwrap().location,
+      "https://gmail.com/"
+    );
+  }
+
+  #[test]
+  fn custom_command_parser() {
+    struct PathParser;
+    impl CommandParse for PathParser {
+      fn parse(&self, uri: &Uri) -> Result<Command, String> {
+        let raw = uri.path().trim_start_matches('/');
+        let mut parts = raw.split('/');
+        Ok(Command {
+          name: parts.next().unwrap_or_default().to_string(),
+          args: parts.map(|s| s.to_string()).collect(),
+          is_bang: false,
+          raw: raw.to_string(),
+        })
+      }
+    }
+
+    let redirector = Redirector::with_rules(
+      rules(&[("npm", "https://npmjs.com/search?q={ARGS}")]),
+      Some(Arc::new(PathParser)),
+    );
+    let uri = "/npm/finder".parse().unwrap();
+    assert_eq!(
+      redirector.evaluate(&uri).unwrap().location,
+      "https://npmjs.com/search?q=finder"
+    );
+  }
+
+  #[test]
+  fn resolution_times_rule() {
+    struct SlowRule;
+    impl Rule for SlowRule {
+      fn produce_uri(&self, _cmd: &str, _args: &[String]) -> Result<Uri, RuleError> {
+        std::thread::sleep(Duration::from_millis(5));
+        Ok(Uri::from_static("https://example.com/"))
+      }
+    }
+    let mut rules = RuleRegistry::new();
+    rules.insert("slow".to_string(), Box::new(SlowRule));
+
+    let resolution = Redirector::with_rules(rules, None)
+      .evaluate(&"/?q=slow".parse().unwrap())
+      .unwrap();
+    assert!(resolution.elapsed >= Duration::from_millis(5));
+  }
+
+  #[tokio::test]
+  async fn evaluate_async_awaits_async_rules() {
+    use crate::rules::AsyncRule;
+
+    struct Lookup;
+    impl Rule for Lookup {
+      fn produce_uri(&self, _cmd: &str, _args: &[String]) -> Result<Uri, RuleError> {
+        Err(RuleError::TemplateError(
+          "only works asynchronously".to_string(),
+        ))
+      }
+
+      fn as_async(&self) -> Option<&dyn AsyncRule> {
+        Some(self)
+      }
+    }
+    #[async_trait::async_trait]
+    impl AsyncRule for Lookup {
+      async fn produce_uri(&self, cmd: &str, _args: &[String]) -> Result<Uri, RuleError> {
+        tokio::task::yield_now().await;
+        Ok(format!("https://example.com/{}", cmd).parse()?)
+      }
+    }
+    let mut rules = rules(&[("m", "https://gmail.com/")]);
+    rules.insert("s".to_string(), Box::new(Lookup));
+    let redirector = Redirector::with_rules(rules, None);
+
+    let resolution = redirector
+      .evaluate_async(&"/?q=s".parse().unwrap())
+      .await
+      .unwrap();
+    assert_eq!(resolution.location, "https://example.com/s");
+    assert!(redirector.evaluate(&"/?q=s".parse().unwrap()).is_err());
+    let resolution = redirector
+      .evaluate_async(&"/?q=m".parse().unwrap())
+      .await
+      .unwrap();
+    assert_eq!(resolution.location, "https://gmail.com/");
+    assert_eq!(redirector.stats()["s"].hits, 1);
+  }
+
+  #[test]
+  fn unknown_command_without_fallback_is_an_error() {
+    let redirector = Redirector::with_rules(rules(&[("m", "https://gmail.com/")]), None);
+    let uri = "/?q=best%20pizza".parse().unwrap();
+    assert!(redirector.evaluate(&uri).is_err());
+  }
+
+  #[test]
+  fn nested_commands_fall_back_to_shallower_keywords() {
+    let rules = rules(&[
+      ("gh", "https://github.com/{ARGS}"),
+      ("gh.pr", "https://github.com/pulls?q={ARGS}"),
+      ("gh.pr.merge", "https://github.com/merge/{ARG1}"),
+    ]);
+    let evaluate = |redirector: &Redirector, q: &str| {
+      let resolution = redirector
+        .evaluate(&format!("/?q={}", q).parse().unwrap())
+        .unwrap();
+      (resolution.keyword, resolution.location)
+    };
+
+    let flat = Redirector::with_rules(rules, None);
+    assert_eq!(
+      evaluate(&flat, "gh%20pr%20merge%2042"),
+      (
+        "gh".to_string(),
+        "https://github.com/pr%20merge%2042".to_string()
+      )
+    );
+
+    let nested = flat.clone().with_max_command_depth(3);
+    assert_eq!(
+      evaluate(&nested, "gh%20pr%20merge%2042"),
+      (
+        "gh.pr.merge".to_string(),
+        "https://github.com/merge/42".to_string()
+      )
+    );
+    assert_eq!(
+      evaluate(&nested, "gh%20pr%20mine"),
+      (
+        "gh.pr".to_string(),
+        "https://github.com/pulls?q=mine".to_string()
+      )
+    );
+    assert_eq!(
+      evaluate(&nested, "gh%20tokio-rs"),
+      ("gh".to_string(), "https://github.com/tokio-rs".to_string())
+    );
+    assert_eq!(
+      evaluate(&nested, "gh%20pr"),
+      (
+        "gh.pr".to_string(),
+        "https://github.com/pulls?q=".to_string()
+      )
+    );
+
+    let two_deep = flat.with_max_command_depth(2);
+    assert_eq!(
+      evaluate(&two_deep, "gh%20pr%20merge%2042"),
+      (
+        "gh.pr".to_string(),
+        "https://github.com/pulls?q=merge%2042".to_string()
+      )
+    );
+  }
+
+  #[test]
+  fn builder_shares_rules_between_builds() {
+    let mut builder = Redirector::builder();
+    builder.with_rule("m", ConfigRule::new("m", "https://gmail.com/"));
+    let first = builder.build();
+    builder.with_default(ConfigRule::new(
+      DEFAULT_RULE_KEY,
+      "https://www.google.com/search?q={ALL}",
+    ));
+    let second = builder.build();
+
+    let uri = "/?q=best%20pizza".parse().unwrap();
+    assert!(matches!(
+      first.evaluate(&uri),
+      Err(EvaluateError::NoRule { .. })
+    ));
+    assert_eq!(
+      second.evaluate(&uri).unwrap().location,
+      "https://www.google.com/search?q=best%20pizza"
+    );
+    assert_eq!(
+      second.evaluate(&"/?q=m".parse().unwrap()).unwrap().location,
+      "https://gmail.com/"
+    );
+  }
+
+  #[test]
+  fn inserted_and_removed_rules_are_seen_by_clones() {
+    let redirector = Redirector::with_rules(rules(&[("m", "https://gmail.com/")]), None);
+    let clone = redirector.clone();
+    let evaluate = |q: &str| {
+      clone
+        .evaluate(&format!("/?q={}", q).parse().unwrap())
+        .map(|resolution| resolution.location)
+    };
+
+    redirector.insert_rule(
+      "c".to_string(),
+      Box::new(ConfigRule::new("c", "https://calendar.google.com/")),
+    );
+    assert_eq!(evaluate("c").unwrap(), "https://calendar.google.com/");
+
+    redirector.insert_rule(
+      r"re:^jira-(\d+)$".to_string(),
+      Box::new(
+        RegexRule::new(
+          r"^jira-(\d+)$",
+          "https://myco.atlassian.net/browse/JIRA-{MATCH_1}",
+        )
+        .unwrap(),
+      ),
+    );
+    assert_eq!(
+      evaluate("jira-7").unwrap(),
+      "https://myco.atlassian.net/browse/JIRA-7"
+    );
+
+    assert!(redirector.remove_rule(r"re:^jira-(\d+)$"));
+    assert!(matches!(
+      evaluate("jira-7"),
+      Err(EvaluateError::NoRule { .. })
+    ));
+    assert!(redirector.remove_rule("m"));
+    assert!(!redirector.remove_rule("m"));
+    let mut keywords: Vec<String> = clone.rules().keywords().map(String::from).collect();
+    keywords.sort();
+    assert_eq!(keywords, vec!["c"]);
+  }
+
+  #[test]
+  fn chained_rules_delegate() {
+    let redirector = Redirector::with_rules(
+      config::parse_rules_from_str(
+        "gh = https://github.com/search?q={ALL}\n\
+         issues[301] = gh is:issue {ARGS} -> gh\n\
+         mine = gh author:me {ARGS} -> issues\n\
+         ping = pong -> pong\n\
+         pong = ping -> ping\n\
+         gone = x -> nope\n",
+      )
+      .unwrap(),
+      None,
+    );
+
+    let resolution = redirector.evaluate_query("issues", &["rust"]).unwrap();
+    assert_eq!(resolution.keyword, "issues");
+    assert_eq!(resolution.status, 301);
+    assert_eq!(
+      resolution.location,
+      "https://github.com/search?q=gh%20is%3Aissue%20rust"
+    );
+    let resolution = redirector.evaluate_query("mine", &["open"]).unwrap();
+    assert_eq!(
+      resolution.location,
+      "https://github.com/search?q=gh%20is%3Aissue%20author%3Ame%20open"
+    );
+    assert_eq!(redirector.evaluate_query("gh", &[]).unwrap().status, 302);
+
+    match redirector.evaluate_query("ping", &[]) {
+      Err(EvaluateError::Rule {
+        keyword,
+        source: RuleError::CycleDetected { chain },
+      }) => {
+        assert_eq!(keyword, "ping");
+        assert_eq!(chain, ["ping", "pong", "ping"]);
+      }
+      other => panic!("Expected CycleDetected, got {:?}", other.map(|_| ())),
+    }
+    assert!(matches!(
+      redirector.evaluate_query("gone", &[]),
+      Err(EvaluateError::NoRule { command }) if command == "nope"
+    ));
+  }
+
+  #[test]
+  fn chained_rules_give_up_past_max_depth() {
+    let mut config = String::from("r0 = https://example.com/{ARGS}\n");
+    for i in 1..=MAX_CHAIN_DEPTH + 1 {
+      config.push_str(&format!("r{} = r{} {{ARGS}} -> r{}\n", i, i - 1, i - 1));
+    }
+    let redirector = Redirector::with_rules(config::parse_rules_from_str(&config).unwrap(), None);
+    let deepest = format!("r{}", MAX_CHAIN_DEPTH);
+    assert_eq!(
+      redirector
+        .evaluate_query(&deepest, &["a"])
+        .unwrap()
+        .location,
+      "https://example.com/a"
+    );
+    let too_deep = format!("r{}", MAX_CHAIN_DEPTH + 1);
+    assert!(matches!(
+      redirector.evaluate_query(&too_deep, &[]),
+      Err(EvaluateError::Rule {
+        source: RuleError::CycleDetected { .. },
+        ..
+      })
+    ));
+  }
+
+  #[test]
+  fn fallback_chain_tries_rules_in_order() {
+    let config = "\
+      man = https://man7.org/linux/man-pages/man{ARG1}/{0}.{ARG1}.html\n\
+      g = https://www.google.com/search?q={ALL}\n\
+      _ = https://duckduckgo.com/?q={ALL}\n\
+      _fallback_order = missing,man,g,_\n";
+    let redirector = Redirector::with_rules(config::parse_rules_from_str(config).unwrap(), None);
+    // The whole command goes to each rule; man needs an argument, so this falls through to g.
+    let resolution = redirector.evaluate_query("ls", &[]).unwrap();
+    assert_eq!(resolution.keyword, "g");
+    let resolution = redirector.evaluate_query("ls", &["1"]).unwrap();
+    assert_eq!(resolution.keyword, "man");
+    // Exact matches don't use the chain.
+    let resolution = redirector.evaluate_query("man", &["1"]).unwrap();
+    assert_eq!(resolution.keyword, "man");
+    assert!(redirector.evaluate_query("man", &[]).is_err());
+  }
+
+  #[test]
+  fn fallback_chain_gives_up_when_no_rule_succeeds() {
+    let redirector = Redirector::with_rules(
+      rules(&[("man", "https://man7.org/linux/man-pages/man{ARG1}/")]),
+      None,
+    )
+    .with_fallback_chain(["man", "missing"]);
+    assert!(matches!(
+      redirector.evaluate_query("ls", &[]),
+      Err(EvaluateError::Rule { .. })
+    ));
+    let redirector = Redirector::with_rules(rules(&[]), None).with_fallback_chain(["missing"]);
+    assert!(matches!(
+      redirector.evaluate_query("ls", &[]),
+      Err(EvaluateError::NoRule { .. })
+    ));
+  }
+
+  #[test]
+  fn evaluate_str_and_query() {
+    let redirector = Redirector::with_rules(
+      rules(&[
+        ("npm", "https://npmjs.com/search?q={ARGS}"),
+        ("gh.pr", "https://github.com/pulls?q={ARGS}"),
+      ]),
+      None,
+    )
+    .with_max_command_depth(2);
+
+    for query in ["q=npm+file+finder", "?q=npm%20file%20finder"] {
+      assert_eq!(
+        redirector.evaluate_str(query).unwrap().location,
+        "https://npmjs.com/search?q=file%20finder"
+      );
+    }
+    assert!(matches!(
+      redirector.evaluate_str("search=npm"),
+      Err(EvaluateError::InvalidCommand(_))
+    ));
+    assert!(matches!(
+      redirector.evaluate_str("q=a b"),
+      Err(EvaluateError::InvalidCommand(_))
+    ));
+
+    assert_eq!(
+      redirector
+        .evaluate_query("npm", &["file", "finder"])
+        .unwrap()
+        .location,
+      "https://npmjs.com/search?q=file%20finder"
+    );
+    let resolution = redirector.evaluate_query("gh", &["pr", "mine"]).unwrap();
+    assert_eq!(resolution.keyword, "gh.pr");
+    assert_eq!(resolution.location, "https://github.com/pulls?q=mine");
+    let resolution = redirector.evaluate_query("!yt", &["rust"]).unwrap();
+    assert_eq!(resolution.keyword, BANG_DEFAULT_RULE_KEY);
+    assert_eq!(
+      resolution.location,
+      "https://duckduckgo.com/?q=%21yt%20rust"
+    );
+    assert!(matches!(
+      redirector.evaluate_query("m", &[]),
+      Err(EvaluateError::NoRule { command }) if command == "m"
+    ));
+  }
+}
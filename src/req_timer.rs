@@ -0,0 +1,97 @@
+//! Request IDs and timing, to tie together the logs about each request.
+use std::fmt::Display;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Returns a new ID for a request, e.g. `request-550e8400-e29b-41d4-a716-446655440000`, to tie
+/// together the logs about it.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::req_timer::get_request_uid;
+///
+/// let rid = get_request_uid();
+/// assert!(rid.starts_with("request-"));
+/// assert_ne!(rid, get_request_uid());
+/// ```
+pub fn get_request_uid() -> String {
+  format!("request-{}", Uuid::new_v4())
+}
+
+/// Logs at trace level that the request with ID `rid` took `elapsed`. Called by
+/// [`time_request!`](crate::time_request).
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::req_timer::log_request_time;
+/// use std::time::Duration;
+///
+/// log_request_time("request-1", Duration::from_micros(250));
+/// ```
+pub fn log_request_time<R: Display>(rid: R, elapsed: Duration) {
+  tracing::trace!(
+    target: "ezproxy::req_timer",
+    request_id = %rid,
+    "[{}] Completed in {}micros",
+    rid,
+    elapsed.as_micros()
+  );
+}
+
+/// Evaluates a block, then logs how long it took at trace level, and returns its value. The
+/// request ID to log it under may be given first; otherwise a new one is generated with
+/// [`get_request_uid`](crate::req_timer::get_request_uid).
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::time_request;
+///
+/// let sum = time_request!({ 1 + 1 });
+/// assert_eq!(sum, 2);
+///
+/// let rid = "request-1".to_string();
+/// let product = time_request!(&rid, { 2 * 3 });
+/// assert_eq!(product, 6);
+/// ```
+#[macro_export]
+macro_rules! time_request {
+  ($req_blk:block) => {{
+    $crate::time_request!($crate::req_timer::get_request_uid(), $req_blk)
+  }};
+  ($rid:expr, $req_blk:block) => {{
+    let rid = $rid;
+    let start = ::std::time::Instant::now();
+    let res = $req_blk;
+    $crate::req_timer::log_request_time(rid, start.elapsed());
+    res
+  }};
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn request_uids_are_unique() {
+    let first = get_request_uid();
+    let second = get_request_uid();
+    assert_ne!(first, second);
+    let uuid = first.strip_prefix("request-").unwrap();
+    assert_eq!(Uuid::parse_str(uuid).unwrap().get_version_num(), 4);
+  }
+
+  #[test]
+  fn time_request_returns_block_value() {
+    let mut calls = 0;
+    let value = time_request!({
+      calls += 1;
+      "done"
+    });
+    assert_eq!(value, "done");
+    assert_eq!(calls, 1);
+    assert_eq!(time_request!("request-1", { Some(42) }), Some(42));
+  }
+}
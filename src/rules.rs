@@ -1,15 +1,1745 @@
+//! The [`Rule`] trait, which maps a command typed into the address bar to a redirect target.
+use crate::config::ConfigRule;
+use async_trait::async_trait;
+use hyper::http::uri::InvalidUri;
 use hyper::Uri;
+use indexmap::IndexMap;
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::any::Any;
+use std::error::Error;
+use std::fmt;
+use std::io::Read;
+use std::process::{ExitStatus, Stdio};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-pub trait Rule: Send + Sync {
-  fn produce_uri(&self, cmd: &str, args: &[String]) -> Result<Uri, String>;
+#[cfg(feature = "test-utils")]
+pub mod test_utils;
+
+/// A shortcut that knows how to turn a command and its arguments into a URI to redirect to.
+///
+/// Rules are looked up by keyword, so a rule never has to check `cmd` to decide whether it
+/// applies; it's passed in for rules (like the default rule) that want to use it in the target.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::rules::{Rule, RuleError};
+/// use hyper::Uri;
+///
+/// struct Docs;
+/// impl Rule for Docs {
+///   fn produce_uri(&self, _cmd: &str, args: &[String]) -> Result<Uri, RuleError> {
+///     Ok(format!("https://docs.rs/{}", args.join("/")).parse()?)
+///   }
+/// }
+///
+/// let uri = Docs.produce_uri("docs", &["tokio".to_string()]).unwrap();
+/// assert_eq!(uri, "https://docs.rs/tokio");
+/// ```
+pub trait Rule: Any + Send + Sync {
+  /// Produces the URI to redirect to for `cmd` invoked with `args`.
+  ///
+  /// `args` holds every whitespace-separated word typed after the command. Returns an error if
+  /// no valid URI can be produced.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ConfigRule;
+  /// use ezproxy::rules::Rule;
+  ///
+  /// let rule = ConfigRule::new("m", "https://gmail.com/");
+  /// assert_eq!(rule.produce_uri("m", &[]).unwrap(), "https://gmail.com/");
+  /// ```
+  fn produce_uri(&self, cmd: &str, args: &[String]) -> Result<Uri, RuleError>;
+
+  /// Produces the value of the `Location` header to redirect to for `cmd` invoked with `args`.
+  ///
+  /// [`Uri`] can't represent a fragment (`#section`), so rules whose targets may have one should
+  /// override this to keep it; browsers apply the fragment after following the redirect. The
+  /// default implementation formats the result of [`Rule::produce_uri`].
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ConfigRule;
+  /// use ezproxy::rules::Rule;
+  ///
+  /// let rule = ConfigRule::new("rt", "https://docs.rs/tokio/latest/tokio/runtime/index.html#{ARGS}");
+  /// let location = rule.produce_location("rt", &["structs".to_string()]).unwrap();
+  /// assert_eq!(location, "https://docs.rs/tokio/latest/tokio/runtime/index.html#structs");
+  /// ```
+  fn produce_location(&self, cmd: &str, args: &[String]) -> Result<String, RuleError> {
+    self.produce_uri(cmd, args).map(|uri| uri.to_string())
+  }
+
+  /// Returns the URL template this rule redirects to, as written in a config file, or `None` if
+  /// the rule can't be written as one. Defaults to `None`.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ConfigRule;
+  /// use ezproxy::rules::{BangRule, Rule};
+  ///
+  /// let rule = ConfigRule::new("npm", "https://npmjs.com/search?q={ARGS}");
+  /// assert_eq!(rule.template(), Some("https://npmjs.com/search?q={ARGS}"));
+  /// assert_eq!(BangRule.template(), None);
+  /// ```
+  fn template(&self) -> Option<&str> {
+    None
+  }
+
+  /// Returns the HTTP status code to redirect with. Defaults to [`DEFAULT_STATUS_CODE`].
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ConfigRule;
+  /// use ezproxy::rules::Rule;
+  ///
+  /// assert_eq!(ConfigRule::new("m", "https://gmail.com/").status_code(), 302);
+  /// assert_eq!(ConfigRule::new("m", "https://gmail.com/").with_status_code(301).status_code(), 301);
+  /// ```
+  fn status_code(&self) -> u16 {
+    DEFAULT_STATUS_CODE
+  }
+
+  /// Returns the regular expression a command must match for this rule to apply, or `None` if
+  /// the rule only applies to its keyword. Defaults to `None`.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ConfigRule;
+  /// use ezproxy::rules::{RegexRule, Rule};
+  ///
+  /// let rule = RegexRule::new(r"^jira-(\d+)$", "https://myco.atlassian.net/browse/JIRA-{MATCH_1}").unwrap();
+  /// assert!(rule.pattern().unwrap().is_match("jira-123"));
+  /// assert!(ConfigRule::new("m", "https://gmail.com/").pattern().is_none());
+  /// ```
+  fn pattern(&self) -> Option<&Regex> {
+    None
+  }
+
+  /// Describes this rule for listings and admin tools, which can't otherwise see inside a
+  /// `dyn Rule`. Defaults to [`RuleDescription::unknown`].
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ConfigRule;
+  /// use ezproxy::rules::{BangRule, PlaceholderMode, Rule, RuleDescription};
+  ///
+  /// let description = ConfigRule::new("npm", "https://npmjs.com/search?q={ARGS}").describe();
+  /// assert_eq!(description.keyword.as_deref(), Some("npm"));
+  /// assert_eq!(description.placeholder_mode, PlaceholderMode::Args);
+  /// assert_eq!(BangRule.describe(), RuleDescription::unknown());
+  /// ```
+  fn describe(&self) -> RuleDescription {
+    RuleDescription::unknown()
+  }
+
+  /// Returns this rule as an [`AsyncRule`] if it has an asynchronous implementation, which the
+  /// server uses instead of [`Rule::produce_location`] so that it doesn't block while the rule
+  /// waits on the network. Defaults to `None`.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ConfigRule;
+  /// use ezproxy::rules::Rule;
+  ///
+  /// assert!(ConfigRule::new("m", "https://gmail.com/").as_async().is_none());
+  /// ```
+  fn as_async(&self) -> Option<&dyn AsyncRule> {
+    None
+  }
+
+  /// Returns this rule as a [`ChainedRule`] if it delegates to another rule, which a
+  /// [`Redirector`](crate::redirector::Redirector) follows instead of calling
+  /// [`Rule::produce_location`]. Defaults to `None`.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ConfigRule;
+  /// use ezproxy::rules::{ChainedRule, Rule};
+  ///
+  /// assert!(ChainedRule::new("gh", "gh", ["issues", "{ARGS}"]).as_chained().is_some());
+  /// assert!(ConfigRule::new("m", "https://gmail.com/").as_chained().is_none());
+  /// ```
+  fn as_chained(&self) -> Option<&ChainedRule> {
+    None
+  }
+}
+
+impl dyn Rule {
+  /// Returns this rule as [`Any`], so it can be downcast to its concrete type, e.g. to clone or
+  /// serialize a [`ConfigRule`].
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ConfigRule;
+  /// use ezproxy::rules::Rule;
+  ///
+  /// let rule: Box<dyn Rule> = Box::new(ConfigRule::new("m", "https://gmail.com/"));
+  /// assert!(rule.as_any().is::<ConfigRule>());
+  /// ```
+  pub fn as_any(&self) -> &dyn Any {
+    self
+  }
+
+  /// Returns this rule as a `T`, or `None` if it's some other type of rule.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ConfigRule;
+  /// use ezproxy::rules::{BangRule, Rule};
+  ///
+  /// let rule: Box<dyn Rule> = Box::new(ConfigRule::new("m", "https://gmail.com/"));
+  /// let config_rule = rule.downcast_ref::<ConfigRule>().unwrap();
+  /// assert_eq!(config_rule.uri(), "https://gmail.com/");
+  /// assert!(rule.downcast_ref::<BangRule>().is_none());
+  /// ```
+  pub fn downcast_ref<T: Rule>(&self) -> Option<&T> {
+    self.as_any().downcast_ref()
+  }
+}
+
+/// A shortcut that has to wait on something, e.g. a web service, to produce its URI.
+///
+/// To be added to a [`Redirector`](crate::redirector::Redirector), a type implements this as well
+/// as [`Rule`], returning itself from [`Rule::as_async`]; its [`Rule`] implementation is used
+/// where blocking is fine, e.g. by `ezproxy test`.
+///
+/// # Examples
+///
+/// ```
+/// use async_trait::async_trait;
+/// use ezproxy::rules::{AsyncRule, RuleError};
+/// use hyper::Uri;
+///
+/// struct Shortener;
+/// #[async_trait]
+/// impl AsyncRule for Shortener {
+///   async fn produce_uri(&self, _cmd: &str, args: &[String]) -> Result<Uri, RuleError> {
+///     // Imagine looking up the short link in a database here.
+///     Ok(format!("https://example.com/{}", args.join("/")).parse()?)
+///   }
+/// }
+///
+/// # tokio::runtime::Runtime::new().unwrap().block_on(async {
+/// let uri = Shortener.produce_uri("s", &["abc".to_string()]).await.unwrap();
+/// assert_eq!(uri, "https://example.com/abc");
+/// # });
+/// ```
+#[async_trait]
+pub trait AsyncRule: Send + Sync {
+  /// Produces the URI to redirect to for `cmd` invoked with `args`; see [`Rule::produce_uri`].
+  async fn produce_uri(&self, cmd: &str, args: &[String]) -> Result<Uri, RuleError>;
+
+  /// Produces the value of the `Location` header to redirect to for `cmd` invoked with `args`;
+  /// see [`Rule::produce_location`]. The default implementation formats the result of
+  /// [`AsyncRule::produce_uri`].
+  async fn produce_location(&self, cmd: &str, args: &[String]) -> Result<String, RuleError> {
+    self.produce_uri(cmd, args).await.map(|uri| uri.to_string())
+  }
+}
+
+/// Runs a shared rule on tokio's blocking thread pool, so that a slow [`Rule`] doesn't hold up
+/// other requests.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::config::ConfigRule;
+/// use ezproxy::rules::AsyncRule;
+/// use std::sync::Arc;
+///
+/// let rule = Arc::new(ConfigRule::new("m", "https://gmail.com/"));
+/// # tokio::runtime::Runtime::new().unwrap().block_on(async {
+/// assert_eq!(rule.produce_uri("m", &[]).await.unwrap(), "https://gmail.com/");
+/// # });
+/// ```
+#[async_trait]
+impl<R: Rule + ?Sized + 'static> AsyncRule for Arc<R> {
+  async fn produce_uri(&self, cmd: &str, args: &[String]) -> Result<Uri, RuleError> {
+    if let Some(rule) = (**self).as_async() {
+      return rule.produce_uri(cmd, args).await;
+    }
+    let (rule, cmd, args) = (self.clone(), cmd.to_string(), args.to_vec());
+    tokio::task::spawn_blocking(move || Rule::produce_uri(&*rule, &cmd, &args))
+      .await
+      .map_err(|e| RuleError::ExternalError(Box::new(e)))?
+  }
+
+  async fn produce_location(&self, cmd: &str, args: &[String]) -> Result<String, RuleError> {
+    if let Some(rule) = (**self).as_async() {
+      return rule.produce_location(cmd, args).await;
+    }
+    let (rule, cmd, args) = (self.clone(), cmd.to_string(), args.to_vec());
+    tokio::task::spawn_blocking(move || Rule::produce_location(&*rule, &cmd, &args))
+      .await
+      .map_err(|e| RuleError::ExternalError(Box::new(e)))?
+  }
+}
+
+/// Which placeholders a URL template fills in with the command typed into the address bar.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::rules::PlaceholderMode;
+///
+/// assert_eq!(PlaceholderMode::detect("https://gmail.com/"), PlaceholderMode::None);
+/// assert_eq!(PlaceholderMode::detect("https://github.com/{ARG1}/{ARG2:}"), PlaceholderMode::Named);
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PlaceholderMode {
+  /// The template doesn't use the command.
+  #[default]
+  None,
+  /// The template uses `{ARGS}`, `{ARGS_CSV}` or `{ARGS_JSON}`, the arguments after the keyword.
+  Args,
+  /// The template uses `{ALL}`, the whole command including the keyword.
+  All,
+  /// The template uses individual arguments, e.g. `{ARG1}`, or pattern matches, e.g. `{MATCH_1}`.
+  Named,
+}
+
+impl PlaceholderMode {
+  /// Detects the placeholders `template` uses. When it uses several kinds, the one that takes
+  /// precedence when it's filled in wins: `{ALL}`, then `{ARGS}`, then individual arguments.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::rules::PlaceholderMode;
+  ///
+  /// assert_eq!(PlaceholderMode::detect("https://www.google.com/search?q={ALL}"), PlaceholderMode::All);
+  /// assert_eq!(PlaceholderMode::detect("https://npmjs.com/search?q={ARGS}"), PlaceholderMode::Args);
+  /// ```
+  pub fn detect(template: &str) -> Self {
+    if template.contains("{ALL}") {
+      PlaceholderMode::All
+    } else if template.contains("{ARGS") {
+      PlaceholderMode::Args
+    } else if template.contains("{ARG") || template.contains("{MATCH_") {
+      PlaceholderMode::Named
+    } else {
+      PlaceholderMode::None
+    }
+  }
+}
+
+/// What a rule does, as returned by [`Rule::describe`].
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::config::ConfigRule;
+/// use ezproxy::rules::{PlaceholderMode, Rule, RuleDescription};
+///
+/// assert_eq!(
+///   ConfigRule::new("m", "https://gmail.com/").describe(),
+///   RuleDescription {
+///     keyword: Some("m".to_string()),
+///     template: Some("https://gmail.com/".to_string()),
+///     placeholder_mode: PlaceholderMode::None,
+///     static_redirect: true,
+///   }
+/// );
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RuleDescription {
+  /// The keyword the rule was written with, if it's known.
+  pub keyword: Option<String>,
+  /// The URL template the rule redirects to, if it has one.
+  pub template: Option<String>,
+  /// Which placeholders the template uses.
+  pub placeholder_mode: PlaceholderMode,
+  /// Whether the rule always redirects to the same place, no matter what's typed.
+  pub static_redirect: bool,
+}
+
+impl RuleDescription {
+  /// Describes a rule nothing is known about.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::rules::RuleDescription;
+  ///
+  /// assert_eq!(RuleDescription::unknown().keyword, None);
+  /// assert!(!RuleDescription::unknown().static_redirect);
+  /// ```
+  pub fn unknown() -> Self {
+    RuleDescription::default()
+  }
+
+  /// Describes a rule written as `keyword = template` in a config file.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::rules::{PlaceholderMode, RuleDescription};
+  ///
+  /// let description = RuleDescription::from_template("yt", "https://youtube.com/results?search_query={ARGS}");
+  /// assert_eq!(description.placeholder_mode, PlaceholderMode::Args);
+  /// assert!(!description.static_redirect);
+  /// ```
+  pub fn from_template<K: Into<String>, T: Into<String>>(keyword: K, template: T) -> Self {
+    let template = template.into();
+    RuleDescription {
+      keyword: Some(keyword.into()),
+      placeholder_mode: PlaceholderMode::detect(&template),
+      static_redirect: !template.contains('{'),
+      template: Some(template),
+    }
+  }
+}
+
+/// Why a [`Rule`] couldn't produce a URI.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::config::ConfigRule;
+/// use ezproxy::rules::{Rule, RuleError};
+///
+/// let rule = ConfigRule::new("jira", "https://myco.atlassian.net/browse/{ARG1}");
+/// match rule.produce_uri("jira", &[]) {
+///   Err(RuleError::MissingArgument { index }) => assert_eq!(index, 1),
+///   _ => panic!("Expected a MissingArgument error"),
+/// }
+/// ```
+#[derive(Debug)]
+pub enum RuleError {
+  /// The URI the rule produced isn't valid.
+  UriParseError(InvalidUri),
+  /// The template uses an argument, e.g. `{ARG2}`, that wasn't given and has no fallback, or the
+  /// rule needs at least this many arguments and fewer were given.
+  MissingArgument {
+    /// The one-indexed argument number, so `2` for `{ARG2}`.
+    index: usize,
+  },
+  /// The template can't be filled in, e.g. because it has an `{ARG0}` placeholder.
+  TemplateError(String),
+  /// Something the rule relies on outside of ezproxy, like a script or a web service, failed.
+  ExternalError(Box<dyn Error + Send + Sync>),
+  /// More arguments were given than the rule allows.
+  TooManyArguments {
+    /// The most arguments the rule allows.
+    max: usize,
+    /// The number of arguments given.
+    given: usize,
+  },
+  /// Rules delegating to each other, e.g. with [`ChainedRule`], loop or go more than
+  /// [`MAX_CHAIN_DEPTH`] rules deep.
+  CycleDetected {
+    /// The keywords of the rules delegated through, in order.
+    chain: Vec<String>,
+  },
+}
+
+impl fmt::Display for RuleError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      RuleError::UriParseError(e) => write!(f, "URI Parse error: {}", e),
+      RuleError::MissingArgument { index } => write!(
+        f,
+        "Argument {} (one-indexed) is required, but it wasn't given",
+        index
+      ),
+      RuleError::TooManyArguments { max, given } => write!(
+        f,
+        "At most {} arguments are allowed, but {} were given",
+        max, given
+      ),
+      RuleError::TemplateError(msg) => write!(f, "{}", msg),
+      RuleError::ExternalError(e) => write!(f, "{}", e),
+      RuleError::CycleDetected { chain } => write!(
+        f,
+        "Rules delegate to each other in a loop or more than {} deep: {}",
+        MAX_CHAIN_DEPTH,
+        chain.join(" -> ")
+      ),
+    }
+  }
+}
+
+impl Error for RuleError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      RuleError::UriParseError(e) => Some(e),
+      RuleError::ExternalError(e) => Some(e.as_ref()),
+      _ => None,
+    }
+  }
+}
+
+impl From<InvalidUri> for RuleError {
+  fn from(e: InvalidUri) -> Self {
+    RuleError::UriParseError(e)
+  }
+}
+
+/// Shares a rule between several keywords, e.g. aliases in a config file.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::config::ConfigRule;
+/// use ezproxy::rules::{Rule, RuleRegistry};
+/// use std::sync::Arc;
+///
+/// let google = Arc::new(ConfigRule::new("g", "https://www.google.com/search?q={ARGS}"));
+/// let mut rules = RuleRegistry::new();
+/// rules.insert("g", Box::new(google.clone()));
+/// rules.insert("google", Box::new(google));
+/// assert_eq!(rules["google"].template(), Some("https://www.google.com/search?q={ARGS}"));
+/// ```
+impl<R: Rule + ?Sized> Rule for Arc<R> {
+  fn produce_uri(&self, cmd: &str, args: &[String]) -> Result<Uri, RuleError> {
+    (**self).produce_uri(cmd, args)
+  }
+
+  fn produce_location(&self, cmd: &str, args: &[String]) -> Result<String, RuleError> {
+    (**self).produce_location(cmd, args)
+  }
+
+  fn template(&self) -> Option<&str> {
+    (**self).template()
+  }
+
+  fn status_code(&self) -> u16 {
+    (**self).status_code()
+  }
+
+  fn pattern(&self) -> Option<&Regex> {
+    (**self).pattern()
+  }
+
+  fn describe(&self) -> RuleDescription {
+    (**self).describe()
+  }
+
+  fn as_async(&self) -> Option<&dyn AsyncRule> {
+    (**self).as_async()
+  }
+
+  fn as_chained(&self) -> Option<&ChainedRule> {
+    (**self).as_chained()
+  }
+}
+
+/// A set of rules keyed by keyword, in the order they were inserted, as read from a config file or
+/// evaluated by a [`Redirector`](crate::redirector::Redirector).
+///
+/// Rules with a [`Rule::pattern`] are also kept in insertion order, which is the order they're
+/// tried in for commands without a rule of their own.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::config::ConfigRule;
+/// use ezproxy::rules::RuleRegistry;
+///
+/// let mut rules = RuleRegistry::new();
+/// rules.insert("m", Box::new(ConfigRule::new("m", "https://gmail.com/")));
+/// assert_eq!(rules.len(), 1);
+/// assert_eq!(rules["m"].produce_uri("m", &[]).unwrap(), "https://gmail.com/");
+/// ```
+#[derive(Clone, Default)]
+pub struct RuleRegistry {
+  rules: IndexMap<String, Arc<dyn Rule>>,
+  /// Keywords of the rules with a [`Rule::pattern`], in insertion order.
+  pattern_keys: Vec<String>,
 }
 
-pub static DEFAULT_RULE_KEY: &'static str = "_";
+impl RuleRegistry {
+  /// Creates an empty registry.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::rules::RuleRegistry;
+  ///
+  /// assert!(RuleRegistry::new().is_empty());
+  /// ```
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Returns the rule for `keyword`, if there is one.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ConfigRule;
+  /// use ezproxy::rules::RuleRegistry;
+  ///
+  /// let mut rules = RuleRegistry::new();
+  /// rules.insert("m", Box::new(ConfigRule::new("m", "https://gmail.com/")));
+  /// assert_eq!(rules.get("m").unwrap().template(), Some("https://gmail.com/"));
+  /// assert!(rules.get("c").is_none());
+  /// ```
+  pub fn get(&self, keyword: &str) -> Option<&dyn Rule> {
+    self.rules.get(keyword).map(|rule| rule.as_ref())
+  }
+
+  /// Returns whether there's a rule for `keyword`.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ConfigRule;
+  /// use ezproxy::rules::RuleRegistry;
+  ///
+  /// let mut rules = RuleRegistry::new();
+  /// rules.insert("m", Box::new(ConfigRule::new("m", "https://gmail.com/")));
+  /// assert!(rules.contains_key("m"));
+  /// assert!(!rules.contains_key("c"));
+  /// ```
+  pub fn contains_key(&self, keyword: &str) -> bool {
+    self.rules.contains_key(keyword)
+  }
+
+  /// Adds `rule` for `keyword`. A rule replacing another keeps its place in the iteration order; a
+  /// new one goes last.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ConfigRule;
+  /// use ezproxy::rules::RuleRegistry;
+  ///
+  /// let mut rules = RuleRegistry::new();
+  /// rules.insert("m", Box::new(ConfigRule::new("m", "https://gmail.com/")));
+  /// rules.insert("c", Box::new(ConfigRule::new("c", "https://calendar.google.com/")));
+  /// rules.insert("m", Box::new(ConfigRule::new("m", "https://mail.yahoo.com/")));
+  /// assert_eq!(rules.keywords().collect::<Vec<_>>(), vec!["m", "c"]);
+  /// assert_eq!(rules["m"].template(), Some("https://mail.yahoo.com/"));
+  /// ```
+  pub fn insert<K: Into<String>>(&mut self, keyword: K, rule: Box<dyn Rule>) {
+    self.insert_shared(keyword.into(), Arc::from(rule));
+  }
+
+  /// Like [`RuleRegistry::insert`], for a rule that may also be used elsewhere, e.g. under an
+  /// alias.
+  pub(crate) fn insert_shared(&mut self, keyword: String, rule: Arc<dyn Rule>) {
+    let has_pattern = rule.pattern().is_some();
+    let (index, replaced) = self.rules.insert_full(keyword, rule);
+    if replaced.is_some() {
+      self.update_pattern_keys();
+    } else if has_pattern {
+      let (keyword, _) = self.rules.get_index(index).unwrap();
+      self.pattern_keys.push(keyword.clone());
+    }
+  }
+
+  /// Adds every rule in `other`, replacing the rules of any keywords that are already here.
+  /// Replaced rules keep their place in the order; new ones go at the end, in `other`'s order.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ConfigRule;
+  /// use ezproxy::rules::RuleRegistry;
+  ///
+  /// let mut rules = RuleRegistry::new();
+  /// rules.insert("m", Box::new(ConfigRule::new("m", "https://gmail.com/")));
+  /// rules.insert("c", Box::new(ConfigRule::new("c", "https://calendar.google.com/")));
+  /// let mut overrides = RuleRegistry::new();
+  /// overrides.insert("npm", Box::new(ConfigRule::new("npm", "https://npmjs.com/")));
+  /// overrides.insert("m", Box::new(ConfigRule::new("m", "https://mail.yahoo.com/")));
+  ///
+  /// rules.extend(overrides);
+  /// assert_eq!(rules.keywords().collect::<Vec<_>>(), vec!["m", "c", "npm"]);
+  /// assert_eq!(rules["m"].template(), Some("https://mail.yahoo.com/"));
+  /// ```
+  pub fn extend(&mut self, other: RuleRegistry) {
+    for (keyword, rule) in other.into_shared() {
+      self.insert_shared(keyword, rule);
+    }
+  }
+
+  /// Removes the rule for `keyword`, returning whether there was one. The other rules keep their
+  /// order.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ConfigRule;
+  /// use ezproxy::rules::RuleRegistry;
+  ///
+  /// let mut rules = RuleRegistry::new();
+  /// rules.insert("m", Box::new(ConfigRule::new("m", "https://gmail.com/")));
+  /// assert!(rules.remove("m"));
+  /// assert!(!rules.remove("m"));
+  /// assert!(rules.is_empty());
+  /// ```
+  pub fn remove(&mut self, keyword: &str) -> bool {
+    match self.rules.shift_remove(keyword) {
+      Some(rule) => {
+        if rule.pattern().is_some() {
+          self.pattern_keys.retain(|kw| kw != keyword);
+        }
+        true
+      }
+      None => false,
+    }
+  }
+
+  /// Returns the keywords and their rules, in order.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ConfigRule;
+  /// use ezproxy::rules::RuleRegistry;
+  ///
+  /// let mut rules = RuleRegistry::new();
+  /// rules.insert("m", Box::new(ConfigRule::new("m", "https://gmail.com/")));
+  /// for (keyword, rule) in rules.iter() {
+  ///   assert_eq!(keyword, "m");
+  ///   assert_eq!(rule.template(), Some("https://gmail.com/"));
+  /// }
+  /// ```
+  pub fn iter(&self) -> impl Iterator<Item = (&str, &dyn Rule)> + '_ {
+    self
+      .rules
+      .iter()
+      .map(|(keyword, rule)| (keyword.as_str(), rule.as_ref()))
+  }
+
+  /// Returns the keywords, in order.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ConfigRule;
+  /// use ezproxy::rules::RuleRegistry;
+  ///
+  /// let mut rules = RuleRegistry::new();
+  /// rules.insert("m", Box::new(ConfigRule::new("m", "https://gmail.com/")));
+  /// rules.insert("c", Box::new(ConfigRule::new("c", "https://calendar.google.com/")));
+  /// assert_eq!(rules.keywords().collect::<Vec<_>>(), vec!["m", "c"]);
+  /// ```
+  pub fn keywords(&self) -> impl Iterator<Item = &str> + '_ {
+    self.rules.keys().map(String::as_str)
+  }
+
+  /// Returns the number of rules.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::config::ConfigRule;
+  /// use ezproxy::rules::RuleRegistry;
+  ///
+  /// let mut rules = RuleRegistry::new();
+  /// rules.insert("m", Box::new(ConfigRule::new("m", "https://gmail.com/")));
+  /// assert_eq!(rules.len(), 1);
+  /// ```
+  pub fn len(&self) -> usize {
+    self.rules.len()
+  }
+
+  /// Returns whether there are no rules.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::rules::RuleRegistry;
+  ///
+  /// assert!(RuleRegistry::new().is_empty());
+  /// ```
+  pub fn is_empty(&self) -> bool {
+    self.rules.is_empty()
+  }
+
+  /// Returns the rule for `keyword` along with the keyword as stored, sharing the rule rather than
+  /// borrowing it.
+  pub(crate) fn get_shared(&self, keyword: &str) -> Option<(&str, &Arc<dyn Rule>)> {
+    self
+      .rules
+      .get_key_value(keyword)
+      .map(|(keyword, rule)| (keyword.as_str(), rule))
+  }
+
+  /// Returns the first rule whose [`Rule::pattern`] matches `name`, along with its keyword.
+  pub(crate) fn pattern_rule(&self, name: &str) -> Option<(&str, &Arc<dyn Rule>)> {
+    self
+      .pattern_keys
+      .iter()
+      .map(|keyword| (keyword.as_str(), &self.rules[keyword]))
+      .find(|(_, rule)| rule.pattern().is_some_and(|p| p.is_match(name)))
+  }
+
+  /// Returns the keywords and their shared rules, in order, consuming the registry.
+  pub(crate) fn into_shared(self) -> impl Iterator<Item = (String, Arc<dyn Rule>)> {
+    self.rules.into_iter()
+  }
+
+  fn update_pattern_keys(&mut self) {
+    self.pattern_keys = self
+      .rules
+      .iter()
+      .filter(|(_, rule)| rule.pattern().is_some())
+      .map(|(keyword, _)| keyword.clone())
+      .collect();
+  }
+}
+
+impl fmt::Debug for RuleRegistry {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_map()
+      .entries(
+        self
+          .iter()
+          .map(|(keyword, rule)| (keyword, rule.template())),
+      )
+      .finish()
+  }
+}
+
+impl std::ops::Index<&str> for RuleRegistry {
+  type Output = dyn Rule;
+
+  /// Returns the rule for `keyword`.
+  ///
+  /// # Panics
+  ///
+  /// Panics if there's no rule for `keyword`.
+  fn index(&self, keyword: &str) -> &Self::Output {
+    match self.rules.get(keyword) {
+      Some(rule) => rule.as_ref(),
+      None => panic!("No rule for {}", keyword),
+    }
+  }
+}
+
+impl<K: Into<String>> FromIterator<(K, Box<dyn Rule>)> for RuleRegistry {
+  fn from_iter<I: IntoIterator<Item = (K, Box<dyn Rule>)>>(iter: I) -> Self {
+    let mut rules = RuleRegistry::new();
+    for (keyword, rule) in iter {
+      rules.insert(keyword, rule);
+    }
+    rules
+  }
+}
+
+impl<'a> IntoIterator for &'a RuleRegistry {
+  type Item = (&'a str, &'a dyn Rule);
+  type IntoIter = Box<dyn Iterator<Item = (&'a str, &'a dyn Rule)> + 'a>;
+
+  fn into_iter(self) -> Self::IntoIter {
+    Box::new(self.iter())
+  }
+}
+
+/// Gives up each keyword and its rule, in order.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::config::ConfigRule;
+/// use ezproxy::rules::RuleRegistry;
+///
+/// let mut rules = RuleRegistry::new();
+/// rules.insert("m", Box::new(ConfigRule::new("m", "https://gmail.com/")));
+/// let mut copy = RuleRegistry::new();
+/// for (keyword, rule) in rules {
+///   copy.insert(keyword, rule);
+/// }
+/// assert!(copy.contains_key("m"));
+/// ```
+impl IntoIterator for RuleRegistry {
+  type Item = (String, Box<dyn Rule>);
+  type IntoIter = Box<dyn Iterator<Item = (String, Box<dyn Rule>)>>;
+
+  fn into_iter(self) -> Self::IntoIter {
+    Box::new(
+      self
+        .rules
+        .into_iter()
+        .map(|(keyword, rule)| (keyword, Box::new(rule) as Box<dyn Rule>)),
+    )
+  }
+}
+
+/// The HTTP status code rules redirect with unless they say otherwise: 302 Found.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::rules::DEFAULT_STATUS_CODE;
+///
+/// assert_eq!(DEFAULT_STATUS_CODE, 302);
+/// ```
+pub static DEFAULT_STATUS_CODE: u16 = 302;
+
+/// The HTTP status codes a rule may redirect with: 301, 302, 303, 307 and 308.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::rules::REDIRECT_STATUS_CODES;
+///
+/// assert!(REDIRECT_STATUS_CODES.contains(&308));
+/// assert!(!REDIRECT_STATUS_CODES.contains(&200));
+/// ```
+pub static REDIRECT_STATUS_CODES: [u16; 5] = [301, 302, 303, 307, 308];
+
+/// The keyword of the rule used when no other rule matches a command.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::rules::DEFAULT_RULE_KEY;
+///
+/// // `_ = https://www.google.com/search?q={ALL}` in a config file sets the default rule.
+/// assert_eq!(DEFAULT_RULE_KEY, "_");
+/// ```
+pub static DEFAULT_RULE_KEY: &str = "_";
+
+/// The keyword of the rule used when no other rule matches a bang command like `!gh`.
+///
+/// Without a rule for this keyword, unmatched bang commands are forwarded to DuckDuckGo by
+/// [`BangRule`].
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::rules::BANG_DEFAULT_RULE_KEY;
+///
+/// // `!default = https://duckduckgo.com/?q={ALL}` in a config file sets the bang default rule.
+/// assert_eq!(BANG_DEFAULT_RULE_KEY, "!default");
+/// ```
+pub static BANG_DEFAULT_RULE_KEY: &str = "!default";
+
+/// The keyword of the config file directive listing the rules to fall back to, in order, when no
+/// other rule matches a command, e.g. `_fallback_order = g,ddg,_`; see [`FallbackOrder`].
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::rules::FALLBACK_ORDER_KEY;
+///
+/// assert_eq!(FALLBACK_ORDER_KEY, "_fallback_order");
+/// ```
+pub static FALLBACK_ORDER_KEY: &str = "_fallback_order";
+
+/// The keywords of the rules to fall back to, in order, when no other rule matches a command.
+///
+/// This isn't a shortcut of its own: stored under [`FALLBACK_ORDER_KEY`], it tells a
+/// [`Redirector`](crate::redirector::Redirector) to try each rule in turn, with the command as
+/// typed, until one succeeds, instead of only trying the [`DEFAULT_RULE_KEY`] rule. A keyword
+/// without a rule is skipped, and `_` may be listed to try the default rule.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::rules::{FallbackOrder, Rule};
+///
+/// let order = FallbackOrder::new(["g", "ddg", "_"]);
+/// assert_eq!(order.keywords(), ["g", "ddg", "_"]);
+/// assert_eq!(order.template(), Some("g,ddg,_"));
+/// assert!(order.produce_uri("_fallback_order", &[]).is_err());
+/// ```
+#[derive(Clone, Debug)]
+pub struct FallbackOrder {
+  keywords: Vec<String>,
+  /// The keywords as written in a config file.
+  template: String,
+}
+
+impl FallbackOrder {
+  /// Falls back to the rules for each of `keywords`, in order.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::rules::FallbackOrder;
+  ///
+  /// assert!(FallbackOrder::new(Vec::<String>::new()).keywords().is_empty());
+  /// ```
+  pub fn new<I, S>(keywords: I) -> Self
+  where
+    I: IntoIterator<Item = S>,
+    S: Into<String>,
+  {
+    let keywords: Vec<String> = keywords.into_iter().map(Into::into).collect();
+    Self {
+      template: keywords.join(","),
+      keywords,
+    }
+  }
+
+  /// Returns the keywords of the rules to fall back to, in order.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::rules::FallbackOrder;
+  ///
+  /// assert_eq!(FallbackOrder::new(["ddg", "_"]).keywords(), ["ddg", "_"]);
+  /// ```
+  pub fn keywords(&self) -> &[String] {
+    &self.keywords
+  }
+}
+
+impl Rule for FallbackOrder {
+  fn produce_uri(&self, _cmd: &str, _args: &[String]) -> Result<Uri, RuleError> {
+    Err(RuleError::TemplateError(format!(
+      "{} sets the order of fallback rules; it isn't a shortcut",
+      FALLBACK_ORDER_KEY
+    )))
+  }
+
+  fn template(&self) -> Option<&str> {
+    Some(&self.template)
+  }
+
+  fn describe(&self) -> RuleDescription {
+    RuleDescription {
+      keyword: Some(FALLBACK_ORDER_KEY.to_string()),
+      template: Some(self.template.clone()),
+      placeholder_mode: PlaceholderMode::None,
+      static_redirect: false,
+    }
+  }
+}
+
+/// Forwards a bang command and its arguments to DuckDuckGo, which knows thousands of bangs.
+///
+/// `cmd` should include its leading `!`.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::rules::{BangRule, Rule};
+///
+/// let uri = BangRule.produce_uri("!gh", &["rust".to_string()]).unwrap();
+/// assert_eq!(uri, "https://duckduckgo.com/?q=%21gh%20rust");
+/// ```
+#[derive(Debug, Default)]
+pub struct BangRule;
+
+impl Rule for BangRule {
+  fn produce_uri(&self, cmd: &str, args: &[String]) -> Result<Uri, RuleError> {
+    let query = std::iter::once(cmd)
+      .chain(args.iter().map(String::as_str))
+      .collect::<Vec<_>>()
+      .join(" ");
+    Ok(format!("https://duckduckgo.com/?q={}", urlencoding::encode(&query)).parse()?)
+  }
+}
+
+/// The prefix marking a config file keyword as a [`RegexRule`] pattern, e.g.
+/// `re:^jira-(\d+)$ = https://myco.atlassian.net/browse/JIRA-{MATCH_1}`.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::rules::REGEX_RULE_PREFIX;
+///
+/// assert_eq!(REGEX_RULE_PREFIX, "re:");
+/// ```
+pub static REGEX_RULE_PREFIX: &str = "re:";
+
+/// Applies to every command matching a regular expression, rather than to a single keyword.
+///
+/// The URL template may use `{MATCH_1}`, `{MATCH_2}`, etc. for the pattern's capture groups, which
+/// are URL-encoded when substituted, as well as the placeholders a [`ConfigRule`] supports. A
+/// group that didn't participate in the match is replaced with nothing.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::rules::{RegexRule, Rule};
+///
+/// let rule = RegexRule::new(r"^jira-(\d+)$", "https://myco.atlassian.net/browse/JIRA-{MATCH_1}").unwrap();
+/// let uri = rule.produce_uri("jira-123", &[]).unwrap();
+/// assert_eq!(uri, "https://myco.atlassian.net/browse/JIRA-123");
+/// ```
+#[derive(Debug)]
+pub struct RegexRule {
+  pattern: Regex,
+  template: String,
+}
+
+impl RegexRule {
+  /// Creates a rule redirecting commands matching `pattern` to the URL template `template`.
+  ///
+  /// Returns an error if `pattern` isn't a valid regular expression.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::rules::RegexRule;
+  ///
+  /// assert!(RegexRule::new(r"^jira-(\d+)$", "https://myco.atlassian.net/browse/JIRA-{MATCH_1}").is_ok());
+  /// assert!(RegexRule::new(r"^jira-(\d+$", "https://myco.atlassian.net/").is_err());
+  /// ```
+  pub fn new<U: Into<String>>(pattern: &str, template: U) -> Result<Self, regex::Error> {
+    Ok(Self {
+      pattern: Regex::new(pattern)?,
+      template: template.into().trim().to_string(),
+    })
+  }
+
+  /// Substitutes the capture groups of `cmd` into the template, leaving the remaining
+  /// placeholders for a [`ConfigRule`] to fill in.
+  fn expand_matches(&self, cmd: &str) -> Result<ConfigRule, RuleError> {
+    lazy_static! {
+      static ref MATCH_RE: Regex = Regex::new(r"\{MATCH_(\d+)\}").unwrap();
+    }
+    let captures = self.pattern.captures(cmd).ok_or_else(|| {
+      RuleError::TemplateError(format!(
+        "Command {} does not match pattern {}",
+        cmd, self.pattern
+      ))
+    })?;
+    let template = MATCH_RE.replace_all(&self.template, |m: &regex::Captures| {
+      let group = m[1].parse::<usize>().ok().and_then(|i| captures.get(i));
+      urlencoding::encode(group.map_or("", |g| g.as_str())).into_owned()
+    });
+    Ok(ConfigRule::new(cmd, template))
+  }
+}
+
+impl Rule for RegexRule {
+  fn produce_uri(&self, cmd: &str, args: &[String]) -> Result<Uri, RuleError> {
+    self.expand_matches(cmd)?.produce_uri(cmd, args)
+  }
+
+  fn produce_location(&self, cmd: &str, args: &[String]) -> Result<String, RuleError> {
+    self.expand_matches(cmd)?.produce_location(cmd, args)
+  }
+
+  fn template(&self) -> Option<&str> {
+    Some(&self.template)
+  }
+
+  fn pattern(&self) -> Option<&Regex> {
+    Some(&self.pattern)
+  }
+
+  fn describe(&self) -> RuleDescription {
+    RuleDescription::from_template(
+      format!("{}{}", REGEX_RULE_PREFIX, self.pattern.as_str()),
+      self.template.clone(),
+    )
+  }
+}
+
+/// Takes turns redirecting to each of several URL templates, e.g. to spread requests across mirrors.
+///
+/// Each template supports the placeholders a [`ConfigRule`] does. In a config file, the templates
+/// are separated by ` | `, e.g. `cdn = https://cdn1.example.com/{ARGS} | https://cdn2.example.com/{ARGS}`.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::rules::{LoadBalancerRule, Rule};
+///
+/// let rule = LoadBalancerRule::new(["https://cdn1.example.com/{ARGS}", "https://cdn2.example.com/{ARGS}"]);
+/// let args = ["logo.png".to_string()];
+/// assert_eq!(rule.produce_uri("cdn", &args).unwrap(), "https://cdn1.example.com/logo.png");
+/// assert_eq!(rule.produce_uri("cdn", &args).unwrap(), "https://cdn2.example.com/logo.png");
+/// assert_eq!(rule.produce_uri("cdn", &args).unwrap(), "https://cdn1.example.com/logo.png");
+/// ```
+#[derive(Debug)]
+pub struct LoadBalancerRule {
+  templates: Vec<String>,
+  /// The templates as written in a config file.
+  template: String,
+  next: AtomicUsize,
+  status_code: u16,
+}
+
+impl LoadBalancerRule {
+  /// Creates a rule redirecting to each of `templates` in turn, starting with the first.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::rules::LoadBalancerRule;
+  ///
+  /// let rule = LoadBalancerRule::new(["https://a.example.com/", "https://b.example.com/"]);
+  /// assert_eq!(rule.templates(), ["https://a.example.com/", "https://b.example.com/"]);
+  /// ```
+  pub fn new<I, S>(templates: I) -> Self
+  where
+    I: IntoIterator<Item = S>,
+    S: Into<String>,
+  {
+    let templates: Vec<String> = templates
+      .into_iter()
+      .map(|template| template.into().trim().to_string())
+      .collect();
+    Self {
+      template: templates.join(" | "),
+      templates,
+      next: AtomicUsize::new(0),
+      status_code: DEFAULT_STATUS_CODE,
+    }
+  }
+
+  /// Redirects with `status_code` rather than [`DEFAULT_STATUS_CODE`].
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::rules::{LoadBalancerRule, Rule};
+  ///
+  /// let rule = LoadBalancerRule::new(["https://a.example.com/"]).with_status_code(307);
+  /// assert_eq!(rule.status_code(), 307);
+  /// ```
+  pub fn with_status_code(mut self, status_code: u16) -> Self {
+    self.status_code = status_code;
+    self
+  }
+
+  /// Returns the URL templates taken in turn.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::rules::LoadBalancerRule;
+  ///
+  /// assert!(LoadBalancerRule::new(Vec::<String>::new()).templates().is_empty());
+  /// ```
+  pub fn templates(&self) -> &[String] {
+    &self.templates
+  }
+
+  /// Returns a rule for the template whose turn it is.
+  fn next_target(&self, cmd: &str) -> Result<ConfigRule, RuleError> {
+    let next = self.next.fetch_add(1, Ordering::Relaxed);
+    next
+      .checked_rem(self.templates.len())
+      .map(|i| ConfigRule::new(cmd, self.templates[i].as_str()))
+      .ok_or_else(|| RuleError::TemplateError("No URL templates to choose from".to_string()))
+  }
+}
+
+impl Rule for LoadBalancerRule {
+  fn produce_uri(&self, cmd: &str, args: &[String]) -> Result<Uri, RuleError> {
+    self.next_target(cmd)?.produce_uri(cmd, args)
+  }
+
+  fn produce_location(&self, cmd: &str, args: &[String]) -> Result<String, RuleError> {
+    self.next_target(cmd)?.produce_location(cmd, args)
+  }
+
+  fn template(&self) -> Option<&str> {
+    Some(&self.template)
+  }
+
+  fn status_code(&self) -> u16 {
+    self.status_code
+  }
+
+  fn describe(&self) -> RuleDescription {
+    RuleDescription {
+      keyword: None,
+      ..RuleDescription::from_template("", self.template.clone())
+    }
+  }
+}
+
+/// A time of day range, written `HH:MM-HH:MM` in 24-hour time, e.g. `08:00-09:30`. The start is
+/// included and the end isn't. A range that ends before it starts, e.g. `22:00-06:00`, wraps
+/// around midnight.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::rules::TimeRange;
+///
+/// let range: TimeRange = "08:00-09:30".parse().unwrap();
+/// assert!(range.contains(8, 0));
+/// assert!(range.contains(9, 29));
+/// assert!(!range.contains(9, 30));
+/// assert_eq!(range.to_string(), "08:00-09:30");
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimeRange {
+  /// Minutes since midnight.
+  start: u16,
+  /// Minutes since midnight.
+  end: u16,
+}
+
+impl TimeRange {
+  /// Returns whether `hour:minute` falls within this range.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::rules::TimeRange;
+  ///
+  /// let night: TimeRange = "22:00-06:00".parse().unwrap();
+  /// assert!(night.contains(23, 15));
+  /// assert!(night.contains(5, 59));
+  /// assert!(!night.contains(12, 0));
+  /// ```
+  pub fn contains(&self, hour: u8, minute: u8) -> bool {
+    let time = u16::from(hour) * 60 + u16::from(minute);
+    if self.start <= self.end {
+      self.start <= time && time < self.end
+    } else {
+      self.start <= time || time < self.end
+    }
+  }
+}
+
+impl FromStr for TimeRange {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let parse_time = |time: &str| {
+      let (hour, minute) = time.split_once(':')?;
+      let digits = |field: &str| field.len() == 2 && field.bytes().all(|b| b.is_ascii_digit());
+      if !digits(hour) || !digits(minute) {
+        return None;
+      }
+      match (hour.parse::<u16>().ok()?, minute.parse::<u16>().ok()?) {
+        (hour, minute) if hour < 24 && minute < 60 => Some(hour * 60 + minute),
+        _ => None,
+      }
+    };
+    s.split_once('-')
+      .and_then(|(start, end)| {
+        Some(TimeRange {
+          start: parse_time(start.trim())?,
+          end: parse_time(end.trim())?,
+        })
+      })
+      .ok_or_else(|| format!("Invalid time range {}: expected HH:MM-HH:MM", s))
+  }
+}
+
+impl fmt::Display for TimeRange {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "{:02}:{:02}-{:02}:{:02}",
+      self.start / 60,
+      self.start % 60,
+      self.end / 60,
+      self.end % 60
+    )
+  }
+}
+
+/// Redirects to a different URL template depending on the local time of day, e.g. to a meeting
+/// during standup and to the calendar otherwise.
+///
+/// Each template has a [`TimeRange`], or `None` to match any time, and the first one matching the
+/// current time is used. Each template supports the placeholders a [`ConfigRule`] does. In a
+/// config file, the templates are separated by ` | ` and each is followed by its range in
+/// brackets, or `[*]` for any time, e.g.
+/// `standup = https://meet.google.com/abc [08:00-09:30] | https://calendar.google.com/ [*]`.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::rules::TimedRule;
+///
+/// let rule = TimedRule::new(vec![
+///   (Some("08:00-09:30".parse().unwrap()), "https://meet.google.com/abc".to_string()),
+///   (None, "https://calendar.google.com/".to_string()),
+/// ]);
+/// assert_eq!(rule.template_at(8, 45), Some("https://meet.google.com/abc"));
+/// assert_eq!(rule.template_at(14, 0), Some("https://calendar.google.com/"));
+/// ```
+#[derive(Debug)]
+pub struct TimedRule {
+  entries: Vec<(Option<TimeRange>, String)>,
+  /// The templates and their ranges as written in a config file.
+  template: String,
+  status_code: u16,
+}
+
+impl TimedRule {
+  /// Creates a rule redirecting to the first of `entries` whose range contains the current time.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::rules::{Rule, TimedRule};
+  ///
+  /// let rule = TimedRule::new(vec![(None, "https://calendar.google.com/".to_string())]);
+  /// assert_eq!(rule.template(), Some("https://calendar.google.com/ [*]"));
+  /// ```
+  pub fn new(entries: Vec<(Option<TimeRange>, String)>) -> Self {
+    let template = entries
+      .iter()
+      .map(|(range, template)| match range {
+        Some(range) => format!("{} [{}]", template, range),
+        None => format!("{} [*]", template),
+      })
+      .collect::<Vec<_>>()
+      .join(" | ");
+    Self {
+      entries,
+      template,
+      status_code: DEFAULT_STATUS_CODE,
+    }
+  }
+
+  /// Redirects with `status_code` rather than [`DEFAULT_STATUS_CODE`].
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::rules::{Rule, TimedRule};
+  ///
+  /// let rule = TimedRule::new(vec![]).with_status_code(307);
+  /// assert_eq!(rule.status_code(), 307);
+  /// ```
+  pub fn with_status_code(mut self, status_code: u16) -> Self {
+    self.status_code = status_code;
+    self
+  }
+
+  /// Returns the template used at `hour:minute`, or `None` if no range contains it.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::rules::TimedRule;
+  ///
+  /// let rule = TimedRule::new(vec![(Some("08:00-09:30".parse().unwrap()), "https://meet.google.com/abc".to_string())]);
+  /// assert_eq!(rule.template_at(10, 0), None);
+  /// ```
+  pub fn template_at(&self, hour: u8, minute: u8) -> Option<&str> {
+    self
+      .entries
+      .iter()
+      .find(|(range, _)| range.is_none_or(|range| range.contains(hour, minute)))
+      .map(|(_, template)| template.as_str())
+  }
+
+  /// Returns a rule for the template matching the current local time.
+  fn current_target(&self, cmd: &str) -> Result<ConfigRule, RuleError> {
+    let (hour, minute) = local_time_of_day();
+    self
+      .template_at(hour, minute)
+      .map(|template| ConfigRule::new(cmd, template))
+      .ok_or_else(|| {
+        RuleError::TemplateError(format!("No URL template for {:02}:{:02}", hour, minute))
+      })
+  }
+}
+
+impl Rule for TimedRule {
+  fn produce_uri(&self, cmd: &str, args: &[String]) -> Result<Uri, RuleError> {
+    self.current_target(cmd)?.produce_uri(cmd, args)
+  }
+
+  fn produce_location(&self, cmd: &str, args: &[String]) -> Result<String, RuleError> {
+    self.current_target(cmd)?.produce_location(cmd, args)
+  }
+
+  fn template(&self) -> Option<&str> {
+    Some(&self.template)
+  }
+
+  fn status_code(&self) -> u16 {
+    self.status_code
+  }
+
+  fn describe(&self) -> RuleDescription {
+    RuleDescription {
+      keyword: None,
+      ..RuleDescription::from_template("", self.template.clone())
+    }
+  }
+}
+
+/// Returns the current local hour and minute, or the UTC ones if the local time zone is unknown.
+fn local_time_of_day() -> (u8, u8) {
+  #[cfg(unix)]
+  {
+    // SAFETY: localtime_r only writes to the tm we pass it.
+    let local = unsafe {
+      let now = libc::time(std::ptr::null_mut());
+      let mut tm: libc::tm = std::mem::zeroed();
+      (!libc::localtime_r(&now, &mut tm).is_null()).then_some(tm)
+    };
+    if let Some(tm) = local {
+      return (tm.tm_hour as u8, tm.tm_min as u8);
+    }
+  }
+  let secs = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .map_or(0, |since| since.as_secs());
+  (((secs / 3600) % 24) as u8, ((secs / 60) % 60) as u8)
+}
+
+/// The prefix marking a config file URL as a [`ScriptRule`], e.g.
+/// `g = script:/usr/local/bin/route.sh`.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::rules::SCRIPT_RULE_PREFIX;
+///
+/// assert_eq!(SCRIPT_RULE_PREFIX, "script:");
+/// ```
+pub static SCRIPT_RULE_PREFIX: &str = "script:";
+
+/// How long a [`ScriptRule`] may run before it's killed, unless it says otherwise: 5 seconds.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::rules::DEFAULT_SCRIPT_TIMEOUT;
+/// use std::time::Duration;
+///
+/// assert_eq!(DEFAULT_SCRIPT_TIMEOUT, Duration::from_secs(5));
+/// ```
+pub const DEFAULT_SCRIPT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Runs an external program to decide where to redirect to.
+///
+/// The program is passed the command and then each argument as its own process arguments, and
+/// should print the URI to redirect to. It fails if it exits with a nonzero status, prints
+/// nothing, or doesn't finish within its timeout, in which case it's killed.
+///
+/// # Examples
+///
+/// ```no_run
+/// use ezproxy::rules::{Rule, ScriptRule};
+/// use std::time::Duration;
+///
+/// let rule = ScriptRule::new("/usr/local/bin/route.sh").with_timeout(Duration::from_secs(1));
+/// let uri = rule.produce_uri("g", &["rust".to_string()]).unwrap();
+/// ```
+#[derive(Debug)]
+pub struct ScriptRule {
+  command: String,
+  timeout: Duration,
+  status_code: u16,
+}
+
+impl ScriptRule {
+  /// Creates a rule running the program at `command`, with the [`DEFAULT_SCRIPT_TIMEOUT`].
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::rules::ScriptRule;
+  ///
+  /// assert_eq!(ScriptRule::new("/usr/local/bin/route.sh").command(), "/usr/local/bin/route.sh");
+  /// ```
+  pub fn new<C: Into<String>>(command: C) -> Self {
+    Self {
+      command: command.into(),
+      timeout: DEFAULT_SCRIPT_TIMEOUT,
+      status_code: DEFAULT_STATUS_CODE,
+    }
+  }
+
+  /// Kills the program and fails if it runs for longer than `timeout`.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::rules::ScriptRule;
+  /// use std::time::Duration;
+  ///
+  /// let rule = ScriptRule::new("/usr/local/bin/route.sh").with_timeout(Duration::from_secs(1));
+  /// assert_eq!(rule.timeout(), Duration::from_secs(1));
+  /// ```
+  pub fn with_timeout(mut self, timeout: Duration) -> Self {
+    self.timeout = timeout;
+    self
+  }
+
+  /// Redirects with `status_code` rather than [`DEFAULT_STATUS_CODE`].
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::rules::{Rule, ScriptRule};
+  ///
+  /// assert_eq!(ScriptRule::new("/usr/local/bin/route.sh").with_status_code(301).status_code(), 301);
+  /// ```
+  pub fn with_status_code(mut self, status_code: u16) -> Self {
+    self.status_code = status_code;
+    self
+  }
+
+  /// Returns the path of the program to run.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::rules::ScriptRule;
+  ///
+  /// assert_eq!(ScriptRule::new("route.sh").command(), "route.sh");
+  /// ```
+  pub fn command(&self) -> &str {
+    &self.command
+  }
+
+  /// Returns how long the program may run before it's killed.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::rules::{ScriptRule, DEFAULT_SCRIPT_TIMEOUT};
+  ///
+  /// assert_eq!(ScriptRule::new("route.sh").timeout(), DEFAULT_SCRIPT_TIMEOUT);
+  /// ```
+  pub fn timeout(&self) -> Duration {
+    self.timeout
+  }
+
+  /// Turns the program's exit status and output into the location it printed. The location is
+  /// checked to be a valid URI, but is returned as printed so that it keeps any fragment.
+  fn location_from_output(&self, status: ExitStatus, stdout: &[u8]) -> Result<String, RuleError> {
+    if !status.success() {
+      return Err(RuleError::ExternalError(
+        format!("Script {} failed: {}", self.command, status).into(),
+      ));
+    }
+    let location = String::from_utf8_lossy(stdout);
+    let location = location.trim();
+    if location.is_empty() {
+      return Err(RuleError::ExternalError(
+        format!("Script {} printed no URI", self.command).into(),
+      ));
+    }
+    location.parse::<Uri>()?;
+    Ok(location.to_string())
+  }
+
+  fn timed_out(&self) -> RuleError {
+    RuleError::ExternalError(
+      format!("Script {} timed out after {:?}", self.command, self.timeout).into(),
+    )
+  }
+
+  fn spawn_failed(&self, e: std::io::Error) -> RuleError {
+    RuleError::ExternalError(format!("Could not run script {}: {}", self.command, e).into())
+  }
+
+  /// Runs the program, blocking until it exits or times out.
+  fn run(&self, cmd: &str, args: &[String]) -> Result<String, RuleError> {
+    let mut child = std::process::Command::new(&self.command)
+      .arg(cmd)
+      .args(args)
+      .stdin(Stdio::null())
+      .stdout(Stdio::piped())
+      .spawn()
+      .map_err(|e| self.spawn_failed(e))?;
+    // Read the output on another thread, so that a chatty program can't fill the pipe and
+    // block before it exits.
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let reader = std::thread::spawn(move || {
+      let mut output = Vec::new();
+      stdout.read_to_end(&mut output).map(|_| output)
+    });
+    let deadline = Instant::now() + self.timeout;
+    let status = loop {
+      match child.try_wait().map_err(|e| self.spawn_failed(e))? {
+        Some(status) => break status,
+        None if Instant::now() >= deadline => {
+          let _ = child.kill();
+          let _ = child.wait();
+          return Err(self.timed_out());
+        }
+        None => std::thread::sleep(Duration::from_millis(5)),
+      }
+    };
+    let stdout = reader
+      .join()
+      .expect("reading the script's output doesn't panic")
+      .map_err(|e| self.spawn_failed(e))?;
+    self.location_from_output(status, &stdout)
+  }
+
+  /// Runs the program without blocking the async runtime.
+  async fn run_async(&self, cmd: &str, args: &[String]) -> Result<String, RuleError> {
+    let child = tokio::process::Command::new(&self.command)
+      .arg(cmd)
+      .args(args)
+      .stdin(Stdio::null())
+      .stdout(Stdio::piped())
+      .kill_on_drop(true)
+      .spawn()
+      .map_err(|e| self.spawn_failed(e))?;
+    // Dropping the child when the timeout expires kills it.
+    let output = tokio::time::timeout(self.timeout, child.wait_with_output())
+      .await
+      .map_err(|_| self.timed_out())?
+      .map_err(|e| self.spawn_failed(e))?;
+    self.location_from_output(output.status, &output.stdout)
+  }
+}
+
+impl Rule for ScriptRule {
+  fn produce_uri(&self, cmd: &str, args: &[String]) -> Result<Uri, RuleError> {
+    Ok(self.run(cmd, args)?.parse()?)
+  }
+
+  fn produce_location(&self, cmd: &str, args: &[String]) -> Result<String, RuleError> {
+    self.run(cmd, args)
+  }
+
+  fn status_code(&self) -> u16 {
+    self.status_code
+  }
+
+  fn describe(&self) -> RuleDescription {
+    let mut template = format!("{}{}", SCRIPT_RULE_PREFIX, self.command);
+    // Written out like this, the rule reads back with the same timeout.
+    if self.timeout != DEFAULT_SCRIPT_TIMEOUT {
+      template.push_str(&format!(" {}", self.timeout.as_secs_f64()));
+    }
+    RuleDescription {
+      keyword: None,
+      template: Some(template),
+      placeholder_mode: PlaceholderMode::All,
+      static_redirect: false,
+    }
+  }
+
+  fn as_async(&self) -> Option<&dyn AsyncRule> {
+    Some(self)
+  }
+}
+
+#[async_trait]
+impl AsyncRule for ScriptRule {
+  async fn produce_uri(&self, cmd: &str, args: &[String]) -> Result<Uri, RuleError> {
+    Ok(self.run_async(cmd, args).await?.parse()?)
+  }
+
+  async fn produce_location(&self, cmd: &str, args: &[String]) -> Result<String, RuleError> {
+    self.run_async(cmd, args).await
+  }
+}
 
 // #[derive(Default)]
 // pub struct YouTubeRule;
 // impl Rule for YouTubeRule {
-//   fn produce_uri(&self, _cmd: &str, args: &Vec<String>) -> Result<Uri, String> {
+//   fn produce_uri(&self, _cmd: &str, args: &[String]) -> Result<Uri, RuleError> {
 //     let builder = Uri::builder().scheme("https").authority("youtube.com");
 
 //     let res = match args[..] {
@@ -22,6 +1752,190 @@ pub static DEFAULT_RULE_KEY: &'static str = "_";
 //       }
 //     };
 
-//     res.map_err(|e| format!("Error producing URI: {}", e))
+//     res.map_err(|e| RuleError::TemplateError(e.to_string()))
 //   }
 // }
+
+/// The most rules a command may be delegated through by [`ChainedRule`]s before evaluation gives
+/// up with [`RuleError::CycleDetected`].
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::rules::MAX_CHAIN_DEPTH;
+///
+/// assert_eq!(MAX_CHAIN_DEPTH, 5);
+/// ```
+pub const MAX_CHAIN_DEPTH: usize = 5;
+
+/// Delegates to the rule for another keyword, passing it a command of its own, e.g. to make a
+/// shorthand for a longer command.
+///
+/// In a config file, the command and the keyword of the rule to delegate to are separated by
+/// ` -> `: `issues = gh issues {ARGS} -> gh` evaluates `issues rust` as the `gh` rule would
+/// `gh issues rust`. `{ARGS}` stands for the arguments typed after the keyword; without it, they're
+/// dropped.
+///
+/// Rules can't see each other, so a chained rule is followed by the
+/// [`Redirector`](crate::redirector::Redirector) evaluating it; evaluated on its own, it fails.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::rules::{ChainedRule, Rule};
+///
+/// let rule = ChainedRule::new("gh", "gh", ["issues", "{ARGS}"]);
+/// assert_eq!(rule.template(), Some("gh issues {ARGS} -> gh"));
+/// assert_eq!(rule.args_for(&["rust".to_string()]), ["issues", "rust"]);
+/// assert!(rule.produce_uri("issues", &[]).is_err());
+/// ```
+#[derive(Clone, Debug)]
+pub struct ChainedRule {
+  /// The keyword of the rule delegated to.
+  target: String,
+  /// The name of the command passed to the target rule.
+  command: String,
+  /// The arguments passed to the target rule, where `{ARGS}` stands for the original ones.
+  args: Vec<String>,
+  /// The rule as written in a config file.
+  template: String,
+  /// The status to redirect with instead of the target rule's.
+  status_code: Option<u16>,
+}
+
+impl ChainedRule {
+  /// Creates a rule that evaluates the command `command` with `args` by the rule for `target`.
+  /// An argument of `{ARGS}` is replaced by the arguments typed after the keyword.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::rules::ChainedRule;
+  ///
+  /// let rule = ChainedRule::new("gh", "gh", ["issues", "{ARGS}"]);
+  /// assert_eq!(rule.target(), "gh");
+  /// assert_eq!(rule.command(), "gh");
+  /// ```
+  pub fn new<T, C, I, S>(target: T, command: C, args: I) -> Self
+  where
+    T: Into<String>,
+    C: Into<String>,
+    I: IntoIterator<Item = S>,
+    S: Into<String>,
+  {
+    let target = target.into();
+    let command = command.into();
+    let args: Vec<String> = args.into_iter().map(Into::into).collect();
+    let template = std::iter::once(command.as_str())
+      .chain(args.iter().map(String::as_str))
+      .chain(["->", target.as_str()])
+      .collect::<Vec<_>>()
+      .join(" ");
+    Self {
+      target,
+      command,
+      args,
+      template,
+      status_code: None,
+    }
+  }
+
+  /// Redirects with `status_code` rather than the status of the rule delegated to.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::rules::{ChainedRule, Rule};
+  ///
+  /// let rule = ChainedRule::new("gh", "gh", ["{ARGS}"]).with_status_code(301);
+  /// assert_eq!(rule.status_code(), 301);
+  /// ```
+  pub fn with_status_code(mut self, status_code: u16) -> Self {
+    self.status_code = Some(status_code);
+    self
+  }
+
+  /// Returns the keyword of the rule delegated to.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::rules::ChainedRule;
+  ///
+  /// assert_eq!(ChainedRule::new("gh", "gh", ["{ARGS}"]).target(), "gh");
+  /// ```
+  pub fn target(&self) -> &str {
+    &self.target
+  }
+
+  /// Returns the name of the command passed to the rule delegated to.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::rules::ChainedRule;
+  ///
+  /// assert_eq!(ChainedRule::new("gh", "github", ["{ARGS}"]).command(), "github");
+  /// ```
+  pub fn command(&self) -> &str {
+    &self.command
+  }
+
+  /// Returns the arguments to pass to the rule delegated to when this rule is invoked with
+  /// `args`.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::rules::ChainedRule;
+  ///
+  /// let rule = ChainedRule::new("gh", "gh", ["issues", "{ARGS}", "--open"]);
+  /// let args = ["rust".to_string(), "async".to_string()];
+  /// assert_eq!(rule.args_for(&args), ["issues", "rust", "async", "--open"]);
+  /// ```
+  pub fn args_for(&self, args: &[String]) -> Vec<String> {
+    let mut chained = Vec::with_capacity(self.args.len() + args.len());
+    for arg in &self.args {
+      if arg == "{ARGS}" {
+        chained.extend_from_slice(args);
+      } else {
+        chained.push(arg.clone());
+      }
+    }
+    chained
+  }
+
+  /// Returns the status this rule redirects with instead of the status of the rule delegated to,
+  /// if it has one.
+  pub(crate) fn status_override(&self) -> Option<u16> {
+    self.status_code
+  }
+}
+
+impl Rule for ChainedRule {
+  fn produce_uri(&self, _cmd: &str, _args: &[String]) -> Result<Uri, RuleError> {
+    Err(RuleError::TemplateError(format!(
+      "Rule delegates to {}, so it can only be evaluated by a Redirector",
+      self.target
+    )))
+  }
+
+  fn template(&self) -> Option<&str> {
+    Some(&self.template)
+  }
+
+  fn status_code(&self) -> u16 {
+    self.status_code.unwrap_or(DEFAULT_STATUS_CODE)
+  }
+
+  fn describe(&self) -> RuleDescription {
+    RuleDescription {
+      keyword: None,
+      ..RuleDescription::from_template("", self.template.clone())
+    }
+  }
+
+  fn as_chained(&self) -> Option<&ChainedRule> {
+    Some(self)
+  }
+}
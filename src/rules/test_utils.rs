@@ -0,0 +1,108 @@
+//! Rules for testing code that evaluates rules, such as a [`Redirector`](crate::redirector::Redirector),
+//! without writing an `impl Rule` for each test. Only available with the `test-utils` feature.
+
+use super::{Rule, RuleError};
+use hyper::Uri;
+
+/// What a [`MockRule`] does when it's asked for a URI.
+#[derive(Clone, Debug)]
+enum Behavior {
+  Redirect(Uri),
+  Echo,
+  Error(String),
+}
+
+/// A rule that behaves the same way for every command.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::redirector::Redirector;
+/// use ezproxy::rules::test_utils::MockRule;
+///
+/// let redirector = Redirector::builder()
+///   .with_rule("e", MockRule::echo())
+///   .with_rule("fail", MockRule::error("unavailable"))
+///   .build();
+/// let resolution = redirector.evaluate_query("e", &["a"]).unwrap();
+/// assert_eq!(resolution.location, "https://example.com/echo?cmd=e&arg=a");
+/// assert!(redirector.evaluate_query("fail", &[]).is_err());
+/// ```
+#[derive(Clone, Debug)]
+pub struct MockRule {
+  behavior: Behavior,
+}
+
+impl MockRule {
+  /// Creates a rule redirecting to `uri` no matter the command.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::rules::test_utils::MockRule;
+  /// use ezproxy::rules::Rule;
+  /// use hyper::Uri;
+  ///
+  /// let rule = MockRule::always_redirect(Uri::from_static("https://example.com/"));
+  /// assert_eq!(rule.produce_uri("m", &["a".to_string()]).unwrap(), "https://example.com/");
+  /// ```
+  pub fn always_redirect(uri: Uri) -> Self {
+    MockRule {
+      behavior: Behavior::Redirect(uri),
+    }
+  }
+
+  /// Creates a rule redirecting to `https://example.com/echo`, with the command as the `cmd`
+  /// query parameter and each argument as an `arg` parameter, all URL-encoded.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::rules::test_utils::MockRule;
+  /// use ezproxy::rules::Rule;
+  ///
+  /// let uri = MockRule::echo()
+  ///   .produce_uri("npm", &["file finder".to_string(), "cli".to_string()])
+  ///   .unwrap();
+  /// assert_eq!(uri, "https://example.com/echo?cmd=npm&arg=file%20finder&arg=cli");
+  /// ```
+  pub fn echo() -> Self {
+    MockRule {
+      behavior: Behavior::Echo,
+    }
+  }
+
+  /// Creates a rule that always fails with a [`RuleError::ExternalError`] saying `msg`.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::rules::test_utils::MockRule;
+  /// use ezproxy::rules::Rule;
+  ///
+  /// let err = MockRule::error("unavailable").produce_uri("m", &[]).unwrap_err();
+  /// assert_eq!(err.to_string(), "unavailable");
+  /// ```
+  pub fn error<S: Into<String>>(msg: S) -> Self {
+    MockRule {
+      behavior: Behavior::Error(msg.into()),
+    }
+  }
+}
+
+impl Rule for MockRule {
+  fn produce_uri(&self, cmd: &str, args: &[String]) -> Result<Uri, RuleError> {
+    match &self.behavior {
+      Behavior::Redirect(uri) => Ok(uri.clone()),
+      Behavior::Echo => {
+        let mut uri = format!("https://example.com/echo?cmd={}", urlencoding::encode(cmd));
+        for arg in args {
+          uri.push_str("&arg=");
+          uri.push_str(&urlencoding::encode(arg));
+        }
+        Ok(uri.parse()?)
+      }
+      Behavior::Error(msg) => Err(RuleError::ExternalError(msg.clone().into())),
+    }
+  }
+}
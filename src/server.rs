@@ -0,0 +1,1584 @@
+//! Serving ezproxy over HTTP: [`handle`] answers a request with a redirect, or from one of the
+//! health check, metrics and stats endpoints, as configured by an [`AppContext`].
+//!
+//! The `ezproxy` binary serves [`handle`] on its own, but it can also be mounted in an existing
+//! hyper application.
+//!
+//! Besides the `q` query parameter of a GET, the command may be posted as a form, i.e. a POST with
+//! an `application/x-www-form-urlencoded` body such as `q=npm+file+finder`.
+use crate::auth::BasicAuth;
+use crate::cors::Cors;
+use crate::ip_filter::IpFilter;
+use crate::rate_limit::RateLimiter;
+use crate::redirector::{EvaluateError, Redirector, Resolution};
+use crate::req_timer::get_request_uid;
+use arc_swap::ArcSwap;
+use http::header::{
+  HeaderValue, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_MAX_AGE,
+  ACCESS_CONTROL_REQUEST_METHOD, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, ORIGIN, RETRY_AFTER,
+  VARY, WWW_AUTHENTICATE,
+};
+use http::{Method, Uri};
+use hyper::body::HttpBody;
+use hyper::{Body, Request, Response};
+use prometheus::{
+  Encoder, Histogram, HistogramOpts, IntCounterVec, Opts, Registry, TextEncoder, TEXT_FORMAT,
+};
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc;
+
+#[cfg(feature = "test-utils")]
+#[doc(hidden)]
+pub mod test_utils;
+
+fn uri_from_conn<T>(req: &mut Request<T>) -> Uri {
+  req.uri().to_owned()
+}
+
+static GENERIC_ERROR_MESSAGE: &str = "Internal server error";
+static GENERIC_CLIENT_ERROR_MESSAGE: &str = "Bad request";
+
+/// The largest form body read by default; see [`AppContext::with_max_body_size`].
+pub const DEFAULT_MAX_BODY_SIZE: usize = 8 * 1024;
+
+/// Escapes `text` for use inside an HTML attribute or element.
+fn escape_html(text: &str) -> String {
+  let mut escaped = String::with_capacity(text.len());
+  for c in text.chars() {
+    match c {
+      '&' => escaped.push_str("&amp;"),
+      '<' => escaped.push_str("&lt;"),
+      '>' => escaped.push_str("&gt;"),
+      '"' => escaped.push_str("&quot;"),
+      '\'' => escaped.push_str("&#39;"),
+      _ => escaped.push(c),
+    }
+  }
+  escaped
+}
+
+/// A page pointing at `location`, for clients that don't follow redirects on their own.
+fn redirect_body(location: &str) -> String {
+  let target = escape_html(location);
+  format!(
+    r#"<html><head><meta http-equiv="refresh" content="0;url={0}"></head><body><a href="{0}">Click here</a></body></html>"#,
+    target
+  )
+}
+
+fn somehow_make_response(
+  eval_result: Result<Resolution, EvaluateError>,
+  hide_errors: bool,
+) -> http::Result<Response<Body>> {
+  let builder = Response::builder().header("X-EZ-Made-This", "true");
+
+  match eval_result {
+    Ok(resolution) => {
+      let body = redirect_body(&resolution.location);
+      builder
+        .status(resolution.status)
+        .header("Location", resolution.location)
+        .header(CONTENT_TYPE, "text/html; charset=utf-8")
+        .body(Body::from(body))
+    }
+    Err(e) => {
+      let status = e.status_code();
+      let message = match (hide_errors, status) {
+        (false, _) => e.to_string(),
+        (true, 400) => GENERIC_CLIENT_ERROR_MESSAGE.to_string(),
+        (true, _) => GENERIC_ERROR_MESSAGE.to_string(),
+      };
+      builder.status(status).body(Body::from(message))
+    }
+  }
+}
+
+/// The address of the client that sent a request. Servers put it in each request's extensions
+/// before handing the request to [`handle`]; without it, the client's address is unknown.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::server::RemoteAddr;
+/// use hyper::{Body, Request};
+/// use std::net::SocketAddr;
+///
+/// let mut req = Request::get("/?q=m").body(Body::empty()).unwrap();
+/// req
+///   .extensions_mut()
+///   .insert(RemoteAddr(SocketAddr::from(([127, 0, 0, 1], 54321))));
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RemoteAddr(pub SocketAddr);
+
+/// Returns a `403 Forbidden` response if the client that sent `req` isn't allowed by the IP
+/// filter, or `None` if the request should be answered.
+fn ip_filter(
+  context: &AppContext,
+  req: &Request<Body>,
+  request_id: &str,
+) -> Option<http::Result<Response<Body>>> {
+  if context.ip_filter.is_empty() {
+    return None;
+  }
+  let ip = context.client_ip(req);
+  if context.ip_filter.is_allowed(ip) {
+    return None;
+  }
+  tracing::warn!(
+      target: "ezproxy::handle",
+      request_id = %request_id,
+      client = ?ip,
+      "Refused request for {} from a client that isn't allowed",
+      req.uri()
+  );
+  context
+    .metrics
+    .observe_request(NO_RULE_LABEL, 403, Duration::ZERO);
+  Some(
+    Response::builder()
+      .header("X-EZ-Made-This", "true")
+      .status(403)
+      .body(Body::from("Forbidden")),
+  )
+}
+
+/// Returns a `401 Unauthorized` response if `req` doesn't log in as required, or `None` if the
+/// request should be answered.
+fn authenticate(
+  context: &AppContext,
+  req: &Request<Body>,
+  request_id: &str,
+) -> Option<http::Result<Response<Body>>> {
+  let auth = context.auth.as_ref()?;
+  let path = req.uri().path();
+  if context.public_health && (path == &*context.health_path || path == &*context.metrics_path) {
+    return None;
+  }
+  if auth.is_authorized(req.headers().get(AUTHORIZATION)) {
+    return None;
+  }
+  // Failed logins count against the rate limit, so it also slows down password guessing.
+  if path != &*context.health_path {
+    if let Some(response) = rate_limit(context, req, request_id) {
+      return Some(response);
+    }
+  }
+  tracing::warn!(
+      target: "ezproxy::handle",
+      request_id = %request_id,
+      "Refused request for {} without valid credentials",
+      req.uri()
+  );
+  context
+    .metrics
+    .observe_request(NO_RULE_LABEL, 401, Duration::ZERO);
+  Some(
+    Response::builder()
+      .header("X-EZ-Made-This", "true")
+      .header(
+        WWW_AUTHENTICATE,
+        format!("Basic realm=\"{}\"", BasicAuth::REALM),
+      )
+      .status(401)
+      .body(Body::from("Unauthorized")),
+  )
+}
+
+/// Returns a `429 Too Many Requests` response if the client that sent `req` is over the rate
+/// limit, or `None` if the request should be answered.
+fn rate_limit(
+  context: &AppContext,
+  req: &Request<Body>,
+  request_id: &str,
+) -> Option<http::Result<Response<Body>>> {
+  let limiter = context.rate_limiter.as_ref()?;
+  let ip = context.client_ip(req)?;
+  let retry_after = limiter.check(ip).err()?;
+  tracing::warn!(
+      target: "ezproxy::handle",
+      request_id = %request_id,
+      client = %ip,
+      "Rate limited request for {}",
+      req.uri()
+  );
+  context
+    .metrics
+    .observe_request(NO_RULE_LABEL, 429, Duration::ZERO);
+  // Retry-After only has whole seconds, so round up to be sure the client waits long enough.
+  let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+  Some(
+    Response::builder()
+      .header("X-EZ-Made-This", "true")
+      .header(RETRY_AFTER, secs.max(1))
+      .status(429)
+      .body(Body::from("Too many requests")),
+  )
+}
+
+/// Whether `req` posts a form, whose body holds the command instead of the query string.
+fn is_form(req: &Request<Body>) -> bool {
+  req.method() == Method::POST
+    && req
+      .headers()
+      .get(CONTENT_TYPE)
+      .and_then(|value| value.to_str().ok())
+      .and_then(|value| value.split(';').next())
+      .is_some_and(|mime| {
+        mime
+          .trim()
+          .eq_ignore_ascii_case("application/x-www-form-urlencoded")
+      })
+}
+
+/// Reads the body of a form posted to ezproxy. Returns `Ok(None)` for every other request, and
+/// an error response if the body is larger than the context allows or can't be read.
+async fn read_form(
+  context: &AppContext,
+  req: &mut Request<Body>,
+  request_id: &str,
+) -> Result<Option<String>, http::Result<Response<Body>>> {
+  if !is_form(req) {
+    return Ok(None);
+  }
+  let uri = req.uri().clone();
+  let reject = |status: u16, message: &'static str| {
+    tracing::warn!(
+        target: "ezproxy::handle",
+        request_id = %request_id,
+        "Rejected form posted to {}: {}",
+        uri,
+        message
+    );
+    context
+      .metrics
+      .observe_request(NO_RULE_LABEL, status, Duration::ZERO);
+    Response::builder()
+      .header("X-EZ-Made-This", "true")
+      .status(status)
+      .body(Body::from(message))
+  };
+
+  let mut body = std::mem::take(req.body_mut());
+  let mut form = Vec::new();
+  while let Some(chunk) = body.data().await {
+    let chunk = match chunk {
+      Ok(chunk) => chunk,
+      Err(_) => return Err(reject(400, "Could not read request body")),
+    };
+    if form.len() + chunk.len() > context.max_body_size {
+      return Err(reject(413, "Request body too large"));
+    }
+    form.extend_from_slice(&chunk);
+  }
+  match String::from_utf8(form) {
+    Ok(form) => Ok(Some(form)),
+    Err(_) => Err(reject(400, "Request body is not UTF-8")),
+  }
+}
+
+/// Everything [`handle`] needs to answer a request: the redirector, and how to serve the
+/// endpoints alongside it. Cloning a context is cheap, and clones share their redirector, metrics
+/// and access log.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::redirector::Redirector;
+/// use ezproxy::server::AppContext;
+///
+/// let context = AppContext::new(Redirector::builder().build())
+///   .with_hide_errors(true)
+///   .with_health_path("/healthz");
+/// assert_eq!(context.redirector().load().rules().len(), 0);
+/// ```
+#[derive(Clone)]
+pub struct AppContext {
+  /// Swapped out wholesale when the binary reloads its config on SIGHUP, so each request sees
+  /// either the old rules or the new ones, never a mix. A reload replaces the fallbacks and
+  /// parser options along with the rules, which clones of a plain `Redirector` can't share.
+  redirector: Arc<ArcSwap<Redirector>>,
+  hide_errors: bool,
+  /// Requests to this path get a health check response instead of a redirect.
+  health_path: Arc<str>,
+  metrics: Arc<Metrics>,
+  /// Requests to this path get the Prometheus metrics instead of a redirect.
+  metrics_path: Arc<str>,
+  /// Requests to this path get per-rule stats instead of a redirect.
+  stats_path: Arc<str>,
+  access_log: Option<AccessLog>,
+  ip_filter: Arc<IpFilter>,
+  auth: Option<Arc<BasicAuth>>,
+  cors: Arc<Cors>,
+  /// Whether the health check and metrics endpoints skip `auth`.
+  public_health: bool,
+  rate_limiter: Option<Arc<RateLimiter>>,
+  /// Whether to take the client's address from `X-Forwarded-For` rather than the connection.
+  trust_proxy: bool,
+  /// The largest form body read, in bytes.
+  max_body_size: usize,
+}
+
+impl AppContext {
+  /// Creates a context redirecting with `redirector`, with health checks on `/health`, metrics
+  /// on `/metrics` and stats on `/stats`, full error messages, and no access log.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::redirector::Redirector;
+  /// use ezproxy::server::AppContext;
+  ///
+  /// let context = AppContext::new(Redirector::builder().build());
+  /// assert!(context.redirector().load().rules().is_empty());
+  /// ```
+  pub fn new(redirector: Redirector) -> Self {
+    Self {
+      redirector: Arc::new(ArcSwap::from_pointee(redirector)),
+      hide_errors: false,
+      health_path: Arc::from("/health"),
+      metrics: Arc::new(Metrics::new()),
+      metrics_path: Arc::from("/metrics"),
+      stats_path: Arc::from("/stats"),
+      access_log: None,
+      ip_filter: Arc::default(),
+      auth: None,
+      cors: Arc::default(),
+      public_health: false,
+      rate_limiter: None,
+      trust_proxy: false,
+      max_body_size: DEFAULT_MAX_BODY_SIZE,
+    }
+  }
+
+  /// Responds to failed requests with a generic error instead of the full error message, which
+  /// can reveal details of the config.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::redirector::Redirector;
+  /// use ezproxy::server::AppContext;
+  ///
+  /// let context = AppContext::new(Redirector::builder().build()).with_hide_errors(true);
+  /// ```
+  pub fn with_hide_errors(mut self, hide_errors: bool) -> Self {
+    self.hide_errors = hide_errors;
+    self
+  }
+
+  /// Answers health checks on `path` instead of `/health`.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::redirector::Redirector;
+  /// use ezproxy::server::AppContext;
+  ///
+  /// let context = AppContext::new(Redirector::builder().build()).with_health_path("/healthz");
+  /// ```
+  pub fn with_health_path(mut self, path: &str) -> Self {
+    self.health_path = Arc::from(path);
+    self
+  }
+
+  /// Serves Prometheus metrics on `path` instead of `/metrics`.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::redirector::Redirector;
+  /// use ezproxy::server::AppContext;
+  ///
+  /// let context = AppContext::new(Redirector::builder().build()).with_metrics_path("/prometheus");
+  /// ```
+  pub fn with_metrics_path(mut self, path: &str) -> Self {
+    self.metrics_path = Arc::from(path);
+    self
+  }
+
+  /// Serves per-rule stats on `path` instead of `/stats`.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::redirector::Redirector;
+  /// use ezproxy::server::AppContext;
+  ///
+  /// let context = AppContext::new(Redirector::builder().build()).with_stats_path("/rule-stats");
+  /// ```
+  pub fn with_stats_path(mut self, path: &str) -> Self {
+    self.stats_path = Arc::from(path);
+    self
+  }
+
+  /// Records each request in `access_log`.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::redirector::Redirector;
+  /// use ezproxy::server::{AccessLog, AppContext};
+  ///
+  /// # tokio::runtime::Runtime::new().unwrap().block_on(async {
+  /// let path = std::env::temp_dir().join("ezproxy-doctest-with-access-log.log");
+  /// let access_log = AccessLog::open(path.clone()).await.unwrap();
+  /// let context = AppContext::new(Redirector::builder().build()).with_access_log(access_log);
+  /// # std::fs::remove_file(&path).unwrap();
+  /// # });
+  /// ```
+  pub fn with_access_log(mut self, access_log: AccessLog) -> Self {
+    self.access_log = Some(access_log);
+    self
+  }
+
+  /// Refuses requests from clients that `ip_filter` doesn't allow with `403 Forbidden`, including
+  /// requests to the health check, metrics and stats endpoints.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::ip_filter::IpFilter;
+  /// use ezproxy::redirector::Redirector;
+  /// use ezproxy::server::AppContext;
+  ///
+  /// let context = AppContext::new(Redirector::builder().build())
+  ///   .with_ip_filter(IpFilter::new().with_allowed("127.0.0.0/8".parse().unwrap()));
+  /// ```
+  pub fn with_ip_filter(mut self, ip_filter: IpFilter) -> Self {
+    self.ip_filter = Arc::new(ip_filter);
+    self
+  }
+
+  /// Requires every request to log in as `auth`, answering any that don't with `401
+  /// Unauthorized`.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::auth::BasicAuth;
+  /// use ezproxy::redirector::Redirector;
+  /// use ezproxy::server::AppContext;
+  ///
+  /// let context = AppContext::new(Redirector::builder().build())
+  ///   .with_basic_auth(BasicAuth::new("travis", "hunter2"));
+  /// ```
+  pub fn with_basic_auth(mut self, auth: BasicAuth) -> Self {
+    self.auth = Some(Arc::new(auth));
+    self
+  }
+
+  /// Lets requests to the health check and metrics endpoints through without logging in, so
+  /// monitoring doesn't need the password. Stats and redirects still need it.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::auth::BasicAuth;
+  /// use ezproxy::redirector::Redirector;
+  /// use ezproxy::server::AppContext;
+  ///
+  /// let context = AppContext::new(Redirector::builder().build())
+  ///   .with_basic_auth(BasicAuth::new("travis", "hunter2"))
+  ///   .with_public_health(true);
+  /// ```
+  pub fn with_public_health(mut self, public_health: bool) -> Self {
+    self.public_health = public_health;
+    self
+  }
+
+  /// Lets JavaScript on the origins allowed by `cors` call ezproxy, adding CORS headers to
+  /// their responses and answering their preflight requests.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::cors::Cors;
+  /// use ezproxy::redirector::Redirector;
+  /// use ezproxy::server::AppContext;
+  ///
+  /// let context =
+  ///   AppContext::new(Redirector::builder().build()).with_cors(Cors::new(["https://example.com"]));
+  /// ```
+  pub fn with_cors(mut self, cors: Cors) -> Self {
+    self.cors = Arc::new(cors);
+    self
+  }
+
+  /// Limits how many redirects each client can ask for with `rate_limiter`, answering any
+  /// beyond the limit with `429 Too Many Requests`. The health check, metrics and stats endpoints
+  /// aren't limited, and neither are requests whose client address isn't known; see
+  /// [`RemoteAddr`].
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::rate_limit::RateLimiter;
+  /// use ezproxy::redirector::Redirector;
+  /// use ezproxy::server::AppContext;
+  /// use std::time::Duration;
+  ///
+  /// let context = AppContext::new(Redirector::builder().build())
+  ///   .with_rate_limiter(RateLimiter::new(10, Duration::from_secs(60)));
+  /// ```
+  pub fn with_rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+    self.rate_limiter = Some(Arc::new(rate_limiter));
+    self
+  }
+
+  /// Takes the client's address from the last address in the `X-Forwarded-For` header, if
+  /// there is one, instead of from the connection. That's the address the proxy in front of
+  /// ezproxy appended; earlier ones are sent by the client, who can send whatever they like. Only
+  /// trust it behind a proxy that sets the header.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::redirector::Redirector;
+  /// use ezproxy::server::AppContext;
+  ///
+  /// let context = AppContext::new(Redirector::builder().build()).with_trust_proxy(true);
+  /// ```
+  pub fn with_trust_proxy(mut self, trust_proxy: bool) -> Self {
+    self.trust_proxy = trust_proxy;
+    self
+  }
+
+  /// Reads forms posted with a body of up to `max_body_size` bytes, rather than
+  /// [`DEFAULT_MAX_BODY_SIZE`], answering larger ones with `413 Payload Too Large`.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::redirector::Redirector;
+  /// use ezproxy::server::AppContext;
+  ///
+  /// let context = AppContext::new(Redirector::builder().build()).with_max_body_size(1024);
+  /// ```
+  pub fn with_max_body_size(mut self, max_body_size: usize) -> Self {
+    self.max_body_size = max_body_size;
+    self
+  }
+
+  /// Returns the client address of `req`, if it's known.
+  fn client_ip<T>(&self, req: &Request<T>) -> Option<IpAddr> {
+    let forwarded = self
+      .trust_proxy
+      .then(|| req.headers().get("X-Forwarded-For"))
+      .flatten()
+      .and_then(|value| value.to_str().ok())
+      // Proxies append the address they saw, so only the last entry can be trusted.
+      .and_then(|value| value.rsplit(',').next())
+      .and_then(|ip| ip.trim().parse().ok());
+    forwarded
+      .or_else(|| req.extensions().get::<RemoteAddr>().map(|addr| addr.0.ip()))
+      // A server listening on both IPv4 and IPv6 sees IPv4 clients as IPv4-mapped addresses.
+      .map(|ip| ip.to_canonical())
+  }
+
+  /// Returns the redirector requests are evaluated with. Store a new one in it to change the
+  /// rules of every clone of this context, e.g. when the config is reloaded.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::redirector::Redirector;
+  /// use ezproxy::server::AppContext;
+  /// use std::sync::Arc;
+  ///
+  /// let context = AppContext::new(Redirector::builder().build());
+  /// context.redirector().store(Arc::new(Redirector::builder().build()));
+  /// ```
+  pub fn redirector(&self) -> &Arc<ArcSwap<Redirector>> {
+    &self.redirector
+  }
+
+  /// Returns the metrics served on the metrics path.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::redirector::Redirector;
+  /// use ezproxy::server::AppContext;
+  ///
+  /// let context = AppContext::new(Redirector::builder().build());
+  /// context.metrics().observe_reload(true);
+  /// ```
+  pub fn metrics(&self) -> &Arc<Metrics> {
+    &self.metrics
+  }
+}
+
+enum AccessLogMessage {
+  Entry(String),
+  /// Close the file and open it again, e.g. after it's been rotated.
+  Reopen,
+}
+
+/// Appends a line to the access log file for each request, in the format
+/// `<timestamp> <method> <uri> <status> <location> <duration_ms>`. Lines are written by a
+/// background task, so that requests never wait on the disk.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::server::AccessLog;
+///
+/// # tokio::runtime::Runtime::new().unwrap().block_on(async {
+/// let path = std::env::temp_dir().join("ezproxy-doctest-access-log.log");
+/// let access_log = AccessLog::open(path.clone()).await.unwrap();
+/// access_log.reopen_file();
+/// # std::fs::remove_file(&path).unwrap();
+/// # });
+/// ```
+#[derive(Clone)]
+pub struct AccessLog {
+  sender: mpsc::UnboundedSender<AccessLogMessage>,
+}
+
+impl AccessLog {
+  /// Opens the file at `path` for appending, creating it if needed, and starts the task that
+  /// writes to it. Must be called from within a tokio runtime.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the file can't be opened.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::server::AccessLog;
+  ///
+  /// # tokio::runtime::Runtime::new().unwrap().block_on(async {
+  /// let path = std::env::temp_dir().join("ezproxy-doctest-access-log-open.log");
+  /// assert!(AccessLog::open(path.clone()).await.is_ok());
+  /// # std::fs::remove_file(&path).unwrap();
+  /// # });
+  /// ```
+  pub async fn open(path: PathBuf) -> io::Result<Self> {
+    let mut writer = BufWriter::new(Self::open_file(&path).await?);
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+      while let Some(message) = receiver.recv().await {
+        let mut message = Some(message);
+        // Write everything that's queued up before flushing.
+        while let Some(next) = message {
+          let result = match next {
+            AccessLogMessage::Entry(line) => writer.write_all(line.as_bytes()).await,
+            AccessLogMessage::Reopen => Self::reopen(&mut writer, &path).await,
+          };
+          if let Err(e) = result {
+            log::warn!(target: "ezproxy::access_log", "Could not write to {}: {}", path.display(), e);
+          }
+          message = receiver.try_recv().ok();
+        }
+        if let Err(e) = writer.flush().await {
+          log::warn!(target: "ezproxy::access_log", "Could not write to {}: {}", path.display(), e);
+        }
+      }
+    });
+    Ok(AccessLog { sender })
+  }
+
+  async fn open_file(path: &Path) -> io::Result<tokio::fs::File> {
+    tokio::fs::OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(path)
+      .await
+  }
+
+  /// Flushes `writer` and points it at a newly opened file at `path`. If the file can't be
+  /// opened, the old one is kept.
+  async fn reopen(writer: &mut BufWriter<tokio::fs::File>, path: &Path) -> io::Result<()> {
+    writer.flush().await?;
+    *writer = BufWriter::new(Self::open_file(path).await?);
+    log::info!(target: "ezproxy::access_log", "Reopened {}", path.display());
+    Ok(())
+  }
+
+  /// Queues a line for the response `resp` to `req`, which took `elapsed` to handle.
+  fn record<T>(&self, req: &Request<T>, resp: &Response<Body>, elapsed: Duration) {
+    let timestamp = OffsetDateTime::now_utc()
+      .format(&Rfc3339)
+      .unwrap_or_else(|_| "-".to_string());
+    let location = resp
+      .headers()
+      .get("Location")
+      .and_then(|location| location.to_str().ok())
+      .unwrap_or("-");
+    let line = format!(
+      "{} {} {} {} {} {:.3}\n",
+      timestamp,
+      req.method(),
+      req.uri(),
+      resp.status().as_u16(),
+      location,
+      elapsed.as_secs_f64() * 1000.0
+    );
+    // The writer task only stops when every sender is gone.
+    let _ = self.sender.send(AccessLogMessage::Entry(line));
+  }
+
+  /// Asks the writer task to reopen the file, e.g. after it's been rotated.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::server::AccessLog;
+  ///
+  /// # tokio::runtime::Runtime::new().unwrap().block_on(async {
+  /// let path = std::env::temp_dir().join("ezproxy-doctest-access-log-reopen.log");
+  /// let access_log = AccessLog::open(path.clone()).await.unwrap();
+  /// access_log.reopen_file();
+  /// # std::fs::remove_file(&path).unwrap();
+  /// # });
+  /// ```
+  pub fn reopen_file(&self) {
+    let _ = self.sender.send(AccessLogMessage::Reopen);
+  }
+}
+
+/// Prometheus metrics about the requests ezproxy has served.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::server::Metrics;
+///
+/// let metrics = Metrics::new();
+/// metrics.observe_reload(false);
+/// ```
+pub struct Metrics {
+  registry: Registry,
+  /// Redirect requests, labelled by the keyword of the rule that handled them and the response
+  /// status.
+  requests: IntCounterVec,
+  /// How long redirect requests took to handle.
+  request_duration: Histogram,
+  /// Config reloads, labelled by whether they succeeded.
+  config_reloads: IntCounterVec,
+}
+
+/// The `rule` label of requests no rule handled.
+static NO_RULE_LABEL: &str = "none";
+
+impl Default for Metrics {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Metrics {
+  /// Creates metrics with every count at zero.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::server::Metrics;
+  ///
+  /// let metrics = Metrics::new();
+  /// ```
+  pub fn new() -> Self {
+    let requests = IntCounterVec::new(
+      Opts::new("ezproxy_requests_total", "Redirect requests handled"),
+      &["rule", "status"],
+    )
+    .unwrap();
+    let request_duration = Histogram::with_opts(HistogramOpts::new(
+      "ezproxy_request_duration_seconds",
+      "Time taken to handle redirect requests",
+    ))
+    .unwrap();
+    let config_reloads = IntCounterVec::new(
+      Opts::new("ezproxy_config_reloads_total", "Config reloads"),
+      &["result"],
+    )
+    .unwrap();
+
+    let registry = Registry::new();
+    registry.register(Box::new(requests.clone())).unwrap();
+    registry
+      .register(Box::new(request_duration.clone()))
+      .unwrap();
+    registry.register(Box::new(config_reloads.clone())).unwrap();
+    Self {
+      registry,
+      requests,
+      request_duration,
+      config_reloads,
+    }
+  }
+
+  fn observe_request(&self, rule: &str, status: u16, duration: Duration) {
+    self
+      .requests
+      .with_label_values(&[rule, &status.to_string()])
+      .inc();
+    self.request_duration.observe(duration.as_secs_f64());
+  }
+
+  /// Counts a config reload, labelled by whether it `succeeded`.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use ezproxy::server::Metrics;
+  ///
+  /// Metrics::new().observe_reload(true);
+  /// ```
+  pub fn observe_reload(&self, succeeded: bool) {
+    let result = if succeeded { "success" } else { "error" };
+    self.config_reloads.with_label_values(&[result]).inc();
+  }
+
+  /// Returns every metric in the Prometheus text format.
+  fn render(&self) -> String {
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+      .encode(&self.registry.gather(), &mut buffer)
+      .unwrap();
+    String::from_utf8(buffer).unwrap()
+  }
+}
+
+/// Answers Prometheus scrapes sent to the metrics path. Returns `None` for every other request.
+fn metrics_endpoint(
+  context: &AppContext,
+  req: &Request<Body>,
+) -> Option<http::Result<Response<Body>>> {
+  if req.uri().path() != &*context.metrics_path {
+    return None;
+  }
+  Some(
+    Response::builder()
+      .header("X-EZ-Made-This", "true")
+      .header(CONTENT_TYPE, TEXT_FORMAT)
+      .status(200)
+      .body(Body::from(context.metrics.render())),
+  )
+}
+
+/// Answers requests to the stats path with how often each rule has been used, as JSON. Returns
+/// `None` for every other request.
+fn stats_endpoint(
+  context: &AppContext,
+  req: &Request<Body>,
+) -> Option<http::Result<Response<Body>>> {
+  if req.uri().path() != &*context.stats_path {
+    return None;
+  }
+  let stats: BTreeMap<String, serde_json::Value> = context
+    .redirector
+    .load()
+    .stats()
+    .into_iter()
+    .map(|(kw, stat)| {
+      let avg_micros = stat.avg_micros();
+      let mut value = serde_json::to_value(stat).unwrap();
+      value["avg_micros"] = avg_micros.into();
+      (kw, value)
+    })
+    .collect();
+  Some(
+    Response::builder()
+      .header("X-EZ-Made-This", "true")
+      .header(CONTENT_TYPE, "application/json")
+      .status(200)
+      .body(Body::from(serde_json::to_string(&stats).unwrap())),
+  )
+}
+
+static HEALTH_BODY: &str = r#"{"status":"ok"}"#;
+
+/// Answers liveness and readiness probes sent to the health check path without consulting the
+/// redirector. Returns `None` for every other request.
+fn health_check(context: &AppContext, req: &Request<Body>) -> Option<http::Result<Response<Body>>> {
+  if req.uri().path() != &*context.health_path {
+    return None;
+  }
+  Some(
+    Response::builder()
+      .header("X-EZ-Made-This", "true")
+      .header(CONTENT_TYPE, "application/json")
+      .status(200)
+      .body(Body::from(HEALTH_BODY)),
+  )
+}
+
+/// Answers `req` as the ezproxy server does: health checks, metrics and stats on their paths, and
+/// a redirect for everything else, with CORS headers for the origins allowed by
+/// [`AppContext::with_cors`]. Plug it into a hyper service to serve ezproxy alongside other
+/// routes; see `examples/embed.rs`.
+///
+/// # Examples
+///
+/// ```
+/// use ezproxy::config::ConfigRule;
+/// use ezproxy::redirector::Redirector;
+/// use ezproxy::server::{handle, AppContext};
+/// use hyper::{Body, Request};
+///
+/// let redirector = Redirector::builder()
+///   .with_rule("m", ConfigRule::new("m", "https://gmail.com/"))
+///   .build();
+/// let context = AppContext::new(redirector);
+/// # tokio::runtime::Runtime::new().unwrap().block_on(async {
+/// let req = Request::get("/?q=m").body(Body::empty()).unwrap();
+/// let resp = handle(context, req).await.unwrap();
+/// assert_eq!(resp.status(), 302);
+/// assert_eq!(resp.headers()["Location"], "https://gmail.com/");
+/// # });
+/// ```
+pub async fn handle(context: AppContext, req: Request<Body>) -> http::Result<Response<Body>> {
+  if !context.cors.allows_any() {
+    return answer(context, req).await;
+  }
+  let allow_origin = match context.cors.allow_origin(req.headers().get(ORIGIN)) {
+    Some(allow_origin) => allow_origin,
+    None => return answer(context, req).await,
+  };
+  let preflight =
+    req.method() == Method::OPTIONS && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD);
+  let mut response = if preflight {
+    Response::builder()
+      .header("X-EZ-Made-This", "true")
+      .status(204)
+      .body(Body::empty())?
+  } else {
+    answer(context, req).await?
+  };
+  let headers = response.headers_mut();
+  if allow_origin != "*" {
+    headers.append(VARY, HeaderValue::from_static("Origin"));
+  }
+  headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+  headers.insert(
+    ACCESS_CONTROL_ALLOW_METHODS,
+    HeaderValue::from_static(Cors::ALLOW_METHODS),
+  );
+  headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from(Cors::MAX_AGE));
+  Ok(response)
+}
+
+/// Answers `req`, for [`handle`] to add CORS headers to.
+async fn answer(context: AppContext, mut req: Request<Body>) -> http::Result<Response<Body>> {
+  let request_id = get_request_uid();
+  let received = Instant::now();
+  crate::time_request!(&request_id, {
+    let endpoint_response = ip_filter(&context, &req, &request_id)
+      .or_else(|| authenticate(&context, &req, &request_id))
+      .or_else(|| health_check(&context, &req))
+      .or_else(|| metrics_endpoint(&context, &req))
+      .or_else(|| stats_endpoint(&context, &req))
+      .or_else(|| rate_limit(&context, &req, &request_id));
+    let mut form = None;
+    let endpoint_response = match endpoint_response {
+      None => match read_form(&context, &mut req, &request_id).await {
+        Ok(body) => {
+          form = body;
+          None
+        }
+        Err(response) => Some(response),
+      },
+      response => response,
+    };
+    let response = match endpoint_response {
+      Some(response) => response,
+      None => {
+        let start = Instant::now();
+        let redirector = context.redirector.load_full();
+        let uri = uri_from_conn(&mut req);
+        let evaluated = match &form {
+          Some(form) => {
+            redirector
+              .evaluate_params_async(&querystring::querify(form))
+              .await
+          }
+          None => redirector.evaluate_async(&uri).await,
+        };
+        let eval_result = match evaluated {
+          Ok(resolution) => {
+            tracing::info!(
+                target: "ezproxy::handle",
+                request_id = %request_id,
+                uri = %uri,
+                rule = %resolution.keyword,
+                location = %resolution.location,
+                status = resolution.status,
+                "Returning uri {} (rule took {}micros)",
+                resolution.location,
+                resolution.elapsed.as_micros()
+            );
+            Ok(resolution)
+          }
+          Err(e) => {
+            tracing::error!(
+                target: "ezproxy::handle",
+                request_id = %request_id,
+                uri = %uri,
+                "Error evaluating request: {}",
+                e
+            );
+            Err(e)
+          }
+        };
+        let rule = eval_result
+          .as_ref()
+          .map_or(NO_RULE_LABEL.to_string(), |r| r.keyword.clone());
+        let response = somehow_make_response(eval_result, context.hide_errors);
+        if let Ok(resp) = &response {
+          context
+            .metrics
+            .observe_request(&rule, resp.status().as_u16(), start.elapsed());
+        }
+        response
+      }
+    };
+    let response = if req.method() == Method::HEAD {
+      // Same headers as GET, but HEAD responses must never carry a body.
+      response.map(|resp| {
+        let (mut parts, _) = resp.into_parts();
+        parts.headers.insert(CONTENT_LENGTH, HeaderValue::from(0));
+        Response::from_parts(parts, Body::empty())
+      })
+    } else {
+      response
+    };
+    if let (Some(access_log), Ok(resp)) = (&context.access_log, &response) {
+      access_log.record(&req, resp, received.elapsed());
+    }
+    response
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::test_utils::{body_string, context, rules};
+  use super::*;
+  use crate::config::ConfigRule;
+  use crate::rules::RuleRegistry;
+  use prometheus::TEXT_FORMAT;
+
+  #[tokio::test]
+  async fn redirect_keeps_fragment() {
+    let req = Request::get("/?q=rt").body(Body::empty()).unwrap();
+    let resp = handle(
+      context(&[(
+        "rt",
+        "https://docs.rs/tokio/latest/tokio/runtime/index.html#struct.Runtime",
+      )]),
+      req,
+    )
+    .await
+    .unwrap();
+    assert_eq!(resp.status(), 302);
+    assert_eq!(
+      resp.headers()["Location"],
+      "https://docs.rs/tokio/latest/tokio/runtime/index.html#struct.Runtime"
+    );
+  }
+
+  #[tokio::test]
+  async fn redirect_uses_rule_status_code() {
+    let mut rules = RuleRegistry::new();
+    rules.insert(
+      "m".to_string(),
+      Box::new(ConfigRule::new("m", "https://gmail.com/").with_status_code(301)),
+    );
+    let context = AppContext::new(Redirector::with_rules(rules, None));
+    let req = Request::get("/?q=m").body(Body::empty()).unwrap();
+    let resp = handle(context, req).await.unwrap();
+    assert_eq!(resp.status(), 301);
+    assert_eq!(resp.headers()["Location"], "https://gmail.com/");
+  }
+
+  #[tokio::test]
+  async fn swapped_redirector_applies_to_later_requests() {
+    let context = context(&[("m", "https://gmail.com/")]);
+    context.redirector.store(Arc::new(Redirector::with_rules(
+      rules(&[("m", "https://mail.yahoo.com/")]),
+      None,
+    )));
+    let req = Request::get("/?q=m").body(Body::empty()).unwrap();
+    let resp = handle(context, req).await.unwrap();
+    assert_eq!(resp.headers()["Location"], "https://mail.yahoo.com/");
+  }
+
+  #[tokio::test]
+  async fn health_check_skips_redirector() {
+    let req = Request::get("/health").body(Body::empty()).unwrap();
+    let resp = handle(context(&[]), req).await.unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()[CONTENT_TYPE], "application/json");
+    assert_eq!(body_string(resp).await, r#"{"status":"ok"}"#);
+
+    let context = context(&[("healthz", "https://example.com/")]).with_health_path("/healthz");
+    let req = Request::head("/healthz").body(Body::empty()).unwrap();
+    let resp = handle(context.clone(), req).await.unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(body_string(resp).await, "");
+
+    // Without a query, /health is just a malformed shortcut request.
+    let req = Request::get("/health").body(Body::empty()).unwrap();
+    let resp = handle(context, req).await.unwrap();
+    assert_eq!(resp.status(), 400);
+  }
+
+  #[tokio::test]
+  async fn metrics_count_requests_by_rule_and_status() {
+    let context = context(&[("m", "https://gmail.com/")]);
+    for q in ["/?q=m", "/?q=m", "/?q=nope"] {
+      let req = Request::get(q).body(Body::empty()).unwrap();
+      handle(context.clone(), req).await.unwrap();
+    }
+    context.metrics.observe_reload(true);
+
+    let req = Request::get("/metrics").body(Body::empty()).unwrap();
+    let resp = handle(context, req).await.unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()[CONTENT_TYPE], TEXT_FORMAT);
+    let body = body_string(resp).await;
+    assert!(
+      body.contains(r#"ezproxy_requests_total{rule="m",status="302"} 2"#),
+      "{}",
+      body
+    );
+    assert!(
+      body.contains(r#"ezproxy_requests_total{rule="none",status="500"} 1"#),
+      "{}",
+      body
+    );
+    assert!(
+      body.contains("ezproxy_request_duration_seconds_count 3"),
+      "{}",
+      body
+    );
+    assert!(
+      body.contains(r#"ezproxy_config_reloads_total{result="success"} 1"#),
+      "{}",
+      body
+    );
+  }
+
+  #[tokio::test]
+  async fn stats_report_rule_usage() {
+    let context = context(&[("m", "https://gmail.com/")]);
+    for q in ["/?q=m", "/?q=m"] {
+      let req = Request::get(q).body(Body::empty()).unwrap();
+      handle(context.clone(), req).await.unwrap();
+    }
+
+    let req = Request::get("/stats").body(Body::empty()).unwrap();
+    let resp = handle(context, req).await.unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()[CONTENT_TYPE], "application/json");
+    let stats: serde_json::Value = serde_json::from_str(&body_string(resp).await).unwrap();
+    assert_eq!(stats["m"]["hits"], 2);
+    assert_eq!(stats["m"]["errors"], 0);
+    assert!(stats["m"]["avg_micros"].is_u64());
+  }
+
+  #[tokio::test]
+  async fn head_request_redirects_without_body() {
+    let req = Request::head("/?q=m").body(Body::empty()).unwrap();
+    let resp = handle(context(&[("m", "https://gmail.com/")]), req)
+      .await
+      .unwrap();
+    assert_eq!(resp.status(), 302);
+    assert_eq!(resp.headers()["Location"], "https://gmail.com/");
+    assert_eq!(resp.headers()[CONTENT_LENGTH], "0");
+    assert_eq!(body_string(resp).await, "");
+  }
+
+  #[tokio::test]
+  async fn head_request_error_has_no_body() {
+    let req = Request::head("/?q=nope").body(Body::empty()).unwrap();
+    let resp = handle(context(&[("m", "https://gmail.com/")]), req)
+      .await
+      .unwrap();
+    assert_eq!(resp.status(), 500);
+    assert_eq!(resp.headers()[CONTENT_LENGTH], "0");
+    assert_eq!(body_string(resp).await, "");
+  }
+
+  #[tokio::test]
+  async fn access_log_records_requests_and_reopens() {
+    let dir = assert_fs::TempDir::new().unwrap();
+    let path = dir.path().join("access.log");
+    let access_log = AccessLog::open(path.clone()).await.unwrap();
+    let context = context(&[("m", "https://gmail.com/")]).with_access_log(access_log.clone());
+
+    let read_lines = |path: PathBuf, count: usize| async move {
+      for _ in 0..50 {
+        let contents = std::fs::read_to_string(&path).unwrap_or_default();
+        if contents.lines().count() >= count {
+          return contents;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+      }
+      panic!("Expected {} lines in {}", count, path.display());
+    };
+
+    let req = Request::get("/?q=m").body(Body::empty()).unwrap();
+    handle(context.clone(), req).await.unwrap();
+    let req = Request::head("/health").body(Body::empty()).unwrap();
+    handle(context.clone(), req).await.unwrap();
+    let contents = read_lines(path.clone(), 2).await;
+    let lines: Vec<Vec<&str>> = contents.lines().map(|l| l.split(' ').collect()).collect();
+    assert_eq!(
+      lines[0][1..5],
+      ["GET", "/?q=m", "302", "https://gmail.com/"]
+    );
+    assert_eq!(lines[1][1..5], ["HEAD", "/health", "200", "-"]);
+    assert!(lines[0][0].ends_with('Z'), "{}", lines[0][0]);
+    assert!(lines[0][5].parse::<f64>().is_ok());
+
+    // Rotate the log, as logrotate would.
+    let rotated = dir.path().join("access.log.1");
+    std::fs::rename(&path, &rotated).unwrap();
+    access_log.reopen_file();
+    let req = Request::get("/?q=nope").body(Body::empty()).unwrap();
+    handle(context, req).await.unwrap();
+    let contents = read_lines(path, 1).await;
+    assert!(contents.contains(" GET /?q=nope 500 - "), "{}", contents);
+    assert_eq!(std::fs::read_to_string(rotated).unwrap().lines().count(), 2);
+  }
+
+  #[tokio::test]
+  async fn rate_limit_applies_per_client() {
+    let context = context(&[("m", "https://gmail.com/")])
+      .with_rate_limiter(RateLimiter::new(1, Duration::from_secs(60)));
+    let request = |uri: &str, client: [u8; 4], forwarded_for: Option<&str>| {
+      let mut req = Request::get(uri);
+      if let Some(forwarded_for) = forwarded_for {
+        req = req.header("X-Forwarded-For", forwarded_for);
+      }
+      let mut req = req.body(Body::empty()).unwrap();
+      req
+        .extensions_mut()
+        .insert(RemoteAddr(SocketAddr::from((client, 54321))));
+      req
+    };
+
+    let resp = handle(context.clone(), request("/?q=m", [10, 0, 0, 1], None))
+      .await
+      .unwrap();
+    assert_eq!(resp.status(), 302);
+    let resp = handle(context.clone(), request("/?q=m", [10, 0, 0, 1], None))
+      .await
+      .unwrap();
+    assert_eq!(resp.status(), 429);
+    assert_eq!(resp.headers()[RETRY_AFTER], "1");
+    // Other clients, and endpoints other than redirects, aren't affected.
+    let resp = handle(context.clone(), request("/health", [10, 0, 0, 1], None))
+      .await
+      .unwrap();
+    assert_eq!(resp.status(), 200);
+    let resp = handle(context.clone(), request("/?q=m", [10, 0, 0, 2], None))
+      .await
+      .unwrap();
+    assert_eq!(resp.status(), 302);
+
+    // X-Forwarded-For is ignored unless the proxy is trusted.
+    let forwarded = Some("10.0.0.1, 192.0.2.1");
+    let resp = handle(context.clone(), request("/?q=m", [10, 0, 0, 3], forwarded))
+      .await
+      .unwrap();
+    assert_eq!(resp.status(), 302);
+    let context = context.with_trust_proxy(true);
+    let resp = handle(context.clone(), request("/?q=m", [10, 0, 0, 1], forwarded))
+      .await
+      .unwrap();
+    assert_eq!(resp.status(), 302);
+    let resp = handle(context.clone(), request("/?q=m", [10, 0, 0, 4], forwarded))
+      .await
+      .unwrap();
+    assert_eq!(resp.status(), 429);
+    // A client can't pass for another by sending its own X-Forwarded-For, since the proxy
+    // appends the address it saw.
+    let spoofed = Some("203.0.113.9, 192.0.2.1");
+    let resp = handle(context.clone(), request("/?q=m", [10, 0, 0, 4], spoofed))
+      .await
+      .unwrap();
+    assert_eq!(resp.status(), 429);
+
+    // Without a known address, requests aren't limited.
+    for _ in 0..3 {
+      let req = Request::get("/?q=m").body(Body::empty()).unwrap();
+      assert_eq!(handle(context.clone(), req).await.unwrap().status(), 302);
+    }
+  }
+
+  #[tokio::test]
+  async fn ip_filter_refuses_clients() {
+    let filter = IpFilter::new()
+      .with_allowed("10.0.0.0/8".parse().unwrap())
+      .with_allowed("::1/128".parse().unwrap())
+      .with_denied("10.0.0.13/32".parse().unwrap());
+    let context = context(&[("m", "https://gmail.com/")]).with_ip_filter(filter);
+    let status = |uri: &str, client: Option<IpAddr>| {
+      let mut req = Request::get(uri).body(Body::empty()).unwrap();
+      if let Some(client) = client {
+        req
+          .extensions_mut()
+          .insert(RemoteAddr(SocketAddr::new(client, 54321)));
+      }
+      let context = context.clone();
+      async move { handle(context, req).await.unwrap().status() }
+    };
+
+    assert_eq!(
+      status("/?q=m", Some(IpAddr::from([10, 0, 0, 1]))).await,
+      302
+    );
+    assert_eq!(status("/?q=m", Some("::1".parse().unwrap())).await, 302);
+    assert_eq!(
+      status("/?q=m", Some("::ffff:10.0.0.1".parse().unwrap())).await,
+      302
+    );
+    assert_eq!(
+      status("/?q=m", Some(IpAddr::from([10, 0, 0, 13]))).await,
+      403
+    );
+    assert_eq!(
+      status("/health", Some(IpAddr::from([192, 0, 2, 1]))).await,
+      403
+    );
+    assert_eq!(status("/?q=m", None).await, 403);
+  }
+
+  #[tokio::test]
+  async fn basic_auth_guards_requests() {
+    let context =
+      context(&[("m", "https://gmail.com/")]).with_basic_auth(BasicAuth::new("travis", "hunter2"));
+    let status = |context: AppContext, uri: &str, authorization: Option<&str>| {
+      let mut req = Request::get(uri);
+      if let Some(authorization) = authorization {
+        req = req.header(AUTHORIZATION, authorization);
+      }
+      let req = req.body(Body::empty()).unwrap();
+      async move { handle(context, req).await.unwrap() }
+    };
+    // "travis:hunter2" and "travis:hunter3", base64-encoded.
+    let good = Some("Basic dHJhdmlzOmh1bnRlcjI=");
+    let bad = Some("Basic dHJhdmlzOmh1bnRlcjM=");
+
+    let resp = status(context.clone(), "/?q=m", None).await;
+    assert_eq!(resp.status(), 401);
+    assert_eq!(resp.headers()[WWW_AUTHENTICATE], r#"Basic realm="ezproxy""#);
+    assert_eq!(status(context.clone(), "/?q=m", bad).await.status(), 401);
+    assert_eq!(status(context.clone(), "/?q=m", good).await.status(), 302);
+    assert_eq!(status(context.clone(), "/health", None).await.status(), 401);
+
+    let context = context.with_public_health(true);
+    assert_eq!(status(context.clone(), "/health", None).await.status(), 200);
+    assert_eq!(
+      status(context.clone(), "/metrics", None).await.status(),
+      200
+    );
+    assert_eq!(status(context.clone(), "/stats", None).await.status(), 401);
+    assert_eq!(status(context, "/?q=m", None).await.status(), 401);
+  }
+
+  #[tokio::test]
+  async fn failed_logins_are_rate_limited() {
+    let context = context(&[("m", "https://gmail.com/")])
+      .with_basic_auth(BasicAuth::new("travis", "hunter2"))
+      .with_rate_limiter(RateLimiter::new(1, Duration::from_secs(60)));
+    let request = |authorization: &str| {
+      let mut req = Request::get("/?q=m")
+        .header(AUTHORIZATION, authorization)
+        .body(Body::empty())
+        .unwrap();
+      req
+        .extensions_mut()
+        .insert(RemoteAddr(SocketAddr::from(([10, 0, 0, 1], 54321))));
+      req
+    };
+    // "travis:hunter3", base64-encoded.
+    let bad = "Basic dHJhdmlzOmh1bnRlcjM=";
+
+    let resp = handle(context.clone(), request(bad)).await.unwrap();
+    assert_eq!(resp.status(), 401);
+    let resp = handle(context.clone(), request(bad)).await.unwrap();
+    assert_eq!(resp.status(), 429);
+    assert_eq!(resp.headers()[RETRY_AFTER], "1");
+  }
+
+  #[tokio::test]
+  async fn cors_headers_for_allowed_origins() {
+    let context = context(&[("m", "https://gmail.com/")]);
+    let request = |method: Method, origin: Option<&str>| {
+      let mut req = Request::builder().method(method).uri("/?q=m");
+      if let Some(origin) = origin {
+        req = req
+          .header(ORIGIN, origin)
+          .header(ACCESS_CONTROL_REQUEST_METHOD, "GET");
+      }
+      req.body(Body::empty()).unwrap()
+    };
+
+    // Without any allowed origins, nothing changes.
+    let resp = handle(
+      context.clone(),
+      request(Method::GET, Some("https://example.com")),
+    )
+    .await
+    .unwrap();
+    assert_eq!(resp.status(), 302);
+    assert!(!resp.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+
+    let context = context.with_cors(Cors::new(["https://example.com"]));
+    let resp = handle(
+      context.clone(),
+      request(Method::OPTIONS, Some("https://example.com")),
+    )
+    .await
+    .unwrap();
+    assert_eq!(resp.status(), 204);
+    assert_eq!(
+      resp.headers()[ACCESS_CONTROL_ALLOW_ORIGIN],
+      "https://example.com"
+    );
+    assert_eq!(resp.headers()[ACCESS_CONTROL_ALLOW_METHODS], "GET, POST");
+    assert_eq!(resp.headers()[ACCESS_CONTROL_MAX_AGE], "86400");
+    assert_eq!(resp.headers()[VARY], "Origin");
+
+    let resp = handle(
+      context.clone(),
+      request(Method::GET, Some("https://example.com")),
+    )
+    .await
+    .unwrap();
+    assert_eq!(resp.status(), 302);
+    assert_eq!(
+      resp.headers()[ACCESS_CONTROL_ALLOW_ORIGIN],
+      "https://example.com"
+    );
+
+    for origin in [Some("https://evil.com"), None] {
+      let resp = handle(context.clone(), request(Method::GET, origin))
+        .await
+        .unwrap();
+      assert!(!resp.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+
+    let context = context.with_cors(Cors::new(["*"]));
+    let resp = handle(context, request(Method::GET, Some("https://evil.com")))
+      .await
+      .unwrap();
+    assert_eq!(resp.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+    assert!(!resp.headers().contains_key(VARY));
+  }
+
+  #[tokio::test]
+  async fn redirect_has_html_body() {
+    let req = Request::get("/?q=npm+a%26b").body(Body::empty()).unwrap();
+    let resp = handle(
+      context(&[("npm", "https://npmjs.com/search?q={ARGS}&page=1")]),
+      req,
+    )
+    .await
+    .unwrap();
+    assert_eq!(resp.status(), 302);
+    assert_eq!(resp.headers()[CONTENT_TYPE], "text/html; charset=utf-8");
+    assert_eq!(
+      body_string(resp).await,
+      r#"<html><head><meta http-equiv="refresh" content="0;url=https://npmjs.com/search?q=a%26b&amp;page=1"></head><body><a href="https://npmjs.com/search?q=a%26b&amp;page=1">Click here</a></body></html>"#
+    );
+  }
+
+  #[tokio::test]
+  async fn posted_forms_are_evaluated() {
+    let post = |body: &'static str| {
+      Request::post("/")
+        .header(
+          CONTENT_TYPE,
+          "application/x-www-form-urlencoded; charset=UTF-8",
+        )
+        .body(Body::from(body))
+        .unwrap()
+    };
+    let context = context(&[("npm", "https://npmjs.com/search?q={ARGS}")]);
+    let resp = handle(context.clone(), post("lang=en&q=npm+file+finder"))
+      .await
+      .unwrap();
+    assert_eq!(resp.status(), 302);
+    assert_eq!(
+      resp.headers()["Location"],
+      "https://npmjs.com/search?q=file%20finder"
+    );
+
+    let resp = handle(context.clone(), post("lang=en")).await.unwrap();
+    assert_eq!(resp.status(), 400);
+
+    let context = context.with_max_body_size(8);
+    let resp = handle(context.clone(), post("q=npm+file+finder"))
+      .await
+      .unwrap();
+    assert_eq!(resp.status(), 413);
+
+    // Other bodies are ignored, and the command is read from the query as usual.
+    let req = Request::post("/?q=npm+tokio")
+      .header(CONTENT_TYPE, "text/plain")
+      .body(Body::from("q=npm+file+finder"))
+      .unwrap();
+    let resp = handle(context, req).await.unwrap();
+    assert_eq!(
+      resp.headers()["Location"],
+      "https://npmjs.com/search?q=tokio"
+    );
+  }
+
+  #[tokio::test]
+  async fn make_response_shows_errors_by_default() {
+    let error = EvaluateError::NoRule {
+      command: "m".to_string(),
+    };
+    let resp = somehow_make_response(Err(error), false).unwrap();
+    assert_eq!(resp.status(), 500);
+    assert_eq!(
+      body_string(resp).await,
+      "Could not find rule for cmd m, and no default given"
+    );
+  }
+
+  #[tokio::test]
+  async fn make_response_hides_errors() {
+    let error = EvaluateError::NoRule {
+      command: "m".to_string(),
+    };
+    let resp = somehow_make_response(Err(error), true).unwrap();
+    assert_eq!(resp.status(), 500);
+    assert_eq!(body_string(resp).await, GENERIC_ERROR_MESSAGE);
+
+    let error = EvaluateError::InvalidCommand("Missing q".to_string());
+    let resp = somehow_make_response(Err(error), true).unwrap();
+    assert_eq!(resp.status(), 400);
+    assert_eq!(body_string(resp).await, GENERIC_CLIENT_ERROR_MESSAGE);
+  }
+
+  #[tokio::test]
+  async fn malformed_requests_are_client_errors() {
+    let context = context(&[("gh", "https://github.com/{ARG1}")]);
+    for (uri, status) in [
+      ("/", 400),
+      ("/?search=gh", 400),
+      ("/?q=m", 500),
+      ("/?q=gh", 500),
+    ] {
+      let req = Request::get(uri).body(Body::empty()).unwrap();
+      let resp = handle(context.clone(), req).await.unwrap();
+      assert_eq!(resp.status(), status, "{}", uri);
+    }
+  }
+}
@@ -0,0 +1,38 @@
+//! Fixtures shared by ezproxy's own unit tests and the binary's tests. Only available with the
+//! `test-utils` feature.
+//!
+//! Not part of the public API: these may change or go away in any release. Use
+//! [`MockRule`](crate::rules::test_utils::MockRule) instead.
+
+use super::AppContext;
+use crate::config::ConfigRule;
+use crate::redirector::Redirector;
+use crate::rules::{Rule, RuleRegistry};
+use hyper::{Body, Response};
+
+/// Builds a registry of [`ConfigRule`]s from `(keyword, target)` pairs, in order.
+pub fn rules(config: &[(&str, &str)]) -> RuleRegistry {
+  config
+    .iter()
+    .map(|(kw, uri)| {
+      (
+        kw.to_string(),
+        Box::new(ConfigRule::new(*kw, *uri)) as Box<dyn Rule>,
+      )
+    })
+    .collect()
+}
+
+/// Creates a context serving the rules built by [`rules`] from `(keyword, target)` pairs, with
+/// every other option left at its default.
+pub fn context(config: &[(&str, &str)]) -> AppContext {
+  AppContext::new(Redirector::with_rules(rules(config), None))
+}
+
+/// Reads all of `resp`'s body as UTF-8.
+///
+/// Panics if the body can't be read or isn't UTF-8.
+pub async fn body_string(resp: Response<Body>) -> String {
+  let bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+  String::from_utf8(bytes.to_vec()).unwrap()
+}
@@ -0,0 +1,175 @@
+//! Helpers shared by the integration tests.
+
+use assert_fs::prelude::*;
+use assert_fs::NamedTempFile;
+use ezproxy::redirector::Redirector;
+use ezproxy::server::{handle, AppContext};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Client, Server};
+use std::convert::Infallible;
+use std::fs;
+use std::net::{SocketAddr, TcpListener};
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+/// What's answering a [`TestServer`]'s requests.
+enum Backend {
+  /// A task on the test's runtime, which stops when the sender is dropped or sent to.
+  InProcess(Option<oneshot::Sender<()>>),
+  /// The ezproxy binary, serving the rules in `config`.
+  Binary {
+    process: Child,
+    config: NamedTempFile,
+  },
+}
+
+/// Serves a redirector for as long as it's in scope, either in-process on a background task or
+/// by running the ezproxy binary. Either way it's accepting connections by the time it's
+/// returned, so requests can be sent right away.
+///
+/// Must be started from within a Tokio runtime, e.g. in a `#[tokio::test]`.
+pub struct TestServer {
+  addr: SocketAddr,
+  backend: Backend,
+}
+
+impl TestServer {
+  /// Serves `redirector` in-process on a free port on localhost.
+  pub fn start(redirector: Redirector) -> Self {
+    Self::start_on(SocketAddr::from(([127, 0, 0, 1], 0)), redirector)
+  }
+
+  /// Serves `redirector` in-process on `addr`. With port 0, the OS picks a free port, which
+  /// [`TestServer::base_url`] reports.
+  pub fn start_on(addr: SocketAddr, redirector: Redirector) -> Self {
+    let context = AppContext::new(redirector);
+    let make_svc = make_service_fn(move |_conn| {
+      let context = context.clone();
+      async move { Ok::<_, Infallible>(service_fn(move |req| handle(context.clone(), req))) }
+    });
+    let server = Server::bind(&addr).serve(make_svc);
+    let addr = server.local_addr();
+    let (shutdown, stopped) = oneshot::channel();
+    tokio::spawn(server.with_graceful_shutdown(async {
+      stopped.await.ok();
+    }));
+    TestServer {
+      addr,
+      backend: Backend::InProcess(Some(shutdown)),
+    }
+  }
+
+  /// Runs the ezproxy binary on a free port on localhost, serving the rules in a config file
+  /// holding `content`, which [`TestServer::reload_config`] can rewrite.
+  pub async fn start_binary(content: &str) -> Self {
+    let config = NamedTempFile::new("config.txt").unwrap();
+    config.write_str(content).unwrap();
+    // The binary can't report the port it bound, so pick one that's free now and try another
+    // if it's taken by the time the binary binds it.
+    for _ in 0..5 {
+      let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+      let mut process = Command::new(env!("CARGO_BIN_EXE_ezproxy"))
+        .arg("--port")
+        .arg(port.to_string())
+        .arg(config.path())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+      let addr = SocketAddr::from(([127, 0, 0, 1], port));
+      for _ in 0..100 {
+        if process.try_wait().unwrap().is_some() {
+          break;
+        }
+        if tokio::net::TcpStream::connect(addr).await.is_ok() {
+          return TestServer {
+            addr,
+            backend: Backend::Binary { process, config },
+          };
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+      }
+      let _ = process.kill();
+      let _ = process.wait();
+    }
+    panic!("Could not start the ezproxy binary");
+  }
+
+  /// The URL the server is reachable at, without a trailing slash, e.g. `http://127.0.0.1:5050`.
+  pub fn base_url(&self) -> String {
+    format!("http://{}", self.addr)
+  }
+
+  /// Replaces the config file with `new_content` and sends the binary SIGHUP to reload it,
+  /// returning once the reload has finished.
+  ///
+  /// Panics if the server wasn't started with [`TestServer::start_binary`], or if the reload
+  /// fails.
+  #[cfg(unix)]
+  pub async fn reload_config(&self, new_content: &str) {
+    let (process, config) = match &self.backend {
+      Backend::Binary { process, config } => (process, config),
+      Backend::InProcess(_) => panic!("Only the binary reloads its config"),
+    };
+    let reloads = |metrics: &str, result: &str| {
+      let prefix = format!("ezproxy_config_reloads_total{{result=\"{}\"}} ", result);
+      metrics
+        .lines()
+        .find_map(|line| line.strip_prefix(&prefix))
+        .map_or(0, |count| count.parse::<u64>().unwrap())
+    };
+    let succeeded = reloads(&self.metrics().await, "success");
+
+    // Write beside the config and rename over it, so the reload never sees a partial file.
+    let staged = config.path().with_extension("new");
+    fs::write(&staged, new_content).unwrap();
+    fs::rename(&staged, config.path()).unwrap();
+    let status = Command::new("kill")
+      .arg("-HUP")
+      .arg(process.id().to_string())
+      .status()
+      .unwrap();
+    assert!(status.success());
+
+    for _ in 0..100 {
+      let metrics = self.metrics().await;
+      assert_eq!(reloads(&metrics, "error"), 0, "Could not reload the config");
+      if reloads(&metrics, "success") > succeeded {
+        return;
+      }
+      tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("The config wasn't reloaded");
+  }
+
+  /// Fetches the server's metrics, in the Prometheus text format.
+  #[cfg(unix)]
+  async fn metrics(&self) -> String {
+    let uri = format!("{}/metrics", self.base_url()).parse().unwrap();
+    let resp = Client::new().get(uri).await.unwrap();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    String::from_utf8(body.to_vec()).unwrap()
+  }
+}
+
+impl Drop for TestServer {
+  fn drop(&mut self) {
+    match &mut self.backend {
+      Backend::InProcess(shutdown) => {
+        if let Some(shutdown) = shutdown.take() {
+          // The server may already have stopped if its runtime is shutting down.
+          let _ = shutdown.send(());
+        }
+      }
+      Backend::Binary { process, .. } => {
+        let _ = process.kill();
+        let _ = process.wait();
+      }
+    }
+  }
+}
@@ -1,56 +1,28 @@
+mod common;
+
 use assert_fs::prelude::*;
-use duct;
+use common::TestServer;
+use ezproxy::config::{parse_rules_from, parse_rules_from_str};
+use ezproxy::redirector::Redirector;
 use hyper::Client;
-use scopeguard;
 use std::net::SocketAddr;
 use std::net::TcpListener;
-use std::thread;
-use std::time;
-use tokio;
-
-fn assert_free_port() -> u16 {
-  (1025..65535)
-    .find(
-      |port| match TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], *port))) {
-        Ok(_) => true,
-        Err(_) => false,
-      },
-    )
-    .expect("No free available ports!")
-}
 
 #[tokio::test]
 async fn test_ezproxy() {
-  static CONFIG: &'static str = r#"
+  static CONFIG: &str = r#"
 m = https://gmail.com/
 npm = https://npmjs.com/search?q={ARGS}
 _ = https://www.google.com/search?q={ALL}
   "#;
 
-  let config_file = assert_fs::NamedTempFile::new("config.txt").unwrap();
-  let config_file = scopeguard::guard(config_file, |f| {
-    f.close().unwrap();
-  });
-  config_file.write_str(CONFIG).unwrap();
-
-  let port = assert_free_port();
-  let handle = duct::cmd!(
-    "cargo",
-    "run",
-    "--release",
-    "--",
-    "--port",
-    format!("{}", port),
-    config_file.path(),
-  )
-  .start()
-  .unwrap();
-
-  println!("Hackily sleeping to wait for server startup");
-  thread::sleep(time::Duration::from_secs(1));
+  let server = TestServer::start(Redirector::with_rules(
+    parse_rules_from_str(CONFIG).unwrap(),
+    None,
+  ));
 
   let client = Client::new();
-  let uri = format!("http://localhost:{}/?q=m", port).parse().unwrap();
+  let uri = format!("{}/?q=m", server.base_url()).parse().unwrap();
   let resp = client.get(uri).await.unwrap();
 
   assert_eq!(resp.status(), 302);
@@ -62,7 +34,7 @@ _ = https://www.google.com/search?q={ALL}
     "https://gmail.com/"
   );
 
-  let uri = format!("http://localhost:{}/?q=npm%20file%20finder", port)
+  let uri = format!("{}/?q=npm%20file%20finder", server.base_url())
     .parse()
     .unwrap();
   let resp = client.get(uri).await.unwrap();
@@ -74,7 +46,7 @@ _ = https://www.google.com/search?q={ALL}
     "https://npmjs.com/search?q=file%20finder"
   );
 
-  let uri = format!("http://localhost:{}/?q=best%20restaurants%20nyc", port)
+  let uri = format!("{}/?q=best%20restaurants%20nyc", server.base_url())
     .parse()
     .unwrap();
   let resp = client.get(uri).await.unwrap();
@@ -85,6 +57,87 @@ _ = https://www.google.com/search?q={ALL}
       .expect("Expected Location Header"),
     "https://www.google.com/search?q=best%20restaurants%20nyc"
   );
+}
+
+#[tokio::test]
+async fn test_ipv6() {
+  if let Err(e) = TcpListener::bind("[::1]:0") {
+    println!("Skipping IPv6 test, since [::1] isn't available: {}", e);
+    return;
+  }
+  let server = TestServer::start_on(
+    SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], 0)),
+    Redirector::with_rules(
+      parse_rules_from_str("m = https://gmail.com/\n").unwrap(),
+      None,
+    ),
+  );
+
+  let uri = format!("{}/?q=m", server.base_url()).parse().unwrap();
+  assert!(server.base_url().starts_with("http://[::1]:"));
+  let resp = Client::new().get(uri).await.unwrap();
+  assert_eq!(resp.status(), 302);
+  assert_eq!(
+    resp
+      .headers()
+      .get("Location")
+      .expect("Expected Location Header"),
+    "https://gmail.com/"
+  );
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_reload_on_hangup() {
+  let server = TestServer::start_binary("m = https://gmail.com/\n").await;
+  let client = Client::new();
+  let location = |resp: hyper::Response<hyper::Body>| {
+    resp
+      .headers()
+      .get("Location")
+      .expect("Expected Location Header")
+      .to_str()
+      .unwrap()
+      .to_string()
+  };
+  let uri = || format!("{}/?q=m", server.base_url()).parse().unwrap();
+  assert_eq!(
+    location(client.get(uri()).await.unwrap()),
+    "https://gmail.com/"
+  );
+
+  server.reload_config("m = https://mail.yahoo.com/\n").await;
+  assert_eq!(
+    location(client.get(uri()).await.unwrap()),
+    "https://mail.yahoo.com/"
+  );
+}
 
-  handle.kill().unwrap();
+#[test]
+fn test_crlf_config() {
+  let config_file = assert_fs::NamedTempFile::new("config.txt").unwrap();
+  config_file
+    .write_str("# Windows config\r\nm = https://gmail.com/\r\n\r\nnpm = https://npmjs.com/search?q={ARGS}\r\n")
+    .unwrap();
+
+  let rules = parse_rules_from(config_file.path()).unwrap();
+  let mut keywords: Vec<&str> = rules.keywords().collect();
+  keywords.sort();
+  assert_eq!(keywords, vec!["m", "npm"]);
+
+  let redirector = Redirector::with_rules(rules, None);
+  let resolution = redirector
+    .evaluate(&"/?q=npm%20file%20finder".parse().unwrap())
+    .unwrap();
+  assert_eq!(
+    resolution.location,
+    "https://npmjs.com/search?q=file%20finder"
+  );
+  assert_eq!(
+    redirector
+      .evaluate(&"/?q=m".parse().unwrap())
+      .unwrap()
+      .location,
+    "https://gmail.com/"
+  );
 }
@@ -0,0 +1,39 @@
+use ezproxy::prelude::*;
+
+struct Echo;
+impl Rule for Echo {
+  fn produce_uri(&self, cmd: &str, args: &[String]) -> Result<hyper::Uri, RuleError> {
+    Ok(format!("https://example.com/{}?args={}", cmd, args.len()).parse()?)
+  }
+}
+
+#[test]
+fn test_prelude() {
+  let options = ParseOptions::default().on_duplicate(DuplicatePolicy::KeepFirst);
+  assert_eq!(options.duplicate_policy(), DuplicatePolicy::KeepFirst);
+
+  let mut rules = RuleRegistry::new();
+  rules.insert(
+    "m".to_string(),
+    Box::new(ConfigRule::new("m", "https://gmail.com/")),
+  );
+  rules.insert(DEFAULT_RULE_KEY.to_string(), Box::new(Echo));
+
+  let redirector =
+    Redirector::with_rules(rules, Some(std::sync::Arc::new(CommandParser::default())));
+  let resolution: Resolution = redirector.evaluate(&"/?q=m".parse().unwrap()).unwrap();
+  assert_eq!(resolution.location, "https://gmail.com/");
+  let resolution = redirector
+    .evaluate(&"/?q=best%20pizza".parse().unwrap())
+    .unwrap();
+  assert_eq!(resolution.location, "https://example.com/best?args=1");
+  assert!(matches!(
+    Redirector::with_rules(Default::default(), None).evaluate(&"/?q=m".parse().unwrap()),
+    Err(EvaluateError::NoRule { .. })
+  ));
+
+  let cmd: Command = CommandParser::default()
+    .parse(&"/?q=npm%20finder".parse().unwrap())
+    .unwrap();
+  assert_eq!(cmd.name, "npm");
+}